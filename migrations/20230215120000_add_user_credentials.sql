@@ -0,0 +1,12 @@
+-- Passwords, email verification and single use tokens for users
+alter table users add column if not exists password_hash text;
+alter table users add column if not exists verified boolean not null default false;
+
+create table if not exists _user_tokens (
+  id bigserial primary key,
+  user_id bigint not null references users(id) on delete cascade,
+  kind text not null,
+  token_hash text not null unique,
+  expires_at timestamptz not null,
+  created_at timestamptz not null default now()
+);
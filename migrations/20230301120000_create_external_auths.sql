@@ -0,0 +1,17 @@
+-- Identities from OAuth2 / OpenID Connect providers linked to users
+create table if not exists _external_auths (
+  id bigserial primary key,
+  user_id bigint not null references users(id) on delete cascade,
+  provider text not null,
+  subject text not null,
+  created_at timestamptz not null default now(),
+  unique(provider, subject)
+);
+
+-- Pending authorization code flows, keyed by the state parameter
+create table if not exists _oauth_states (
+  state text primary key,
+  provider text not null,
+  code_verifier text not null,
+  expires_at timestamptz not null
+);
@@ -0,0 +1,17 @@
+-- Administrators and TOTP based multi-factor authentication
+alter table users add column if not exists admin boolean not null default false;
+
+create table if not exists _user_mfa (
+  user_id bigint primary key references users(id) on delete cascade,
+  secret text not null,
+  enabled boolean not null default false,
+  last_used_step bigint,
+  created_at timestamptz not null default now()
+);
+
+create table if not exists _mfa_recovery_codes (
+  id bigserial primary key,
+  user_id bigint not null references users(id) on delete cascade,
+  code_hash text not null,
+  unique(user_id, code_hash)
+);
@@ -0,0 +1,13 @@
+-- Keys for server to server access, scoped to collections and operations
+create table if not exists _api_keys (
+  id bigserial primary key,
+  name text not null,
+  prefix text not null,
+  key_hash text not null unique,
+  scopes jsonb not null default '[]',
+  created_by bigint references users(id) on delete set null,
+  expires_at timestamptz,
+  last_used_at timestamptz,
+  revoked_at timestamptz,
+  created_at timestamptz not null default now()
+);
@@ -0,0 +1,13 @@
+-- Shared state for rate limiting when running several instances
+create table if not exists _rate_limits (
+  key text primary key,
+  count integer not null,
+  window_ends timestamptz not null
+);
+
+create table if not exists _auth_failures (
+  key text primary key,
+  failures integer not null,
+  locked_until timestamptz,
+  updated_at timestamptz not null default now()
+);
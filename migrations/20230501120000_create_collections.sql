@@ -0,0 +1,29 @@
+-- Definitions of user defined collections
+create table if not exists _collections (
+  name text primary key,
+  definition jsonb not null,
+  created_at timestamptz not null default now(),
+  updated_at timestamptz
+);
+
+-- Publishes record changes of collection tables on the rocketbase_changes
+-- channel. Notifications are limited to 8000 bytes, so large records are
+-- sent as just their id.
+create or replace function _rocketbase_notify_change() returns trigger as $$
+declare
+  rec jsonb;
+  payload text;
+begin
+  if tg_op = 'DELETE' then
+    rec := to_jsonb(old);
+  else
+    rec := to_jsonb(new);
+  end if;
+  payload := jsonb_build_object('collection', tg_table_name, 'action', lower(tg_op), 'record', rec)::text;
+  if octet_length(payload) > 7900 then
+    payload := jsonb_build_object('collection', tg_table_name, 'action', lower(tg_op), 'id', rec->'id', 'truncated', true)::text;
+  end if;
+  perform pg_notify('rocketbase_changes', payload);
+  return null;
+end;
+$$ language plpgsql;
@@ -0,0 +1,37 @@
+-- Append-only log of record changes, readable from any sequence number
+create table if not exists _changes (
+  seq bigserial primary key,
+  collection text not null,
+  action text not null,
+  record jsonb not null,
+  created_at timestamptz not null default now()
+);
+create index if not exists _changes_created_at_idx on _changes(created_at);
+
+-- Logs the change before publishing it. The advisory lock is held until the
+-- transaction ends, so changes commit in sequence order and readers never
+-- skip a change that was committed late.
+create or replace function _rocketbase_notify_change() returns trigger as $$
+declare
+  rec jsonb;
+  change_action text;
+  change_seq bigint;
+  payload text;
+begin
+  if tg_op = 'DELETE' then
+    rec := to_jsonb(old);
+  else
+    rec := to_jsonb(new);
+  end if;
+  change_action := case tg_op when 'INSERT' then 'create' else lower(tg_op) end;
+  perform pg_advisory_xact_lock(hashtext('_changes'));
+  insert into _changes(collection, action, record) values (tg_table_name, change_action, rec)
+    returning seq into change_seq;
+  payload := jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'action', change_action, 'record', rec)::text;
+  if octet_length(payload) > 7900 then
+    payload := jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'action', change_action, 'truncated', true)::text;
+  end if;
+  perform pg_notify('rocketbase_changes', payload);
+  return null;
+end;
+$$ language plpgsql;
@@ -0,0 +1,59 @@
+-- Endpoints notified of record changes in a collection
+create table if not exists _webhooks (
+  id bigserial primary key,
+  collection text not null,
+  events text[] not null,
+  url text not null,
+  secret text not null,
+  active boolean not null default true,
+  created_at timestamptz not null default now()
+);
+create index if not exists _webhooks_collection_idx on _webhooks(collection);
+
+-- Queue of webhook deliveries, kept as their log once done
+create table if not exists _webhook_deliveries (
+  id bigserial primary key,
+  webhook_id bigint not null references _webhooks(id) on delete cascade,
+  event text not null,
+  payload jsonb not null,
+  status text not null default 'pending',
+  attempts integer not null default 0,
+  next_attempt_at timestamptz not null default now(),
+  response_status integer,
+  error text,
+  created_at timestamptz not null default now(),
+  delivered_at timestamptz
+);
+create index if not exists _webhook_deliveries_due_idx on _webhook_deliveries(next_attempt_at) where status = 'pending';
+create index if not exists _webhook_deliveries_webhook_idx on _webhook_deliveries(webhook_id, id);
+
+-- Also queues deliveries for the webhooks of the collection, in the same
+-- transaction as the change so that none are lost or sent for rolled back
+-- changes.
+create or replace function _rocketbase_notify_change() returns trigger as $$
+declare
+  rec jsonb;
+  change_action text;
+  change_seq bigint;
+  payload text;
+begin
+  if tg_op = 'DELETE' then
+    rec := to_jsonb(old);
+  else
+    rec := to_jsonb(new);
+  end if;
+  change_action := case tg_op when 'INSERT' then 'create' else lower(tg_op) end;
+  perform pg_advisory_xact_lock(hashtext('_changes'));
+  insert into _changes(collection, action, record) values (tg_table_name, change_action, rec)
+    returning seq into change_seq;
+  insert into _webhook_deliveries(webhook_id, event, payload)
+    select id, change_action, jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'event', change_action, 'record', rec)
+    from _webhooks where active and collection = tg_table_name and change_action = any(events);
+  payload := jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'action', change_action, 'record', rec)::text;
+  if octet_length(payload) > 7900 then
+    payload := jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'action', change_action, 'truncated', true)::text;
+  end if;
+  perform pg_notify('rocketbase_changes', payload);
+  return null;
+end;
+$$ language plpgsql;
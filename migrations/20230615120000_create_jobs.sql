@@ -0,0 +1,23 @@
+-- Scheduled jobs, run by the handler registered under `handler`
+create table if not exists _jobs (
+  name text primary key,
+  schedule text not null,
+  handler text not null,
+  enabled boolean not null default true,
+  next_run_at timestamptz,
+  last_run_at timestamptz,
+  created_at timestamptz not null default now()
+);
+create index if not exists _jobs_due_idx on _jobs(next_run_at) where enabled;
+
+-- Run history of the jobs
+create table if not exists _job_runs (
+  id bigserial primary key,
+  job text not null references _jobs(name) on delete cascade,
+  status text not null default 'running',
+  started_at timestamptz not null default now(),
+  finished_at timestamptz,
+  duration_ms bigint,
+  error text
+);
+create index if not exists _job_runs_job_idx on _job_runs(job, id);
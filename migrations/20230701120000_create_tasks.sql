@@ -0,0 +1,16 @@
+-- Background tasks. Done tasks are deleted, dead ones are kept until they
+-- are retried.
+create table if not exists _tasks (
+  id bigserial primary key,
+  kind text not null,
+  payload jsonb not null,
+  status text not null default 'pending',
+  attempts integer not null default 0,
+  run_at timestamptz not null default now(),
+  locked_until timestamptz,
+  last_error text,
+  created_at timestamptz not null default now(),
+  updated_at timestamptz not null default now()
+);
+create index if not exists _tasks_due_idx on _tasks(run_at, id) where status in ('pending', 'running');
+create index if not exists _tasks_status_idx on _tasks(status, id);
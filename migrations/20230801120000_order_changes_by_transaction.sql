@@ -0,0 +1,36 @@
+-- Orders changes by the transaction that made them instead of committing
+-- them under a lock all writers share. Readers only see the changes of
+-- finished transactions, so a change committed late never lands behind an
+-- offset a reader already moved past. Changes logged so far share the
+-- transaction of this migration and keep their order by seq.
+alter table _changes add column if not exists txid xid8 not null default pg_current_xact_id();
+create index if not exists _changes_txid_seq_idx on _changes(txid, seq);
+
+-- Logs the change and queues its webhook deliveries, no longer waiting for
+-- other writers.
+create or replace function _rocketbase_notify_change() returns trigger as $$
+declare
+  rec jsonb;
+  change_action text;
+  change_seq bigint;
+  payload text;
+begin
+  if tg_op = 'DELETE' then
+    rec := to_jsonb(old);
+  else
+    rec := to_jsonb(new);
+  end if;
+  change_action := case tg_op when 'INSERT' then 'create' else lower(tg_op) end;
+  insert into _changes(collection, action, record) values (tg_table_name, change_action, rec)
+    returning seq into change_seq;
+  insert into _webhook_deliveries(webhook_id, event, payload)
+    select id, change_action, jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'event', change_action, 'record', rec)
+    from _webhooks where active and collection = tg_table_name and change_action = any(events);
+  payload := jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'action', change_action, 'record', rec)::text;
+  if octet_length(payload) > 7900 then
+    payload := jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'action', change_action, 'truncated', true)::text;
+  end if;
+  perform pg_notify('rocketbase_changes', payload);
+  return null;
+end;
+$$ language plpgsql;
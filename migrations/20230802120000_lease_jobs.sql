@@ -0,0 +1,5 @@
+-- Runs of a job hold a lease on it, which the instance running it renews
+-- until the run is over. A lease that ran out, because its instance died,
+-- can be taken by anyone.
+alter table _jobs add column if not exists locked_by text;
+alter table _jobs add column if not exists locked_until timestamptz;
@@ -0,0 +1,39 @@
+-- Webhook deliveries are sent by the task queue: each one comes with a
+-- `webhook_delivery` task, retried with the backoff of the queue, which also
+-- decides when the next attempt is.
+insert into _tasks(kind, payload)
+  select 'webhook_delivery', jsonb_build_object('delivery_id', id) from _webhook_deliveries where status = 'pending';
+drop index if exists _webhook_deliveries_due_idx;
+alter table _webhook_deliveries drop column if exists next_attempt_at;
+
+-- Queues a task along with every delivery.
+create or replace function _rocketbase_notify_change() returns trigger as $$
+declare
+  rec jsonb;
+  change_action text;
+  change_seq bigint;
+  payload text;
+begin
+  if tg_op = 'DELETE' then
+    rec := to_jsonb(old);
+  else
+    rec := to_jsonb(new);
+  end if;
+  change_action := case tg_op when 'INSERT' then 'create' else lower(tg_op) end;
+  insert into _changes(collection, action, record) values (tg_table_name, change_action, rec)
+    returning seq into change_seq;
+  with deliveries as (
+    insert into _webhook_deliveries(webhook_id, event, payload)
+      select id, change_action, jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'event', change_action, 'record', rec)
+      from _webhooks where active and collection = tg_table_name and change_action = any(events)
+      returning id
+  )
+  insert into _tasks(kind, payload) select 'webhook_delivery', jsonb_build_object('delivery_id', id) from deliveries;
+  payload := jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'action', change_action, 'record', rec)::text;
+  if octet_length(payload) > 7900 then
+    payload := jsonb_build_object('seq', change_seq, 'collection', tg_table_name, 'action', change_action, 'truncated', true)::text;
+  end if;
+  perform pg_notify('rocketbase_changes', payload);
+  return null;
+end;
+$$ language plpgsql;
@@ -0,0 +1,3 @@
+-- Expired rate limits and old failures are pruned as new ones are written
+create index if not exists _rate_limits_window_ends_idx on _rate_limits(window_ends);
+create index if not exists _auth_failures_updated_at_idx on _auth_failures(updated_at);
@@ -0,0 +1,2 @@
+-- Dead tasks are pruned once they are past their retention
+create index if not exists _tasks_dead_idx on _tasks(updated_at) where status = 'dead';
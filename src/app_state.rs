@@ -1,23 +1,158 @@
+use chrono::Duration;
 use color_eyre::{Result, Help};
 use std::sync::Arc;
 use tracing::instrument;
+use crate::auth::oauth::OAuthRegistry;
 use crate::db::DB;
+use crate::hooks::Hooks;
+use crate::mailer::{self, LogMailer, Mailer};
+use crate::metrics::Metrics;
+use crate::queue;
+use crate::rate_limit::RateLimiter;
+use crate::realtime::{spawn_pruner, Hub};
+use crate::scheduler::Scheduler;
+use crate::settings::{self, Settings, SETTINGS};
+use crate::shutdown::Shutdown;
+use crate::storage::{self, thumbs::Renders, FileSigner, LocalStorage, Storage};
+use crate::webhooks;
+
+fn file_signer(settings: &settings::Storage) -> FileSigner {
+  FileSigner::new(settings.signing_secret.as_deref(), Duration::seconds(settings.token_ttl_secs))
+}
 
 #[derive(Clone, Debug)]
 pub struct AppState {
+  settings: Arc<Settings>,
   db: Arc<DB>,
+  mailer: Arc<dyn Mailer>,
+  oauth: Arc<OAuthRegistry>,
+  rate_limiter: Arc<RateLimiter>,
+  realtime: Arc<Hub>,
+  storage: Arc<dyn Storage>,
+  thumb_renders: Arc<Renders>,
+  file_signer: Arc<FileSigner>,
+  hooks: Arc<Hooks>,
+  scheduler: Arc<Scheduler>,
+  shutdown: Shutdown,
+  metrics: Arc<Metrics>,
 }
 
 impl AppState {
   #[instrument]
   pub async fn init() -> Result<Self> {
     let db = DB::new().await.suggestion("Ensure that the Database URL environment variable is correct")?;
-    Ok(AppState{db: Arc::new(db)})
+    Self::from_settings(SETTINGS.clone(), db)
+  }
+  /// Builds the state for `settings` around a database that is already
+  /// connected and migrated. Starts background tasks, so it has to be called
+  /// from within the runtime.
+  pub fn from_settings(settings: Settings, db: DB) -> Result<Self> {
+    let mailer = mailer::from_settings(&settings.mail).suggestion("Check the mail section of the settings")?;
+    let oauth = OAuthRegistry::new(&settings.auth.providers, &settings.mail.app_url);
+    let rate_limiter = RateLimiter::from_settings(&settings.rate_limit, db.connection());
+    let storage = storage::from_settings(&settings.storage).suggestion("Check the storage section of the settings")?;
+    let shutdown = Shutdown::new();
+    let realtime = Hub::new(db.connection(), shutdown.token());
+    shutdown.track(spawn_pruner(db.connection(), settings.changes, shutdown.token()));
+    shutdown.track(webhooks::spawn_pruner(db.connection(), settings.webhooks, shutdown.token()));
+    shutdown.track(queue::spawn_pruner(db.connection(), settings.queue, shutdown.token()));
+    Ok(AppState{
+      file_signer: Arc::new(file_signer(&settings.storage)),
+      hooks: Arc::new(Hooks::default()),
+      scheduler: Arc::new(Scheduler::default()),
+      shutdown,
+      metrics: Arc::new(Metrics::new()),
+      settings: Arc::new(settings),
+      db: Arc::new(db),
+      mailer,
+      oauth: Arc::new(oauth),
+      rate_limiter: Arc::new(rate_limiter),
+      realtime: Arc::new(realtime),
+      storage,
+      thumb_renders: Arc::default(),
+    })
+  }
+  pub fn settings(&self) -> Arc<Settings> {
+    self.settings.clone()
   }
   pub fn db(&self) -> Arc<DB> {
     self.db.clone()
   }
+  pub fn mailer(&self) -> Arc<dyn Mailer> {
+    self.mailer.clone()
+  }
+  pub fn oauth(&self) -> Arc<OAuthRegistry> {
+    self.oauth.clone()
+  }
+  pub fn rate_limiter(&self) -> Arc<RateLimiter> {
+    self.rate_limiter.clone()
+  }
+  pub fn realtime(&self) -> Arc<Hub> {
+    self.realtime.clone()
+  }
+  pub fn storage(&self) -> Arc<dyn Storage> {
+    self.storage.clone()
+  }
+  pub fn thumb_renders(&self) -> Arc<Renders> {
+    self.thumb_renders.clone()
+  }
+  pub fn file_signer(&self) -> Arc<FileSigner> {
+    self.file_signer.clone()
+  }
+  pub fn hooks(&self) -> Arc<Hooks> {
+    self.hooks.clone()
+  }
+  pub fn scheduler(&self) -> Arc<Scheduler> {
+    self.scheduler.clone()
+  }
+  pub fn shutdown(&self) -> Shutdown {
+    self.shutdown.clone()
+  }
+  pub fn metrics(&self) -> Arc<Metrics> {
+    self.metrics.clone()
+  }
   pub fn init_with_db(db: DB) -> Self {
-    AppState {db: Arc::new(db)}
+    let rate_limiter = RateLimiter::from_settings(&SETTINGS.rate_limit, db.connection());
+    let shutdown = Shutdown::new();
+    let realtime = Hub::new(db.connection(), shutdown.token());
+    AppState {
+      settings: Arc::new(SETTINGS.clone()),
+      db: Arc::new(db),
+      mailer: Arc::new(LogMailer::default()),
+      oauth: Arc::new(OAuthRegistry::default()),
+      rate_limiter: Arc::new(rate_limiter),
+      realtime: Arc::new(realtime),
+      storage: Arc::new(LocalStorage::new(std::env::temp_dir().join("rocketbase-storage"))),
+      thumb_renders: Arc::default(),
+      file_signer: Arc::new(file_signer(&SETTINGS.storage)),
+      hooks: Arc::new(Hooks::default()),
+      scheduler: Arc::new(Scheduler::default()),
+      shutdown,
+      metrics: Arc::new(Metrics::new()),
+    }
+  }
+  pub fn with_mailer(mut self, mailer: Arc<dyn Mailer>) -> Self {
+    self.mailer = mailer;
+    self
+  }
+  pub fn with_oauth(mut self, oauth: OAuthRegistry) -> Self {
+    self.oauth = Arc::new(oauth);
+    self
+  }
+  pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+    self.hooks = Arc::new(hooks);
+    self
+  }
+  pub fn with_scheduler(mut self, scheduler: Scheduler) -> Self {
+    self.scheduler = Arc::new(scheduler);
+    self
+  }
+  pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+    self.storage = storage;
+    self
+  }
+  pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+    self.rate_limiter = Arc::new(rate_limiter);
+    self
   }
 }
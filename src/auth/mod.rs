@@ -0,0 +1,276 @@
+use axum::{
+  async_trait,
+  extract::{FromRequestParts, State},
+  http::{header, request::Parts, HeaderMap, Request, StatusCode},
+  middleware::Next,
+  response::Response,
+};
+use chrono::Duration;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Executor, PgPool, Postgres};
+
+use crate::{
+  app_state::AppState,
+  model::{collection::{Collection, Operation}, ApiKey, Mfa, TokenKind, User, UserToken},
+  queue::{TaskPayload, Workers},
+  router::auth::send_token_mail,
+  settings,
+};
+
+pub mod oauth;
+pub mod totp;
+
+/// Header carrying API keys for server to server requests.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Who a request is made by, as established by [`authenticate`].
+#[derive(Debug, Clone)]
+pub enum Principal {
+  User(User),
+  Admin(User),
+  ApiKey(ApiKey),
+}
+
+impl Principal {
+  pub fn user(&self) -> Option<&User> {
+    match self {
+      Self::User(user) | Self::Admin(user) => Some(user),
+      Self::ApiKey(_) => None,
+    }
+  }
+
+  /// Returns whether the principal may perform `operation` on `collection`.
+  /// API keys are limited to their scopes, users by the access rules of the
+  /// collection checked against `record` when there is one.
+  pub fn can(&self, collection: &Collection, operation: Operation, record: Option<&Value>) -> bool {
+    match self {
+      Self::Admin(_) => true,
+      Self::ApiKey(key) => key.allows(&collection.name, operation),
+      Self::User(user) => collection.rules.rule(operation).allows(user.id, record),
+    }
+  }
+}
+
+/// Access check for a request that may be anonymous.
+pub fn can_access(principal: Option<&Principal>, collection: &Collection, operation: Operation, record: Option<&Value>) -> bool {
+  match principal {
+    Some(principal) => principal.can(collection, operation, record),
+    None => collection.rules.rule(operation).allows(None, record),
+  }
+}
+
+/// What a user gets after proving their first factor.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoginOutcome {
+  Session(String),
+  /// A second factor is needed. The token only grants access to the MFA
+  /// endpoints and has to be traded in with a TOTP or recovery code.
+  MfaRequired { mfa_token: String, enrollment_required: bool },
+}
+
+/// Decides whether a login needs a second factor. Returns `Some(true)` when
+/// the user first has to enroll.
+pub fn mfa_requirement(mfa_enabled: Option<bool>, is_admin: bool, required_for_admins: bool) -> Option<bool> {
+  match mfa_enabled {
+    Some(true) => Some(false),
+    _ if is_admin && required_for_admins => Some(true),
+    _ => None,
+  }
+}
+
+/// Finishes a login for a user who passed their first factor, either with a
+/// password or through an external provider.
+pub async fn begin_login(pool: &PgPool, settings: &settings::Auth, user_id: i64) -> Result<LoginOutcome> {
+  let mfa_enabled = Mfa::find(pool, user_id).await?.map(|mfa| mfa.enabled);
+  let is_admin = User::is_admin(pool, user_id).await?;
+  match mfa_requirement(mfa_enabled, is_admin, settings.mfa.required_for_admins) {
+    Some(enrollment_required) => {
+      let ttl = Duration::seconds(settings.mfa.pending_ttl_secs);
+      let mfa_token = UserToken::issue(pool, user_id, TokenKind::MfaPending, ttl).await?;
+      Ok(LoginOutcome::MfaRequired { mfa_token, enrollment_required })
+    }
+    None => Ok(LoginOutcome::Session(issue_session(pool, settings, user_id).await?)),
+  }
+}
+
+/// Starts a session for the user and returns the bearer token for it.
+pub async fn issue_session<'a, E>(ex: E, settings: &settings::Auth, user_id: i64) -> Result<String>
+where E: 'a + Executor<'a, Database = Postgres>
+{
+  UserToken::issue(ex, user_id, TokenKind::Session, Duration::seconds(settings.session_ttl_secs)).await
+}
+
+/// Mails a verification or password reset token to `email`, if it belongs to
+/// a user.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MailToken {
+  pub email: String,
+  pub kind: TokenKind,
+}
+
+impl TaskPayload for MailToken {
+  const KIND: &'static str = "mail_token";
+}
+
+/// Adds the handler sending queued token mail with the mailer of `state` to
+/// `workers`.
+pub fn handle_token_mail(workers: Workers, state: AppState) -> Workers {
+  workers.handle(move |task: MailToken, context| {
+    let state = state.clone();
+    async move {
+      match User::find_by_email(&context.pool, &task.email).await? {
+        Some(user) => send_token_mail(&state, &user, task.kind).await,
+        None => Ok(()),
+      }
+    }
+  })
+}
+
+/// Returns the token from an `Authorization: Bearer <token>` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+  headers
+    .get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    .map(str::trim)
+}
+
+/// Middleware that resolves the [`Principal`] behind a request from either an
+/// API key header or a session bearer token. Requests with an invalid API key
+/// are rejected; anything else is passed on for the extractors to decide.
+pub async fn authenticate<B>(State(state): State<AppState>, mut req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+  let pool = state.db().connection();
+  let api_key = req.headers().get(API_KEY_HEADER).map(|value| value.to_str().unwrap_or_default().to_string());
+  let principal = match api_key {
+    Some(key) => {
+      let api_key = ApiKey::authenticate(&pool, &key)
+        .await
+        .map_err(|err| {
+          tracing::error!("{:?}", err);
+          StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+      Some(Principal::ApiKey(api_key))
+    }
+    None => match bearer_token(req.headers()) {
+      Some(token) => session_principal(&state, token).await?,
+      None => None,
+    },
+  };
+  if let Some(principal) = principal {
+    req.extensions_mut().insert(principal);
+  }
+  Ok(next.run(req).await)
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Principal {
+  type Rejection = StatusCode;
+
+  async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    parts.extensions.get::<Principal>().cloned().ok_or(StatusCode::UNAUTHORIZED)
+  }
+}
+
+/// Extractor for handlers that need a signed in user. Requests without a
+/// valid session token are rejected with `401 Unauthorized`.
+#[derive(Debug)]
+pub struct AuthUser(pub User);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+  type Rejection = StatusCode;
+
+  async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    match Principal::from_request_parts(parts, state).await? {
+      Principal::User(user) | Principal::Admin(user) => Ok(AuthUser(user)),
+      Principal::ApiKey(_) => Err(StatusCode::UNAUTHORIZED),
+    }
+  }
+}
+
+/// Extractor for administrative endpoints, rejecting signed in users who are
+/// not admins with `403 Forbidden`.
+#[derive(Debug)]
+pub struct AdminUser(pub User);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminUser {
+  type Rejection = StatusCode;
+
+  async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    match Principal::from_request_parts(parts, state).await? {
+      Principal::Admin(user) => Ok(AdminUser(user)),
+      Principal::User(_) => Err(StatusCode::FORBIDDEN),
+      Principal::ApiKey(_) => Err(StatusCode::UNAUTHORIZED),
+    }
+  }
+}
+
+/// Resolves a session token to a [`Principal::User`], or [`Principal::Admin`]
+/// for admins.
+pub async fn session_principal(state: &AppState, token: &str) -> Result<Option<Principal>, StatusCode> {
+  let Some(user) = user_for_token(state, token, TokenKind::Session).await? else {
+    return Ok(None);
+  };
+  let is_admin = User::is_admin(&state.db().connection(), user.id.unwrap_or_default())
+    .await
+    .map_err(|err| {
+      tracing::error!("{:?}", err);
+      StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+  Ok(Some(if is_admin { Principal::Admin(user) } else { Principal::User(user) }))
+}
+
+async fn user_for_token(state: &AppState, token: &str, kind: TokenKind) -> Result<Option<User>, StatusCode> {
+  let pool = state.db().connection();
+  let internal = |err: color_eyre::Report| {
+    tracing::error!("{:?}", err);
+    StatusCode::INTERNAL_SERVER_ERROR
+  };
+  match UserToken::lookup(&pool, token, kind).await.map_err(internal)? {
+    Some(user_id) => User::find(&pool, user_id).await.map_err(internal),
+    None => Ok(None),
+  }
+}
+
+/// Extractor for the MFA endpoints, which accept a session token or the
+/// token handed out by a login that still needs a second factor.
+#[derive(Debug)]
+pub struct MfaUser {
+  pub user: User,
+  /// The MFA pending token, when the request was not made with a session.
+  pub pending_token: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for MfaUser {
+  type Rejection = StatusCode;
+
+  async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+    let token = bearer_token(&parts.headers).ok_or(StatusCode::UNAUTHORIZED)?;
+    if let Some(user) = user_for_token(state, token, TokenKind::Session).await? {
+      return Ok(MfaUser { user, pending_token: None });
+    }
+    let user = user_for_token(state, token, TokenKind::MfaPending).await?.ok_or(StatusCode::UNAUTHORIZED)?;
+    Ok(MfaUser { user, pending_token: Some(token.to_string()) })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::mfa_requirement;
+
+  #[test]
+  fn should_require_mfa_when_enabled_or_mandatory() {
+    assert_eq!(mfa_requirement(None, false, true), None);
+    assert_eq!(mfa_requirement(Some(false), false, false), None);
+    assert_eq!(mfa_requirement(Some(true), false, false), Some(false));
+    assert_eq!(mfa_requirement(None, true, false), None);
+    assert_eq!(mfa_requirement(None, true, true), Some(true));
+    assert_eq!(mfa_requirement(Some(false), true, true), Some(true));
+    assert_eq!(mfa_requirement(Some(true), true, true), Some(false));
+  }
+}
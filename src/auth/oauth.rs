@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use color_eyre::{eyre::{eyre, WrapErr}, Report};
+use http::{header, Method, Request, StatusCode};
+use hyper::{body, client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tokio::sync::OnceCell;
+use tracing::instrument;
+
+use crate::{
+  model::{user_token::generate_token, ExternalAuth, Mfa, TokenKind, User, UserToken},
+  settings::{OAuthProvider, OAuthProviderKind},
+};
+
+const GOOGLE_ISSUER: &str = "https://accounts.google.com";
+const GITHUB_AUTH_URL: &str = "https://github.com/login/oauth/authorize";
+const GITHUB_TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+const GITHUB_USERINFO_URL: &str = "https://api.github.com/user";
+pub const STATE_TTL_SECS: i64 = 600;
+/// How long a provider gets to answer a request, body included.
+const PROVIDER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OAuthError {
+  #[error("Unknown provider {0}")]
+  UnknownProvider(String),
+  #[error("Invalid or expired state")]
+  InvalidState,
+  #[error("Provider returned an error: {0}")]
+  Provider(String),
+  #[error("Email {0} belongs to an existing account")]
+  EmailConflict(String),
+  #[error(transparent)]
+  Other(#[from] Report),
+}
+
+impl OAuthError {
+  pub fn status(&self) -> StatusCode {
+    match self {
+      Self::UnknownProvider(_) => StatusCode::NOT_FOUND,
+      Self::InvalidState => StatusCode::BAD_REQUEST,
+      Self::Provider(_) => StatusCode::BAD_GATEWAY,
+      Self::EmailConflict(_) => StatusCode::CONFLICT,
+      Self::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+}
+
+impl From<sqlx::Error> for OAuthError {
+  fn from(err: sqlx::Error) -> Self {
+    OAuthError::Other(err.into())
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+  pub auth_url: String,
+  pub token_url: String,
+  pub userinfo_url: String,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+  authorization_endpoint: String,
+  token_endpoint: String,
+  userinfo_endpoint: String,
+}
+
+/// The identity an external provider vouches for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+  pub subject: String,
+  pub email: Option<String>,
+  pub email_verified: bool,
+  pub name: Option<String>,
+}
+
+/// Where to send the user to authorize a login, and the state the callback
+/// has to come back with.
+#[derive(Debug)]
+pub struct Authorization {
+  pub url: String,
+  pub state: String,
+}
+
+#[derive(Debug)]
+pub struct Provider {
+  pub name: String,
+  settings: OAuthProvider,
+  redirect_url: String,
+  endpoints: OnceCell<Endpoints>,
+}
+
+impl Provider {
+  pub fn redirect_url(&self) -> &str {
+    &self.redirect_url
+  }
+
+  fn scopes(&self) -> String {
+    match &self.settings.scopes {
+      Some(scopes) => scopes.join(" "),
+      None => match self.settings.kind {
+        OAuthProviderKind::Github => "read:user user:email".into(),
+        _ => "openid email profile".into(),
+      },
+    }
+  }
+
+  /// Resolves the provider endpoints, running OpenID Connect discovery at
+  /// most once for the lifetime of the registry.
+  async fn endpoints(&self, client: &HttpClient) -> Result<&Endpoints, OAuthError> {
+    self.endpoints.get_or_try_init(|| async {
+      let s = &self.settings;
+      if let (Some(auth_url), Some(token_url), Some(userinfo_url)) = (&s.auth_url, &s.token_url, &s.userinfo_url) {
+        return Ok(Endpoints { auth_url: auth_url.clone(), token_url: token_url.clone(), userinfo_url: userinfo_url.clone() });
+      }
+      let discovered = match s.kind {
+        OAuthProviderKind::Github => Endpoints {
+          auth_url: GITHUB_AUTH_URL.into(),
+          token_url: GITHUB_TOKEN_URL.into(),
+          userinfo_url: GITHUB_USERINFO_URL.into(),
+        },
+        OAuthProviderKind::Google | OAuthProviderKind::Oidc => {
+          let issuer = match (&s.issuer, s.kind) {
+            (Some(issuer), _) => issuer.as_str(),
+            (None, OAuthProviderKind::Google) => GOOGLE_ISSUER,
+            (None, _) => return Err(eyre!("provider {} needs an issuer or explicit endpoints", self.name).into()),
+          };
+          let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+          let request = Request::get(&url).body(Body::empty()).wrap_err_with(|| format!("Invalid issuer {}", issuer))?;
+          let doc: Discovery = serde_json::from_value(send(client, request).await?)
+            .map_err(|e| OAuthError::Provider(format!("invalid discovery document: {}", e)))?;
+          Endpoints { auth_url: doc.authorization_endpoint, token_url: doc.token_endpoint, userinfo_url: doc.userinfo_endpoint }
+        }
+      };
+      Ok(Endpoints {
+        auth_url: s.auth_url.clone().unwrap_or(discovered.auth_url),
+        token_url: s.token_url.clone().unwrap_or(discovered.token_url),
+        userinfo_url: s.userinfo_url.clone().unwrap_or(discovered.userinfo_url),
+      })
+    }).await
+  }
+
+  async fn identity(&self, client: &HttpClient, userinfo_url: &str, access_token: &str) -> Result<Identity, OAuthError> {
+    let info = send(client, authorized_get(userinfo_url, access_token)?).await?;
+    let identity = match self.settings.kind {
+      OAuthProviderKind::Github => {
+        let subject = info["id"].as_i64().map(|id| id.to_string())
+          .ok_or_else(|| OAuthError::Provider("userinfo has no id".into()))?;
+        // The public profile email is always verified, otherwise fall back
+        // to the primary address from the emails API.
+        let mut email = info["email"].as_str().map(String::from);
+        if email.is_none() {
+          let emails = send(client, authorized_get(&format!("{}/emails", userinfo_url), access_token)?).await?;
+          email = emails.as_array().and_then(|emails| {
+            emails.iter()
+              .find(|e| e["primary"].as_bool() == Some(true) && e["verified"].as_bool() == Some(true))
+              .and_then(|e| e["email"].as_str().map(String::from))
+          });
+        }
+        Identity {
+          subject,
+          email_verified: email.is_some(),
+          email,
+          name: info["name"].as_str().or_else(|| info["login"].as_str()).map(String::from),
+        }
+      }
+      OAuthProviderKind::Google | OAuthProviderKind::Oidc => Identity {
+        subject: info["sub"].as_str().map(String::from)
+          .ok_or_else(|| OAuthError::Provider("userinfo has no sub claim".into()))?,
+        email: info["email"].as_str().map(String::from),
+        email_verified: info["email_verified"].as_bool().unwrap_or(false),
+        name: info["name"].as_str().map(String::from),
+      },
+    };
+    Ok(identity)
+  }
+}
+
+/// Login providers configured under `auth.providers` in the settings.
+#[derive(Debug)]
+pub struct OAuthRegistry {
+  providers: HashMap<String, Provider>,
+  client: HttpClient,
+}
+
+impl Default for OAuthRegistry {
+  fn default() -> Self {
+    OAuthRegistry::new(&HashMap::new(), "")
+  }
+}
+
+/// Deletes the states of logins that were abandoned or took too long.
+async fn prune_states(pool: &PgPool) -> Result<(), sqlx::Error> {
+  sqlx::query("delete from _oauth_states where expires_at <= now()").execute(pool).await?;
+  Ok(())
+}
+
+impl OAuthRegistry {
+  pub fn new(providers: &HashMap<String, OAuthProvider>, app_url: &str) -> Self {
+    let providers = providers.iter().map(|(name, settings)| {
+      let redirect_url = settings.redirect_url.clone()
+        .unwrap_or_else(|| format!("{}/auth/oauth/{}/callback", app_url.trim_end_matches('/'), name));
+      (name.clone(), Provider { name: name.clone(), settings: settings.clone(), redirect_url, endpoints: OnceCell::new() })
+    }).collect();
+    let connector = HttpsConnectorBuilder::new()
+      .with_webpki_roots()
+      .https_or_http()
+      .enable_http1()
+      .build();
+    OAuthRegistry { providers, client: Client::builder().build(connector) }
+  }
+
+  pub fn provider(&self, name: &str) -> Result<&Provider, OAuthError> {
+    self.providers.get(name).ok_or_else(|| OAuthError::UnknownProvider(name.into()))
+  }
+
+  pub fn names(&self) -> Vec<&str> {
+    let mut names: Vec<&str> = self.providers.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    names
+  }
+
+  /// Starts an authorization code flow. The state has to be bound to the
+  /// browser, such as with a cookie, and checked before the [`exchange`].
+  ///
+  /// [`exchange`]: Self::exchange
+  #[instrument(skip(self, pool))]
+  pub async fn authorize(&self, pool: &PgPool, name: &str) -> Result<Authorization, OAuthError> {
+    let provider = self.provider(name)?;
+    let endpoints = provider.endpoints(&self.client).await?;
+    let state = generate_token();
+    let verifier = generate_token();
+    prune_states(pool).await?;
+    sqlx::query("insert into _oauth_states(state, provider, code_verifier, expires_at) values($1, $2, $3, $4)")
+      .bind(&state)
+      .bind(name)
+      .bind(&verifier)
+      .bind(Utc::now() + Duration::seconds(STATE_TTL_SECS))
+      .execute(pool).await?;
+    let query = serde_urlencoded::to_string([
+      ("response_type", "code"),
+      ("client_id", provider.settings.client_id.as_str()),
+      ("redirect_uri", provider.redirect_url.as_str()),
+      ("scope", provider.scopes().as_str()),
+      ("state", state.as_str()),
+      ("code_challenge", pkce_challenge(&verifier).as_str()),
+      ("code_challenge_method", "S256"),
+    ]).wrap_err("Unable to encode authorization request")?;
+    let separator = if endpoints.auth_url.contains('?') { '&' } else { '?' };
+    Ok(Authorization { url: format!("{}{}{}", endpoints.auth_url, separator, query), state })
+  }
+
+  /// Exchanges the authorization code returned to the callback and fetches
+  /// the identity of the user from the provider.
+  #[instrument(skip(self, pool, code, state))]
+  pub async fn exchange(&self, pool: &PgPool, name: &str, code: &str, state: &str) -> Result<Identity, OAuthError> {
+    let provider = self.provider(name)?;
+    let verifier = sqlx::query_scalar::<_, String>(
+      "delete from _oauth_states where state = $1 and provider = $2 and expires_at > now() returning code_verifier"
+    )
+      .bind(state)
+      .bind(name)
+      .fetch_optional(pool).await?;
+    prune_states(pool).await?;
+    let verifier = verifier.ok_or(OAuthError::InvalidState)?;
+    let endpoints = provider.endpoints(&self.client).await?;
+    let form = serde_urlencoded::to_string([
+      ("grant_type", "authorization_code"),
+      ("code", code),
+      ("redirect_uri", provider.redirect_url.as_str()),
+      ("client_id", provider.settings.client_id.as_str()),
+      ("client_secret", provider.settings.client_secret.as_str()),
+      ("code_verifier", verifier.as_str()),
+    ]).wrap_err("Unable to encode token request")?;
+    let request = Request::builder()
+      .method(Method::POST)
+      .uri(&endpoints.token_url)
+      .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+      .header(header::ACCEPT, "application/json")
+      .body(Body::from(form))
+      .wrap_err("Invalid token endpoint")?;
+    let token = send(&self.client, request).await?;
+    let access_token = token["access_token"].as_str()
+      .ok_or_else(|| OAuthError::Provider(format!("no access token in response: {}", token)))?;
+    provider.identity(&self.client, &endpoints.userinfo_url, access_token).await
+  }
+}
+
+/// Finds the user linked to the identity, linking an account with the same
+/// verified email or creating a new user when there is none.
+///
+/// Whoever registered an account that was never verified could not prove
+/// the address was theirs, so linking it to the owner of the address first
+/// takes away its password, sessions and second factor.
+#[instrument(skip(pool))]
+pub async fn login(pool: &PgPool, provider: &str, identity: &Identity) -> Result<User, OAuthError> {
+  let mut tx = pool.begin().await?;
+  if let Some(user_id) = ExternalAuth::find_user_id(&mut tx, provider, &identity.subject).await? {
+    let user = User::find(&mut tx, user_id).await?.ok_or_else(|| eyre!("linked user {} is missing", user_id))?;
+    tx.commit().await?;
+    return Ok(user);
+  }
+  let email = identity.email.clone()
+    .ok_or_else(|| OAuthError::Provider("provider did not share an email address".into()))?;
+  let user = match User::find_by_email(&mut tx, &email).await? {
+    Some(user) if identity.email_verified => {
+      let user_id = user.id.ok_or_else(|| eyre!("user has not been saved"))?;
+      if !User::is_verified(&mut tx, user_id).await? {
+        User::clear_password(&mut tx, user_id).await?;
+        UserToken::revoke_all(&mut tx, user_id, &[
+          TokenKind::Session, TokenKind::MfaPending, TokenKind::PasswordReset, TokenKind::Verification,
+        ]).await?;
+        Mfa::delete(&mut tx, user_id).await?;
+      }
+      user
+    }
+    Some(_) => return Err(OAuthError::EmailConflict(email)),
+    None => {
+      let mut user = User { id: None, name: identity.name.clone().unwrap_or_else(|| email.clone()), email };
+      user.insert(&mut tx).await?;
+      user
+    }
+  };
+  let user_id = user.id.ok_or_else(|| eyre!("user has not been saved"))?;
+  if identity.email_verified {
+    User::mark_verified(&mut tx, user_id).await?;
+  }
+  ExternalAuth::link(&mut tx, user_id, provider, &identity.subject).await?;
+  tx.commit().await?;
+  Ok(user)
+}
+
+pub fn pkce_challenge(verifier: &str) -> String {
+  URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn authorized_get(url: &str, access_token: &str) -> Result<Request<Body>, OAuthError> {
+  let request = Request::get(url)
+    .header(header::AUTHORIZATION, format!("Bearer {}", access_token))
+    .header(header::ACCEPT, "application/json")
+    .header(header::USER_AGENT, "rocketbase")
+    .body(Body::empty())
+    .wrap_err_with(|| format!("Invalid provider URL {}", url))?;
+  Ok(request)
+}
+
+async fn send(client: &HttpClient, request: Request<Body>) -> Result<Value, OAuthError> {
+  let uri = request.uri().clone();
+  let received = tokio::time::timeout(PROVIDER_TIMEOUT, async {
+    let response = client.request(request).await.wrap_err_with(|| format!("Unable to reach {}", uri))?;
+    let status = response.status();
+    let bytes = body::to_bytes(response.into_body()).await.wrap_err("Unable to read provider response")?;
+    Ok::<_, Report>((status, bytes))
+  }).await;
+  let (status, bytes) = received.map_err(|_| OAuthError::Provider(format!("{} did not answer within {:?}", uri, PROVIDER_TIMEOUT)))??;
+  if !status.is_success() {
+    return Err(OAuthError::Provider(format!("{} returned {}: {}", uri, status, String::from_utf8_lossy(&bytes))));
+  }
+  serde_json::from_slice(&bytes).map_err(|e| OAuthError::Provider(format!("{} returned invalid JSON: {}", uri, e)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::pkce_challenge;
+
+  #[test]
+  fn should_derive_pkce_challenge() {
+    // Example from RFC 7636, appendix B
+    let challenge = pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+    assert_eq!(challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+  }
+}
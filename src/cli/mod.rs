@@ -0,0 +1,351 @@
+//! The `rocketbase` command line. Commands other than `serve` work on the
+//! database as it is, without migrating it first.
+pub mod shell;
+pub mod table;
+
+use std::{io::{BufRead, Write}, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use clap::{Parser, Subcommand};
+use color_eyre::{eyre::{bail, eyre, WrapErr}, Result};
+
+use crate::{
+  db::DB,
+  mailer,
+  metrics,
+  model::{collection::Collection, User},
+  router::cors::CorsPolicies,
+  settings::{OAuthProviderKind, Settings},
+  storage,
+  tls,
+  Rocketbase,
+};
+
+#[derive(Parser, Debug)]
+#[command(name = "rocketbase", version, about = "A backend as a service in a single binary")]
+pub struct Cli {
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  /// Serve the API, which is also what runs without a command
+  Serve,
+  /// Apply or list database migrations
+  #[command(subcommand)]
+  Migrate(MigrateCommand),
+  /// Manage administrators
+  #[command(subcommand)]
+  Admin(AdminCommand),
+  /// Export and import collection definitions
+  #[command(subcommand)]
+  Collections(CollectionsCommand),
+  /// Inspect users
+  #[command(subcommand)]
+  Users(UsersCommand),
+  /// Validate the settings
+  #[command(subcommand)]
+  Settings(SettingsCommand),
+  /// Explore collections and records at a prompt
+  Shell,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MigrateCommand {
+  /// Apply pending migrations
+  Up,
+  /// List migrations and whether they are applied
+  Status,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+  /// Create an administrator, or with `--reset` make an existing user one
+  Create {
+    #[arg(long)]
+    email: String,
+    /// Defaults to the part of the email before the `@`
+    #[arg(long)]
+    name: Option<String>,
+    /// Prompted for without echo, or read from the first line of stdin, when
+    /// not given
+    #[arg(long)]
+    password: Option<String>,
+    /// Make an existing user with the email an administrator and replace
+    /// their password, instead of failing
+    #[arg(long)]
+    reset: bool,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CollectionsCommand {
+  /// Write the collection definitions as JSON
+  Export {
+    /// File to write to instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+  },
+  /// Create or update collections from a JSON export
+  Import {
+    file: PathBuf,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UsersCommand {
+  /// List users with their account flags
+  List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SettingsCommand {
+  /// Load the settings and check that each section works
+  Check,
+}
+
+/// Runs a command with the settings files, writing its output to `out`.
+pub async fn run(command: Command, out: &mut impl Write) -> Result<()> {
+  if let Command::Settings(SettingsCommand::Check) = command {
+    return check_settings(out).await;
+  }
+  let settings = Settings::new()?;
+  match command {
+    Command::Serve => Rocketbase::builder().settings(settings).serve().await,
+    command => {
+      let db = DB::open(&settings.database).await?;
+      run_with_db(command, &db, out).await
+    }
+  }
+}
+
+/// Runs a command that only needs the database.
+pub async fn run_with_db(command: Command, db: &DB, out: &mut impl Write) -> Result<()> {
+  match command {
+    Command::Migrate(MigrateCommand::Up) => {
+      db.migrate().await?;
+      writeln!(out, "Database is up to date")?;
+    }
+    Command::Migrate(MigrateCommand::Status) => migration_status(db, out).await?,
+    Command::Admin(AdminCommand::Create { email, name, password, reset }) => {
+      let password = match password {
+        Some(password) => password,
+        None => read_password()?,
+      };
+      create_admin(db, &email, name, &password, reset, out).await?;
+    }
+    Command::Collections(CollectionsCommand::Export { output }) => {
+      let collections = Collection::all(&db.connection()).await?;
+      let json = serde_json::to_string_pretty(&collections)?;
+      match output {
+        Some(path) => std::fs::write(&path, json).with_context(|| format!("Unable to write {}", path.display()))?,
+        None => writeln!(out, "{}", json)?,
+      }
+    }
+    Command::Collections(CollectionsCommand::Import { file }) => {
+      let json = std::fs::read_to_string(&file).with_context(|| format!("Unable to read {}", file.display()))?;
+      let collections: Vec<Collection> = serde_json::from_str(&json).context("Expected an array of collections")?;
+      import_collections(db, collections, out).await?;
+    }
+    Command::Users(UsersCommand::List) => {
+      let rows = User::accounts(&db.connection()).await?.into_iter().map(|account| vec![
+        account.user.id.map(|id| id.to_string()).unwrap_or_default(),
+        account.user.name,
+        account.user.email,
+        yes_no(account.admin),
+        yes_no(account.verified),
+      ]).collect::<Vec<_>>();
+      write!(out, "{}", table::render(&["id", "name", "email", "admin", "verified"], &rows))?;
+    }
+    Command::Shell => shell::run(db, out).await?,
+    Command::Serve | Command::Settings(_) => bail!("This command needs the settings"),
+  }
+  Ok(())
+}
+
+fn yes_no(flag: bool) -> String {
+  match flag {
+    true => "yes".to_string(),
+    false => "no".to_string(),
+  }
+}
+
+/// Reads a password from the first line of stdin, without echoing it when
+/// stdin is a terminal.
+fn read_password() -> Result<String> {
+  eprint!("Password: ");
+  let mut password = String::new();
+  {
+    #[cfg(unix)]
+    let _echo = NoEcho::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+  }
+  Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Turns off echo on the terminal of stdin, if it is one, until dropped. The
+/// newline still shows so that output goes on below the prompt.
+#[cfg(unix)]
+struct NoEcho {
+  original: Option<nix::sys::termios::Termios>,
+}
+
+#[cfg(unix)]
+impl NoEcho {
+  fn new() -> Self {
+    use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+    let stdin = std::io::stdin();
+    let original = tcgetattr(&stdin).ok();
+    if let Some(original) = &original {
+      let mut silent = original.clone();
+      silent.local_flags.remove(LocalFlags::ECHO);
+      silent.local_flags.insert(LocalFlags::ECHONL);
+      if let Err(err) = tcsetattr(&stdin, SetArg::TCSANOW, &silent) {
+        tracing::warn!("Unable to turn off echo: {}", err);
+      }
+    }
+    NoEcho { original }
+  }
+}
+
+#[cfg(unix)]
+impl Drop for NoEcho {
+  fn drop(&mut self) {
+    use nix::sys::termios::{tcsetattr, SetArg};
+    if let Some(original) = &self.original {
+      let _ = tcsetattr(std::io::stdin(), SetArg::TCSANOW, original);
+    }
+  }
+}
+
+async fn migration_status(db: &DB, out: &mut impl Write) -> Result<()> {
+  let rows = db.migrations().await?.into_iter().map(|migration| vec![
+    migration.version.to_string(),
+    migration.description,
+    migration.applied_at.map(|at| at.to_rfc3339()).unwrap_or_else(|| "pending".to_string()),
+  ]).collect::<Vec<_>>();
+  write!(out, "{}", table::render(&["version", "description", "applied"], &rows))?;
+  Ok(())
+}
+
+async fn create_admin(db: &DB, email: &str, name: Option<String>, password: &str, reset: bool, out: &mut impl Write) -> Result<()> {
+  if password.is_empty() {
+    bail!("The password can not be empty");
+  }
+  let mut tx = db.connection().begin().await?;
+  let (user, created) = match User::find_by_email(&mut tx, email).await? {
+    Some(_) if !reset => bail!("There is a user with email {} already, pass --reset to make them an administrator with this password", email),
+    Some(user) => (user, false),
+    None => {
+      let name = name.unwrap_or_else(|| email.split('@').next().unwrap_or(email).to_string());
+      let mut user = User { id: None, name, email: email.to_string() };
+      user.insert(&mut tx).await?;
+      (user, true)
+    }
+  };
+  let id = user.id.ok_or_else(|| eyre!("User has no id"))?;
+  User::set_password(&mut tx, id, password).await?;
+  User::set_admin(&mut tx, id, true).await?;
+  User::mark_verified(&mut tx, id).await?;
+  tx.commit().await?;
+  match created {
+    true => writeln!(out, "Created administrator {} with id {}", email, id)?,
+    false => writeln!(out, "Made {} an administrator and set their password", email)?,
+  }
+  Ok(())
+}
+
+/// Creates missing collections and updates existing ones, all or none.
+async fn import_collections(db: &DB, collections: Vec<Collection>, out: &mut impl Write) -> Result<()> {
+  for collection in &collections {
+    collection.validate().with_context(|| format!("Invalid collection {}", collection.name))?;
+  }
+  let mut tx = db.connection().begin().await?;
+  let mut report = vec![];
+  for collection in &collections {
+    match Collection::find(&mut tx, &collection.name).await? {
+      Some(existing) => {
+        existing.update_collection(&mut tx, collection).await
+          .with_context(|| format!("Unable to update collection {}", collection.name))?;
+        report.push(format!("Updated {}", collection.name));
+      }
+      None => {
+        collection.create_collection(&mut tx).await
+          .with_context(|| format!("Unable to create collection {}", collection.name))?;
+        report.push(format!("Created {}", collection.name));
+      }
+    }
+    collection.save(&mut tx).await?;
+  }
+  tx.commit().await?;
+  for line in report {
+    writeln!(out, "{}", line)?;
+  }
+  Ok(())
+}
+
+/// Loads the settings and tries out each section, failing when any of them
+/// does not work.
+async fn check_settings(out: &mut impl Write) -> Result<()> {
+  let settings = match Settings::new() {
+    Ok(settings) => settings,
+    Err(err) => {
+      writeln!(out, "fail  settings: {:#}", err)?;
+      bail!("The settings can not be loaded");
+    }
+  };
+  let mut failures = 0;
+  let address = SocketAddr::from_str(&format!("{}:{}", settings.host, settings.port))
+    .map(|_| ()).map_err(|err| eyre!("Invalid host or port: {}", err));
+  failures += report(out, "server", address)?;
+  if let Some(config) = &settings.tls {
+    failures += report(out, "tls", tls::load_key(config).map(|_| ()))?;
+  }
+  failures += report(out, "cors", CorsPolicies::from_settings(&settings.cors).map(|_| ()))?;
+  if let Some(config) = &settings.metrics {
+    failures += report(out, "metrics", metrics::bind_addr(config).map(|_| ()))?;
+  }
+  let database = async {
+    let db = DB::open(&settings.database).await?;
+    let pending = db.migrations().await?.into_iter().filter(|migration| migration.applied_at.is_none()).count();
+    match pending {
+      0 => Ok(()),
+      pending => Err(eyre!("{} migrations are pending, run `rocketbase migrate up`", pending)),
+    }
+  }.await;
+  failures += report(out, "database", database)?;
+  failures += report(out, "mail", mailer::from_settings(&settings.mail).map(|_| ()))?;
+  failures += report(out, "storage", storage::from_settings(&settings.storage).map(|_| ()))?;
+  let mut incomplete = settings.auth.providers.iter()
+    .filter(|(_, provider)| {
+      let endpoints = provider.issuer.is_some() || (provider.auth_url.is_some() && provider.token_url.is_some());
+      provider.client_id.is_empty() || provider.client_secret.is_empty()
+        || (provider.kind == OAuthProviderKind::Oidc && !endpoints)
+    })
+    .map(|(name, _)| name.as_str())
+    .collect::<Vec<_>>();
+  incomplete.sort_unstable();
+  let providers = match incomplete.is_empty() {
+    true => Ok(()),
+    false => Err(eyre!("Providers without credentials or endpoints: {}", incomplete.join(", "))),
+  };
+  failures += report(out, "auth", providers)?;
+  match failures {
+    0 => Ok(()),
+    failures => bail!("{} sections of the settings have problems", failures),
+  }
+}
+
+/// Writes the outcome of one section check and counts it when it failed.
+fn report(out: &mut impl Write, section: &str, result: Result<()>) -> Result<usize> {
+  match result {
+    Ok(()) => {
+      writeln!(out, "ok    {}", section)?;
+      Ok(0)
+    }
+    Err(err) => {
+      writeln!(out, "fail  {}: {:#}", section, err)?;
+      Ok(1)
+    }
+  }
+}
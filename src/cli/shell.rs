@@ -0,0 +1,268 @@
+//! `rocketbase shell`, a prompt for looking around collections and their
+//! records. Writes go straight to the tables, like the writes of plugins, so
+//! hooks and access rules do not apply.
+use std::{io::Write, path::PathBuf};
+
+use color_eyre::{eyre::{bail, eyre, WrapErr}, Result};
+use rustyline::{
+  completion::Completer, error::ReadlineError, history::DefaultHistory, Context, Editor, Helper, Highlighter, Hinter,
+  Validator,
+};
+use serde_json::{Map, Value};
+use sqlx::PgPool;
+
+use super::{table, yes_no};
+use crate::{
+  db::DB,
+  model::{collection::{Collection, Operation, SYSTEM_COLUMNS}, Record, Records},
+};
+
+const COMMANDS: [&str; 8] = ["collections", "describe", "find", "insert", "update", "help", "exit", "quit"];
+const FIND_LIMIT: i64 = 50;
+const HELP: &str = "\
+collections                      list the collections
+describe <collection>            show the columns and rules of a collection
+find <collection> [id | filter]  show records, all of them, by id, or those containing a JSON filter
+insert <collection> <json>       create a record from a JSON object
+update <collection> <id> <json>  change the fields of a record given in a JSON object
+exit                             leave the shell, as does Ctrl-D
+";
+
+/// Runs commands against the database, keeping the collection definitions at
+/// hand for completion.
+#[derive(Debug)]
+pub struct Shell {
+  pool: PgPool,
+  collections: Vec<Collection>,
+}
+
+impl Shell {
+  pub async fn new(pool: PgPool) -> Result<Self> {
+    let mut shell = Self { pool, collections: vec![] };
+    shell.refresh().await?;
+    Ok(shell)
+  }
+
+  /// Loads the collection definitions again, as they may have changed since.
+  pub async fn refresh(&mut self) -> Result<()> {
+    self.collections = Collection::all(&self.pool).await?;
+    Ok(())
+  }
+
+  pub fn collections(&self) -> &[Collection] {
+    &self.collections
+  }
+
+  fn collection(&self, name: &str) -> Result<&Collection> {
+    match name {
+      "" => bail!("Which collection? See `collections`"),
+      name => self.collections.iter().find(|collection| collection.name == name)
+        .ok_or_else(|| eyre!("There is no collection {}", name)),
+    }
+  }
+
+  /// Runs one line of input, returning false when the shell should exit.
+  pub async fn execute(&mut self, line: &str, out: &mut impl Write) -> Result<bool> {
+    let (command, rest) = split_word(line);
+    match command {
+      "" => {}
+      "exit" | "quit" => return Ok(false),
+      "help" => write!(out, "{}", HELP)?,
+      "collections" => {
+        self.refresh().await?;
+        let rows = self.collections.iter().map(|collection| vec![
+          collection.name.clone(),
+          collection.column_names().collect::<Vec<_>>().join(", "),
+        ]).collect::<Vec<_>>();
+        write!(out, "{}", table::render(&["name", "columns"], &rows))?;
+      }
+      "describe" => {
+        let collection = self.collection(rest)?;
+        let rows = collection.column_defs.iter().map(|cd| Ok(vec![
+          cd.name.clone(),
+          cell(&serde_json::to_value(&cd.column_type)?),
+          yes_no(cd.required),
+          yes_no(cd.unique),
+        ])).collect::<Result<Vec<_>>>()?;
+        write!(out, "{}", table::render(&["column", "type", "required", "unique"], &rows))?;
+        let operations = [Operation::List, Operation::View, Operation::Create, Operation::Update, Operation::Delete];
+        let rows = operations.into_iter().map(|operation| Ok(vec![
+          cell(&serde_json::to_value(operation)?),
+          cell(&serde_json::to_value(collection.rules.rule(operation))?),
+        ])).collect::<Result<Vec<_>>>()?;
+        write!(out, "\n{}", table::render(&["operation", "rule"], &rows))?;
+      }
+      "find" => {
+        let (name, filter) = split_word(rest);
+        let collection = self.collection(name)?;
+        let records = match filter.parse::<i64>() {
+          Ok(id) => Records::find(&self.pool, collection, id).await?.into_iter().collect(),
+          Err(_) => {
+            let filter = match filter {
+              "" => Map::new(),
+              filter => object(filter)?,
+            };
+            Records::matching(&self.pool, collection, &filter, FIND_LIMIT).await?
+          }
+        };
+        write!(out, "{}", records_table(collection, &records))?;
+        writeln!(out, "({} records)", records.len())?;
+      }
+      "insert" => {
+        let (name, data) = split_word(rest);
+        let collection = self.collection(name)?;
+        let record = Records::insert(&self.pool, collection, &object(data)?).await?;
+        write!(out, "{}", records_table(collection, &[record]))?;
+      }
+      "update" => {
+        let (name, rest) = split_word(rest);
+        let (id, data) = split_word(rest);
+        let collection = self.collection(name)?;
+        let id = id.parse::<i64>().map_err(|_| eyre!("Expected the id of a record"))?;
+        match Records::update(&self.pool, collection, id, &object(data)?).await? {
+          Some(record) => write!(out, "{}", records_table(collection, &[record]))?,
+          None => bail!("There is no record {} in {}", id, collection.name),
+        }
+      }
+      command => bail!("Unknown command {}, try `help`", command),
+    }
+    Ok(true)
+  }
+}
+
+/// Splits off the first word of `line`, trimming what is left.
+fn split_word(line: &str) -> (&str, &str) {
+  let line = line.trim();
+  match line.split_once(char::is_whitespace) {
+    Some((word, rest)) => (word, rest.trim_start()),
+    None => (line, ""),
+  }
+}
+
+fn object(json: &str) -> Result<Map<String, Value>> {
+  match serde_json::from_str(json).context("Expected a JSON object")? {
+    Value::Object(fields) => Ok(fields),
+    _ => bail!("Expected a JSON object"),
+  }
+}
+
+/// Strings are shown without quotes and nulls as nothing.
+fn cell(value: &Value) -> String {
+  match value {
+    Value::Null => String::new(),
+    Value::String(s) => s.clone(),
+    value => value.to_string(),
+  }
+}
+
+fn records_table(collection: &Collection, records: &[Record]) -> String {
+  let mut headers = vec!["id"];
+  headers.extend(collection.column_names());
+  headers.extend(&SYSTEM_COLUMNS[1..]);
+  let rows = records.iter()
+    .map(|record| headers.iter().map(|header| cell(&record[*header])).collect())
+    .collect::<Vec<_>>();
+  table::render(&headers, &rows)
+}
+
+/// Completes the word before `pos`: commands first, then collection names,
+/// then the columns of the collection, also inside JSON.
+pub fn complete(collections: &[Collection], line: &str, pos: usize) -> (usize, Vec<String>) {
+  let start = line[..pos].rfind([' ', '{', '"', ',', ':']).map(|i| i + 1).unwrap_or(0);
+  let prefix = &line[start..pos];
+  let words = line[..start].split_whitespace().collect::<Vec<_>>();
+  let options: Vec<String> = match words.as_slice() {
+    [] => COMMANDS.iter().map(|command| command.to_string()).collect(),
+    ["describe" | "find" | "insert" | "update"] => collections.iter().map(|collection| collection.name.clone()).collect(),
+    ["find" | "insert" | "update", name, ..] => collections.iter()
+      .filter(|collection| collection.name == *name)
+      .flat_map(|collection| collection.column_names().chain(SYSTEM_COLUMNS).map(String::from))
+      .collect(),
+    _ => vec![],
+  };
+  let mut candidates = options.into_iter().filter(|option| option.starts_with(prefix)).collect::<Vec<_>>();
+  candidates.sort_unstable();
+  candidates.dedup();
+  (start, candidates)
+}
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ShellHelper {
+  collections: Vec<Collection>,
+}
+
+impl Completer for ShellHelper {
+  type Candidate = String;
+
+  fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+    Ok(complete(&self.collections, line, pos))
+  }
+}
+
+fn history_file() -> PathBuf {
+  std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(".rocketbase_history")
+}
+
+/// Reads commands from the terminal until `exit` or Ctrl-D. Errors of a
+/// command are printed and do not end the shell.
+pub async fn run(db: &DB, out: &mut impl Write) -> Result<()> {
+  let mut shell = Shell::new(db.connection()).await?;
+  let mut editor = Editor::<ShellHelper, DefaultHistory>::new()?;
+  editor.set_helper(Some(ShellHelper { collections: shell.collections().to_vec() }));
+  let history = history_file();
+  // there is no history the first time around
+  let _ = editor.load_history(&history);
+  writeln!(out, "Connected, type `help` for the commands")?;
+  loop {
+    let line = match tokio::task::block_in_place(|| editor.readline("rocketbase> ")) {
+      Ok(line) => line,
+      Err(ReadlineError::Interrupted) => continue,
+      Err(ReadlineError::Eof) => break,
+      Err(err) => return Err(err.into()),
+    };
+    if !line.trim().is_empty() {
+      editor.add_history_entry(line.as_str())?;
+    }
+    match shell.execute(&line, out).await {
+      Ok(true) => {}
+      Ok(false) => break,
+      Err(err) => writeln!(out, "error: {:#}", err)?,
+    }
+    if let Some(helper) = editor.helper_mut() {
+      helper.collections = shell.collections().to_vec();
+    }
+  }
+  if let Err(err) = editor.save_history(&history) {
+    tracing::warn!("Unable to save the shell history to {}: {}", history.display(), err);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::complete;
+  use crate::model::collection::Collection;
+
+  fn posts() -> Collection {
+    serde_json::from_value(json!({
+      "name": "posts",
+      "column_defs": [
+        {"id": "6b6f1b1e-5a43-4c8e-9a3e-0f7d1c1b2a01", "name": "title", "column_type": "Text", "required": true, "unique": false},
+        {"id": "6b6f1b1e-5a43-4c8e-9a3e-0f7d1c1b2a02", "name": "tags", "column_type": "JSON", "required": false, "unique": false}
+      ]
+    })).unwrap()
+  }
+
+  #[test]
+  fn should_complete_commands_collections_and_columns() {
+    let collections = vec![posts()];
+    assert_eq!(complete(&collections, "de", 2), (0, vec!["describe".to_string()]));
+    assert_eq!(complete(&collections, "find p", 6), (5, vec!["posts".to_string()]));
+    let line = r#"find posts {"t"#;
+    assert_eq!(complete(&collections, line, line.len()), (13, vec!["tags".to_string(), "title".to_string()]));
+    assert_eq!(complete(&collections, "find drafts {\"t", 15).1, Vec::<String>::new());
+    assert_eq!(complete(&collections, "collections p", 13).1, Vec::<String>::new());
+  }
+}
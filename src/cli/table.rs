@@ -0,0 +1,41 @@
+/// Lays out rows under their headers in left aligned columns.
+pub fn render(headers: &[&str], rows: &[Vec<String>]) -> String {
+  let mut widths = headers.iter().map(|header| header.chars().count()).collect::<Vec<_>>();
+  for row in rows {
+    for (width, cell) in widths.iter_mut().zip(row) {
+      *width = (*width).max(cell.chars().count());
+    }
+  }
+  let line = |cells: Vec<&str>| {
+    let padded = cells.iter().zip(&widths)
+      .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+      .collect::<Vec<_>>();
+    format!("{}\n", padded.join("  ").trim_end())
+  };
+  let mut table = line(headers.to_vec());
+  let rule = widths.iter().map(|width| "-".repeat(*width)).collect::<Vec<_>>();
+  table.push_str(&line(rule.iter().map(String::as_str).collect()));
+  for row in rows {
+    table.push_str(&line(row.iter().map(String::as_str).collect()));
+  }
+  table
+}
+
+#[cfg(test)]
+mod tests {
+  use super::render;
+
+  #[test]
+  fn should_pad_columns_to_the_widest_cell() {
+    let rows = vec![
+      vec!["1".to_string(), "ada@example.com".to_string()],
+      vec!["12".to_string(), "bob".to_string()],
+    ];
+    assert_eq!(render(&["id", "email"], &rows), "\
+id  email
+--  ---------------
+1   ada@example.com
+12  bob
+");
+  }
+}
@@ -1,10 +1,19 @@
+use chrono::{DateTime, Utc};
 use sqlx::postgres::{PgPool, PgPoolOptions};
-use sqlx::migrate;
+use sqlx::{migrate, query_as, query_scalar};
 use color_eyre::{eyre::WrapErr, Result};
 use tracing::instrument;
-use crate::settings::SETTINGS;
+use crate::settings::{self, SETTINGS};
 use tracing::info;
 
+/// A migration of the binary and when it was applied, if it was.
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+  pub version: i64,
+  pub description: String,
+  pub applied_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug)]
 pub struct DB {
   pool: PgPool
@@ -16,15 +25,44 @@ impl DB {
   }
   #[instrument]
   pub async fn new() -> Result<Self> {
+    Self::connect(&SETTINGS.database).await
+  }
+  /// Connects to the configured database and brings its schema up to date.
+  #[instrument(skip_all)]
+  pub async fn connect(settings: &settings::Database) -> Result<Self> {
+    let db = Self::open(settings).await?;
+    db.migrate().await?;
+    info!("Connected to database: {}", settings.url);
+    Ok(db)
+  }
+  /// Connects to the configured database without migrating it.
+  #[instrument(skip_all)]
+  pub async fn open(settings: &settings::Database) -> Result<Self> {
     let pool = PgPoolOptions::new()
       .max_connections(5)
-      .connect(&SETTINGS.database.url)
+      .connect(&settings.url)
       .await.context("Unable to connect")?;
-    let migrator = migrate!();
-    migrator.run(&pool).await.context("Unable to run migrations!")?;
-    info!("Connected to database: {}", SETTINGS.database.url);
     Ok(DB{pool})
   }
+  pub async fn migrate(&self) -> Result<()> {
+    let migrator = migrate!();
+    migrator.run(&self.pool).await.context("Unable to run migrations!")
+  }
+  /// Every migration of the binary, oldest first, with when it was applied.
+  pub async fn migrations(&self) -> Result<Vec<MigrationStatus>> {
+    let tracked = query_scalar::<_, bool>("select to_regclass('_sqlx_migrations') is not null")
+      .fetch_one(&self.pool).await?;
+    let applied: Vec<(i64, DateTime<Utc>)> = match tracked {
+      true => query_as("select version, installed_on from _sqlx_migrations where success")
+        .fetch_all(&self.pool).await.context("Unable to read migrations")?,
+      false => vec![],
+    };
+    Ok(migrate!().iter().map(|migration| MigrationStatus {
+      version: migration.version,
+      description: migration.description.to_string(),
+      applied_at: applied.iter().find(|(version, _)| *version == migration.version).map(|(_, at)| *at),
+    }).collect())
+  }
   pub fn new_with_pool(pool: PgPool) -> Self {
     DB{pool}
   }
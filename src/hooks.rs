@@ -0,0 +1,220 @@
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+
+use axum::{
+  http::StatusCode,
+  response::{IntoResponse, Response},
+  Json,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{
+  auth::Principal,
+  model::{collection::Collection, Record, User},
+};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type BeforeHook<E> = Arc<dyn Fn(E) -> BoxFuture<Result<E, Rejection>> + Send + Sync>;
+type AfterHook<E> = Arc<dyn Fn(E) -> BoxFuture<()> + Send + Sync>;
+
+/// Why a before hook stopped an operation. The status is answered with the
+/// message, if any, as `{"error": message}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+  pub status: StatusCode,
+  pub message: Option<String>,
+}
+
+impl Rejection {
+  pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+    Rejection { status, message: Some(message.into()) }
+  }
+}
+
+impl From<StatusCode> for Rejection {
+  fn from(status: StatusCode) -> Self {
+    Rejection { status, message: None }
+  }
+}
+
+impl IntoResponse for Rejection {
+  fn into_response(self) -> Response {
+    match self.message {
+      Some(message) => (self.status, Json(json!({ "error": message }))).into_response(),
+      None => self.status.into_response(),
+    }
+  }
+}
+
+/// A record being created, updated or deleted.
+#[derive(Debug, Clone)]
+pub struct RecordEvent {
+  pub collection: Collection,
+  pub principal: Option<Principal>,
+  /// The stored record. Before hooks see it as it is for updates and deletes,
+  /// after hooks as it ended up.
+  pub record: Option<Record>,
+  /// Fields being written, which before hooks may change. Empty for deletes.
+  pub data: Map<String, Value>,
+}
+
+/// How a user signs in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthMethod {
+  Password,
+  OAuth(String),
+}
+
+/// A sign in about to happen. Before hooks may change the email of password
+/// logins; OAuth emails come from the provider and are only for inspection.
+#[derive(Debug, Clone)]
+pub struct AuthAttempt {
+  pub method: AuthMethod,
+  pub email: Option<String>,
+}
+
+/// A user who passed their first factor.
+#[derive(Debug, Clone)]
+pub struct AuthEvent {
+  pub method: AuthMethod,
+  pub user: User,
+}
+
+/// A collection created or changed by an admin. `previous` is `None` for new
+/// collections.
+#[derive(Debug, Clone)]
+pub struct SchemaChange {
+  pub previous: Option<Collection>,
+  pub collection: Collection,
+}
+
+struct BeforeHooks<E>(Vec<BeforeHook<E>>);
+
+impl<E> BeforeHooks<E> {
+  fn add<F, Fut>(&mut self, hook: F)
+  where
+    F: Fn(E) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<E, Rejection>> + Send + 'static,
+  {
+    self.0.push(Arc::new(move |event| Box::pin(hook(event))));
+  }
+
+  /// Passes the event through the hooks in order, stopping at the first one
+  /// that rejects it.
+  async fn run(&self, mut event: E) -> Result<E, Rejection> {
+    for hook in &self.0 {
+      event = hook(event).await?;
+    }
+    Ok(event)
+  }
+}
+
+struct AfterHooks<E>(Vec<AfterHook<E>>);
+
+impl<E: Clone> AfterHooks<E> {
+  fn add<F, Fut>(&mut self, hook: F)
+  where
+    F: Fn(E) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+  {
+    self.0.push(Arc::new(move |event| Box::pin(hook(event))));
+  }
+
+  async fn run(&self, event: E) {
+    for hook in &self.0 {
+      hook(event.clone()).await;
+    }
+  }
+}
+
+impl<E> Default for BeforeHooks<E> {
+  fn default() -> Self {
+    BeforeHooks(vec![])
+  }
+}
+
+impl<E> Default for AfterHooks<E> {
+  fn default() -> Self {
+    AfterHooks(vec![])
+  }
+}
+
+/// Callbacks of embedding applications, installed with
+/// [`AppState::with_hooks`](crate::app_state::AppState::with_hooks). Before
+/// hooks run in the order they were added and may change the event or reject
+/// the operation with a [`Rejection`]. After hooks run once the operation succeeded and
+/// delay the response until they are done.
+#[derive(Default)]
+pub struct Hooks {
+  before_create: BeforeHooks<RecordEvent>,
+  after_create: AfterHooks<RecordEvent>,
+  before_update: BeforeHooks<RecordEvent>,
+  after_update: AfterHooks<RecordEvent>,
+  before_delete: BeforeHooks<RecordEvent>,
+  after_delete: AfterHooks<RecordEvent>,
+  before_auth: BeforeHooks<AuthAttempt>,
+  after_auth: AfterHooks<AuthEvent>,
+  schema_change: AfterHooks<SchemaChange>,
+}
+
+macro_rules! hook {
+  ($register:ident, $run:ident, $field:ident, Before<$event:ty>) => {
+    pub fn $register<F, Fut>(mut self, hook: F) -> Self
+    where
+      F: Fn($event) -> Fut + Send + Sync + 'static,
+      Fut: Future<Output = Result<$event, Rejection>> + Send + 'static,
+    {
+      self.$field.add(hook);
+      self
+    }
+
+    pub(crate) async fn $run(&self, event: $event) -> Result<$event, Rejection> {
+      self.$field.run(event).await
+    }
+  };
+  ($register:ident, $run:ident, $field:ident, After<$event:ty>) => {
+    pub fn $register<F, Fut>(mut self, hook: F) -> Self
+    where
+      F: Fn($event) -> Fut + Send + Sync + 'static,
+      Fut: Future<Output = ()> + Send + 'static,
+    {
+      self.$field.add(hook);
+      self
+    }
+
+    pub(crate) async fn $run(&self, event: $event) {
+      self.$field.run(event).await
+    }
+  };
+}
+
+impl Hooks {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  hook!(before_create, run_before_create, before_create, Before<RecordEvent>);
+  hook!(after_create, run_after_create, after_create, After<RecordEvent>);
+  hook!(before_update, run_before_update, before_update, Before<RecordEvent>);
+  hook!(after_update, run_after_update, after_update, After<RecordEvent>);
+  hook!(before_delete, run_before_delete, before_delete, Before<RecordEvent>);
+  hook!(after_delete, run_after_delete, after_delete, After<RecordEvent>);
+  hook!(before_auth, run_before_auth, before_auth, Before<AuthAttempt>);
+  hook!(after_auth, run_after_auth, after_auth, After<AuthEvent>);
+  hook!(on_schema_change, run_schema_change, schema_change, After<SchemaChange>);
+}
+
+impl fmt::Debug for Hooks {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Hooks")
+      .field("before_create", &self.before_create.0.len())
+      .field("after_create", &self.after_create.0.len())
+      .field("before_update", &self.before_update.0.len())
+      .field("after_update", &self.after_update.0.len())
+      .field("before_delete", &self.before_delete.0.len())
+      .field("after_delete", &self.after_delete.0.len())
+      .field("before_auth", &self.before_auth.0.len())
+      .field("after_auth", &self.after_auth.0.len())
+      .field("schema_change", &self.schema_change.0.len())
+      .finish()
+  }
+}
@@ -1,6 +1,23 @@
+pub mod auth;
+pub mod cli;
 pub mod db;
+pub mod hooks;
+pub mod metrics;
 pub mod model;
+pub mod plugins;
+pub mod queue;
+pub mod rate_limit;
+pub mod realtime;
 pub mod router;
+pub mod scheduler;
 pub mod server;
 pub mod settings;
+pub mod shutdown;
+pub mod storage;
+pub mod telemetry;
+pub mod tls;
 pub mod app_state;
+pub mod mailer;
+pub mod webhooks;
+
+pub use server::{Rocketbase, RocketbaseBuilder};
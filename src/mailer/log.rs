@@ -0,0 +1,64 @@
+use std::path::PathBuf;
+use async_trait::async_trait;
+use chrono::Utc;
+use color_eyre::{eyre::WrapErr, Result};
+use tracing::info;
+
+use super::{Mailer, Message};
+
+/// Mailer for development and tests. Messages are written to the log and,
+/// when a directory is configured, to one file per message.
+#[derive(Debug, Default, Clone)]
+pub struct LogMailer {
+  dir: Option<PathBuf>,
+}
+
+impl LogMailer {
+  pub fn with_dir(dir: impl Into<PathBuf>) -> Self {
+    LogMailer { dir: Some(dir.into()) }
+  }
+}
+
+/// The recipient as part of a file name. Recipients come from signups, so
+/// anything that could leave the mail directory is replaced.
+fn file_safe(to: &str) -> String {
+  to.chars().map(|c| match c {
+    'A'..='Z' | 'a'..='z' | '0'..='9' | '@' | '.' | '_' | '-' => c,
+    _ => '_',
+  }).collect()
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+  async fn send(&self, message: Message) -> Result<()> {
+    info!(to = %message.to, subject = %message.subject, "sending mail");
+    if let Some(dir) = &self.dir {
+      tokio::fs::create_dir_all(dir).await.context("Unable to create mail directory")?;
+      let name = format!("{}-{}.eml", Utc::now().format("%Y%m%d%H%M%S%.f"), file_safe(&message.to));
+      let contents = format!("To: {}\nSubject: {}\n\n{}", message.to, message.subject, message.body);
+      tokio::fs::write(dir.join(name), contents).await.context("Unable to write mail")?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{LogMailer, Mailer, Message};
+
+  #[tokio::test]
+  async fn should_keep_mail_inside_its_directory() {
+    let root = std::env::temp_dir().join(format!("rocketbase-mail-{}", std::process::id()));
+    let dir = root.join("mail");
+    let mailer = LogMailer::with_dir(&dir);
+    let message = Message { to: "../../x/evil@example.com".into(), subject: "Hi".into(), body: "".into() };
+    mailer.send(message).await.unwrap();
+    let names: Vec<String> = std::fs::read_dir(&dir).unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect();
+    assert_eq!(names.len(), 1);
+    assert!(names[0].ends_with("-.._.._x_evil@example.com.eml"), "{}", names[0]);
+    assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+    std::fs::remove_dir_all(root).unwrap();
+  }
+}
@@ -0,0 +1,80 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+
+use crate::settings::{Mail, MailTemplate, MailTransport};
+
+pub use self::log::LogMailer;
+pub use self::smtp::SmtpMailer;
+
+pub(crate) mod log;
+pub(crate) mod smtp;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+  pub to: String,
+  pub subject: String,
+  pub body: String,
+}
+
+/// Delivers outgoing mail. Implementations must be cheap to share across
+/// requests as a single instance lives on `AppState`.
+#[async_trait]
+pub trait Mailer: Debug + Send + Sync {
+  async fn send(&self, message: Message) -> Result<()>;
+}
+
+/// Builds the mailer selected by the `mail.transport` setting.
+pub fn from_settings(settings: &Mail) -> Result<Arc<dyn Mailer>> {
+  let mailer: Arc<dyn Mailer> = match settings.transport {
+    MailTransport::Smtp => {
+      let smtp = settings.smtp.as_ref()
+        .ok_or_else(|| eyre!("mail.smtp must be configured for the smtp transport"))?;
+      Arc::new(SmtpMailer::new(smtp, &settings.from)?)
+    }
+    MailTransport::File => {
+      let dir = settings.dir.as_ref()
+        .ok_or_else(|| eyre!("mail.dir must be configured for the file transport"))?;
+      Arc::new(LogMailer::with_dir(dir))
+    }
+    MailTransport::Log => Arc::new(LogMailer::default()),
+  };
+  Ok(mailer)
+}
+
+/// Renders a template by substituting `{{key}}` placeholders.
+pub fn render(template: &MailTemplate, to: &str, vars: &[(&str, &str)]) -> Message {
+  let fill = |text: &str| {
+    vars.iter().fold(text.to_string(), |acc, (key, value)| {
+      acc.replace(&format!("{{{{{}}}}}", key), value)
+    })
+  };
+  Message {
+    to: to.to_string(),
+    subject: fill(&template.subject),
+    body: fill(&template.body),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::render;
+  use crate::settings::MailTemplate;
+
+  #[test]
+  fn should_render_placeholders() {
+    let template = MailTemplate {
+      subject: "Hi {{name}}".into(),
+      body: "{{app_url}}/verify?token={{token}} {{unknown}}".into(),
+    };
+    let msg = render(&template, "a@example.com", &[
+      ("name", "Vagmi"),
+      ("app_url", "http://localhost"),
+      ("token", "abc"),
+    ]);
+    assert_eq!(msg.to, "a@example.com");
+    assert_eq!(msg.subject, "Hi Vagmi");
+    assert_eq!(msg.body, "http://localhost/verify?token=abc {{unknown}}");
+  }
+}
@@ -0,0 +1,47 @@
+use async_trait::async_trait;
+use color_eyre::{eyre::WrapErr, Result};
+use lettre::{
+  message::Mailbox,
+  transport::smtp::authentication::Credentials,
+  AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+
+use super::{Mailer, Message};
+use crate::settings::Smtp;
+
+#[derive(Debug, Clone)]
+pub struct SmtpMailer {
+  transport: AsyncSmtpTransport<Tokio1Executor>,
+  from: Mailbox,
+}
+
+impl SmtpMailer {
+  pub fn new(settings: &Smtp, from: &str) -> Result<Self> {
+    let mut builder = if settings.starttls {
+      AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)
+        .context("Unable to configure SMTP relay")?
+    } else {
+      AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host)
+    };
+    builder = builder.port(settings.port);
+    if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+      builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    let from = from.parse().context("Invalid mail.from address")?;
+    Ok(SmtpMailer { transport: builder.build(), from })
+  }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+  async fn send(&self, message: Message) -> Result<()> {
+    let email = lettre::Message::builder()
+      .from(self.from.clone())
+      .to(message.to.parse().context("Invalid recipient address")?)
+      .subject(message.subject)
+      .body(message.body)
+      .context("Unable to build mail")?;
+    self.transport.send(email).await.context("Unable to send mail")?;
+    Ok(())
+  }
+}
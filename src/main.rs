@@ -1,22 +1,32 @@
+use clap::Parser;
 use color_eyre::{eyre::WrapErr, Result};
-use librocketbase::{router, server, app_state::AppState};
+use librocketbase::cli::{self, Cli, Command};
+use librocketbase::settings::Settings;
+use librocketbase::telemetry;
 use tracing_error::ErrorLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use librocketbase::settings::SETTINGS;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Cli::parse();
+    // broken settings are reported by the commands, `settings check` included
+    let settings = Settings::new().ok();
+    let rust_log = settings.as_ref().map_or_else(|| "info".to_string(), |settings| settings.rust_log.clone());
+    let filter = std::env::var("RUST_LOG").unwrap_or(rust_log);
+    let otlp = match settings.as_ref().and_then(|settings| settings.otlp.as_ref()) {
+        Some(config) => Some(telemetry::layer(config, &filter)?),
+        None => None,
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG")
-                .unwrap_or_else(|_| SETTINGS.rust_log.clone()),
-        ))
-        .with(tracing_subscriber::fmt::layer())
-        .with(ErrorLayer::default())
+        .with(tracing_subscriber::fmt::layer().and_then(ErrorLayer::default()).with_filter(tracing_subscriber::EnvFilter::new(&filter)))
+        .with(otlp)
         .init();
     color_eyre::install()?;
 
-    let app_state = AppState::init().await?;
-    let router = router::build_router(app_state).await?;
-    server::serve(router).await.context("Unable to serve")
-}
\ No newline at end of file
+    let command = args.command.unwrap_or(Command::Serve);
+    let ran = cli::run(command, &mut std::io::stdout()).await.context("Unable to run command");
+    if settings.and_then(|settings| settings.otlp).is_some() {
+        tokio::task::spawn_blocking(telemetry::shutdown).await?;
+    }
+    ran
+}
@@ -0,0 +1,141 @@
+//! Prometheus metrics. Requests are counted as they are served; the pool,
+//! realtime and queue figures are read when scraped. The pool does not tell
+//! how long queries wait for a connection, but one without idle connections
+//! is one that makes them wait.
+use std::{fmt, net::SocketAddr, time::Instant};
+
+use axum::{
+  extract::{MatchedPath, State},
+  http::{header, HeaderMap, Request, StatusCode},
+  middleware::Next,
+  response::{IntoResponse, Response},
+  routing::get,
+  Router,
+};
+use color_eyre::{eyre::{bail, WrapErr}, Result};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  app_state::AppState,
+  auth::bearer_token,
+  model::task::{Task, TaskStatus},
+  router::auth::internal_error,
+  settings,
+};
+
+pub struct Metrics {
+  registry: Registry,
+  requests: IntCounterVec,
+  latency: HistogramVec,
+  pool_connections: IntGauge,
+  pool_idle: IntGauge,
+  subscribers: IntGauge,
+  tasks: IntGaugeVec,
+}
+
+impl Metrics {
+  pub fn new() -> Self {
+    let labels = ["method", "route", "status"];
+    let requests = IntCounterVec::new(Opts::new("rocketbase_http_requests_total", "HTTP requests served"), &labels).unwrap();
+    let latency = HistogramVec::new(HistogramOpts::new("rocketbase_http_request_duration_seconds", "Time to respond to HTTP requests"), &labels).unwrap();
+    let pool_connections = IntGauge::new("rocketbase_db_pool_connections", "Open database connections").unwrap();
+    let pool_idle = IntGauge::new("rocketbase_db_pool_idle_connections", "Idle database connections").unwrap();
+    let subscribers = IntGauge::new("rocketbase_realtime_subscribers", "Clients subscribed to record changes").unwrap();
+    let tasks = IntGaugeVec::new(Opts::new("rocketbase_queue_tasks", "Tasks in the queue by status"), &["status"]).unwrap();
+    let registry = Registry::new();
+    registry.register(Box::new(requests.clone())).unwrap();
+    registry.register(Box::new(latency.clone())).unwrap();
+    registry.register(Box::new(pool_connections.clone())).unwrap();
+    registry.register(Box::new(pool_idle.clone())).unwrap();
+    registry.register(Box::new(subscribers.clone())).unwrap();
+    registry.register(Box::new(tasks.clone())).unwrap();
+    Self { registry, requests, latency, pool_connections, pool_idle, subscribers, tasks }
+  }
+
+  /// Counts a response to a request for `route`, the path it was routed by.
+  pub fn observe(&self, method: &str, route: &str, status: StatusCode, elapsed: f64) {
+    let labels = [method, route, status.as_str()];
+    self.requests.with_label_values(&labels).inc();
+    self.latency.with_label_values(&labels).observe(elapsed);
+  }
+
+  /// Reads the current figures of `state` and renders all metrics in the
+  /// Prometheus text format.
+  pub async fn render(&self, state: &AppState) -> Result<String> {
+    let pool = state.db().connection();
+    self.pool_connections.set(pool.size() as i64);
+    self.pool_idle.set(pool.num_idle() as i64);
+    self.subscribers.set(state.realtime().subscribers() as i64);
+    for status in [TaskStatus::Pending, TaskStatus::Running, TaskStatus::Dead] {
+      self.tasks.with_label_values(&[status_label(status)]).set(0);
+    }
+    for (status, count) in Task::counts(&pool).await? {
+      self.tasks.with_label_values(&[status_label(status)]).set(count);
+    }
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+  }
+}
+
+impl Default for Metrics {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl fmt::Debug for Metrics {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Metrics").finish_non_exhaustive()
+  }
+}
+
+fn status_label(status: TaskStatus) -> &'static str {
+  match status {
+    TaskStatus::Pending => "pending",
+    TaskStatus::Running => "running",
+    TaskStatus::Dead => "dead",
+  }
+}
+
+/// Middleware that counts requests and times responses, by matched route so
+/// that ids in paths don't each get a series.
+pub async fn track<B>(State(state): State<AppState>, req: Request<B>, next: Next<B>) -> Response {
+  let method = req.method().clone();
+  let route = req.extensions().get::<MatchedPath>().map_or("unmatched", |path| path.as_str()).to_string();
+  let start = Instant::now();
+  let response = next.run(req).await;
+  state.metrics().observe(method.as_str(), &route, response.status(), start.elapsed().as_secs_f64());
+  response
+}
+
+/// Checks that the metrics are guarded, returning the address to serve them
+/// on apart from the rest, if any.
+pub fn bind_addr(config: &settings::Metrics) -> Result<Option<SocketAddr>> {
+  match (&config.token, &config.bind) {
+    (None, None) => bail!("Metrics need a token or a bind address"),
+    (_, Some(bind)) => Ok(Some(bind.parse().with_context(|| format!("Invalid metrics bind address {}", bind))?)),
+    (Some(_), None) => Ok(None),
+  }
+}
+
+/// Serves `/metrics`, asking for the token when there is one.
+pub fn router(state: AppState) -> Router {
+  Router::new().route("/metrics", get(metrics_handler)).with_state(state)
+}
+
+async fn metrics_handler(State(state): State<AppState>, headers: HeaderMap) -> Result<Response, StatusCode> {
+  let Some(config) = &state.settings().metrics else {
+    return Err(StatusCode::NOT_FOUND);
+  };
+  if let Some(token) = &config.token {
+    // digests are compared so that the time taken doesn't tell how much matched
+    let given = bearer_token(&headers).map(|given| Sha256::digest(given.as_bytes()));
+    if given != Some(Sha256::digest(token.as_bytes())) {
+      return Err(StatusCode::UNAUTHORIZED);
+    }
+  }
+  let body = state.metrics().render(&state).await.map_err(internal_error)?;
+  Ok(([(header::CONTENT_TYPE, TextEncoder::new().format_type().to_string())], body).into_response())
+}
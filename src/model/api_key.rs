@@ -0,0 +1,141 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, Executor, Postgres, query, query_as};
+use tracing::instrument;
+use color_eyre::{eyre::WrapErr, Result};
+
+use super::collection::Operation;
+use super::user_token::{generate_token, hash_token};
+
+/// Prefix that makes keys easy to recognise in logs and secret scanners.
+pub const KEY_PREFIX: &str = "rb_";
+
+/// Grants `operations` on a collection, or on every collection when the
+/// collection is `*`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+  pub collection: String,
+  pub operations: Vec<Operation>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewApiKey {
+  pub name: String,
+  pub scopes: Vec<Scope>,
+  pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A key for server to server access. Only a hash of the key is stored; the
+/// key itself is shown once when it is created.
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+  pub id: i64,
+  pub name: String,
+  /// The first characters of the key, to tell keys apart when listing them.
+  pub prefix: String,
+  pub scopes: Json<Vec<Scope>>,
+  pub created_by: Option<i64>,
+  pub expires_at: Option<DateTime<Utc>>,
+  pub last_used_at: Option<DateTime<Utc>>,
+  pub revoked_at: Option<DateTime<Utc>>,
+  pub created_at: DateTime<Utc>,
+}
+
+const COLUMNS: &str = "id, name, prefix, scopes, created_by, expires_at, last_used_at, revoked_at, created_at";
+
+impl ApiKey {
+  /// Returns whether the key grants `operation` on `collection`.
+  pub fn allows(&self, collection: &str, operation: Operation) -> bool {
+    self.scopes.iter().any(|scope| {
+      (scope.collection == "*" || scope.collection == collection) && scope.operations.contains(&operation)
+    })
+  }
+
+  /// Creates a key and returns it along with the raw key to hand out.
+  #[instrument(skip(ex))]
+  pub async fn create<'a, E>(ex: E, new_key: &NewApiKey, created_by: Option<i64>) -> Result<(ApiKey, String)>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let key = format!("{}{}", KEY_PREFIX, generate_token());
+    let api_key = query_as::<_, ApiKey>(&format!(
+      "insert into _api_keys(name, prefix, key_hash, scopes, created_by, expires_at) \
+      values($1, $2, $3, $4, $5, $6) returning {}", COLUMNS))
+    .bind(&new_key.name)
+    .bind(&key[..KEY_PREFIX.len() + 8])
+    .bind(hash_token(&key))
+    .bind(Json(&new_key.scopes))
+    .bind(created_by)
+    .bind(new_key.expires_at)
+    .fetch_one(ex).await.context("Unable to save API key")?;
+    Ok((api_key, key))
+  }
+
+  /// Finds a live key, recording that it was used.
+  #[instrument(skip(ex, key))]
+  pub async fn authenticate<'a, E>(ex: E, key: &str) -> Result<Option<ApiKey>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let api_key = query_as::<_, ApiKey>(&format!(
+      "update _api_keys set last_used_at = now() \
+      where key_hash = $1 and revoked_at is null and (expires_at is null or expires_at > now()) \
+      returning {}", COLUMNS))
+    .bind(hash_token(key))
+    .fetch_optional(ex).await.context("Unable to look up API key")?;
+    Ok(api_key)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn all<'a, E>(ex: E) -> Result<Vec<ApiKey>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let keys = query_as::<_, ApiKey>(&format!("select {} from _api_keys order by id", COLUMNS))
+    .fetch_all(ex).await?;
+    Ok(keys)
+  }
+
+  /// Revokes the key, returning false when there is no such live key.
+  #[instrument(skip(ex))]
+  pub async fn revoke<'a, E>(ex: E, id: i64) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query("update _api_keys set revoked_at = now() where id = $1 and revoked_at is null")
+    .bind(id)
+    .execute(ex).await.context("Unable to revoke API key")?;
+    Ok(res.rows_affected() == 1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::Utc;
+  use sqlx::types::Json;
+
+  use super::{ApiKey, Scope};
+  use crate::model::collection::Operation;
+
+  fn key_with(scopes: Vec<Scope>) -> ApiKey {
+    ApiKey {
+      id: 1,
+      name: "jobs".into(),
+      prefix: "rb_00000000".into(),
+      scopes: Json(scopes),
+      created_by: None,
+      expires_at: None,
+      last_used_at: None,
+      revoked_at: None,
+      created_at: Utc::now(),
+    }
+  }
+
+  #[test]
+  fn should_allow_only_scoped_operations() {
+    let key = key_with(vec![
+      Scope { collection: "posts".into(), operations: vec![Operation::List, Operation::View] },
+      Scope { collection: "*".into(), operations: vec![Operation::Create] },
+    ]);
+    assert!(key.allows("posts", Operation::View));
+    assert!(!key.allows("posts", Operation::Delete));
+    assert!(!key.allows("comments", Operation::View));
+    assert!(key.allows("comments", Operation::Create));
+  }
+}
@@ -0,0 +1,108 @@
+use std::str::FromStr;
+
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Json, Executor, Postgres, query, query_as};
+use tracing::instrument;
+use color_eyre::{eyre::{eyre, WrapErr}, Result};
+
+use super::Record;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+  /// Older triggers reported creates as `insert`.
+  #[serde(alias = "insert")]
+  Create,
+  Update,
+  Delete,
+}
+
+impl Action {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Create => "create",
+      Self::Update => "update",
+      Self::Delete => "delete",
+    }
+  }
+}
+
+impl FromStr for Action {
+  type Err = color_eyre::Report;
+
+  fn from_str(action: &str) -> Result<Self> {
+    match action {
+      "create" | "insert" => Ok(Self::Create),
+      "update" => Ok(Self::Update),
+      "delete" => Ok(Self::Delete),
+      _ => Err(eyre!("Unknown action {}", action)),
+    }
+  }
+}
+
+/// A change to a record, carrying the record as the record API returns it.
+/// Deletes carry the record as it was before being deleted. `seq` identifies
+/// the change and is the offset readers continue from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+  pub seq: i64,
+  pub action: Action,
+  pub collection: String,
+  pub record: Record,
+}
+
+type ChangeRow = (i64, String, String, Json<Value>);
+
+fn from_row((seq, action, collection, Json(record)): ChangeRow) -> Result<ChangeEvent> {
+  Ok(ChangeEvent { seq, action: action.parse()?, collection, record })
+}
+
+/// The `_changes` log the collection triggers append to.
+pub struct Changes;
+
+impl Changes {
+  /// Returns up to `limit` changes following the one numbered `since`.
+  /// Changes are ordered by the transaction that made them, then by `seq`,
+  /// and only those of transactions older than every running one are
+  /// returned: no change can show up before them later on. When `since` no
+  /// longer exists, changes numbered after it are returned.
+  #[instrument(skip(ex))]
+  pub async fn since<'a, E>(ex: E, since: i64, limit: i64) -> Result<Vec<ChangeEvent>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let rows = query_as::<_, ChangeRow>(
+      "with cursor as (select txid, seq from _changes where seq = $1)
+      select seq, action, collection, record from _changes
+      where txid < pg_snapshot_xmin(pg_current_snapshot())
+        and case when exists (select from cursor) then (txid, seq) > (select txid, seq from cursor) else seq > $1 end
+      order by txid, seq limit $2"
+    )
+    .bind(since)
+    .bind(limit)
+    .fetch_all(ex).await.context("Unable to read changes")?;
+    rows.into_iter().map(from_row).collect()
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn find<'a, E>(ex: E, seq: i64) -> Result<Option<ChangeEvent>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let row = query_as::<_, ChangeRow>("select seq, action, collection, record from _changes where seq = $1")
+    .bind(seq)
+    .fetch_optional(ex).await.context("Unable to find change")?;
+    row.map(from_row).transpose()
+  }
+
+  /// Deletes changes older than `retention`, returning how many were deleted.
+  #[instrument(skip(ex))]
+  pub async fn prune<'a, E>(ex: E, retention: Duration) -> Result<u64>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let result = query("delete from _changes where created_at < now() - $1 * interval '1 second'")
+    .bind(retention.num_seconds() as f64)
+    .execute(ex).await.context("Unable to prune changes")?;
+    Ok(result.rows_affected())
+  }
+}
@@ -1,11 +1,25 @@
 use serde::{Deserialize, Serialize};
-use sqlx::{Executor, Postgres};
+use sqlx::{types::Json, Executor, Postgres, query, query_scalar};
 use tokio_stream::StreamExt;
+
+use super::file::Thumb;
 pub mod column_def;
 pub mod column_type;
+pub mod operation;
+pub mod rules;
 
 pub use self::column_def::{ColumnDef, FindById};
 pub use self::column_type::ColumnType;
+pub use self::operation::Operation;
+pub use self::rules::{AccessRules, Rule};
+
+/// Channel the collection triggers publish record changes on.
+pub const CHANGES_CHANNEL: &str = "rocketbase_changes";
+/// Channel the names of saved collections are published on.
+pub const COLLECTIONS_CHANNEL: &str = "rocketbase_collections";
+
+/// Columns every collection table has besides the user defined ones.
+pub const SYSTEM_COLUMNS: [&str; 3] = ["id", "created_at", "updated_at"];
 
 #[derive(Debug)]
 pub enum ColumnChange<'a> {
@@ -39,16 +53,121 @@ impl<'a> ColumnChange<'a> {
 #[derive(Debug, thiserror::Error)]
 pub enum CollectionError {
     #[error("SQLX Error")]
-    SqlxError,
+    SqlxError(#[source] sqlx::Error),
+    #[error("Invalid collection: {0}")]
+    Invalid(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Returns whether `name` can be used as a table or column name as is.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && name.len() <= 63
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Collection {
     pub name: String,
     pub column_defs: Vec<ColumnDef>,
+    #[serde(default)]
+    pub rules: AccessRules,
 }
 
 impl Collection {
+    /// Checks that names are safe to use in SQL and that rules refer to
+    /// existing columns. Collection and column names end up in statements
+    /// verbatim, so this must pass before anything is created.
+    pub fn validate(&self) -> Result<(), CollectionError> {
+        if !is_identifier(&self.name) || self.name == "users" {
+            return Err(CollectionError::Invalid(format!("{} is not a valid collection name", self.name)));
+        }
+        for cd in &self.column_defs {
+            if !is_identifier(&cd.name) || SYSTEM_COLUMNS.contains(&cd.name.as_str()) {
+                return Err(CollectionError::Invalid(format!("{} is not a valid column name", cd.name)));
+            }
+            if let ColumnType::Relation(column_type::RelationType::ManyToOne(table) | column_type::RelationType::OneToOne(table)) = &cd.column_type {
+                if !is_identifier(table) {
+                    return Err(CollectionError::Invalid(format!("{} is not a valid relation", table)));
+                }
+            }
+            if let ColumnType::File { max_size: 0, .. } = cd.column_type {
+                return Err(CollectionError::Invalid(format!("file column {} needs a max_size", cd.name)));
+            }
+            if let ColumnType::File { thumbs, .. } = &cd.column_type {
+                if let Some(thumb) = thumbs.iter().find(|thumb| thumb.parse::<Thumb>().is_err()) {
+                    return Err(CollectionError::Invalid(format!("{} is not a valid thumbnail size", thumb)));
+                }
+            }
+        }
+        for column in self.rules.owner_columns() {
+            match self.column_defs.iter().find(|cd| &cd.name == column) {
+                Some(cd) if cd.column_type == ColumnType::User => {}
+                _ => return Err(CollectionError::Invalid(format!("owner rule needs a user column, {} is not one", column))),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn column_names(&self) -> impl Iterator<Item = &str> {
+        self.column_defs.iter().map(|cd| cd.name.as_str())
+    }
+
+    pub fn file_columns(&self) -> impl Iterator<Item = &ColumnDef> {
+        self.column_defs.iter().filter(|cd| matches!(cd.column_type, ColumnType::File { .. }))
+    }
+
+    /// Statement installing the trigger that publishes record changes.
+    pub fn notify_trigger_statement(&self) -> String {
+        format!(
+            "drop trigger if exists {0}_notify_change on {0}; \
+            create trigger {0}_notify_change after insert or update or delete on {0} \
+            for each row execute function _rocketbase_notify_change();",
+            self.name
+        )
+    }
+
+    pub async fn find<'a, E>(ex: E, name: &str) -> Result<Option<Collection>, CollectionError>
+    where
+        E: 'a + Executor<'a, Database = Postgres>,
+    {
+        let definition = query_scalar::<_, Json<Collection>>("select definition from _collections where name = $1")
+            .bind(name)
+            .fetch_optional(ex)
+            .await
+            .map_err(CollectionError::SqlxError)?;
+        Ok(definition.map(|Json(collection)| collection))
+    }
+
+    pub async fn all<'a, E>(ex: E) -> Result<Vec<Collection>, CollectionError>
+    where
+        E: 'a + Executor<'a, Database = Postgres>,
+    {
+        let definitions = query_scalar::<_, Json<Collection>>("select definition from _collections order by name")
+            .fetch_all(ex)
+            .await
+            .map_err(CollectionError::SqlxError)?;
+        Ok(definitions.into_iter().map(|Json(collection)| collection).collect())
+    }
+
+    /// Stores the definition so that the collection can be found by name, and
+    /// tells listeners on [`COLLECTIONS_CHANNEL`] once it commits.
+    pub async fn save<'a, E>(&self, ex: E) -> Result<(), CollectionError>
+    where
+        E: 'a + Executor<'a, Database = Postgres>,
+    {
+        query("with saved as (insert into _collections(name, definition) values($1, $2) \
+            on conflict (name) do update set definition = excluded.definition, updated_at = now() returning name) \
+            select pg_notify($3, name) from saved")
+            .bind(&self.name)
+            .bind(Json(self))
+            .bind(COLLECTIONS_CHANNEL)
+            .execute(ex)
+            .await
+            .map_err(CollectionError::SqlxError)?;
+        Ok(())
+    }
+
     pub fn create_table_statement(&self) -> String {
         let mut stmt = String::new();
         let cds = self
@@ -113,16 +232,21 @@ impl Collection {
             .join(",");
 
         let mut alter_stmt = String::new();
-        alter_stmt
-            .push_str(format!("alter table {} {};", self.name, column_change_statements).as_str());
+        if !changes.is_empty() {
+            alter_stmt
+                .push_str(format!("alter table {} {};", self.name, column_change_statements).as_str());
+        }
         for cr in column_renames {
             alter_stmt.push_str(
-                format!("alter table {} {}", self.name, cr.get_alter_statement()).as_str(),
+                format!("alter table {} {};", self.name, cr.get_alter_statement()).as_str(),
             );
         }
+        if alter_stmt.is_empty() {
+            return Ok(());
+        }
         let mut res_stream = ex.execute_many(alter_stmt.as_str());
         while let Some(res) = res_stream.next().await {
-            res.map_err(|_| CollectionError::SqlxError)?;
+            res.map_err(CollectionError::SqlxError)?;
         }
         Ok(())
     }
@@ -131,11 +255,11 @@ impl Collection {
     where
         E: 'a + Executor<'a, Database = Postgres>,
     {
-        let create_stmt = self.create_table_statement();
-        println!("{}", create_stmt);
-        ex.execute(create_stmt.as_str())
-            .await
-            .map_err(|_| CollectionError::SqlxError)?;
+        let create_stmt = format!("{};{}", self.create_table_statement(), self.notify_trigger_statement());
+        let mut res_stream = ex.execute_many(create_stmt.as_str());
+        while let Some(res) = res_stream.next().await {
+            res.map_err(CollectionError::SqlxError)?;
+        }
         Ok(())
     }
     // pub fn create_column<'a, E>(&mut self, ex: E) -> Result<(), CollectionError>
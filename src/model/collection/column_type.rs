@@ -17,6 +17,22 @@ pub enum ColumnType {
     Url,
     User,
     Relation(RelationType),
+    /// Metadata of uploaded files, whose bytes are kept in storage.
+    File {
+        /// Largest accepted upload in bytes.
+        max_size: u64,
+        /// Accepted MIME types such as `image/png` or `image/*`, or any type
+        /// when empty.
+        #[serde(default)]
+        mime_types: Vec<String>,
+        /// Holds a list of files rather than a single one.
+        #[serde(default)]
+        multiple: bool,
+        /// Thumbnail sizes such as `200x200` that may be requested for
+        /// images, see [`Thumb`](crate::model::file::Thumb).
+        #[serde(default)]
+        thumbs: Vec<String>,
+    },
 }
 impl ColumnType {
     pub fn pg_sql_type(&self) -> String {
@@ -30,6 +46,7 @@ impl ColumnType {
             Self::Url => "text".into(),
             Self::User => "bigint".into(),
             Self::Relation(RelationType::ManyToOne(_)) => "bigint".into(),
+            Self::File { .. } => "jsonb".into(),
             _ => unimplemented!("unknown type"),
         }
     }
@@ -0,0 +1,12 @@
+use serde::{Serialize, Deserialize};
+
+/// The operations that can be performed on the records of a collection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    List,
+    View,
+    Create,
+    Update,
+    Delete,
+}
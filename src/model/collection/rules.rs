@@ -0,0 +1,70 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use super::Operation;
+
+/// Who may perform an operation on the records of a collection. Admins may
+/// always perform every operation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// Anyone, including anonymous requests.
+    Public,
+    /// Any signed in user.
+    Authenticated,
+    /// Signed in users whose id is stored in the given `User` column.
+    Owner(String),
+    /// Nobody but admins.
+    #[default]
+    Admin,
+}
+
+impl Rule {
+    /// Checks the rule for a user, or an anonymous request when `user_id` is
+    /// `None`. Owner rules need the record, which is not available when
+    /// listing, in which case the caller has to filter by owner instead.
+    pub fn allows(&self, user_id: Option<i64>, record: Option<&Value>) -> bool {
+        match self {
+            Self::Public => true,
+            Self::Authenticated => user_id.is_some(),
+            Self::Owner(column) => match (user_id, record) {
+                (Some(user_id), Some(record)) => record[column].as_i64() == Some(user_id),
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+            Self::Admin => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct AccessRules {
+    pub list: Rule,
+    pub view: Rule,
+    pub create: Rule,
+    pub update: Rule,
+    pub delete: Rule,
+}
+
+impl AccessRules {
+    pub fn rule(&self, operation: Operation) -> &Rule {
+        match operation {
+            Operation::List => &self.list,
+            Operation::View => &self.view,
+            Operation::Create => &self.create,
+            Operation::Update => &self.update,
+            Operation::Delete => &self.delete,
+        }
+    }
+
+    /// Columns referenced by owner rules.
+    pub fn owner_columns(&self) -> impl Iterator<Item = &String> {
+        [&self.list, &self.view, &self.create, &self.update, &self.delete]
+            .into_iter()
+            .filter_map(|rule| match rule {
+                Rule::Owner(column) => Some(column),
+                _ => None,
+            })
+    }
+}
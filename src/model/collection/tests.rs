@@ -15,6 +15,7 @@ fn should_create_create_stmt() {
             required: true,
             unique: true,
         }],
+        rules: AccessRules::default(),
     };
     let ct_stmt = coll.create_table_statement();
     assert_eq!(expected_stmt, ct_stmt, "create statement match failed");
@@ -42,6 +43,7 @@ async fn should_add_column_to_collection(pool: sqlx::PgPool) {
             required: true,
             unique: true,
         }],
+        rules: AccessRules::default(),
     };
     let new_def = Collection {
         name: "organizations".into(),
@@ -61,6 +63,7 @@ async fn should_add_column_to_collection(pool: sqlx::PgPool) {
                 unique: true,
             },
         ],
+        rules: AccessRules::default(),
     };
     coll.create_collection(&mut conn)
         .await
@@ -108,6 +111,7 @@ async fn should_rename_column_in_collection(pool: sqlx::PgPool) {
             required: true,
             unique: true,
         }],
+        rules: AccessRules::default(),
     };
     let new_def = Collection {
         name: "organizations".into(),
@@ -127,6 +131,7 @@ async fn should_rename_column_in_collection(pool: sqlx::PgPool) {
                 unique: true,
             }
         ],
+        rules: AccessRules::default(),
     };
     coll.create_collection(&mut conn)
         .await
@@ -173,6 +178,7 @@ async fn should_create_collection(pool: sqlx::PgPool) {
             required: true,
             unique: true,
         }],
+        rules: AccessRules::default(),
     };
     coll.create_collection(&mut conn)
         .await
@@ -0,0 +1,28 @@
+use sqlx::{Executor, Postgres, query, query_scalar};
+use tracing::instrument;
+use color_eyre::{eyre::WrapErr, Result};
+
+/// Links an identity at an external provider to a row in `users`.
+pub struct ExternalAuth;
+
+impl ExternalAuth {
+  #[instrument(skip(ex))]
+  pub async fn find_user_id<'a, E>(ex: E, provider: &str, subject: &str) -> Result<Option<i64>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let user_id = query_scalar::<_, i64>("select user_id from _external_auths where provider = $1 and subject = $2")
+    .bind(provider).bind(subject)
+    .fetch_optional(ex).await?;
+    Ok(user_id)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn link<'a, E>(ex: E, user_id: i64, provider: &str, subject: &str) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("insert into _external_auths(user_id, provider, subject) values($1, $2, $3)")
+    .bind(user_id).bind(provider).bind(subject)
+    .execute(ex).await.context("Unable to link external identity")?;
+    Ok(())
+  }
+}
@@ -0,0 +1,187 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{collection::Collection, user_token::generate_token, Record};
+
+/// What a file column stores about an uploaded file. The bytes are kept in
+/// storage under [`FileMeta::key`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileMeta {
+  /// Unique name of the file within its collection.
+  pub name: String,
+  pub original_name: String,
+  pub size: u64,
+  pub mime_type: String,
+}
+
+impl FileMeta {
+  pub fn new(original_name: &str, size: u64, mime_type: &str) -> Self {
+    FileMeta {
+      name: format!("{}_{}", &generate_token()[..12], sanitize(original_name)),
+      original_name: original_name.to_string(),
+      size,
+      mime_type: mime_type.to_string(),
+    }
+  }
+
+  /// Storage key of the file.
+  pub fn key(&self, collection: &str) -> String {
+    format!("{}/{}", collection, self.name)
+  }
+
+  /// Storage key of a thumbnail of the file. Generated names contain an
+  /// underscore, so the `thumbs` directory can not clash with a file.
+  pub fn thumb_key(&self, collection: &str, thumb: &Thumb) -> String {
+    format!("{}/thumbs/{}/{}", collection, self.name, thumb)
+  }
+}
+
+/// Largest thumbnail width or height.
+const MAX_THUMB_SIZE: u32 = 4096;
+
+/// How an image is fitted into a thumbnail size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbMode {
+  /// Fills the size, cropping from the center.
+  Crop,
+  /// Fills the size, cropping from the top.
+  CropTop,
+  /// Fills the size, cropping from the bottom.
+  CropBottom,
+  /// Fits the whole image inside the size.
+  Fit,
+}
+
+/// A thumbnail size written `WxH`, followed by `t` or `b` to crop from the
+/// top or bottom, or by `f` to fit rather than crop. A width or height of 0
+/// scales to the other one, keeping the aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thumb {
+  pub width: u32,
+  pub height: u32,
+  pub mode: ThumbMode,
+}
+
+impl FromStr for Thumb {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (size, mode) = match s.as_bytes().last() {
+      Some(b't') => (&s[..s.len() - 1], ThumbMode::CropTop),
+      Some(b'b') => (&s[..s.len() - 1], ThumbMode::CropBottom),
+      Some(b'f') => (&s[..s.len() - 1], ThumbMode::Fit),
+      _ => (s, ThumbMode::Crop),
+    };
+    let (width, height) = size.split_once('x').ok_or(())?;
+    let (width, height) = (width.parse::<u32>().map_err(|_| ())?, height.parse::<u32>().map_err(|_| ())?);
+    let valid = width <= MAX_THUMB_SIZE
+      && height <= MAX_THUMB_SIZE
+      && match (width, height) {
+        (0, 0) => false,
+        // only the plain form scales to one side
+        (0, _) | (_, 0) => mode == ThumbMode::Crop,
+        _ => true,
+      };
+    match valid {
+      true => Ok(Thumb { width, height, mode }),
+      false => Err(()),
+    }
+  }
+}
+
+impl fmt::Display for Thumb {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let suffix = match self.mode {
+      ThumbMode::Crop => "",
+      ThumbMode::CropTop => "t",
+      ThumbMode::CropBottom => "b",
+      ThumbMode::Fit => "f",
+    };
+    write!(f, "{}x{}{}", self.width, self.height, suffix)
+  }
+}
+
+/// Keeps letters, digits, dots, dashes and underscores of the last path
+/// segment, so that names are safe in storage keys and URLs.
+fn sanitize(file_name: &str) -> String {
+  let base = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+  let clean: String = base
+    .chars()
+    .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+    .take(100)
+    .collect();
+  match clean.trim_matches('.') {
+    "" => "file".to_string(),
+    trimmed => trimmed.to_string(),
+  }
+}
+
+/// Whether `mime_type` matches one of `patterns`, which may end in `/*`. An
+/// empty list accepts anything.
+pub fn mime_allowed(patterns: &[String], mime_type: &str) -> bool {
+  patterns.is_empty() || patterns.iter().any(|pattern| match pattern.strip_suffix("/*") {
+    Some(prefix) => mime_type.split('/').next() == Some(prefix),
+    None => pattern.eq_ignore_ascii_case(mime_type),
+  })
+}
+
+/// Files held by a file column value, which is a single file, a list of them
+/// or null.
+pub fn files_in(value: &Value) -> Vec<FileMeta> {
+  match value {
+    Value::Array(values) => values.iter().filter_map(|value| serde_json::from_value(value.clone()).ok()).collect(),
+    Value::Object(_) => serde_json::from_value(value.clone()).into_iter().collect(),
+    _ => vec![],
+  }
+}
+
+/// Files held by all file columns of a record.
+pub fn record_files(collection: &Collection, record: &Record) -> Vec<FileMeta> {
+  collection.file_columns().flat_map(|cd| files_in(&record[&cd.name])).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::{files_in, mime_allowed, sanitize, FileMeta, Thumb, ThumbMode};
+
+  #[test]
+  fn should_sanitize_file_names() {
+    assert_eq!(sanitize("../../etc/passwd"), "passwd");
+    assert_eq!(sanitize("C:\\photos\\my cat.png"), "my_cat.png");
+    assert_eq!(sanitize(".."), "file");
+    assert!(FileMeta::new("a b.png", 1, "image/png").name.ends_with("_a_b.png"));
+  }
+
+  #[test]
+  fn should_match_mime_patterns() {
+    let images = vec!["image/*".to_string(), "application/pdf".to_string()];
+    assert!(mime_allowed(&images, "image/png"));
+    assert!(mime_allowed(&images, "application/pdf"));
+    assert!(!mime_allowed(&images, "text/html"));
+    assert!(mime_allowed(&[], "text/html"));
+  }
+
+  #[test]
+  fn should_read_single_and_multiple_files() {
+    let file = json!({"name": "x_a.png", "original_name": "a.png", "size": 3, "mime_type": "image/png"});
+    assert_eq!(files_in(&file).len(), 1);
+    assert_eq!(files_in(&json!([file.clone(), file])).len(), 2);
+    assert!(files_in(&json!(null)).is_empty());
+  }
+
+  #[test]
+  fn should_parse_thumb_sizes() {
+    assert_eq!("200x100".parse(), Ok(Thumb { width: 200, height: 100, mode: ThumbMode::Crop }));
+    assert_eq!("200x100t".parse::<Thumb>().unwrap().mode, ThumbMode::CropTop);
+    assert_eq!("200x100b".parse::<Thumb>().unwrap().mode, ThumbMode::CropBottom);
+    assert_eq!("200x100f".parse::<Thumb>().unwrap().mode, ThumbMode::Fit);
+    assert_eq!("0x100".parse::<Thumb>().unwrap().to_string(), "0x100");
+    for invalid in ["", "200", "0x0", "0x100f", "200x100x", "-1x100", "9999x10", "200X100"] {
+      assert!(invalid.parse::<Thumb>().is_err(), "{} parsed", invalid);
+    }
+  }
+}
@@ -0,0 +1,259 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Executor, Postgres, query, query_as};
+use tracing::instrument;
+use color_eyre::{eyre::WrapErr, Result};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewJob {
+  pub name: String,
+  /// Cron expression, with or without a seconds field, evaluated in UTC.
+  pub schedule: String,
+  /// Name of the registered handler the job runs.
+  pub handler: String,
+  #[serde(default = "default_enabled")]
+  pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+  true
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct JobUpdate {
+  pub schedule: Option<String>,
+  pub handler: Option<String>,
+  pub enabled: Option<bool>,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+  pub name: String,
+  pub schedule: String,
+  pub handler: String,
+  pub enabled: bool,
+  /// `None` when the schedule has no further occurrences.
+  pub next_run_at: Option<DateTime<Utc>>,
+  pub last_run_at: Option<DateTime<Utc>>,
+  pub created_at: DateTime<Utc>,
+}
+
+const JOB_COLUMNS: &str = "name, schedule, handler, enabled, next_run_at, last_run_at, created_at";
+
+impl Job {
+  /// Creates the job, returning `None` when one with the same name exists.
+  #[instrument(skip(ex))]
+  pub async fn create<'a, E>(ex: E, new_job: &NewJob, next_run_at: Option<DateTime<Utc>>) -> Result<Option<Job>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let job = query_as::<_, Job>(&format!(
+      "insert into _jobs(name, schedule, handler, enabled, next_run_at) values($1, $2, $3, $4, $5) \
+      on conflict (name) do nothing returning {}", JOB_COLUMNS))
+    .bind(&new_job.name)
+    .bind(&new_job.schedule)
+    .bind(&new_job.handler)
+    .bind(new_job.enabled)
+    .bind(next_run_at)
+    .fetch_optional(ex).await.context("Unable to save job")?;
+    Ok(job)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn all<'a, E>(ex: E) -> Result<Vec<Job>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let jobs = query_as::<_, Job>(&format!("select {} from _jobs order by name", JOB_COLUMNS))
+    .fetch_all(ex).await?;
+    Ok(jobs)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn find<'a, E>(ex: E, name: &str) -> Result<Option<Job>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let job = query_as::<_, Job>(&format!("select {} from _jobs where name = $1", JOB_COLUMNS))
+    .bind(name)
+    .fetch_optional(ex).await?;
+    Ok(job)
+  }
+
+  /// Changes the given fields and when the job runs next, returning `None`
+  /// when there is no such job.
+  #[instrument(skip(ex))]
+  pub async fn update<'a, E>(ex: E, name: &str, update: &JobUpdate, next_run_at: Option<DateTime<Utc>>) -> Result<Option<Job>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let job = query_as::<_, Job>(&format!(
+      "update _jobs set schedule = coalesce($2, schedule), handler = coalesce($3, handler), \
+      enabled = coalesce($4, enabled), next_run_at = $5 where name = $1 returning {}", JOB_COLUMNS))
+    .bind(name)
+    .bind(&update.schedule)
+    .bind(&update.handler)
+    .bind(update.enabled)
+    .bind(next_run_at)
+    .fetch_optional(ex).await.context("Unable to update job")?;
+    Ok(job)
+  }
+
+  /// Deletes the job along with its runs.
+  #[instrument(skip(ex))]
+  pub async fn delete<'a, E>(ex: E, name: &str) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query("delete from _jobs where name = $1")
+    .bind(name)
+    .execute(ex).await.context("Unable to delete job")?;
+    Ok(res.rows_affected() == 1)
+  }
+
+  /// Enabled jobs whose next run is due.
+  #[instrument(skip(ex))]
+  pub async fn due<'a, E>(ex: E) -> Result<Vec<Job>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let jobs = query_as::<_, Job>(&format!(
+      "select {} from _jobs where enabled and next_run_at <= now() order by next_run_at", JOB_COLUMNS))
+    .fetch_all(ex).await?;
+    Ok(jobs)
+  }
+
+  /// Moves a due job on to `next_run_at` and leases it to `owner`, returning
+  /// `None` when it is not due anymore because another instance got to it
+  /// first, or when it is still running.
+  #[instrument(skip(ex))]
+  pub async fn claim<'a, E>(ex: E, name: &str, next_run_at: Option<DateTime<Utc>>, owner: &str, lease: Duration) -> Result<Option<Job>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let job = query_as::<_, Job>(&format!(
+      "update _jobs set last_run_at = now(), next_run_at = $2, locked_by = $3, \
+      locked_until = now() + $4 * interval '1 millisecond' \
+      where name = $1 and enabled and next_run_at <= now() and (locked_until is null or locked_until < now()) \
+      returning {}", JOB_COLUMNS))
+    .bind(name)
+    .bind(next_run_at)
+    .bind(owner)
+    .bind(lease.num_milliseconds() as f64)
+    .fetch_optional(ex).await.context("Unable to claim job")?;
+    Ok(job)
+  }
+
+  /// Leases the job to `owner`, returning `false` when it is running.
+  #[instrument(skip(ex))]
+  pub async fn lock<'a, E>(ex: E, name: &str, owner: &str, lease: Duration) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query(
+      "update _jobs set locked_by = $2, locked_until = now() + $3 * interval '1 millisecond' \
+      where name = $1 and (locked_until is null or locked_until < now())")
+    .bind(name)
+    .bind(owner)
+    .bind(lease.num_milliseconds() as f64)
+    .execute(ex).await.context("Unable to lock job")?;
+    Ok(res.rows_affected() == 1)
+  }
+
+  /// Renews the lease of `owner`, returning `false` when it lost it.
+  #[instrument(skip(ex))]
+  pub async fn extend<'a, E>(ex: E, name: &str, owner: &str, lease: Duration) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query("update _jobs set locked_until = now() + $3 * interval '1 millisecond' where name = $1 and locked_by = $2")
+    .bind(name)
+    .bind(owner)
+    .bind(lease.num_milliseconds() as f64)
+    .execute(ex).await.context("Unable to extend job lease")?;
+    Ok(res.rows_affected() == 1)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn unlock<'a, E>(ex: E, name: &str, owner: &str) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("update _jobs set locked_by = null, locked_until = null where name = $1 and locked_by = $2")
+    .bind(name)
+    .bind(owner)
+    .execute(ex).await.context("Unable to unlock job")?;
+    Ok(())
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum RunStatus {
+  Running,
+  Succeeded,
+  Failed,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct JobRun {
+  pub id: i64,
+  pub job: String,
+  pub status: RunStatus,
+  pub started_at: DateTime<Utc>,
+  pub finished_at: Option<DateTime<Utc>>,
+  pub duration_ms: Option<i64>,
+  pub error: Option<String>,
+}
+
+const RUN_COLUMNS: &str = "id, job, status, started_at, finished_at, duration_ms, error";
+
+impl JobRun {
+  #[instrument(skip(ex))]
+  pub async fn start<'a, E>(ex: E, job: &str) -> Result<JobRun>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let run = query_as::<_, JobRun>(&format!("insert into _job_runs(job) values($1) returning {}", RUN_COLUMNS))
+    .bind(job)
+    .fetch_one(ex).await.context("Unable to record job run")?;
+    Ok(run)
+  }
+
+  /// Records how the run ended, failed when there is an `error`.
+  #[instrument(skip(ex))]
+  pub async fn finish<'a, E>(ex: E, id: i64, duration_ms: i64, error: Option<&str>) -> Result<JobRun>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let status = match error {
+      Some(_) => RunStatus::Failed,
+      None => RunStatus::Succeeded,
+    };
+    let run = query_as::<_, JobRun>(&format!(
+      "update _job_runs set status = $2, finished_at = now(), duration_ms = $3, error = $4 where id = $1 returning {}",
+      RUN_COLUMNS))
+    .bind(id)
+    .bind(status)
+    .bind(duration_ms)
+    .bind(error)
+    .fetch_one(ex).await.context("Unable to record job run")?;
+    Ok(run)
+  }
+
+  /// Latest runs of a job, newest first.
+  #[instrument(skip(ex))]
+  pub async fn for_job<'a, E>(ex: E, job: &str, limit: i64) -> Result<Vec<JobRun>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let runs = query_as::<_, JobRun>(&format!(
+      "select {} from _job_runs where job = $1 order by id desc limit $2", RUN_COLUMNS))
+    .bind(job)
+    .bind(limit)
+    .fetch_all(ex).await?;
+    Ok(runs)
+  }
+
+  /// Deletes all but the latest `keep` runs of a job.
+  #[instrument(skip(ex))]
+  pub async fn prune<'a, E>(ex: E, job: &str, keep: i64) -> Result<u64>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query(
+      "delete from _job_runs where job = $1 and id < \
+      (select coalesce(min(id), 0) from (select id from _job_runs where job = $1 order by id desc limit $2) latest)")
+    .bind(job)
+    .bind(keep)
+    .execute(ex).await.context("Unable to prune job runs")?;
+    Ok(res.rows_affected())
+  }
+}
@@ -0,0 +1,112 @@
+use sqlx::{FromRow, Executor, Postgres, query, query_as};
+use tracing::instrument;
+use color_eyre::{eyre::WrapErr, Result};
+
+use super::user_token::{generate_token, hash_token};
+
+/// TOTP enrollment for a user. The secret only becomes effective once the
+/// user proves they can generate codes with it and it is `enabled`.
+#[derive(FromRow, Debug)]
+pub struct Mfa {
+  pub user_id: i64,
+  pub secret: String,
+  pub enabled: bool,
+  pub last_used_step: Option<i64>,
+}
+
+impl Mfa {
+  #[instrument(skip(ex))]
+  pub async fn find<'a, E>(ex: E, user_id: i64) -> Result<Option<Mfa>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let mfa = query_as::<_, Mfa>("select user_id, secret, enabled, last_used_step from _user_mfa where user_id = $1")
+    .bind(user_id)
+    .fetch_optional(ex).await?;
+    Ok(mfa)
+  }
+
+  /// Stores a new pending secret, replacing any earlier enrollment that was
+  /// never confirmed.
+  #[instrument(skip(ex, secret))]
+  pub async fn start_enrollment<'a, E>(ex: E, user_id: i64, secret: &str) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("insert into _user_mfa(user_id, secret) values($1, $2) \
+      on conflict (user_id) do update set secret = excluded.secret, last_used_step = null \
+      where _user_mfa.enabled = false")
+    .bind(user_id).bind(secret)
+    .execute(ex).await.context("Unable to save MFA secret")?;
+    Ok(())
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn enable<'a, E>(ex: E, user_id: i64) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("update _user_mfa set enabled = true where user_id = $1")
+    .bind(user_id)
+    .execute(ex).await.context("Unable to enable MFA")?;
+    Ok(())
+  }
+
+  /// Marks the time step of a code as used. Returns false when it or a later
+  /// step was used already, so that a code is only accepted once even by
+  /// concurrent requests.
+  #[instrument(skip(ex))]
+  pub async fn record_step<'a, E>(ex: E, user_id: i64, step: i64) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let result = query("update _user_mfa set last_used_step = $2 \
+      where user_id = $1 and (last_used_step is null or last_used_step < $2)")
+    .bind(user_id).bind(step)
+    .execute(ex).await.context("Unable to record MFA step")?;
+    Ok(result.rows_affected() == 1)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn delete<'a, E>(ex: E, user_id: i64) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("with codes as (delete from _mfa_recovery_codes where user_id = $1) delete from _user_mfa where user_id = $1")
+    .bind(user_id)
+    .execute(ex).await.context("Unable to remove MFA")?;
+    Ok(())
+  }
+}
+
+/// One time codes that stand in for a TOTP code when the device is lost.
+pub struct RecoveryCode;
+
+impl RecoveryCode {
+  pub const COUNT: usize = 10;
+
+  /// Replaces the user's recovery codes and returns the new raw codes.
+  #[instrument(skip(tx))]
+  pub async fn regenerate(tx: &mut sqlx::Transaction<'_, Postgres>, user_id: i64) -> Result<Vec<String>> {
+    query("delete from _mfa_recovery_codes where user_id = $1")
+    .bind(user_id)
+    .execute(&mut *tx).await.context("Unable to remove recovery codes")?;
+    let mut codes = Vec::with_capacity(Self::COUNT);
+    for _ in 0..Self::COUNT {
+      // 80 bits, as four groups of five hex digits
+      let token = generate_token();
+      let code = format!("{}-{}-{}-{}", &token[0..5], &token[5..10], &token[10..15], &token[15..20]);
+      query("insert into _mfa_recovery_codes(user_id, code_hash) values($1, $2)")
+      .bind(user_id).bind(hash_token(&code))
+      .execute(&mut *tx).await.context("Unable to save recovery code")?;
+      codes.push(code);
+    }
+    Ok(codes)
+  }
+
+  /// Uses up a recovery code, returning whether it was valid.
+  #[instrument(skip(ex, code))]
+  pub async fn consume<'a, E>(ex: E, user_id: i64, code: &str) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query("delete from _mfa_recovery_codes where user_id = $1 and code_hash = $2")
+    .bind(user_id).bind(hash_token(&code.trim().to_lowercase()))
+    .execute(ex).await.context("Unable to consume recovery code")?;
+    Ok(res.rows_affected() == 1)
+  }
+}
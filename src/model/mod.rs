@@ -1,4 +1,23 @@
 pub(crate) mod user;
-pub(crate) mod collection;
+pub mod api_key;
+pub mod change;
+pub mod collection;
+pub mod external_auth;
+pub mod file;
+pub mod job;
+pub mod mfa;
+pub mod record;
+pub mod task;
+pub mod user_token;
+pub mod webhook;
 
-pub use user::User;
+pub use user::{User, UserAccount};
+pub use api_key::ApiKey;
+pub use change::{ChangeEvent, Changes};
+pub use external_auth::ExternalAuth;
+pub use job::{Job, JobRun};
+pub use mfa::{Mfa, RecoveryCode};
+pub use record::{Record, Records};
+pub use task::Task;
+pub use user_token::{TokenKind, UserToken};
+pub use webhook::{Delivery, Webhook};
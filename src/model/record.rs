@@ -0,0 +1,125 @@
+use serde_json::{Map, Value};
+use sqlx::{Executor, Postgres, query_scalar};
+use tracing::instrument;
+use color_eyre::{eyre::WrapErr, Result};
+
+use super::collection::Collection;
+
+/// Records are passed around as JSON objects shaped like `to_jsonb` of the
+/// row: the system columns `id`, `created_at` and `updated_at` followed by
+/// the columns of the collection.
+pub type Record = Value;
+
+/// Which page of records [`Records::list`] returns.
+#[derive(Debug)]
+pub struct ListQuery<'q> {
+  pub limit: i64,
+  pub offset: i64,
+  /// Only return records whose owner column holds this user id.
+  pub owner: Option<(&'q str, i64)>,
+}
+
+/// Generic CRUD on collection tables. Only columns defined on the collection
+/// are ever written, and the collection definition is assumed to be valid.
+pub struct Records;
+
+impl Records {
+  /// Keys of `data` that name columns of the collection.
+  fn writable_columns(collection: &Collection, data: &Map<String, Value>) -> Vec<String> {
+    collection.column_names()
+      .filter(|name| data.contains_key(*name))
+      .map(String::from)
+      .collect()
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn list<'a, E>(ex: E, collection: &Collection, list: ListQuery<'_>) -> Result<Vec<Record>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let filter = match list.owner {
+      Some((column, _)) => format!("where {} = $3", column),
+      None => String::new(),
+    };
+    let stmt = format!("select to_jsonb(t) from {} t {} order by id limit $1 offset $2", collection.name, filter);
+    let mut query = query_scalar::<_, Value>(&stmt)
+    .bind(list.limit)
+    .bind(list.offset);
+    if let Some((_, user_id)) = list.owner {
+      query = query.bind(user_id);
+    }
+    let records = query.fetch_all(ex).await.context("Unable to list records")?;
+    Ok(records)
+  }
+
+  /// Records whose fields contain all of `filter`, compared as JSON, so that
+  /// `{"title": "hello"}` matches records titled hello.
+  #[instrument(skip(ex))]
+  pub async fn matching<'a, E>(ex: E, collection: &Collection, filter: &Map<String, Value>, limit: i64) -> Result<Vec<Record>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let stmt = format!("select to_jsonb(t) from {} t where to_jsonb(t) @> $1 order by id limit $2", collection.name);
+    let records = query_scalar::<_, Value>(&stmt)
+    .bind(Value::Object(filter.clone()))
+    .bind(limit)
+    .fetch_all(ex).await.context("Unable to find records")?;
+    Ok(records)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn find<'a, E>(ex: E, collection: &Collection, id: i64) -> Result<Option<Record>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let record = query_scalar::<_, Value>(&format!("select to_jsonb(t) from {} t where id = $1", collection.name))
+    .bind(id)
+    .fetch_optional(ex).await.context("Unable to find record")?;
+    Ok(record)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn insert<'a, E>(ex: E, collection: &Collection, data: &Map<String, Value>) -> Result<Record>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let columns = Self::writable_columns(collection, data).join(", ");
+    let stmt = if columns.is_empty() {
+      format!("insert into {0} default values returning to_jsonb({0}.*)", collection.name)
+    } else {
+      format!(
+        "insert into {0} ({1}) select {1} from jsonb_populate_record(null::{0}, $1) returning to_jsonb({0}.*)",
+        collection.name, columns
+      )
+    };
+    let record = query_scalar::<_, Value>(&stmt)
+    .bind(Value::Object(data.clone()))
+    .fetch_one(ex).await.context("Unable to insert record")?;
+    Ok(record)
+  }
+
+  /// Updates the columns present in `data`, leaving the others untouched.
+  #[instrument(skip(ex))]
+  pub async fn update<'a, E>(ex: E, collection: &Collection, id: i64, data: &Map<String, Value>) -> Result<Option<Record>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let columns = Self::writable_columns(collection, data).join(", ");
+    let assignments = if columns.is_empty() {
+      String::new()
+    } else {
+      format!("({0}) = (select {0} from jsonb_populate_record(t, $2)), ", columns)
+    };
+    let record = query_scalar::<_, Value>(&format!(
+      "update {} t set {}updated_at = now() where id = $1 returning to_jsonb(t)", collection.name, assignments))
+    .bind(id)
+    .bind(Value::Object(data.clone()))
+    .fetch_optional(ex).await.context("Unable to update record")?;
+    Ok(record)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn delete<'a, E>(ex: E, collection: &Collection, id: i64) -> Result<Option<Record>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let record = query_scalar::<_, Value>(&format!("delete from {} t where id = $1 returning to_jsonb(t)", collection.name))
+    .bind(id)
+    .fetch_optional(ex).await.context("Unable to delete record")?;
+    Ok(record)
+  }
+}
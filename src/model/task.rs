@@ -0,0 +1,195 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Json, FromRow, Executor, Postgres, query, query_as};
+use tracing::instrument;
+use color_eyre::{eyre::WrapErr, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum TaskStatus {
+  /// Waiting for its first or next attempt.
+  Pending,
+  /// Taken by a worker until `locked_until`.
+  Running,
+  /// Gave up after the last attempt, or the payload could not be read.
+  Dead,
+}
+
+/// A unit of background work, run by the worker handling its `kind`.
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct Task {
+  pub id: i64,
+  pub kind: String,
+  pub payload: Json<Value>,
+  pub status: TaskStatus,
+  pub attempts: i32,
+  pub run_at: DateTime<Utc>,
+  pub locked_until: Option<DateTime<Utc>>,
+  /// Why the last attempt failed.
+  pub last_error: Option<String>,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+const TASK_COLUMNS: &str = "id, kind, payload, status, attempts, run_at, locked_until, last_error, created_at, updated_at";
+
+impl Task {
+  /// Queues a task to run at `run_at`, or right away. Enqueueing within a
+  /// transaction only makes the task visible once it commits.
+  #[instrument(skip(ex, payload))]
+  pub async fn enqueue<'a, E>(ex: E, kind: &str, payload: &Value, run_at: Option<DateTime<Utc>>) -> Result<Task>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let task = query_as::<_, Task>(&format!(
+      "insert into _tasks(kind, payload, run_at) values($1, $2, coalesce($3, now())) returning {}", TASK_COLUMNS))
+    .bind(kind)
+    .bind(Json(payload))
+    .bind(run_at)
+    .fetch_one(ex).await.context("Unable to enqueue task")?;
+    Ok(task)
+  }
+
+  /// Tasks by id, newest first.
+  #[instrument(skip(ex))]
+  pub async fn list<'a, E>(ex: E, status: Option<TaskStatus>, kind: Option<&str>, limit: i64) -> Result<Vec<Task>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let tasks = query_as::<_, Task>(&format!(
+      "select {} from _tasks where ($1::text is null or status = $1) and ($2::text is null or kind = $2) \
+      order by id desc limit $3", TASK_COLUMNS))
+    .bind(status)
+    .bind(kind)
+    .bind(limit)
+    .fetch_all(ex).await?;
+    Ok(tasks)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn find<'a, E>(ex: E, id: i64) -> Result<Option<Task>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let task = query_as::<_, Task>(&format!("select {} from _tasks where id = $1", TASK_COLUMNS))
+    .bind(id)
+    .fetch_optional(ex).await?;
+    Ok(task)
+  }
+
+  /// Takes up to `limit` due tasks of the given kinds for `lease`, counting
+  /// an attempt. Running tasks whose lease ran out, because their worker
+  /// died, are due again, unless that was their last attempt out of
+  /// `max_attempts`, in which case they are dead.
+  #[instrument(skip(ex))]
+  pub async fn claim<'a, E>(ex: E, kinds: &[String], limit: i64, lease: Duration, max_attempts: i32) -> Result<Vec<Task>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let tasks = query_as::<_, Task>(&format!(
+      "with expired as ( \
+        update _tasks set status = 'dead', locked_until = null, updated_at = now(), \
+        last_error = 'Lease ran out during the last attempt' \
+        where kind = any($1) and status = 'running' and locked_until < now() and attempts >= $4 \
+      ), due as ( \
+        select id from _tasks \
+        where kind = any($1) and ((status = 'pending' and run_at <= now()) \
+        or (status = 'running' and locked_until < now() and attempts < $4)) \
+        order by run_at, id limit $2 for update skip locked \
+      ) \
+      update _tasks t set status = 'running', attempts = attempts + 1, \
+      locked_until = now() + $3 * interval '1 millisecond', updated_at = now() \
+      from due where t.id = due.id returning {}",
+      TASK_COLUMNS.split(", ").map(|column| format!("t.{}", column)).collect::<Vec<_>>().join(", ")))
+    .bind(kinds)
+    .bind(limit)
+    .bind(lease.num_milliseconds() as f64)
+    .bind(max_attempts)
+    .fetch_all(ex).await.context("Unable to claim tasks")?;
+    Ok(tasks)
+  }
+
+  /// Renews the lease of a running attempt, returning `false` when it ran
+  /// out and was taken over.
+  #[instrument(skip(ex))]
+  pub async fn extend<'a, E>(ex: E, id: i64, attempts: i32, lease: Duration) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query(
+      "update _tasks set locked_until = now() + $3 * interval '1 millisecond', updated_at = now() \
+      where id = $1 and attempts = $2 and status = 'running'")
+    .bind(id)
+    .bind(attempts)
+    .bind(lease.num_milliseconds() as f64)
+    .execute(ex).await.context("Unable to extend task lease")?;
+    Ok(res.rows_affected() == 1)
+  }
+
+  /// Removes a task that ran to completion. `attempts` guards against
+  /// finishing an attempt whose lease ran out and was taken over.
+  #[instrument(skip(ex))]
+  pub async fn complete<'a, E>(ex: E, id: i64, attempts: i32) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("delete from _tasks where id = $1 and attempts = $2 and status = 'running'")
+    .bind(id)
+    .bind(attempts)
+    .execute(ex).await.context("Unable to complete task")?;
+    Ok(())
+  }
+
+  /// Records a failed attempt. The task is tried again at `retry_at`, or is
+  /// dead when there is none.
+  #[instrument(skip(ex))]
+  pub async fn fail<'a, E>(ex: E, id: i64, attempts: i32, error: &str, retry_at: Option<DateTime<Utc>>) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let status = match retry_at {
+      Some(_) => TaskStatus::Pending,
+      None => TaskStatus::Dead,
+    };
+    query(
+      "update _tasks set status = $3, last_error = $4, run_at = coalesce($5, run_at), locked_until = null, \
+      updated_at = now() where id = $1 and attempts = $2 and status = 'running'")
+    .bind(id)
+    .bind(attempts)
+    .bind(status)
+    .bind(error)
+    .bind(retry_at)
+    .execute(ex).await.context("Unable to record task failure")?;
+    Ok(())
+  }
+
+  /// Queues a dead task again with a fresh set of attempts.
+  #[instrument(skip(ex))]
+  pub async fn retry<'a, E>(ex: E, id: i64) -> Result<Option<Task>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let task = query_as::<_, Task>(&format!(
+      "update _tasks set status = 'pending', attempts = 0, run_at = now(), updated_at = now() \
+      where id = $1 and status = 'dead' returning {}", TASK_COLUMNS))
+    .bind(id)
+    .fetch_optional(ex).await.context("Unable to retry task")?;
+    Ok(task)
+  }
+
+  /// Deletes dead tasks that have not changed for `retention`, returning how
+  /// many were deleted.
+  #[instrument(skip(ex))]
+  pub async fn prune<'a, E>(ex: E, retention: Duration) -> Result<u64>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query("delete from _tasks where status = 'dead' and updated_at < now() - $1 * interval '1 second'")
+    .bind(retention.num_seconds() as f64)
+    .execute(ex).await.context("Unable to prune tasks")?;
+    Ok(res.rows_affected())
+  }
+
+  /// How many tasks there are of each status.
+  #[instrument(skip(ex))]
+  pub async fn counts<'a, E>(ex: E) -> Result<Vec<(TaskStatus, i64)>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let counts = query_as::<_, (TaskStatus, i64)>("select status, count(*) from _tasks group by status")
+    .fetch_all(ex).await.context("Unable to count tasks")?;
+    Ok(counts)
+  }
+}
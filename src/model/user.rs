@@ -1,23 +1,39 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use sqlx::{
     FromRow,
     Executor,
     Postgres,
+    query,
     query_as,
     query_scalar,
 };
 use tracing::instrument;
 use std::fmt::{Display, Formatter};
 use serde::{Deserialize, Serialize};
-use color_eyre::{eyre::WrapErr, Result};
+use color_eyre::{eyre::{eyre, WrapErr}, Result};
+use lazy_static::lazy_static;
 
 
-#[derive(FromRow, Debug, Serialize, Deserialize)]
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
 pub struct User {
   pub id: Option<i64>,
   pub name: String,
   pub email: String
 }
 
+/// A user with the flags of their account, as listed to operators.
+#[derive(FromRow, Debug, Clone, Serialize)]
+pub struct UserAccount {
+  #[sqlx(flatten)]
+  #[serde(flatten)]
+  pub user: User,
+  pub admin: bool,
+  pub verified: bool,
+}
+
 impl Display for User {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     match self.id {
@@ -46,4 +62,141 @@ impl User {
     self.id = Some(id);
     Ok(())
   }
+
+  #[instrument(skip(ex))]
+  pub async fn find<'a, E>(ex: E, id: i64) -> Result<Option<User>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let user = query_as::<_, User>("select * from users where id = $1")
+    .bind(id)
+    .fetch_optional(ex).await?;
+    Ok(user)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn find_by_email<'a, E>(ex: E, email: &str) -> Result<Option<User>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let user = query_as::<_, User>("select * from users where email = $1")
+    .bind(email)
+    .fetch_optional(ex).await?;
+    Ok(user)
+  }
+
+  #[instrument(skip(ex, password))]
+  pub async fn set_password<'a, E>(ex: E, id: i64, password: &str) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let hash = hash_password(password)?;
+    query("update users set password_hash = $1 where id = $2")
+    .bind(hash).bind(id)
+    .execute(ex).await.context("Unable to update password")?;
+    Ok(())
+  }
+
+  /// Removes the password, so that the user can only sign in through an
+  /// external provider or after a password reset.
+  #[instrument(skip(ex))]
+  pub async fn clear_password<'a, E>(ex: E, id: i64) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("update users set password_hash = null where id = $1")
+    .bind(id)
+    .execute(ex).await.context("Unable to clear password")?;
+    Ok(())
+  }
+
+  /// Returns the user with the email if the password matches.
+  #[instrument(skip(ex, password))]
+  pub async fn authenticate<'a, E>(ex: E, email: &str, password: &str) -> Result<Option<User>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    #[derive(FromRow)]
+    struct Credentials {
+      #[sqlx(flatten)]
+      user: User,
+      password_hash: Option<String>,
+    }
+    let found = query_as::<_, Credentials>("select * from users where email = $1")
+    .bind(email)
+    .fetch_optional(ex).await?;
+    match found {
+      Some(Credentials { user, password_hash: Some(hash) }) => Ok(verify_password(password, &hash).then_some(user)),
+      // hashing anyway takes as long as for a registered address
+      _ => {
+        verify_password(password, &DUMMY_HASH);
+        Ok(None)
+      }
+    }
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn is_admin<'a, E>(ex: E, id: i64) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let admin = query_scalar::<_, bool>("select admin from users where id = $1")
+    .bind(id)
+    .fetch_one(ex).await?;
+    Ok(admin)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn set_admin<'a, E>(ex: E, id: i64, admin: bool) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("update users set admin = $1 where id = $2")
+    .bind(admin).bind(id)
+    .execute(ex).await.context("Unable to update user")?;
+    Ok(())
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn accounts<'a, E>(ex: E) -> Result<Vec<UserAccount>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let accounts = query_as::<_, UserAccount>("select id, name, email, admin, verified from users order by id")
+    .fetch_all(ex).await?;
+    Ok(accounts)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn mark_verified<'a, E>(ex: E, id: i64) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("update users set verified = true where id = $1")
+    .bind(id)
+    .execute(ex).await.context("Unable to mark user as verified")?;
+    Ok(())
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn is_verified<'a, E>(ex: E, id: i64) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let verified = query_scalar::<_, bool>("select verified from users where id = $1")
+    .bind(id)
+    .fetch_one(ex).await?;
+    Ok(verified)
+  }
+}
+
+lazy_static! {
+  /// Checked against when there is no password to check, so that a login
+  /// does not tell whether the address is registered by how long it takes.
+  static ref DUMMY_HASH: String = hash_password("rocketbase").expect("hashing works with default parameters");
+}
+
+fn hash_password(password: &str) -> Result<String> {
+  let salt = SaltString::generate(&mut OsRng);
+  let hash = Argon2::default()
+    .hash_password(password.as_bytes(), &salt)
+    .map_err(|e| eyre!("Unable to hash password: {}", e))?;
+  Ok(hash.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+  match PasswordHash::new(hash) {
+    Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+    Err(_) => false,
+  }
 }
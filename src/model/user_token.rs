@@ -0,0 +1,104 @@
+use chrono::{Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Postgres, query, query_scalar};
+use tracing::instrument;
+use color_eyre::{eyre::WrapErr, Result};
+
+/// The purpose a token was issued for. A token issued for one purpose can
+/// never be consumed for another.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+  Verification,
+  PasswordReset,
+  Session,
+  MfaPending,
+}
+
+impl TokenKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Verification => "verification",
+      Self::PasswordReset => "password_reset",
+      Self::Session => "session",
+      Self::MfaPending => "mfa_pending",
+    }
+  }
+}
+
+/// Opaque tokens handed out to users, either single use tokens that are mailed
+/// out or session tokens. Only the SHA-256 of the token is stored so a leaked
+/// table can not be used to take over accounts.
+pub struct UserToken;
+
+impl UserToken {
+  /// Creates a token for the user and returns the raw value to be handed out.
+  #[instrument(skip(ex))]
+  pub async fn issue<'a, E>(ex: E, user_id: i64, kind: TokenKind, ttl: Duration) -> Result<String>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let token = generate_token();
+    query("insert into _user_tokens(user_id, kind, token_hash, expires_at) values($1, $2, $3, $4)")
+    .bind(user_id)
+    .bind(kind.as_str())
+    .bind(hash_token(&token))
+    .bind(Utc::now() + ttl)
+    .execute(ex).await.context("Unable to save token")?;
+    Ok(token)
+  }
+
+  /// Deletes the token and returns the user it belongs to, provided it was
+  /// issued for `kind` and has not expired.
+  #[instrument(skip(ex, token))]
+  pub async fn consume<'a, E>(ex: E, token: &str, kind: TokenKind) -> Result<Option<i64>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let user_id = query_scalar::<_, i64>(
+      "delete from _user_tokens where token_hash = $1 and kind = $2 and expires_at > now() returning user_id"
+    )
+    .bind(hash_token(token))
+    .bind(kind.as_str())
+    .fetch_optional(ex).await.context("Unable to consume token")?;
+    Ok(user_id)
+  }
+
+  /// Deletes the tokens of `kinds` the user holds, such as their sessions
+  /// once the password changed.
+  #[instrument(skip(ex))]
+  pub async fn revoke_all<'a, E>(ex: E, user_id: i64, kinds: &[TokenKind]) -> Result<u64>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let kinds: Vec<&str> = kinds.iter().map(TokenKind::as_str).collect();
+    let revoked = query("delete from _user_tokens where user_id = $1 and kind = any($2)")
+    .bind(user_id)
+    .bind(kinds)
+    .execute(ex).await.context("Unable to revoke tokens")?;
+    Ok(revoked.rows_affected())
+  }
+
+  /// Returns the user a live token belongs to without consuming it.
+  #[instrument(skip(ex, token))]
+  pub async fn lookup<'a, E>(ex: E, token: &str, kind: TokenKind) -> Result<Option<i64>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let user_id = query_scalar::<_, i64>(
+      "select user_id from _user_tokens where token_hash = $1 and kind = $2 and expires_at > now()"
+    )
+    .bind(hash_token(token))
+    .bind(kind.as_str())
+    .fetch_optional(ex).await.context("Unable to look up token")?;
+    Ok(user_id)
+  }
+}
+
+pub fn generate_token() -> String {
+  let mut bytes = [0u8; 32];
+  OsRng.fill_bytes(&mut bytes);
+  hex::encode(bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+  hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,243 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Json, FromRow, Executor, Postgres, query, query_as};
+use tracing::instrument;
+use color_eyre::{eyre::WrapErr, Result};
+
+use super::change::Action;
+use super::user_token::generate_token;
+
+#[derive(Deserialize, Debug)]
+pub struct NewWebhook {
+  pub collection: String,
+  pub events: Vec<Action>,
+  pub url: String,
+  /// Key for signing deliveries. Generated when not given.
+  pub secret: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct WebhookUpdate {
+  pub events: Option<Vec<Action>>,
+  pub url: Option<String>,
+  pub active: Option<bool>,
+}
+
+/// An endpoint that gets the changes of a collection posted to it. Deliveries
+/// are queued by the change trigger, see [`Delivery`], and sent as tasks of
+/// the queue.
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+  pub id: i64,
+  pub collection: String,
+  pub events: Vec<String>,
+  pub url: String,
+  /// Only shown when the webhook is created.
+  #[serde(skip_serializing, default)]
+  pub secret: String,
+  pub active: bool,
+  pub created_at: DateTime<Utc>,
+}
+
+const WEBHOOK_COLUMNS: &str = "id, collection, events, url, secret, active, created_at";
+
+fn event_names(events: &[Action]) -> Vec<&'static str> {
+  events.iter().map(Action::as_str).collect()
+}
+
+impl Webhook {
+  #[instrument(skip(ex, new_webhook))]
+  pub async fn create<'a, E>(ex: E, new_webhook: &NewWebhook) -> Result<Webhook>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let secret = new_webhook.secret.clone().unwrap_or_else(generate_token);
+    let webhook = query_as::<_, Webhook>(&format!(
+      "insert into _webhooks(collection, events, url, secret) values($1, $2, $3, $4) returning {}", WEBHOOK_COLUMNS))
+    .bind(&new_webhook.collection)
+    .bind(event_names(&new_webhook.events))
+    .bind(&new_webhook.url)
+    .bind(secret)
+    .fetch_one(ex).await.context("Unable to save webhook")?;
+    Ok(webhook)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn all<'a, E>(ex: E) -> Result<Vec<Webhook>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let webhooks = query_as::<_, Webhook>(&format!("select {} from _webhooks order by id", WEBHOOK_COLUMNS))
+    .fetch_all(ex).await?;
+    Ok(webhooks)
+  }
+
+  #[instrument(skip(ex))]
+  pub async fn find<'a, E>(ex: E, id: i64) -> Result<Option<Webhook>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let webhook = query_as::<_, Webhook>(&format!("select {} from _webhooks where id = $1", WEBHOOK_COLUMNS))
+    .bind(id)
+    .fetch_optional(ex).await?;
+    Ok(webhook)
+  }
+
+  /// Changes the given fields, returning `None` when there is no such webhook.
+  #[instrument(skip(ex))]
+  pub async fn update<'a, E>(ex: E, id: i64, update: &WebhookUpdate) -> Result<Option<Webhook>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let webhook = query_as::<_, Webhook>(&format!(
+      "update _webhooks set events = coalesce($2, events), url = coalesce($3, url), active = coalesce($4, active) \
+      where id = $1 returning {}", WEBHOOK_COLUMNS))
+    .bind(id)
+    .bind(update.events.as_deref().map(event_names))
+    .bind(&update.url)
+    .bind(update.active)
+    .fetch_optional(ex).await.context("Unable to update webhook")?;
+    Ok(webhook)
+  }
+
+  /// Deletes the webhook along with its deliveries.
+  #[instrument(skip(ex))]
+  pub async fn delete<'a, E>(ex: E, id: i64) -> Result<bool>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query("delete from _webhooks where id = $1")
+    .bind(id)
+    .execute(ex).await.context("Unable to delete webhook")?;
+    Ok(res.rows_affected() == 1)
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum DeliveryStatus {
+  /// Waiting for its first or next attempt.
+  Pending,
+  Delivered,
+  /// Gave up after the last attempt, or the webhook was deactivated.
+  Failed,
+}
+
+/// A change posted, or to be posted, to a webhook.
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+  pub id: i64,
+  pub webhook_id: i64,
+  pub event: String,
+  pub payload: Json<Value>,
+  pub status: DeliveryStatus,
+  pub attempts: i32,
+  /// Status code of the last response, if there was one.
+  pub response_status: Option<i32>,
+  /// Why the last attempt failed.
+  pub error: Option<String>,
+  pub created_at: DateTime<Utc>,
+  pub delivered_at: Option<DateTime<Utc>>,
+}
+
+const DELIVERY_COLUMNS: &str = "id, webhook_id, event, payload, status, attempts, response_status, error, created_at, delivered_at";
+
+/// A delivery about to be sent, along with where it goes.
+#[derive(FromRow, Debug)]
+pub struct DueDelivery {
+  #[sqlx(flatten)]
+  pub delivery: Delivery,
+  pub url: String,
+  pub secret: String,
+  pub active: bool,
+}
+
+impl Delivery {
+  /// Latest deliveries of a webhook, newest first.
+  #[instrument(skip(ex))]
+  pub async fn for_webhook<'a, E>(ex: E, webhook_id: i64, limit: i64) -> Result<Vec<Delivery>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let deliveries = query_as::<_, Delivery>(&format!(
+      "select {} from _webhook_deliveries where webhook_id = $1 order by id desc limit $2", DELIVERY_COLUMNS))
+    .bind(webhook_id)
+    .bind(limit)
+    .fetch_all(ex).await?;
+    Ok(deliveries)
+  }
+
+  /// Copies a delivery of the webhook into a new pending one, for a task to
+  /// send.
+  #[instrument(skip(ex))]
+  pub async fn redeliver<'a, E>(ex: E, webhook_id: i64, id: i64) -> Result<Option<Delivery>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let delivery = query_as::<_, Delivery>(&format!(
+      "insert into _webhook_deliveries(webhook_id, event, payload) \
+      select webhook_id, event, payload from _webhook_deliveries where webhook_id = $1 and id = $2 \
+      returning {}", DELIVERY_COLUMNS))
+    .bind(webhook_id)
+    .bind(id)
+    .fetch_optional(ex).await.context("Unable to queue delivery")?;
+    Ok(delivery)
+  }
+
+  /// A delivery that was not delivered yet, returning `None` once it was or
+  /// when its webhook is gone.
+  #[instrument(skip(ex))]
+  pub async fn find_due<'a, E>(ex: E, id: i64) -> Result<Option<DueDelivery>>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let columns = DELIVERY_COLUMNS.split(", ").map(|column| format!("d.{}", column)).collect::<Vec<_>>().join(", ");
+    let due = query_as::<_, DueDelivery>(&format!(
+      "select {}, w.url, w.secret, w.active from _webhook_deliveries d join _webhooks w on w.id = d.webhook_id \
+      where d.id = $1 and d.status != 'delivered'", columns))
+    .bind(id)
+    .fetch_optional(ex).await.context("Unable to find delivery")?;
+    Ok(due)
+  }
+
+  /// Records an attempt. A failed attempt leaves the delivery pending, unless
+  /// it was the last one.
+  #[instrument(skip(ex))]
+  pub async fn record_attempt<'a, E>(ex: E, id: i64, response_status: Option<u16>, error: Option<&str>, last: bool) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let status = match (error, last) {
+      (None, _) => DeliveryStatus::Delivered,
+      (Some(_), false) => DeliveryStatus::Pending,
+      (Some(_), true) => DeliveryStatus::Failed,
+    };
+    query(
+      "update _webhook_deliveries set status = $2, attempts = attempts + 1, response_status = $3, error = $4, \
+      delivered_at = case when $2 = 'delivered' then now() end \
+      where id = $1")
+    .bind(id)
+    .bind(status)
+    .bind(response_status.map(i32::from))
+    .bind(error)
+    .execute(ex).await.context("Unable to record delivery attempt")?;
+    Ok(())
+  }
+
+  /// Gives up a delivery without attempting it.
+  #[instrument(skip(ex))]
+  pub async fn cancel<'a, E>(ex: E, id: i64, error: &str) -> Result<()>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    query("update _webhook_deliveries set status = 'failed', error = $2 where id = $1 and status = 'pending'")
+    .bind(id)
+    .bind(error)
+    .execute(ex).await.context("Unable to cancel delivery")?;
+    Ok(())
+  }
+
+  /// Deletes delivered and failed deliveries older than `retention`,
+  /// returning how many were deleted.
+  #[instrument(skip(ex))]
+  pub async fn prune<'a, E>(ex: E, retention: Duration) -> Result<u64>
+  where E: 'a + Executor<'a, Database = Postgres>
+  {
+    let res = query("delete from _webhook_deliveries where status != 'pending' and created_at < now() - $1 * interval '1 second'")
+    .bind(retention.num_seconds() as f64)
+    .execute(ex).await.context("Unable to prune deliveries")?;
+    Ok(res.rows_affected())
+  }
+}
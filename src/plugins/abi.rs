@@ -0,0 +1,177 @@
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Map, Value};
+use sqlx::PgPool;
+use wasmtime::{AsContext, AsContextMut, Caller, Extern, Linker, Memory, StoreLimits, TypedFunc};
+
+use crate::model::{collection::Collection, record::ListQuery, Records};
+
+use super::{HookRegistration, RouteRegistration};
+
+/// Module of the functions plugins import from the host.
+const HOST_MODULE: &str = "rocketbase";
+/// Most records a single `list` call returns.
+const MAX_LIST_LIMIT: i64 = 500;
+/// Largest JSON message read from a plugin.
+const MAX_MESSAGE_BYTES: usize = 16 << 20;
+
+/// Data of the store a plugin runs in. Registrations are only kept from the
+/// store `init` ran in.
+pub(super) struct HostState {
+  pub plugin: String,
+  pub pool: PgPool,
+  pub limits: StoreLimits,
+  pub hooks: Vec<HookRegistration>,
+  pub routes: Vec<RouteRegistration>,
+}
+
+/// Guest functions return the location of their output as `ptr << 32 | len`.
+pub(super) fn pack(ptr: i32, len: i32) -> i64 {
+  ((ptr as u32 as i64) << 32) | len as u32 as i64
+}
+
+pub(super) fn unpack(packed: i64) -> (i32, i32) {
+  ((packed >> 32) as i32, packed as i32)
+}
+
+/// Parses the JSON a guest left at `ptr`, which has to lie within its
+/// memory and be at most `MAX_MESSAGE_BYTES` long.
+pub(super) fn read_json<T: DeserializeOwned>(
+  store: impl AsContext,
+  memory: Memory,
+  ptr: i32,
+  len: i32,
+) -> wasmtime::Result<T> {
+  let (start, len) = (ptr as u32 as usize, len as u32 as usize);
+  if len > MAX_MESSAGE_BYTES {
+    return Err(wasmtime::Error::msg(format!("Plugin message of {} bytes is larger than {}", len, MAX_MESSAGE_BYTES)));
+  }
+  let data = memory.data(&store);
+  let bytes = data.get(start..start + len)
+    .ok_or_else(|| wasmtime::Error::msg("Plugin message lies outside its memory"))?;
+  Ok(serde_json::from_slice(bytes)?)
+}
+
+/// Copies `value` into memory handed out by the guest's `alloc`.
+pub(super) async fn write_json(
+  mut store: impl AsContextMut<Data = HostState>,
+  memory: Memory,
+  alloc: &TypedFunc<i32, i32>,
+  value: &Value,
+) -> wasmtime::Result<(i32, i32)> {
+  let bytes = serde_json::to_vec(value)?;
+  let len = i32::try_from(bytes.len())?;
+  let ptr = alloc.call_async(&mut store, len).await?;
+  memory.write(&mut store, ptr as u32 as usize, &bytes)?;
+  Ok((ptr, len))
+}
+
+fn memory(caller: &mut Caller<'_, HostState>) -> wasmtime::Result<Memory> {
+  caller.get_export("memory")
+    .and_then(Extern::into_memory)
+    .ok_or_else(|| wasmtime::Error::msg("Plugin does not export its memory"))
+}
+
+fn alloc(caller: &mut Caller<'_, HostState>) -> wasmtime::Result<TypedFunc<i32, i32>> {
+  caller.get_export("alloc")
+    .and_then(Extern::into_func)
+    .ok_or_else(|| wasmtime::Error::msg("Plugin does not export alloc"))?
+    .typed(&caller)
+}
+
+/// Requests plugins make through `rocketbase.call`. Writes go straight to
+/// the tables, without running hooks.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum HostCall {
+  Find { collection: String, id: i64 },
+  List {
+    collection: String,
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+  },
+  Create { collection: String, data: Map<String, Value> },
+  Update { collection: String, id: i64, data: Map<String, Value> },
+  Delete { collection: String, id: i64 },
+  Log { message: String },
+}
+
+fn default_limit() -> i64 {
+  100
+}
+
+async fn collection(pool: &PgPool, name: &str) -> color_eyre::Result<Collection> {
+  Collection::find(pool, name).await?
+    .ok_or_else(|| color_eyre::eyre::eyre!("No collection named {}", name))
+}
+
+async fn host_call(pool: &PgPool, plugin: &str, call: HostCall) -> color_eyre::Result<Value> {
+  let value = match call {
+    HostCall::Find { collection: name, id } => {
+      Records::find(pool, &collection(pool, &name).await?, id).await?.unwrap_or(Value::Null)
+    }
+    HostCall::List { collection: name, limit, offset } => {
+      let list = ListQuery { limit: limit.clamp(0, MAX_LIST_LIMIT), offset: offset.max(0), owner: None };
+      Value::Array(Records::list(pool, &collection(pool, &name).await?, list).await?)
+    }
+    HostCall::Create { collection: name, data } => {
+      Records::insert(pool, &collection(pool, &name).await?, &data).await?
+    }
+    HostCall::Update { collection: name, id, data } => {
+      Records::update(pool, &collection(pool, &name).await?, id, &data).await?.unwrap_or(Value::Null)
+    }
+    HostCall::Delete { collection: name, id } => {
+      Records::delete(pool, &collection(pool, &name).await?, id).await?.unwrap_or(Value::Null)
+    }
+    HostCall::Log { message } => {
+      tracing::info!(plugin, "{}", message);
+      Value::Null
+    }
+  };
+  Ok(value)
+}
+
+/// Links the host API:
+///
+/// - `register_hook(ptr, len)` takes `{"event", "collection"?, "handler"}`
+/// - `register_route(ptr, len)` takes `{"method", "path", "handler"}`
+/// - `call(ptr, len) -> i64` takes a request such as
+///   `{"op": "find", "collection": "posts", "id": 1}` and answers with
+///   `{"ok": ...}` or `{"error": "..."}`
+///
+/// Registrations made outside of `init` are ignored.
+pub(super) fn link(linker: &mut Linker<HostState>) -> wasmtime::Result<()> {
+  linker.func_wrap(HOST_MODULE, "register_hook", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+    let memory = memory(&mut caller)?;
+    let hook = read_json(&caller, memory, ptr, len)?;
+    caller.data_mut().hooks.push(hook);
+    Ok(())
+  })?;
+  linker.func_wrap(HOST_MODULE, "register_route", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+    let memory = memory(&mut caller)?;
+    let route = read_json(&caller, memory, ptr, len)?;
+    caller.data_mut().routes.push(route);
+    Ok(())
+  })?;
+  linker.func_wrap_async(HOST_MODULE, "call", |mut caller: Caller<'_, HostState>, (ptr, len): (i32, i32)| {
+    Box::new(async move {
+      let memory = memory(&mut caller)?;
+      let response = match read_json::<HostCall>(&caller, memory, ptr, len) {
+        Ok(call) => {
+          let HostState { pool, plugin, .. } = caller.data();
+          let (pool, plugin) = (pool.clone(), plugin.clone());
+          match host_call(&pool, &plugin, call).await {
+            Ok(value) => json!({ "ok": value }),
+            Err(err) => json!({ "error": format!("{:#}", err) }),
+          }
+        }
+        Err(err) => json!({ "error": format!("Invalid request: {:#}", err) }),
+      };
+      let alloc = alloc(&mut caller)?;
+      let (ptr, len) = write_json(&mut caller, memory, &alloc, &response).await?;
+      Ok(pack(ptr, len))
+    })
+  })?;
+  Ok(())
+}
@@ -0,0 +1,448 @@
+//! WebAssembly plugins, loaded from `plugins.dir` and reloaded when their
+//! file changes. A plugin is a `.wasm` module, or a `.wat` one while
+//! developing, named after its file. It exports:
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, where the host copies inputs to
+//! - optionally `init()`, run on load to register hooks and routes through
+//!   the functions imported from `rocketbase`, see [`abi::link`]
+//! - handlers `(ptr: i32, len: i32) -> i64` taking JSON and returning the
+//!   location of their JSON output packed as `ptr << 32 | len`
+//!
+//! Record hooks get `{"event", "collection", "record", "data", "user"}`.
+//! Before hooks may answer `{"data": ...}` to replace the fields written or
+//! `{"reject": status, "message": ...}`, the message being optional. Routes
+//! are served under `/api/plugins/{plugin}{path}` and get `{"method", "path",
+//! "query", "body", "user", "admin"}`, answering `{"status", "body"}` or just
+//! the body.
+//!
+//! Every call, `init` included, runs in a fresh instance limited to
+//! `plugins.fuel_per_call` and `plugins.max_memory_bytes`, and is given up
+//! after `plugins.call_timeout_ms`, which also bounds the time spent waiting
+//! on the host.
+mod abi;
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  ffi::OsStr,
+  io::ErrorKind,
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+  time::{Duration, SystemTime},
+};
+
+use axum::{
+  body::Bytes,
+  extract::{Path as UrlPath, Query, State},
+  http::{Method, StatusCode},
+  response::{IntoResponse, Response},
+  routing::any,
+  Json, Router,
+};
+use color_eyre::{eyre::{eyre, WrapErr}, Report, Result};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use wasmtime::{Config, Engine, Linker, Module, Store, StoreLimitsBuilder};
+
+use crate::{
+  app_state::AppState,
+  auth::Principal,
+  hooks::{Hooks, RecordEvent, Rejection, SchemaChange},
+  settings,
+};
+
+use abi::{read_json, unpack, write_json, HostState};
+
+/// Fuel burnt between yields to the runtime, so that busy plugins don't hold
+/// up other tasks.
+const FUEL_YIELD_INTERVAL: u64 = 100_000;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+  BeforeCreate,
+  AfterCreate,
+  BeforeUpdate,
+  AfterUpdate,
+  BeforeDelete,
+  AfterDelete,
+  SchemaChange,
+}
+
+impl HookEvent {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Self::BeforeCreate => "before_create",
+      Self::AfterCreate => "after_create",
+      Self::BeforeUpdate => "before_update",
+      Self::AfterUpdate => "after_update",
+      Self::BeforeDelete => "before_delete",
+      Self::AfterDelete => "after_delete",
+      Self::SchemaChange => "schema_change",
+    }
+  }
+}
+
+/// A handler of a plugin to call on `event`, for any collection unless one
+/// is given.
+#[derive(Deserialize, Debug, Clone)]
+pub struct HookRegistration {
+  pub event: HookEvent,
+  pub collection: Option<String>,
+  pub handler: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RouteRegistration {
+  pub method: String,
+  /// Path below `/api/plugins/{plugin}`, matched exactly.
+  pub path: String,
+  pub handler: String,
+}
+
+/// A loaded version of a plugin.
+#[derive(Debug)]
+pub struct Plugin {
+  pub name: String,
+  pub hooks: Vec<HookRegistration>,
+  pub routes: Vec<RouteRegistration>,
+  module: Module,
+  modified: SystemTime,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct BeforeOutput {
+  data: Option<Map<String, Value>>,
+  reject: Option<u16>,
+  message: Option<String>,
+}
+
+/// Loads plugins and runs their handlers. Hooks and routes of plugins are
+/// looked up on every call, so reloaded plugins take effect right away.
+pub struct PluginHost {
+  config: settings::Plugins,
+  pool: PgPool,
+  engine: Engine,
+  linker: Linker<HostState>,
+  plugins: RwLock<BTreeMap<String, Arc<Plugin>>>,
+  /// Modification times of files that failed to load, not to retry them
+  /// until they change again.
+  failed: RwLock<HashMap<String, SystemTime>>,
+}
+
+fn wasm_error(err: wasmtime::Error) -> Report {
+  eyre!("{:#}", err)
+}
+
+impl PluginHost {
+  pub fn new(config: settings::Plugins, pool: PgPool) -> Result<Arc<Self>> {
+    let mut engine_config = Config::new();
+    engine_config.async_support(true).consume_fuel(true);
+    let engine = Engine::new(&engine_config).map_err(wasm_error)?;
+    let mut linker = Linker::new(&engine);
+    abi::link(&mut linker).map_err(wasm_error)?;
+    Ok(Arc::new(PluginHost {
+      config,
+      pool,
+      engine,
+      linker,
+      plugins: RwLock::new(BTreeMap::new()),
+      failed: RwLock::new(HashMap::new()),
+    }))
+  }
+
+  /// Loaded plugins, ordered by name, which is the order their hooks run in.
+  pub fn plugins(&self) -> Vec<Arc<Plugin>> {
+    self.plugins.read().unwrap().values().cloned().collect()
+  }
+
+  pub fn plugin(&self, name: &str) -> Option<Arc<Plugin>> {
+    self.plugins.read().unwrap().get(name).cloned()
+  }
+
+  fn store(&self, plugin: &str) -> Store<HostState> {
+    let limits = StoreLimitsBuilder::new()
+      .memory_size(self.config.max_memory_bytes)
+      .instances(1)
+      .build();
+    let mut store = Store::new(&self.engine, HostState {
+      plugin: plugin.to_string(),
+      pool: self.pool.clone(),
+      limits,
+      hooks: vec![],
+      routes: vec![],
+    });
+    store.limiter(|state| &mut state.limits);
+    store.set_fuel(self.config.fuel_per_call).expect("fuel is enabled");
+    store.fuel_async_yield_interval(Some(FUEL_YIELD_INTERVAL)).expect("fuel and async are enabled");
+    store
+  }
+
+  async fn load(&self, name: &str, path: &Path, modified: SystemTime) -> Result<Plugin> {
+    let bytes = tokio::fs::read(path).await.context("Unable to read plugin")?;
+    let engine = self.engine.clone();
+    let module = tokio::task::spawn_blocking(move || Module::new(&engine, bytes)).await?
+      .map_err(wasm_error).context("Unable to compile plugin")?;
+    let mut store = self.store(name);
+    let limit = Duration::from_millis(self.config.call_timeout_ms);
+    let initialized = tokio::time::timeout(limit, async {
+      let instance = self.linker.instantiate_async(&mut store, &module).await?;
+      if let Ok(init) = instance.get_typed_func::<(), ()>(&mut store, "init") {
+        init.call_async(&mut store, ()).await?;
+      }
+      wasmtime::Result::<()>::Ok(())
+    }).await;
+    match initialized {
+      Ok(initialized) => initialized.map_err(wasm_error).context("Plugin failed to initialize")?,
+      Err(_) => return Err(eyre!("Plugin did not initialize within {:?}", limit)),
+    }
+    let HostState { hooks, routes, .. } = store.into_data();
+    Ok(Plugin { name: name.to_string(), hooks, routes, module, modified })
+  }
+
+  /// Plugin files in the directory by name, with their modification time.
+  async fn scan(&self) -> Result<HashMap<String, (PathBuf, SystemTime)>> {
+    let mut found = HashMap::new();
+    let mut entries = match tokio::fs::read_dir(&self.config.dir).await {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(found),
+      Err(err) => return Err(err).context("Unable to read plugins directory"),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+      let path = entry.path();
+      if !matches!(path.extension().and_then(OsStr::to_str), Some("wasm" | "wat")) {
+        continue;
+      }
+      let Some(name) = path.file_stem().and_then(OsStr::to_str).map(String::from) else {
+        continue;
+      };
+      let modified = entry.metadata().await?.modified()?;
+      found.insert(name, (path, modified));
+    }
+    Ok(found)
+  }
+
+  /// Loads new and changed plugins and drops those whose file is gone. A
+  /// plugin that fails to load keeps running its previous version.
+  pub async fn reload(&self) -> Result<()> {
+    let found = self.scan().await?;
+    let mut plugins = self.plugins.read().unwrap().clone();
+    plugins.retain(|name, _| {
+      let keep = found.contains_key(name);
+      if !keep {
+        tracing::info!("Unloaded plugin {}", name);
+      }
+      keep
+    });
+    self.failed.write().unwrap().retain(|name, _| found.contains_key(name));
+    for (name, (path, modified)) in found {
+      let loaded = plugins.get(&name).map(|plugin| plugin.modified);
+      let failed = self.failed.read().unwrap().get(&name).copied();
+      if loaded == Some(modified) || failed == Some(modified) {
+        continue;
+      }
+      match self.load(&name, &path, modified).await {
+        Ok(plugin) => {
+          tracing::info!("Loaded plugin {} with {} hooks and {} routes", name, plugin.hooks.len(), plugin.routes.len());
+          self.failed.write().unwrap().remove(&name);
+          plugins.insert(name, Arc::new(plugin));
+        }
+        Err(err) => {
+          tracing::error!("Unable to load plugin {}: {:?}", name, err);
+          self.failed.write().unwrap().insert(name, modified);
+        }
+      }
+    }
+    *self.plugins.write().unwrap() = plugins;
+    Ok(())
+  }
+
+  /// Reloads plugins every `plugins.reload_interval_ms` until shutdown.
+  pub fn spawn_watcher(self: &Arc<Self>, shutdown: CancellationToken) -> JoinHandle<()> {
+    let host = self.clone();
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(Duration::from_millis(host.config.reload_interval_ms));
+      interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+      while !host.pool.is_closed() {
+        tokio::select! {
+          _ = shutdown.cancelled() => break,
+          _ = interval.tick() => {}
+        }
+        if let Err(err) = host.reload().await {
+          tracing::error!("{:?}", err);
+        }
+      }
+    })
+  }
+
+  /// Runs `handler` of `plugin` in a fresh instance.
+  pub async fn call(&self, plugin: &Plugin, handler: &str, input: &Value) -> Result<Value> {
+    let mut store = self.store(&plugin.name);
+    let limit = Duration::from_millis(self.config.call_timeout_ms);
+    let output = tokio::time::timeout(limit, async {
+      let instance = self.linker.instantiate_async(&mut store, &plugin.module).await?;
+      let memory = instance.get_memory(&mut store, "memory")
+        .ok_or_else(|| wasmtime::Error::msg("Plugin does not export its memory"))?;
+      let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+      let (ptr, len) = write_json(&mut store, memory, &alloc, input).await?;
+      let func = instance.get_typed_func::<(i32, i32), i64>(&mut store, handler)?;
+      let (ptr, len) = unpack(func.call_async(&mut store, (ptr, len)).await?);
+      read_json(&store, memory, ptr, len)
+    }).await;
+    let output = match output {
+      Ok(output) => output.map_err(wasm_error),
+      Err(_) => Err(eyre!("Timed out after {:?}", limit)),
+    };
+    output.with_context(|| format!("Plugin {} failed in {}", plugin.name, handler))
+  }
+
+  fn handlers(&self, event: HookEvent, collection: &str) -> Vec<(Arc<Plugin>, String)> {
+    self.plugins().into_iter()
+      .flat_map(|plugin| {
+        let handlers = plugin.hooks.iter()
+          .filter(|hook| hook.event == event && hook.collection.as_deref().is_none_or(|name| name == collection))
+          .map(|hook| hook.handler.clone())
+          .collect::<Vec<_>>();
+        handlers.into_iter().map(move |handler| (plugin.clone(), handler))
+      })
+      .collect()
+  }
+
+  async fn before_record(&self, event: HookEvent, mut record_event: RecordEvent) -> Result<RecordEvent, Rejection> {
+    for (plugin, handler) in self.handlers(event, &record_event.collection.name) {
+      let output = self.call(&plugin, &handler, &record_input(event, &record_event)).await.map_err(|err| {
+        tracing::error!("{:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+      })?;
+      let output = serde_json::from_value::<BeforeOutput>(output).unwrap_or_default();
+      if let Some(status) = output.reject {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        return Err(Rejection { status, message: output.message });
+      }
+      if let Some(data) = output.data {
+        record_event.data = data;
+      }
+    }
+    Ok(record_event)
+  }
+
+  async fn after(&self, event: HookEvent, collection: &str, input: Value) {
+    for (plugin, handler) in self.handlers(event, collection) {
+      if let Err(err) = self.call(&plugin, &handler, &input).await {
+        tracing::error!("{:?}", err);
+      }
+    }
+  }
+
+  /// Adds hooks to `hooks` that run the hooks registered by plugins, after
+  /// the ones already there.
+  pub fn hooks(self: &Arc<Self>, hooks: Hooks) -> Hooks {
+    let before = |event: HookEvent| {
+      let host = self.clone();
+      move |record_event: RecordEvent| {
+        let host = host.clone();
+        async move { host.before_record(event, record_event).await }
+      }
+    };
+    let after = |event: HookEvent| {
+      let host = self.clone();
+      move |record_event: RecordEvent| {
+        let host = host.clone();
+        async move { host.after(event, &record_event.collection.name, record_input(event, &record_event)).await }
+      }
+    };
+    let host = self.clone();
+    hooks
+      .before_create(before(HookEvent::BeforeCreate))
+      .after_create(after(HookEvent::AfterCreate))
+      .before_update(before(HookEvent::BeforeUpdate))
+      .after_update(after(HookEvent::AfterUpdate))
+      .before_delete(before(HookEvent::BeforeDelete))
+      .after_delete(after(HookEvent::AfterDelete))
+      .on_schema_change(move |change: SchemaChange| {
+        let host = host.clone();
+        async move {
+          let input = json!({
+            "event": HookEvent::SchemaChange.as_str(),
+            "collection": change.collection,
+            "previous": change.previous,
+          });
+          host.after(HookEvent::SchemaChange, &change.collection.name, input).await
+        }
+      })
+  }
+
+  /// Routes of plugins, to be nested under `/api/plugins`.
+  pub fn router(self: &Arc<Self>) -> Router<AppState> {
+    Router::new()
+      .route("/:plugin/*path", any(route_handler))
+      .with_state(self.clone())
+  }
+}
+
+impl std::fmt::Debug for PluginHost {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("PluginHost")
+      .field("config", &self.config)
+      .field("plugins", &self.plugins.read().unwrap().keys().collect::<Vec<_>>())
+      .finish()
+  }
+}
+
+fn user_input(principal: Option<&Principal>) -> Value {
+  principal.and_then(Principal::user).map(|user| json!(user)).unwrap_or(Value::Null)
+}
+
+fn record_input(event: HookEvent, record_event: &RecordEvent) -> Value {
+  json!({
+    "event": event.as_str(),
+    "collection": record_event.collection.name,
+    "record": record_event.record,
+    "data": record_event.data,
+    "user": user_input(record_event.principal.as_ref()),
+  })
+}
+
+async fn route_handler(
+  State(host): State<Arc<PluginHost>>,
+  UrlPath((name, path)): UrlPath<(String, String)>,
+  Query(query): Query<HashMap<String, String>>,
+  method: Method,
+  principal: Option<Principal>,
+  body: Bytes,
+) -> Result<Response, StatusCode> {
+  let plugin = host.plugin(&name).ok_or(StatusCode::NOT_FOUND)?;
+  let path = format!("/{}", path.trim_start_matches('/'));
+  let route = plugin.routes.iter()
+    .find(|route| route.path == path && route.method.eq_ignore_ascii_case(method.as_str()))
+    .ok_or(StatusCode::NOT_FOUND)?;
+  let body = match body.is_empty() {
+    true => Value::Null,
+    false => serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?,
+  };
+  let input = json!({
+    "method": method.as_str(),
+    "path": path,
+    "query": query,
+    "body": body,
+    "user": user_input(principal.as_ref()),
+    "admin": matches!(principal, Some(Principal::Admin(_))),
+  });
+  let output = host.call(&plugin, &route.handler, &input).await.map_err(|err| {
+    tracing::error!("{:?}", err);
+    StatusCode::INTERNAL_SERVER_ERROR
+  })?;
+  let (status, body) = match output {
+    Value::Object(mut output) if output.contains_key("status") => {
+      let status = output.get("status").and_then(Value::as_u64)
+        .and_then(|status| StatusCode::from_u16(status as u16).ok())
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+      (status, output.remove("body").unwrap_or(Value::Null))
+    }
+    body => (StatusCode::OK, body),
+  };
+  Ok((status, Json(body)).into_response())
+}
@@ -0,0 +1,232 @@
+use std::{collections::BTreeMap, fmt, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use tokio::{sync::Semaphore, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::{db::DB, model::task::Task, settings};
+
+/// Longest wait between two attempts of a task.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(6 * 60 * 60);
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type Handler = Arc<dyn Fn(Value, TaskContext) -> BoxFuture<TaskOutcome> + Send + Sync>;
+
+/// A typed task payload. `KIND` picks the handler it runs with.
+pub trait TaskPayload: Serialize + DeserializeOwned + Send + 'static {
+  const KIND: &'static str;
+}
+
+/// What a task handler gets besides the payload.
+#[derive(Debug, Clone)]
+pub struct TaskContext {
+  pub pool: PgPool,
+  pub id: i64,
+  /// Attempts so far, this one included.
+  pub attempts: i32,
+  /// Whether the task is dead if this attempt fails.
+  pub last_attempt: bool,
+}
+
+enum TaskOutcome {
+  Done,
+  Failed(String),
+  /// Not worth retrying, such as a payload that does not deserialize.
+  Dead(String),
+}
+
+/// Wait before the attempt following `attempts` failed ones, doubling each
+/// time.
+pub fn retry_delay(base: Duration, attempts: u32) -> Duration {
+  base.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1))).min(MAX_RETRY_DELAY)
+}
+
+impl DB {
+  /// Queues a task to run as soon as a worker is free.
+  pub async fn enqueue<T: TaskPayload>(&self, payload: &T) -> Result<Task> {
+    self.enqueue_at(payload, None).await
+  }
+  /// Queues a task to run at `run_at`, or right away.
+  pub async fn enqueue_at<T: TaskPayload>(&self, payload: &T, run_at: Option<DateTime<Utc>>) -> Result<Task> {
+    Task::enqueue(&self.connection(), T::KIND, &serde_json::to_value(payload)?, run_at).await
+  }
+}
+
+/// Handlers of background tasks. Workers only take tasks of the kinds they
+/// have a handler for, so instances may handle different sets of kinds.
+#[derive(Default)]
+pub struct Workers {
+  handlers: BTreeMap<String, Handler>,
+}
+
+impl Workers {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Runs tasks of `T::KIND` with `handler`. Failed tasks are retried with
+  /// exponential backoff until `queue.max_attempts`, then they are dead.
+  pub fn handle<T, F, Fut>(mut self, handler: F) -> Self
+  where
+    T: TaskPayload,
+    F: Fn(T, TaskContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+  {
+    let handler = Arc::new(handler);
+    self.handlers.insert(T::KIND.to_string(), Arc::new(move |payload, context| {
+      let handler = handler.clone();
+      Box::pin(async move {
+        let payload = match serde_json::from_value::<T>(payload) {
+          Ok(payload) => payload,
+          Err(err) => return TaskOutcome::Dead(format!("Invalid payload: {}", err)),
+        };
+        match handler(payload, context).await {
+          Ok(()) => TaskOutcome::Done,
+          Err(err) => TaskOutcome::Failed(format!("{:#}", err)),
+        }
+      })
+    }));
+    self
+  }
+
+  pub fn kinds(&self) -> Vec<String> {
+    self.handlers.keys().cloned().collect()
+  }
+
+  async fn process(&self, pool: &PgPool, config: &settings::Queue, mut task: Task) {
+    let last_attempt = task.attempts as u32 >= config.max_attempts;
+    let context = TaskContext { pool: pool.clone(), id: task.id, attempts: task.attempts, last_attempt };
+    let payload = task.payload.0.take();
+    let handled = async {
+      match self.handlers.get(&task.kind) {
+        // spawned so that a panicking handler only fails its task
+        Some(handler) => match tokio::spawn(handler(payload, context)).await {
+          Ok(outcome) => outcome,
+          Err(err) => TaskOutcome::Failed(format!("Task panicked: {}", err)),
+        },
+        None => TaskOutcome::Dead(format!("No handler for {}", task.kind)),
+      }
+    };
+    tokio::pin!(handled);
+    // the lease is renewed well before it runs out while the handler runs,
+    // so that only tasks of workers that died are taken over
+    let lease = chrono::Duration::seconds(config.lease_secs);
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(config.lease_secs.max(1) as u64) / 3);
+    heartbeat.tick().await;
+    let outcome = loop {
+      tokio::select! {
+        outcome = &mut handled => break outcome,
+        _ = heartbeat.tick() => match Task::extend(pool, task.id, task.attempts, lease).await {
+          Ok(true) => {}
+          Ok(false) => tracing::warn!("Task {} lost its lease while running", task.id),
+          Err(err) => tracing::error!("{:?}", err),
+        },
+      }
+    };
+    let result = match outcome {
+      TaskOutcome::Done => Task::complete(pool, task.id, task.attempts).await,
+      TaskOutcome::Failed(error) if (task.attempts as u32) < config.max_attempts => {
+        let delay = retry_delay(Duration::from_millis(config.retry_base_ms), task.attempts as u32);
+        tracing::debug!("Task {} failed, retrying in {:?}: {}", task.id, delay, error);
+        let retry_at = chrono::Duration::from_std(delay).ok().map(|delay| Utc::now() + delay);
+        Task::fail(pool, task.id, task.attempts, &error, retry_at).await
+      }
+      TaskOutcome::Failed(error) | TaskOutcome::Dead(error) => {
+        tracing::warn!("Task {} of kind {} is dead after {} attempts: {}", task.id, task.kind, task.attempts, error);
+        Task::fail(pool, task.id, task.attempts, &error, None).await
+      }
+    };
+    if let Err(err) = result {
+      tracing::error!("{:?}", err);
+    }
+  }
+
+  /// Runs tasks with up to `queue.concurrency` at a time until shutdown, then
+  /// waits for the tasks in progress. Running tasks keep renewing their
+  /// lease; tasks of a worker that died are taken over by other workers once
+  /// it runs out. Any number of
+  /// instances can work the same queue.
+  pub fn spawn(self: &Arc<Self>, pool: PgPool, config: settings::Queue, shutdown: CancellationToken) -> JoinHandle<()> {
+    let workers = self.clone();
+    let kinds = self.kinds();
+    let lease = chrono::Duration::seconds(config.lease_secs);
+    let slots = Arc::new(Semaphore::new(config.concurrency));
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(Duration::from_millis(config.poll_interval_ms));
+      interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+      while !pool.is_closed() {
+        tokio::select! {
+          _ = shutdown.cancelled() => break,
+          _ = interval.tick() => {}
+        }
+        let free = slots.available_permits();
+        if free == 0 || kinds.is_empty() {
+          continue;
+        }
+        let tasks = match Task::claim(&pool, &kinds, free as i64, lease, config.max_attempts as i32).await {
+          Ok(tasks) => tasks,
+          Err(err) => {
+            tracing::error!("{:?}", err);
+            continue;
+          }
+        };
+        for task in tasks {
+          let Ok(permit) = slots.clone().acquire_owned().await else {
+            break;
+          };
+          let (workers, pool) = (workers.clone(), pool.clone());
+          tokio::spawn(async move {
+            workers.process(&pool, &config, task).await;
+            drop(permit);
+          });
+        }
+      }
+      // all permits are back once the tasks in progress are done
+      let _ = slots.acquire_many(config.concurrency as u32).await;
+    })
+  }
+}
+
+/// Prunes dead tasks past their retention until shutdown.
+pub fn spawn_pruner(pool: PgPool, config: settings::Queue, shutdown: CancellationToken) -> JoinHandle<()> {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.prune_interval_secs));
+    while !pool.is_closed() {
+      tokio::select! {
+        _ = shutdown.cancelled() => break,
+        _ = interval.tick() => {}
+      }
+      match Task::prune(&pool, chrono::Duration::seconds(config.dead_retention_secs)).await {
+        Ok(0) => {}
+        Ok(pruned) => tracing::info!("Pruned {} dead tasks", pruned),
+        Err(err) => tracing::error!("{:?}", err),
+      }
+    }
+  })
+}
+
+impl fmt::Debug for Workers {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Workers").field("kinds", &self.kinds()).finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::retry_delay;
+
+  #[test]
+  fn should_back_off_exponentially() {
+    let base = Duration::from_secs(10);
+    assert_eq!(retry_delay(base, 1), Duration::from_secs(10));
+    assert_eq!(retry_delay(base, 2), Duration::from_secs(20));
+    assert_eq!(retry_delay(base, 4), Duration::from_secs(80));
+    assert_eq!(retry_delay(base, 40), Duration::from_secs(6 * 60 * 60));
+  }
+}
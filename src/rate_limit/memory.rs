@@ -0,0 +1,88 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::Result;
+
+use super::{Store, FAILURE_RETENTION_SECS};
+
+/// Entries are pruned once the map grows past this many keys.
+const PRUNE_AT: usize = 4096;
+
+#[derive(Debug, Default)]
+struct Entry {
+  count: u32,
+  window_ends: Option<DateTime<Utc>>,
+  failures: u32,
+  last_failure: Option<DateTime<Utc>>,
+  locked_until: Option<DateTime<Utc>>,
+}
+
+impl Entry {
+  fn is_stale(&self, now: DateTime<Utc>) -> bool {
+    self.window_ends.is_none_or(|ends| ends <= now)
+      && self.locked_until.is_none_or(|until| until <= now)
+      && self.last_failure.is_none_or(|last| last + Duration::seconds(FAILURE_RETENTION_SECS) <= now)
+  }
+}
+
+/// Keeps rate limits in process memory. Limits are not shared between
+/// instances and are lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+  entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+  async fn hit(&self, key: &str, window: Duration) -> Result<(u32, DateTime<Utc>)> {
+    let now = Utc::now();
+    let mut entries = self.entries.lock().unwrap();
+    if entries.len() > PRUNE_AT {
+      entries.retain(|_, entry| !entry.is_stale(now));
+    }
+    let entry = entries.entry(key.to_string()).or_default();
+    match entry.window_ends {
+      Some(ends) if ends > now => entry.count += 1,
+      _ => {
+        entry.count = 1;
+        entry.window_ends = Some(now + window);
+      }
+    }
+    Ok((entry.count, entry.window_ends.unwrap_or(now)))
+  }
+
+  async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>> {
+    let now = Utc::now();
+    let entries = self.entries.lock().unwrap();
+    Ok(entries.get(key).and_then(|entry| entry.locked_until).filter(|until| *until > now))
+  }
+
+  async fn record_failure(&self, key: &str, window: Duration) -> Result<u32> {
+    let now = Utc::now();
+    let mut entries = self.entries.lock().unwrap();
+    let entry = entries.entry(key.to_string()).or_default();
+    if entry.last_failure.is_some_and(|last| last + window <= now) {
+      entry.failures = 0;
+    }
+    entry.failures += 1;
+    entry.last_failure = Some(now);
+    Ok(entry.failures)
+  }
+
+  async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<()> {
+    let mut entries = self.entries.lock().unwrap();
+    entries.entry(key.to_string()).or_default().locked_until = Some(until);
+    Ok(())
+  }
+
+  async fn reset(&self, key: &str) -> Result<()> {
+    let mut entries = self.entries.lock().unwrap();
+    if let Some(entry) = entries.get_mut(key) {
+      entry.failures = 0;
+      entry.last_failure = None;
+      entry.locked_until = None;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,266 @@
+use std::{
+  fmt::Debug,
+  future::Future,
+  net::SocketAddr,
+  pin::Pin,
+  sync::Arc,
+  task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use axum::{
+  body::{self, Body, Empty},
+  extract::ConnectInfo,
+  response::Response,
+};
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::Result;
+use http::{header, request::Parts, HeaderMap, HeaderValue, Request, StatusCode};
+use sqlx::PgPool;
+use tower::{Layer, Service};
+
+use crate::model::user_token::hash_token;
+use crate::settings::{self, RateLimitStore};
+
+pub use self::memory::MemoryStore;
+pub use self::postgres::PostgresStore;
+
+pub(crate) mod memory;
+pub(crate) mod postgres;
+
+/// Largest request body the layer buffers while looking for the account.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// Failures are kept at most this long when pruning.
+const FAILURE_RETENTION_SECS: i64 = 24 * 60 * 60;
+
+/// Keeps the counters behind the rate limiter. Implementations must be safe
+/// to share between instances that limit the same keys.
+#[async_trait]
+pub trait Store: Debug + Send + Sync {
+  /// Counts a request for `key` in a fixed window and returns the count so
+  /// far along with the end of the window.
+  async fn hit(&self, key: &str, window: Duration) -> Result<(u32, DateTime<Utc>)>;
+  /// Returns when a lock on `key` ends, if it is locked.
+  async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>>;
+  /// Counts a failed attempt for `key` and returns the number of failures in
+  /// a row. Failures older than `window` are forgotten.
+  async fn record_failure(&self, key: &str, window: Duration) -> Result<u32>;
+  async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<()>;
+  /// Clears failures and locks after a successful attempt.
+  async fn reset(&self, key: &str) -> Result<()>;
+}
+
+/// Length of the lock after `failures` failures in a row. The lock doubles
+/// with every failure past the threshold, up to `max_secs`.
+pub fn lockout_duration(failures: u32, lockout: &settings::Lockout) -> Option<Duration> {
+  if failures < lockout.threshold {
+    return None;
+  }
+  let exponent = (failures - lockout.threshold).min(30);
+  let secs = lockout.base_secs.saturating_mul(1i64 << exponent).min(lockout.max_secs);
+  Some(Duration::seconds(secs))
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+  config: settings::RateLimit,
+  store: Arc<dyn Store>,
+}
+
+impl RateLimiter {
+  pub fn new(config: settings::RateLimit, store: Arc<dyn Store>) -> Self {
+    RateLimiter { config, store }
+  }
+
+  /// Builds the limiter with the store selected by `rate_limit.store`.
+  pub fn from_settings(config: &settings::RateLimit, pool: PgPool) -> Self {
+    let store: Arc<dyn Store> = match config.store {
+      RateLimitStore::Memory => Arc::new(MemoryStore::default()),
+      RateLimitStore::Postgres => Arc::new(PostgresStore::new(pool)),
+    };
+    RateLimiter::new(config.clone(), store)
+  }
+
+  /// Returns how long the client has to wait, if it is over a limit.
+  pub async fn check(&self, ip: &str, account: Option<&str>) -> Result<Option<Duration>> {
+    let now = Utc::now();
+    let (count, window_ends) = self.store.hit(&format!("ip:{}", ip), Duration::seconds(self.config.ip.window_secs)).await?;
+    if count > self.config.ip.max_requests {
+      return Ok(Some(window_ends - now));
+    }
+    if let Some(account) = account {
+      let key = format!("account:{}", account);
+      if let Some(until) = self.store.locked_until(&key).await? {
+        return Ok(Some(until - now));
+      }
+      let (count, window_ends) = self.store.hit(&key, Duration::seconds(self.config.account.window_secs)).await?;
+      if count > self.config.account.max_requests {
+        return Ok(Some(window_ends - now));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Updates failure tracking for the account from the response status.
+  pub async fn record_outcome(&self, account: &str, status: StatusCode) -> Result<()> {
+    let key = format!("account:{}", account);
+    if status == StatusCode::UNAUTHORIZED {
+      let failures = self.store.record_failure(&key, Duration::seconds(self.config.account.window_secs)).await?;
+      if let Some(duration) = lockout_duration(failures, &self.config.lockout) {
+        self.store.lock(&key, Utc::now() + duration).await?;
+      }
+    } else if status.is_success() {
+      self.store.reset(&key).await?;
+    }
+    Ok(())
+  }
+
+  fn client_ip(&self, parts: &Parts) -> String {
+    forwarded_ip(&parts.headers, self.config.trusted_proxies)
+      .or_else(|| parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip().to_string()))
+      .unwrap_or_else(|| "unknown".into())
+  }
+}
+
+/// The `X-Forwarded-For` entry added by the outermost of `trusted_proxies`.
+/// Entries left of it come from the client, which can put anything there.
+fn forwarded_ip(headers: &HeaderMap, trusted_proxies: usize) -> Option<String> {
+  if trusted_proxies == 0 {
+    return None;
+  }
+  let forwarded = headers.get_all("x-forwarded-for").iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .map(str::trim)
+    .collect::<Vec<_>>();
+  forwarded.iter().rev().nth(trusted_proxies - 1).map(|ip| ip.to_string())
+}
+
+/// The account a request is about: the `email` of a JSON body, or else the
+/// bearer token, so that MFA codes can not be guessed without limit either.
+fn account_key(parts: &Parts, body: &[u8]) -> Option<String> {
+  let email = serde_json::from_slice::<serde_json::Value>(body).ok()
+    .and_then(|value| value["email"].as_str().map(|email| email.trim().to_lowercase()));
+  email.or_else(|| {
+    crate::auth::bearer_token(&parts.headers).map(|token| format!("token:{}", hash_token(token)))
+  })
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+  let secs = (retry_after.num_milliseconds().max(0) + 999) / 1000;
+  Response::builder()
+    .status(StatusCode::TOO_MANY_REQUESTS)
+    .header(header::RETRY_AFTER, HeaderValue::from(secs.max(1)))
+    .body(body::boxed(Empty::new()))
+    .unwrap()
+}
+
+/// Layer limiting requests per client address and per account, locking out
+/// accounts with exponentially growing locks after repeated failures.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+  limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+  pub fn new(limiter: Arc<RateLimiter>) -> Self {
+    RateLimitLayer { limiter }
+  }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+  type Service = RateLimit<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    RateLimit { inner, limiter: self.limiter.clone() }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+  inner: S,
+  limiter: Arc<RateLimiter>,
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+  S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+  S::Future: Send,
+{
+  type Response = Response;
+  type Error = S::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<Body>) -> Self::Future {
+    // The clone may not be ready, so keep the service that was polled.
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    let limiter = self.limiter.clone();
+    Box::pin(async move {
+      if !limiter.config.enabled {
+        return inner.call(req).await;
+      }
+      let (parts, body) = req.into_parts();
+      let bytes = match hyper::body::to_bytes(http_body::Limited::new(body, MAX_BODY_BYTES)).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+          return Ok(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(body::boxed(Empty::new()))
+            .unwrap())
+        }
+      };
+      let ip = limiter.client_ip(&parts);
+      let account = account_key(&parts, &bytes);
+      match limiter.check(&ip, account.as_deref()).await {
+        Ok(Some(retry_after)) => return Ok(too_many_requests(retry_after)),
+        Ok(None) => {}
+        // Rather let requests through than lock everybody out.
+        Err(err) => tracing::error!("Rate limiter unavailable: {:?}", err),
+      }
+      let response = inner.call(Request::from_parts(parts, Body::from(bytes))).await?;
+      if let Some(account) = account {
+        if let Err(err) = limiter.record_outcome(&account, response.status()).await {
+          tracing::error!("Unable to record login outcome: {:?}", err);
+        }
+      }
+      Ok(response)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::Duration;
+
+  use axum::http::HeaderMap;
+
+  use super::{forwarded_ip, lockout_duration};
+  use crate::settings::Lockout;
+
+  #[test]
+  fn should_double_lockouts_up_to_max() {
+    let lockout = Lockout { threshold: 3, base_secs: 30, max_secs: 300 };
+    assert_eq!(lockout_duration(2, &lockout), None);
+    assert_eq!(lockout_duration(3, &lockout), Some(Duration::seconds(30)));
+    assert_eq!(lockout_duration(4, &lockout), Some(Duration::seconds(60)));
+    assert_eq!(lockout_duration(6, &lockout), Some(Duration::seconds(240)));
+    assert_eq!(lockout_duration(7, &lockout), Some(Duration::seconds(300)));
+    assert_eq!(lockout_duration(500, &lockout), Some(Duration::seconds(300)));
+  }
+
+  #[test]
+  fn should_only_trust_forwarded_entries_of_proxies() {
+    let mut headers = HeaderMap::new();
+    headers.append("x-forwarded-for", "1.1.1.1, 10.0.0.1".parse().unwrap());
+    headers.append("x-forwarded-for", "10.0.0.2".parse().unwrap());
+    assert_eq!(forwarded_ip(&headers, 0), None);
+    assert_eq!(forwarded_ip(&headers, 1).as_deref(), Some("10.0.0.2"));
+    assert_eq!(forwarded_ip(&headers, 2).as_deref(), Some("10.0.0.1"));
+    assert_eq!(forwarded_ip(&headers, 4), None);
+  }
+}
@@ -0,0 +1,98 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::{eyre::WrapErr, Result};
+use sqlx::{query, query_as, query_scalar, PgPool};
+
+use super::{Store, FAILURE_RETENTION_SECS};
+
+/// Expired entries are pruned once every this many requests counted.
+const PRUNE_EVERY: u64 = 1000;
+
+/// Keeps rate limits in Postgres so that every instance sees the same counts.
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+  pool: PgPool,
+  hits: Arc<AtomicU64>,
+}
+
+impl PostgresStore {
+  pub fn new(pool: PgPool) -> Self {
+    PostgresStore { pool, hits: Arc::default() }
+  }
+
+  /// Deletes windows that ended and failures past their retention that are
+  /// not locked, returning how many entries were deleted. Every key ever
+  /// seen would be kept otherwise, and clients pick the keys.
+  pub async fn prune(&self) -> Result<u64> {
+    let windows = query("delete from _rate_limits where window_ends <= now()")
+    .execute(&self.pool).await.context("Unable to prune rate limits")?;
+    let failures = query(
+      "delete from _auth_failures where updated_at <= now() - $1 * interval '1 second' \
+      and (locked_until is null or locked_until <= now())"
+    )
+    .bind(FAILURE_RETENTION_SECS as f64)
+    .execute(&self.pool).await.context("Unable to prune auth failures")?;
+    Ok(windows.rows_affected() + failures.rows_affected())
+  }
+}
+
+#[async_trait]
+impl Store for PostgresStore {
+  async fn hit(&self, key: &str, window: Duration) -> Result<(u32, DateTime<Utc>)> {
+    let (count, window_ends) = query_as::<_, (i32, DateTime<Utc>)>(
+      "insert into _rate_limits(key, count, window_ends) values($1, 1, $2) \
+      on conflict (key) do update set \
+        count = case when _rate_limits.window_ends <= now() then 1 else _rate_limits.count + 1 end, \
+        window_ends = case when _rate_limits.window_ends <= now() then excluded.window_ends else _rate_limits.window_ends end \
+      returning count, window_ends"
+    )
+    .bind(key)
+    .bind(Utc::now() + window)
+    .fetch_one(&self.pool).await.context("Unable to count request")?;
+    if self.hits.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1 {
+      if let Err(err) = self.prune().await {
+        tracing::error!("{:?}", err);
+      }
+    }
+    Ok((count as u32, window_ends))
+  }
+
+  async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>> {
+    let until = query_scalar::<_, DateTime<Utc>>(
+      "select locked_until from _auth_failures where key = $1 and locked_until > now()"
+    )
+    .bind(key)
+    .fetch_optional(&self.pool).await.context("Unable to read lock")?;
+    Ok(until)
+  }
+
+  async fn record_failure(&self, key: &str, window: Duration) -> Result<u32> {
+    let failures = query_scalar::<_, i32>(
+      "insert into _auth_failures(key, failures) values($1, 1) \
+      on conflict (key) do update set \
+        failures = case when _auth_failures.updated_at <= now() - $2 * interval '1 second' then 1 else _auth_failures.failures + 1 end, \
+        updated_at = now() \
+      returning failures"
+    )
+    .bind(key)
+    .bind(window.num_seconds() as f64)
+    .fetch_one(&self.pool).await.context("Unable to record failure")?;
+    Ok(failures as u32)
+  }
+
+  async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<()> {
+    query("update _auth_failures set locked_until = $2 where key = $1")
+    .bind(key).bind(until)
+    .execute(&self.pool).await.context("Unable to lock account")?;
+    Ok(())
+  }
+
+  async fn reset(&self, key: &str) -> Result<()> {
+    query("delete from _auth_failures where key = $1")
+    .bind(key)
+    .execute(&self.pool).await.context("Unable to reset failures")?;
+    Ok(())
+  }
+}
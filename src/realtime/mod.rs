@@ -0,0 +1,250 @@
+use std::{collections::{HashMap, HashSet}, str::FromStr, sync::Arc, time::Duration};
+
+use color_eyre::{eyre::{eyre, WrapErr}, Result};
+use serde::Deserialize;
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::{sync::{broadcast, watch, OnceCell}, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+pub mod protocol;
+
+use crate::{
+  auth::{can_access, Principal},
+  model::{collection::{Collection, Operation, CHANGES_CHANNEL, COLLECTIONS_CHANNEL}, Changes, Record},
+  settings,
+};
+
+pub use crate::model::change::{Action, ChangeEvent};
+
+/// Events buffered per subscriber before it starts lagging behind.
+pub const CAPACITY: usize = 256;
+
+/// Payload of the notifications sent by `_rocketbase_notify_change`.
+#[derive(Deserialize, Debug)]
+struct Notification {
+  seq: i64,
+  collection: String,
+  action: Action,
+  record: Option<Record>,
+}
+
+/// A collection, or a single record of it when written as `posts/12`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Topic {
+  pub collection: String,
+  pub id: Option<i64>,
+}
+
+impl FromStr for Topic {
+  type Err = String;
+
+  fn from_str(topic: &str) -> Result<Self, Self::Err> {
+    let (collection, id) = match topic.split_once('/') {
+      Some((collection, id)) => (collection, Some(id.parse().map_err(|_| format!("{} is not a record id", id))?)),
+      None => (topic, None),
+    };
+    if collection.is_empty() {
+      return Err(format!("{} is not a valid topic", topic));
+    }
+    Ok(Topic { collection: collection.to_string(), id })
+  }
+}
+
+/// The topics a client is subscribed to.
+#[derive(Debug, Default, Clone)]
+pub struct Subscriptions(HashSet<Topic>);
+
+impl Subscriptions {
+  /// Parses a comma separated list of topics.
+  pub fn parse(topics: &str) -> Result<Self, String> {
+    let topics = topics.split(',').map(str::trim).filter(|topic| !topic.is_empty());
+    Ok(Subscriptions(topics.map(Topic::from_str).collect::<Result<_, _>>()?))
+  }
+
+  pub fn add(&mut self, topic: Topic) {
+    self.0.insert(topic);
+  }
+
+  pub fn remove(&mut self, topic: &Topic) {
+    self.0.remove(topic);
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  pub fn matches(&self, event: &ChangeEvent) -> bool {
+    let id = event.record["id"].as_i64();
+    self.0.iter().any(|topic| topic.collection == event.collection && (topic.id.is_none() || topic.id == id))
+  }
+}
+
+/// Decides which events a subscriber may see, by the view rule of the
+/// collection or the scopes of an API key. Collection definitions are cached
+/// until any collection is saved, so that tightened rules apply to
+/// subscriptions that are already open.
+#[derive(Debug)]
+pub struct Access {
+  pool: PgPool,
+  principal: Option<Principal>,
+  collections: HashMap<String, Option<Collection>>,
+  saved: watch::Receiver<u64>,
+}
+
+impl Access {
+  pub fn set_principal(&mut self, principal: Option<Principal>) {
+    self.principal = principal;
+  }
+
+  pub async fn can_view(&mut self, event: &ChangeEvent) -> bool {
+    if self.saved.has_changed().unwrap_or(false) {
+      self.saved.borrow_and_update();
+      self.collections.clear();
+    }
+    if !self.collections.contains_key(&event.collection) {
+      let collection = match Collection::find(&self.pool, &event.collection).await {
+        Ok(collection) => collection,
+        Err(err) => {
+          tracing::error!("Unable to load collection {}: {:?}", event.collection, err);
+          return false;
+        }
+      };
+      self.collections.insert(event.collection.clone(), collection);
+    }
+    match &self.collections[&event.collection] {
+      Some(collection) => can_access(self.principal.as_ref(), collection, Operation::View, Some(&event.record)),
+      None => false,
+    }
+  }
+}
+
+/// Fans record changes out to subscribers. The hub listens for the change
+/// notifications of the collection triggers once the first client subscribes,
+/// and stops at shutdown so that its connection goes back to the pool.
+#[derive(Debug)]
+pub struct Hub {
+  pool: PgPool,
+  sender: broadcast::Sender<Arc<ChangeEvent>>,
+  /// Counts the collections saved, or possibly saved while the listener
+  /// was reconnecting.
+  saved: Arc<watch::Sender<u64>>,
+  listening: OnceCell<()>,
+  shutdown: CancellationToken,
+}
+
+impl Hub {
+  pub fn new(pool: PgPool, shutdown: CancellationToken) -> Self {
+    let (sender, _) = broadcast::channel(CAPACITY);
+    let (saved, _) = watch::channel(0);
+    Hub { pool, sender, saved: Arc::new(saved), listening: OnceCell::new(), shutdown }
+  }
+
+  /// Access checks for a subscriber, kept up to date with the collections.
+  pub fn access(&self, principal: Option<Principal>) -> Access {
+    Access { pool: self.pool.clone(), principal, collections: HashMap::new(), saved: self.saved.subscribe() }
+  }
+
+  /// Returns a receiver for every change made from now on.
+  #[instrument(skip(self))]
+  pub async fn subscribe(&self) -> Result<broadcast::Receiver<Arc<ChangeEvent>>> {
+    self.listening.get_or_try_init(|| async {
+      let mut listener = PgListener::connect_with(&self.pool).await.context("Unable to connect listener")?;
+      listener.listen_all([CHANGES_CHANNEL, COLLECTIONS_CHANNEL]).await.context("Unable to listen for changes")?;
+      tokio::spawn(Self::listen(self.pool.clone(), listener, self.sender.clone(), self.saved.clone(), self.shutdown.clone()));
+      Ok::<_, color_eyre::Report>(())
+    }).await?;
+    Ok(self.sender.subscribe())
+  }
+
+  /// How many receivers are subscribed right now.
+  pub fn subscribers(&self) -> usize {
+    self.sender.receiver_count()
+  }
+
+  async fn listen(
+    pool: PgPool,
+    mut listener: PgListener,
+    sender: broadcast::Sender<Arc<ChangeEvent>>,
+    saved: Arc<watch::Sender<u64>>,
+    shutdown: CancellationToken,
+  ) {
+    while !pool.is_closed() {
+      let received = tokio::select! {
+        _ = shutdown.cancelled() => break,
+        received = listener.recv() => received,
+      };
+      match received {
+        Ok(notification) if notification.channel() == COLLECTIONS_CHANNEL => saved.send_modify(|saved| *saved += 1),
+        Ok(notification) => match Self::event(&pool, notification.payload()).await {
+          // sending only fails while nobody is subscribed
+          Ok(event) => { let _ = sender.send(Arc::new(event)); }
+          Err(err) => tracing::error!("Unable to read change: {:?}", err),
+        },
+        Err(err) => {
+          tracing::error!("Change listener failed: {:?}", err);
+          // notifications are lost until it reconnects
+          saved.send_modify(|saved| *saved += 1);
+          tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+      }
+    }
+  }
+
+  /// Builds the event for a notification, reading changes that were too
+  /// large to be sent along from the change log.
+  async fn event(pool: &PgPool, payload: &str) -> Result<ChangeEvent> {
+    let notification: Notification = serde_json::from_str(payload).context("Invalid change notification")?;
+    match notification.record {
+      Some(record) => Ok(ChangeEvent { seq: notification.seq, action: notification.action, collection: notification.collection, record }),
+      None => Changes::find(pool, notification.seq).await?.ok_or_else(|| eyre!("Change {} was pruned", notification.seq)),
+    }
+  }
+}
+
+/// Prunes the change log of entries past their retention until shutdown.
+pub fn spawn_pruner(pool: PgPool, config: settings::Changes, shutdown: CancellationToken) -> JoinHandle<()> {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.prune_interval_secs));
+    while !pool.is_closed() {
+      tokio::select! {
+        _ = shutdown.cancelled() => break,
+        _ = interval.tick() => {}
+      }
+      match Changes::prune(&pool, chrono::Duration::seconds(config.retention_secs)).await {
+        Ok(0) => {}
+        Ok(pruned) => tracing::info!("Pruned {} changes", pruned),
+        Err(err) => tracing::error!("{:?}", err),
+      }
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::{Action, ChangeEvent, Subscriptions};
+
+  #[test]
+  fn should_match_collections_and_records() {
+    let subscriptions = Subscriptions::parse("posts/12, comments").unwrap();
+    let event = |collection: &str, id: i64| ChangeEvent {
+      seq: 1,
+      action: Action::Create,
+      collection: collection.into(),
+      record: json!({"id": id}),
+    };
+    assert!(subscriptions.matches(&event("posts", 12)));
+    assert!(!subscriptions.matches(&event("posts", 13)));
+    assert!(subscriptions.matches(&event("comments", 1)));
+    assert!(!subscriptions.matches(&event("users", 1)));
+    assert!(Subscriptions::parse("posts/abc").is_err());
+  }
+
+  #[test]
+  fn should_read_trigger_actions() {
+    assert_eq!(serde_json::from_str::<Action>("\"insert\"").unwrap(), Action::Create);
+    assert_eq!(serde_json::to_string(&Action::Create).unwrap(), "\"create\"");
+  }
+}
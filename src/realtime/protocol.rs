@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+use super::ChangeEvent;
+
+/// Close code sent to clients that can not keep up with their changes.
+pub const SLOW_CONSUMER: u16 = 4008;
+/// Close code sent to clients whose messages are not understood.
+pub const INVALID_MESSAGE: u16 = 4000;
+/// Close code sent to every client when the server shuts down.
+pub const GOING_AWAY: u16 = 1001;
+
+/// Messages clients send over the realtime WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+  /// Topics are written as in the SSE endpoint: `posts` or `posts/12`.
+  Subscribe { topics: Vec<String> },
+  Unsubscribe { topics: Vec<String> },
+  Ping,
+  /// Replaces the session of the connection, for example after the client
+  /// signed in or refreshed its token.
+  Auth { token: String },
+}
+
+/// Messages the server sends over the realtime WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+  Subscribed { topics: Vec<String> },
+  Unsubscribed { topics: Vec<String> },
+  Pong,
+  Authenticated,
+  Event(ChangeEvent),
+  Error { message: String },
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::{ClientMessage, ServerMessage};
+  use crate::realtime::{Action, ChangeEvent};
+
+  #[test]
+  fn should_tag_messages_by_type() {
+    let message: ClientMessage = serde_json::from_value(json!({"type": "subscribe", "topics": ["posts/1"]})).unwrap();
+    assert_eq!(message, ClientMessage::Subscribe { topics: vec!["posts/1".into()] });
+    let event = ServerMessage::Event(ChangeEvent { seq: 7, action: Action::Update, collection: "posts".into(), record: json!({"id": 1}) });
+    assert_eq!(
+      serde_json::to_value(event).unwrap(),
+      json!({"type": "event", "seq": 7, "action": "update", "collection": "posts", "record": {"id": 1}})
+    );
+  }
+}
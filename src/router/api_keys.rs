@@ -0,0 +1,50 @@
+use axum::{
+  extract::{Path, State},
+  http::StatusCode,
+  Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use super::auth::internal_error;
+use crate::{
+  app_state::AppState,
+  auth::AdminUser,
+  model::{api_key::NewApiKey, ApiKey},
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreatedApiKey {
+  /// The key itself. It is not stored and can not be shown again.
+  pub key: String,
+  pub api_key: ApiKey,
+}
+
+#[instrument(skip(state, admin))]
+pub async fn create_api_key_handler(
+  State(state): State<AppState>,
+  AdminUser(admin): AdminUser,
+  Json(payload): Json<NewApiKey>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), StatusCode> {
+  if payload.name.trim().is_empty() || payload.scopes.is_empty() {
+    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  let (api_key, key) = ApiKey::create(&state.db().connection(), &payload, admin.id)
+    .await
+    .map_err(internal_error)?;
+  Ok((StatusCode::CREATED, Json(CreatedApiKey { key, api_key })))
+}
+
+#[instrument(skip_all)]
+pub async fn list_api_keys_handler(State(state): State<AppState>, _admin: AdminUser) -> Result<Json<Vec<ApiKey>>, StatusCode> {
+  let keys = ApiKey::all(&state.db().connection()).await.map_err(internal_error)?;
+  Ok(Json(keys))
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn revoke_api_key_handler(State(state): State<AppState>, _admin: AdminUser, Path(id): Path<i64>) -> Result<StatusCode, StatusCode> {
+  match ApiKey::revoke(&state.db().connection(), id).await.map_err(internal_error)? {
+    true => Ok(StatusCode::NO_CONTENT),
+    false => Err(StatusCode::NOT_FOUND),
+  }
+}
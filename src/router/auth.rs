@@ -0,0 +1,150 @@
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+  app_state::AppState,
+  auth::{begin_login, AuthUser, LoginOutcome, MailToken},
+  hooks::{AuthAttempt, AuthEvent, AuthMethod, Rejection},
+  mailer,
+  model::{TokenKind, User, UserToken},
+};
+
+#[derive(Deserialize, Debug)]
+pub struct EmailRequest {
+  pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+  pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+  pub token: String,
+  pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+  pub email: String,
+  pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionResponse {
+  pub token: String,
+  pub user: User,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum LoginResponse {
+  Session(SessionResponse),
+  MfaRequired { mfa_token: String, enrollment_required: bool },
+}
+
+pub(crate) fn internal_error(err: color_eyre::Report) -> StatusCode {
+  tracing::error!("{:?}", err);
+  StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Builds the response for a user who passed their first factor.
+pub(crate) async fn login_response(state: &AppState, user: User) -> Result<LoginResponse, StatusCode> {
+  let user_id = user.id.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+  let response = match begin_login(&state.db().connection(), &state.settings().auth, user_id).await.map_err(internal_error)? {
+    LoginOutcome::Session(token) => LoginResponse::Session(SessionResponse { token, user }),
+    LoginOutcome::MfaRequired { mfa_token, enrollment_required } => LoginResponse::MfaRequired { mfa_token, enrollment_required },
+  };
+  Ok(response)
+}
+
+#[instrument(skip_all)]
+pub async fn login_handler(State(state): State<AppState>, Json(payload): Json<LoginRequest>) -> Result<Json<LoginResponse>, Rejection> {
+  let pool = state.db().connection();
+  let hooks = state.hooks();
+  let attempt = hooks.run_before_auth(AuthAttempt { method: AuthMethod::Password, email: Some(payload.email) }).await?;
+  let email = attempt.email.ok_or(StatusCode::UNAUTHORIZED)?;
+  let user = User::authenticate(&pool, &email, &payload.password)
+    .await
+    .map_err(internal_error)?
+    .ok_or(StatusCode::UNAUTHORIZED)?;
+  hooks.run_after_auth(AuthEvent { method: AuthMethod::Password, user: user.clone() }).await;
+  Ok(Json(login_response(&state, user).await?))
+}
+
+/// Issues a token of `kind` for the user and mails it using the matching template.
+pub(crate) async fn send_token_mail(state: &AppState, user: &User, kind: TokenKind) -> color_eyre::Result<()> {
+  let user_id = user.id.ok_or_else(|| color_eyre::eyre::eyre!("user has not been saved"))?;
+  let settings = &state.settings().mail;
+  let (template, ttl) = match kind {
+    TokenKind::Verification => (&settings.templates.verification, settings.verification_ttl_secs),
+    TokenKind::PasswordReset => (&settings.templates.password_reset, settings.password_reset_ttl_secs),
+    TokenKind::Session | TokenKind::MfaPending => return Err(color_eyre::eyre::eyre!("{} tokens are never mailed", kind.as_str())),
+  };
+  let token = UserToken::issue(&state.db().connection(), user_id, kind, Duration::seconds(ttl)).await?;
+  let message = mailer::render(template, &user.email, &[
+    ("name", &user.name),
+    ("email", &user.email),
+    ("token", &token),
+    ("app_url", &settings.app_url),
+  ]);
+  state.mailer().send(message).await
+}
+
+/// Queues a token of `kind` to be mailed if the address belongs to a user.
+/// The lookup and the mail happen in the background so that neither the
+/// response nor the time it takes tell whether the address is registered.
+async fn request_token(state: &AppState, email: &str, kind: TokenKind) -> Result<StatusCode, StatusCode> {
+  state.db().enqueue(&MailToken { email: email.to_string(), kind })
+    .await
+    .map_err(internal_error)?;
+  Ok(StatusCode::ACCEPTED)
+}
+
+#[instrument(skip(state))]
+pub async fn request_verification_handler(State(state): State<AppState>, Json(payload): Json<EmailRequest>) -> Result<StatusCode, StatusCode> {
+  request_token(&state, &payload.email, TokenKind::Verification).await
+}
+
+#[instrument(skip_all)]
+pub async fn verify_handler(State(state): State<AppState>, Json(payload): Json<TokenRequest>) -> Result<StatusCode, StatusCode> {
+  let pool = state.db().connection();
+  let user_id = UserToken::consume(&pool, &payload.token, TokenKind::Verification)
+    .await
+    .map_err(internal_error)?
+    .ok_or(StatusCode::BAD_REQUEST)?;
+  User::mark_verified(&pool, user_id).await.map_err(internal_error)?;
+  Ok(StatusCode::NO_CONTENT)
+}
+
+#[instrument(skip(state))]
+pub async fn request_password_reset_handler(State(state): State<AppState>, Json(payload): Json<EmailRequest>) -> Result<StatusCode, StatusCode> {
+  request_token(&state, &payload.email, TokenKind::PasswordReset).await
+}
+
+#[instrument(skip_all)]
+pub async fn reset_password_handler(State(state): State<AppState>, Json(payload): Json<ResetPasswordRequest>) -> Result<StatusCode, StatusCode> {
+  if payload.password.is_empty() {
+    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  let mut tx = state.db().connection().begin().await.map_err(|err| internal_error(err.into()))?;
+  let user_id = UserToken::consume(&mut tx, &payload.token, TokenKind::PasswordReset)
+    .await
+    .map_err(internal_error)?
+    .ok_or(StatusCode::BAD_REQUEST)?;
+  User::set_password(&mut tx, user_id, &payload.password).await.map_err(internal_error)?;
+  // whoever knew the old password or got hold of another link is locked out
+  let stale = [TokenKind::Session, TokenKind::MfaPending, TokenKind::PasswordReset];
+  UserToken::revoke_all(&mut tx, user_id, &stale).await.map_err(internal_error)?;
+  // Following the link proves ownership of the address as well.
+  User::mark_verified(&mut tx, user_id).await.map_err(internal_error)?;
+  tx.commit().await.map_err(|err| internal_error(err.into()))?;
+  Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn me_handler(AuthUser(user): AuthUser) -> Json<User> {
+  Json(user)
+}
@@ -0,0 +1,160 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+  body::StreamBody,
+  extract::{Query, State},
+  http::{header, HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+  Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+  sync::{broadcast::error::RecvError, mpsc},
+  time::timeout,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::instrument;
+
+use super::auth::internal_error;
+use crate::{
+  app_state::AppState,
+  auth::Principal,
+  model::{ChangeEvent, Changes},
+  realtime::Access,
+};
+
+pub const NDJSON: &str = "application/x-ndjson";
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+const DEFAULT_WAIT_SECS: u64 = 30;
+const MAX_WAIT_SECS: u64 = 60;
+/// How often a waiting reader reads the log again without a notification.
+/// Changes only become readable once every older transaction has finished,
+/// which may be after they were announced.
+const RECHECK: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug)]
+pub struct ChangesQuery {
+  /// Sequence number of the last change the client has seen.
+  #[serde(default)]
+  since: i64,
+  limit: Option<i64>,
+  /// Seconds to wait for changes when there are none yet.
+  wait: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChangesPage {
+  pub changes: Vec<ChangeEvent>,
+  /// Where to continue from. Changes the client may not view are skipped but
+  /// still advance this.
+  pub next: i64,
+}
+
+/// Reads the change log following `since`. Clients asking for NDJSON get a
+/// stream of every change from there on; anyone else gets a page, waiting
+/// for the first change when there are none yet.
+#[instrument(skip(state, principal, headers))]
+pub async fn changes_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  headers: HeaderMap,
+  Query(query): Query<ChangesQuery>,
+) -> Result<Response, StatusCode> {
+  let access = state.realtime().access(principal);
+  let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+  let streaming = headers
+    .get(header::ACCEPT)
+    .and_then(|accept| accept.to_str().ok())
+    .is_some_and(|accept| accept.contains(NDJSON));
+  if streaming {
+    return stream_changes(state, access, query.since, limit).await;
+  }
+  let wait = Duration::from_secs(query.wait.unwrap_or(DEFAULT_WAIT_SECS).min(MAX_WAIT_SECS));
+  let page = poll_changes(state, access, query.since, limit, wait).await?;
+  Ok(Json(page).into_response())
+}
+
+async fn poll_changes(state: AppState, mut access: Access, since: i64, limit: i64, wait: Duration) -> Result<ChangesPage, StatusCode> {
+  let pool = state.db().connection();
+  // subscribe first so that nothing committed after the read is missed
+  let mut notifications = state.realtime().subscribe().await.map_err(internal_error)?;
+  let mut changes = Changes::since(&pool, since, limit).await.map_err(internal_error)?;
+  if changes.is_empty() && !wait.is_zero() {
+    let shutdown = state.shutdown();
+    timeout(wait, async {
+      loop {
+        tokio::select! {
+          // answered right away so that the server can finish draining
+          _ = shutdown.started() => return Ok::<_, color_eyre::Report>(()),
+          notification = notifications.recv() => if let Err(RecvError::Closed) = notification {
+            std::future::pending::<()>().await
+          },
+          _ = tokio::time::sleep(RECHECK) => {},
+        }
+        changes = Changes::since(&pool, since, limit).await?;
+        if !changes.is_empty() {
+          return Ok(());
+        }
+      }
+    })
+    .await
+    .unwrap_or(Ok(()))
+    .map_err(internal_error)?;
+  }
+  let next = changes.last().map_or(since, |change| change.seq);
+  let mut visible = Vec::with_capacity(changes.len());
+  for change in changes {
+    if access.can_view(&change).await {
+      visible.push(change);
+    }
+  }
+  Ok(ChangesPage { changes: visible, next })
+}
+
+async fn stream_changes(state: AppState, mut access: Access, since: i64, limit: i64) -> Result<Response, StatusCode> {
+  let pool = state.db().connection();
+  let mut notifications = state.realtime().subscribe().await.map_err(internal_error)?;
+  let shutdown = state.shutdown();
+  let (sender, receiver) = mpsc::channel::<Result<String, Infallible>>(16);
+  tokio::spawn(async move {
+    let mut cursor = since;
+    loop {
+      let changes = match Changes::since(&pool, cursor, limit).await {
+        Ok(changes) => changes,
+        Err(err) => {
+          tracing::error!("{:?}", err);
+          break;
+        }
+      };
+      let caught_up = (changes.len() as i64) < limit;
+      for change in changes {
+        cursor = change.seq;
+        if !access.can_view(&change).await {
+          continue;
+        }
+        let line = match serde_json::to_string(&change) {
+          Ok(line) => line + "\n",
+          Err(err) => {
+            tracing::error!("Unable to encode change: {:?}", err);
+            continue;
+          }
+        };
+        if sender.send(Ok(line)).await.is_err() {
+          return;
+        }
+      }
+      if caught_up {
+        // the log is read again on any notification, which also recovers
+        // from lagging behind the hub
+        tokio::select! {
+          _ = sender.closed() => return,
+          _ = shutdown.started() => return,
+          notification = notifications.recv() => if let Err(RecvError::Closed) = notification { return },
+          _ = tokio::time::sleep(RECHECK) => {},
+        }
+      }
+    }
+  });
+  Ok(([(header::CONTENT_TYPE, NDJSON)], StreamBody::new(ReceiverStream::new(receiver))).into_response())
+}
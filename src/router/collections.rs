@@ -0,0 +1,73 @@
+use axum::{
+  extract::{Path, State},
+  http::StatusCode,
+  Json,
+};
+use tracing::instrument;
+
+use super::auth::internal_error;
+use crate::{
+  app_state::AppState,
+  auth::AdminUser,
+  hooks::SchemaChange,
+  model::collection::{Collection, CollectionError},
+};
+
+pub(crate) fn collection_error(err: CollectionError) -> StatusCode {
+  match err {
+    CollectionError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+    CollectionError::SqlxError(_) => internal_error(err.into()),
+  }
+}
+
+#[instrument(skip_all)]
+pub async fn list_collections_handler(State(state): State<AppState>, _admin: AdminUser) -> Result<Json<Vec<Collection>>, StatusCode> {
+  let collections = Collection::all(&state.db().connection()).await.map_err(collection_error)?;
+  Ok(Json(collections))
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn get_collection_handler(State(state): State<AppState>, _admin: AdminUser, Path(name): Path<String>) -> Result<Json<Collection>, StatusCode> {
+  let collection = Collection::find(&state.db().connection(), &name).await.map_err(collection_error)?;
+  collection.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Creates the table of the collection along with the trigger that feeds
+/// realtime subscriptions.
+#[instrument(skip(state, _admin))]
+pub async fn create_collection_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Json(collection): Json<Collection>,
+) -> Result<(StatusCode, Json<Collection>), StatusCode> {
+  collection.validate().map_err(collection_error)?;
+  let mut tx = state.db().connection().begin().await.map_err(|err| internal_error(err.into()))?;
+  if Collection::find(&mut tx, &collection.name).await.map_err(collection_error)?.is_some() {
+    return Err(StatusCode::CONFLICT);
+  }
+  collection.create_collection(&mut tx).await.map_err(collection_error)?;
+  collection.save(&mut tx).await.map_err(collection_error)?;
+  tx.commit().await.map_err(|err| internal_error(err.into()))?;
+  state.hooks().run_schema_change(SchemaChange { previous: None, collection: collection.clone() }).await;
+  Ok((StatusCode::CREATED, Json(collection)))
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn update_collection_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Path(name): Path<String>,
+  Json(collection): Json<Collection>,
+) -> Result<Json<Collection>, StatusCode> {
+  if collection.name != name {
+    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  collection.validate().map_err(collection_error)?;
+  let mut tx = state.db().connection().begin().await.map_err(|err| internal_error(err.into()))?;
+  let existing = Collection::find(&mut tx, &name).await.map_err(collection_error)?.ok_or(StatusCode::NOT_FOUND)?;
+  existing.update_collection(&mut tx, &collection).await.map_err(collection_error)?;
+  collection.save(&mut tx).await.map_err(collection_error)?;
+  tx.commit().await.map_err(|err| internal_error(err.into()))?;
+  state.hooks().run_schema_change(SchemaChange { previous: Some(existing), collection: collection.clone() }).await;
+  Ok(Json(collection))
+}
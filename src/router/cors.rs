@@ -0,0 +1,140 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+  body::Body,
+  extract::State,
+  http::{header::HeaderName, HeaderValue, Method, Request},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use color_eyre::{eyre::eyre, Result};
+use tower::{Layer, ServiceExt};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::settings;
+
+/// Whether `origin` is allowed by `pattern`, which may be `*` or have a `*.`
+/// standing for any subdomain, as in `https://*.example.com`.
+pub fn origin_matches(pattern: &str, origin: &str) -> bool {
+  if pattern == "*" || pattern.eq_ignore_ascii_case(origin) {
+    return true;
+  }
+  let Some((prefix, suffix)) = pattern.split_once("*.") else {
+    return false;
+  };
+  let origin = origin.to_ascii_lowercase();
+  let (prefix, suffix) = (prefix.to_ascii_lowercase(), suffix.to_ascii_lowercase());
+  match origin.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(&suffix)) {
+    Some(subdomain) => subdomain.ends_with('.') && subdomain.len() > 1 && !subdomain.contains([':', '/']),
+    None => false,
+  }
+}
+
+/// The layer for a policy, or none when it allows no origins.
+fn layer(policy: &settings::CorsPolicy) -> Result<Option<CorsLayer>> {
+  if policy.allowed_origins.is_empty() {
+    return Ok(None);
+  }
+  // every origin would be echoed back as allowed to send credentials along
+  if policy.allow_credentials && policy.allowed_origins.iter().any(|origin| origin == "*") {
+    return Err(eyre!("CORS can not allow credentials for any origin, list the allowed origins instead of *"));
+  }
+  let origins = policy.allowed_origins.clone();
+  // matched origins are echoed back, which also works with credentials
+  let mut layer = CorsLayer::new()
+    .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+      origin.to_str().is_ok_and(|origin| origins.iter().any(|pattern| origin_matches(pattern, origin)))
+    }))
+    .allow_credentials(policy.allow_credentials);
+  layer = match policy.allowed_methods.iter().any(|method| method == "*") {
+    true => layer.allow_methods(AllowMethods::mirror_request()),
+    false => {
+      let methods = policy.allowed_methods.iter()
+        .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()).map_err(|_| eyre!("Invalid CORS method {}", method)))
+        .collect::<Result<Vec<_>>>()?;
+      layer.allow_methods(methods)
+    }
+  };
+  layer = match policy.allowed_headers.iter().any(|header| header == "*") {
+    true => layer.allow_headers(AllowHeaders::mirror_request()),
+    false => {
+      let headers = policy.allowed_headers.iter()
+        .map(|header| HeaderName::from_bytes(header.as_bytes()).map_err(|_| eyre!("Invalid CORS header {}", header)))
+        .collect::<Result<Vec<_>>>()?;
+      layer.allow_headers(headers)
+    }
+  };
+  if let Some(max_age) = policy.max_age_secs {
+    layer = layer.max_age(Duration::from_secs(max_age));
+  }
+  Ok(Some(layer))
+}
+
+/// The CORS policies of the settings, picked by path.
+#[derive(Debug, Clone)]
+pub struct CorsPolicies {
+  default: Option<CorsLayer>,
+  /// Longest paths first.
+  routes: Vec<(String, Option<CorsLayer>)>,
+}
+
+impl CorsPolicies {
+  pub fn from_settings(settings: &settings::Cors) -> Result<Self> {
+    let mut routes = settings.routes.iter()
+      .map(|route| Ok((route.path.trim_end_matches('/').to_string(), layer(&route.policy)?)))
+      .collect::<Result<Vec<_>>>()?;
+    routes.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+    Ok(Self { default: layer(&settings.policy)?, routes })
+  }
+
+  fn for_path(&self, path: &str) -> Option<&CorsLayer> {
+    let route = self.routes.iter().find(|(prefix, _)| {
+      path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    });
+    match route {
+      Some((_, layer)) => layer.as_ref(),
+      None => self.default.as_ref(),
+    }
+  }
+}
+
+/// Answers preflight requests and adds CORS headers to responses, with the
+/// policy for the path of the request.
+pub async fn cors(State(policies): State<Arc<CorsPolicies>>, req: Request<Body>, next: Next<Body>) -> Response {
+  let Some(layer) = policies.for_path(req.uri().path()) else {
+    return next.run(req).await;
+  };
+  let mut next = Some(next);
+  let service = tower::service_fn(move |req| {
+    let next = next.take();
+    async move {
+      match next {
+        Some(next) => Ok::<_, Infallible>(next.run(req).await),
+        None => unreachable!("the service is called once"),
+      }
+    }
+  });
+  match layer.layer(service).oneshot(req).await {
+    Ok(response) => response.into_response(),
+    Err(err) => match err {},
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::origin_matches;
+
+  #[test]
+  fn should_match_origins_and_wildcard_subdomains() {
+    assert!(origin_matches("*", "https://anything.test"));
+    assert!(origin_matches("https://app.example.com", "https://app.example.com"));
+    assert!(!origin_matches("https://app.example.com", "http://app.example.com"));
+    assert!(origin_matches("https://*.example.com", "https://app.example.com"));
+    assert!(origin_matches("https://*.example.com", "https://a.b.Example.com"));
+    assert!(origin_matches("https://*.example.com:8443", "https://app.example.com:8443"));
+    assert!(!origin_matches("https://*.example.com", "https://example.com"));
+    assert!(!origin_matches("https://*.example.com", "https://evilexample.com"));
+    assert!(!origin_matches("https://*.example.com", "https://app.example.com.evil.test"));
+    assert!(!origin_matches("https://*.example.com", "http://app.example.com"));
+  }
+}
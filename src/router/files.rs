@@ -0,0 +1,392 @@
+use std::{collections::HashMap, ops::Range};
+
+use axum::{
+  async_trait,
+  body::Body,
+  extract::{FromRequest, Multipart, Path, Query, State},
+  http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+  response::{IntoResponse, Response},
+  Json,
+};
+use chrono::{DateTime, Utc};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::instrument;
+
+use super::{
+  auth::internal_error,
+  records::{collection, denied, record_error},
+};
+use crate::{
+  app_state::AppState,
+  auth::{can_access, Principal},
+  model::{
+    collection::{Collection, ColumnType, Operation},
+    file::{files_in, mime_allowed, FileMeta, Thumb},
+    Records,
+  },
+  storage::{thumbs, Storage},
+};
+
+/// Body of the record create and update endpoints: either JSON, or a
+/// multipart form whose file parts go to file columns.
+#[derive(Debug)]
+pub enum RecordInput {
+  Json(Map<String, Value>),
+  Multipart(Multipart),
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S, Body> for RecordInput {
+  type Rejection = Response;
+
+  async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+    let multipart = req
+      .headers()
+      .get(header::CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok())
+      .is_some_and(|value| value.starts_with("multipart/form-data"));
+    match multipart {
+      true => Multipart::from_request(req, state).await.map(Self::Multipart).map_err(IntoResponse::into_response),
+      false => Json::from_request(req, state).await.map(|Json(data)| Self::Json(data)).map_err(IntoResponse::into_response),
+    }
+  }
+}
+
+/// A file uploaded to a file column, not stored yet.
+#[derive(Debug)]
+pub struct Upload {
+  pub column: String,
+  pub meta: FileMeta,
+  pub bytes: Bytes,
+}
+
+/// Splits the input into record fields and uploads, enforcing the size and
+/// type limits of the file columns. Text parts of a form are taken as strings
+/// unless sent as `application/json`.
+pub async fn read_input(collection: &Collection, input: RecordInput) -> Result<(Map<String, Value>, Vec<Upload>), StatusCode> {
+  let (data, uploads) = match input {
+    RecordInput::Json(data) => (data, vec![]),
+    RecordInput::Multipart(multipart) => read_multipart(collection, multipart).await?,
+  };
+  // file columns only change through uploads, or are cleared with null
+  for cd in collection.file_columns() {
+    if data.get(&cd.name).is_some_and(|value| !value.is_null()) {
+      return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+  }
+  Ok((data, uploads))
+}
+
+async fn read_multipart(collection: &Collection, mut multipart: Multipart) -> Result<(Map<String, Value>, Vec<Upload>), StatusCode> {
+  let mut data = Map::new();
+  let mut uploads: Vec<Upload> = vec![];
+  while let Some(mut field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+    let Some(name) = field.name().map(String::from) else { continue };
+    let column = collection.column_defs.iter().find(|cd| cd.name == name);
+    let Some(file_name) = field.file_name().map(String::from) else {
+      let json = field.content_type().is_some_and(|mime| mime.starts_with("application/json"));
+      let text = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+      let value = match json {
+        true => serde_json::from_str(&text).map_err(|_| StatusCode::BAD_REQUEST)?,
+        false => Value::String(text),
+      };
+      data.insert(name, value);
+      continue;
+    };
+    let Some(ColumnType::File { max_size, mime_types, multiple, .. }) = column.map(|cd| &cd.column_type) else {
+      return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    };
+    // browsers send an empty part for file inputs left empty
+    if file_name.is_empty() {
+      continue;
+    }
+    if !multiple && uploads.iter().any(|upload| upload.column == name) {
+      return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let mime_type = field
+      .content_type()
+      .map(String::from)
+      .unwrap_or_else(|| mime_guess::from_path(&file_name).first_or_octet_stream().to_string());
+    if !mime_allowed(mime_types, &mime_type) {
+      return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+      if (bytes.len() + chunk.len()) as u64 > *max_size {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+      }
+      bytes.extend_from_slice(&chunk);
+    }
+    let meta = FileMeta::new(&file_name, bytes.len() as u64, &mime_type);
+    uploads.push(Upload { column: name, meta, bytes: Bytes::from(bytes) });
+  }
+  Ok((data, uploads))
+}
+
+/// Stores the uploads and sets their columns in `data`. Nothing is left in
+/// storage when this fails.
+pub async fn store_uploads(storage: &dyn Storage, collection: &Collection, uploads: Vec<Upload>, data: &mut Map<String, Value>) -> Result<(), StatusCode> {
+  let mut stored: Vec<FileMeta> = vec![];
+  let mut columns: HashMap<String, Vec<FileMeta>> = HashMap::new();
+  for upload in uploads {
+    let key = upload.meta.key(&collection.name);
+    if let Err(err) = storage.put(&key, upload.bytes, &upload.meta.mime_type).await {
+      tracing::error!("{:?}", err);
+      remove_files(storage, collection, &stored).await;
+      return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    stored.push(upload.meta.clone());
+    columns.entry(upload.column).or_default().push(upload.meta);
+  }
+  for cd in collection.file_columns() {
+    let (Some(files), ColumnType::File { multiple, .. }) = (columns.remove(&cd.name), &cd.column_type) else { continue };
+    let value = match multiple {
+      true => serde_json::to_value(files),
+      false => serde_json::to_value(&files[0]),
+    };
+    data.insert(cd.name.clone(), value.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
+  }
+  Ok(())
+}
+
+/// Deletes files and their thumbnails from storage. Failures are logged
+/// rather than returned, as they only leave unreferenced files behind.
+pub async fn remove_files(storage: &dyn Storage, collection: &Collection, files: &[FileMeta]) {
+  let thumbs: Vec<Thumb> = collection
+    .file_columns()
+    .flat_map(|cd| match &cd.column_type {
+      ColumnType::File { thumbs, .. } => thumbs.iter().filter_map(|thumb| thumb.parse().ok()).collect(),
+      _ => vec![],
+    })
+    .collect();
+  for file in files {
+    let keys = std::iter::once(file.key(&collection.name)).chain(thumbs.iter().map(|thumb| file.thumb_key(&collection.name, thumb)));
+    for key in keys {
+      if let Err(err) = storage.delete(&key).await {
+        tracing::error!("Unable to delete {}: {:?}", key, err);
+      }
+    }
+  }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DownloadQuery {
+  token: Option<String>,
+  #[serde(default)]
+  download: bool,
+  /// One of the thumbnail sizes of the file column.
+  thumb: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileToken {
+  token: String,
+  url: String,
+  expires_at: DateTime<Utc>,
+}
+
+fn file_path(collection: &str, id: i64, name: &str) -> String {
+  format!("{}/{}/{}", collection, id, name)
+}
+
+/// Finds a file of a record along with the thumbnail sizes of its column,
+/// checking that `principal` may view the record unless a valid token is
+/// given.
+async fn find_file(state: &AppState, principal: Option<&Principal>, (name, id, file_name): (&str, i64, &str), token: Option<&str>) -> Result<(Collection, FileMeta, Vec<String>), StatusCode> {
+  let collection = collection(state, name).await?;
+  let record = Records::find(&state.db().connection(), &collection, id)
+    .await
+    .map_err(record_error)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  let signed = token.is_some_and(|token| state.file_signer().verify(&file_path(name, id, file_name), token, Utc::now()));
+  if !signed && !can_access(principal, &collection, Operation::View, Some(&record)) {
+    return Err(denied(principal));
+  }
+  let (file, thumbs) = collection
+    .file_columns()
+    .find_map(|cd| {
+      let file = files_in(&record[&cd.name]).into_iter().find(|file| file.name == file_name)?;
+      match &cd.column_type {
+        ColumnType::File { thumbs, .. } => Some((file, thumbs.clone())),
+        _ => None,
+      }
+    })
+    .ok_or(StatusCode::NOT_FOUND)?;
+  Ok((collection, file, thumbs))
+}
+
+/// Returns a thumbnail of an image file, made and stored on first use, along
+/// with its type. `None` means the file is not an image thumbnails are made of.
+async fn thumbnail(state: &AppState, collection: &Collection, file: &FileMeta, thumb: &Thumb) -> Result<Option<(Bytes, &'static str)>, StatusCode> {
+  let mime_type = mime_guess::from_path(&file.name).first_or_octet_stream();
+  if !thumbs::THUMB_TYPES.contains(&mime_type.essence_str()) {
+    return Ok(None);
+  }
+  let key = file.thumb_key(&collection.name, thumb);
+  if state.thumb_renders().failed(&key) {
+    return Ok(None);
+  }
+  let bytes = match state.storage().get(&key).await.map_err(internal_error)? {
+    Some(bytes) => bytes,
+    None => match render_thumbnail(state, collection, file, thumb, &key).await? {
+      Some(bytes) => bytes,
+      None => return Ok(None),
+    },
+  };
+  let thumb_type = image::guess_format(&bytes).map_err(|err| internal_error(err.into()))?.to_mime_type();
+  Ok(Some((bytes, thumb_type)))
+}
+
+/// Renders a thumbnail and stores it at `key`, unless another request did
+/// while this one waited for its turn.
+async fn render_thumbnail(state: &AppState, collection: &Collection, file: &FileMeta, thumb: &Thumb, key: &str) -> Result<Option<Bytes>, StatusCode> {
+  let (storage, renders) = (state.storage(), state.thumb_renders());
+  let _render = renders.start(key).await;
+  if renders.failed(key) {
+    return Ok(None);
+  }
+  if let Some(bytes) = storage.get(key).await.map_err(internal_error)? {
+    return Ok(Some(bytes));
+  }
+  let original = storage.get(&file.key(&collection.name)).await.map_err(internal_error)?.ok_or(StatusCode::NOT_FOUND)?;
+  let thumb = *thumb;
+  let rendered = tokio::task::spawn_blocking(move || thumbs::render(&original, &thumb))
+    .await
+    .map_err(|err| internal_error(err.into()))?
+    .map_err(internal_error)?;
+  let Some(rendered) = rendered else {
+    renders.fail(key);
+    return Ok(None);
+  };
+  let bytes = Bytes::from(rendered);
+  let mime_type = mime_guess::from_path(&file.name).first_or_octet_stream();
+  // a thumbnail that could not be cached is still worth serving
+  if let Err(err) = storage.put(key, bytes.clone(), mime_type.essence_str()).await {
+    tracing::error!("Unable to store thumbnail {}: {:?}", key, err);
+  }
+  Ok(Some(bytes))
+}
+
+/// Parses a `Range` header holding a single byte range. `Ok(None)` means the
+/// whole file is served, either because no range was asked for or because the
+/// header is not understood, as RFC 9110 allows.
+fn parse_range(headers: &HeaderMap, size: u64) -> Result<Option<Range<u64>>, ()> {
+  let Some(spec) = headers
+    .get(header::RANGE)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.trim().strip_prefix("bytes="))
+  else {
+    return Ok(None);
+  };
+  let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else { return Ok(None) };
+  let (start, end) = (start.trim(), end.trim());
+  let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+    // the last `end` bytes
+    (Err(_), Ok(suffix)) if start.is_empty() => size.saturating_sub(suffix)..size,
+    (Ok(start), Err(_)) if end.is_empty() => start..size,
+    (Ok(start), Ok(end)) if start <= end => start..size.min(end + 1),
+    _ => return Ok(None),
+  };
+  match range.start < range.end {
+    true => Ok(Some(range)),
+    false => Err(()),
+  }
+}
+
+/// Serves a file of a record to whoever may view the record, or holds a token
+/// for it. Single byte ranges are supported. Images can be asked for in one of
+/// the thumbnail sizes of their column, other files are served as they are.
+#[instrument(skip(state, principal, headers))]
+pub async fn download_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Path((name, id, file_name)): Path<(String, i64, String)>,
+  Query(query): Query<DownloadQuery>,
+  headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+  let (collection, file, thumbs) = find_file(&state, principal.as_ref(), (&name, id, &file_name), query.token.as_deref()).await?;
+  let thumbnail = match query.thumb {
+    Some(spec) => {
+      // only the listed sizes, so that clients can not fill storage with variants
+      let thumb = spec.parse::<Thumb>().map_err(|_| StatusCode::BAD_REQUEST)?;
+      if !thumbs.iter().any(|allowed| allowed.parse() == Ok(thumb)) {
+        return Err(StatusCode::BAD_REQUEST);
+      }
+      thumbnail(&state, &collection, &file, &thumb).await?
+    }
+    None => None,
+  };
+  let size = match &thumbnail {
+    Some((bytes, _)) => bytes.len() as u64,
+    None => file.size,
+  };
+  let range = match parse_range(&headers, size) {
+    Ok(range) => range,
+    Err(()) => {
+      let content_range = format!("bytes */{}", size);
+      return Ok((StatusCode::RANGE_NOT_SATISFIABLE, [(header::CONTENT_RANGE, content_range)]).into_response());
+    }
+  };
+  let (body, mime_type) = match thumbnail {
+    Some((bytes, thumb_type)) => {
+      let bytes = match &range {
+        Some(range) => bytes.slice(range.start as usize..range.end as usize),
+        None => bytes,
+      };
+      (Body::from(bytes), thumb_type.to_string())
+    }
+    None => {
+      let body = state
+        .storage()
+        .read(&file.key(&collection.name), range.clone())
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+      // the type is derived from the name, so uploads can not pick one a browser would run
+      (body, mime_guess::from_path(&file.name).first_or_octet_stream().to_string())
+    }
+  };
+  let disposition = match query.download {
+    true => "attachment",
+    false => "inline",
+  };
+  let original_name: String = file.original_name.chars().filter(|c| c.is_ascii_graphic() && !matches!(c, '"' | '\\') || *c == ' ').collect();
+  let mut response = Response::new(axum::body::boxed(body));
+  let response_headers = response.headers_mut();
+  let mut insert = |name, value: String| {
+    if let Ok(value) = HeaderValue::from_str(&value) {
+      response_headers.insert(name, value);
+    }
+  };
+  insert(header::CONTENT_TYPE, mime_type);
+  insert(header::CONTENT_DISPOSITION, format!("{}; filename=\"{}\"", disposition, original_name));
+  insert(header::ACCEPT_RANGES, "bytes".to_string());
+  insert(header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string());
+  insert(header::CONTENT_SECURITY_POLICY, "sandbox".to_string());
+  match range {
+    Some(range) => {
+      insert(header::CONTENT_LENGTH, (range.end - range.start).to_string());
+      insert(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, size));
+      *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
+    None => insert(header::CONTENT_LENGTH, size.to_string()),
+  }
+  Ok(response)
+}
+
+/// Issues a short-lived token for a file, for use where credentials can not
+/// be sent, such as `<img>` tags.
+#[instrument(skip(state, principal))]
+pub async fn file_token_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Path((name, id, file_name)): Path<(String, i64, String)>,
+) -> Result<Json<FileToken>, StatusCode> {
+  find_file(&state, principal.as_ref(), (&name, id, &file_name), None).await?;
+  let path = file_path(&name, id, &file_name);
+  let (token, expires_at) = state.file_signer().sign(&path, Utc::now());
+  let url = format!("/api/files/{}?token={}", path, token);
+  Ok(Json(FileToken { token, url, expires_at }))
+}
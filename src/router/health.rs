@@ -0,0 +1,88 @@
+use std::{future::Future, time::{Duration, Instant}};
+
+use axum::{extract::State, http::StatusCode, Json};
+use color_eyre::{eyre::eyre, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::app_state::AppState;
+
+/// How long a readiness check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+  /// `ok` or `failed`.
+  pub status: &'static str,
+  pub latency_ms: f64,
+  /// Says that a check failed without telling why, as anyone may ask. The
+  /// reason is logged.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<&'static str>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+  /// `ok`, `failed`, or `shutting_down` once shutdown started.
+  pub status: &'static str,
+  pub database: Check,
+  pub migrations: Check,
+  pub workers: Check,
+}
+
+async fn check<F: Future<Output = Result<()>>>(name: &str, check: F) -> Check {
+  let start = Instant::now();
+  let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+    Ok(result) => result,
+    Err(_) => Err(eyre!("Timed out after {:?}", CHECK_TIMEOUT)),
+  };
+  let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+  match result {
+    Ok(()) => Check { status: "ok", latency_ms, error: None },
+    Err(err) => {
+      tracing::warn!("Readiness check of the {} failed: {:?}", name, err);
+      Check { status: "failed", latency_ms, error: Some("Check failed, see the server log") }
+    }
+  }
+}
+
+/// Answers as long as the process serves requests.
+pub async fn healthz_handler() -> Json<Value> {
+  Json(json!({"status": "ok"}))
+}
+
+/// Whether the instance can take traffic: the database answers, its
+/// migrations are applied and no background task stopped. Not ready once
+/// shutdown started, so that traffic moves elsewhere while it drains.
+pub async fn readyz_handler(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+  let db = state.db();
+  let database = check("database", async {
+    sqlx::query("select 1").execute(&db.connection()).await?;
+    Ok(())
+  });
+  let migrations = check("migrations", async {
+    let pending = db.migrations().await?.into_iter().filter(|migration| migration.applied_at.is_none()).count();
+    match pending {
+      0 => Ok(()),
+      pending => Err(eyre!("{} migrations are pending", pending)),
+    }
+  });
+  let workers = check("workers", async {
+    match state.shutdown().stopped() {
+      0 => Ok(()),
+      stopped => Err(eyre!("{} background tasks stopped", stopped)),
+    }
+  });
+  let (database, migrations, workers) = tokio::join!(database, migrations, workers);
+  let failed = [&database, &migrations, &workers].iter().any(|check| check.error.is_some());
+  let status = match (state.shutdown().is_started(), failed) {
+    (true, _) => "shutting_down",
+    (false, true) => "failed",
+    (false, false) => "ok",
+  };
+  let code = match status {
+    "ok" => StatusCode::OK,
+    _ => StatusCode::SERVICE_UNAVAILABLE,
+  };
+  (code, Json(Readiness { status, database, migrations, workers }))
+}
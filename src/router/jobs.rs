@@ -0,0 +1,120 @@
+use axum::{
+  extract::{Path, Query, State},
+  http::StatusCode,
+  Json,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use tracing::instrument;
+
+use super::auth::internal_error;
+use crate::{
+  app_state::AppState,
+  auth::AdminUser,
+  model::{
+    job::{JobUpdate, NewJob},
+    Job, JobRun,
+  },
+  scheduler::{next_run, parse_schedule},
+};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct RunPage {
+  limit: Option<i64>,
+}
+
+fn valid_name(name: &str) -> bool {
+  !name.is_empty() && name.len() <= 100 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn create_job_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Json(payload): Json<NewJob>,
+) -> Result<(StatusCode, Json<Job>), StatusCode> {
+  if !valid_name(&payload.name) || parse_schedule(&payload.schedule).is_err() || !state.scheduler().has_handler(&payload.handler) {
+    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  let next_run_at = next_run(&payload.schedule, Utc::now());
+  let job = Job::create(&state.db().connection(), &payload, next_run_at).await.map_err(internal_error)?;
+  job.map(|job| (StatusCode::CREATED, Json(job))).ok_or(StatusCode::CONFLICT)
+}
+
+#[instrument(skip_all)]
+pub async fn list_jobs_handler(State(state): State<AppState>, _admin: AdminUser) -> Result<Json<Vec<Job>>, StatusCode> {
+  let jobs = Job::all(&state.db().connection()).await.map_err(internal_error)?;
+  Ok(Json(jobs))
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn get_job_handler(State(state): State<AppState>, _admin: AdminUser, Path(name): Path<String>) -> Result<Json<Job>, StatusCode> {
+  let job = Job::find(&state.db().connection(), &name).await.map_err(internal_error)?;
+  job.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Changes a job, scheduling its next run from now on.
+#[instrument(skip(state, _admin))]
+pub async fn update_job_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Path(name): Path<String>,
+  Json(payload): Json<JobUpdate>,
+) -> Result<Json<Job>, StatusCode> {
+  if payload.schedule.as_deref().is_some_and(|schedule| parse_schedule(schedule).is_err())
+    || payload.handler.as_deref().is_some_and(|handler| !state.scheduler().has_handler(handler)) {
+    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  let pool = state.db().connection();
+  let job = Job::find(&pool, &name).await.map_err(internal_error)?.ok_or(StatusCode::NOT_FOUND)?;
+  let next_run_at = next_run(payload.schedule.as_deref().unwrap_or(&job.schedule), Utc::now());
+  let job = Job::update(&pool, &name, &payload, next_run_at).await.map_err(internal_error)?;
+  job.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn delete_job_handler(State(state): State<AppState>, _admin: AdminUser, Path(name): Path<String>) -> Result<StatusCode, StatusCode> {
+  match Job::delete(&state.db().connection(), &name).await.map_err(internal_error)? {
+    true => Ok(StatusCode::NO_CONTENT),
+    false => Err(StatusCode::NOT_FOUND),
+  }
+}
+
+/// The run history of a job, newest first.
+#[instrument(skip(state, _admin))]
+pub async fn list_runs_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Path(name): Path<String>,
+  Query(page): Query<RunPage>,
+) -> Result<Json<Vec<JobRun>>, StatusCode> {
+  let pool = state.db().connection();
+  Job::find(&pool, &name).await.map_err(internal_error)?.ok_or(StatusCode::NOT_FOUND)?;
+  let limit = page.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+  let runs = JobRun::for_job(&pool, &name, limit).await.map_err(internal_error)?;
+  Ok(Json(runs))
+}
+
+/// Runs a job right away, leaving its schedule as it is. Answers with
+/// `202 Accepted` and the started run, whose outcome shows in the runs of the
+/// job once it is over, or with `409 Conflict` when the job is already
+/// running.
+#[instrument(skip(state, _admin))]
+pub async fn run_job_handler(State(state): State<AppState>, _admin: AdminUser, Path(name): Path<String>) -> Result<(StatusCode, Json<JobRun>), StatusCode> {
+  let pool = state.db().connection();
+  let job = Job::find(&pool, &name).await.map_err(internal_error)?.ok_or(StatusCode::NOT_FOUND)?;
+  let (scheduler, config) = (state.scheduler(), state.settings().scheduler);
+  let started = scheduler.start(&pool, job, false, &config).await.map_err(internal_error)?.ok_or(StatusCode::CONFLICT)?;
+  let run = started.run().clone();
+  // carried on apart from the request, so that a client going away does not
+  // leave the run behind
+  state.shutdown().track(tokio::spawn(async move {
+    if let Err(err) = scheduler.finish(&pool, started, &config).await {
+      tracing::error!("{:?}", err);
+    }
+  }));
+  Ok((StatusCode::ACCEPTED, Json(run)))
+}
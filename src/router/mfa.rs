@@ -0,0 +1,131 @@
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use super::auth::{internal_error, SessionResponse};
+use crate::{
+  app_state::AppState,
+  auth::{issue_session, totp, AuthUser, MfaUser},
+  model::{Mfa, RecoveryCode, TokenKind, User, UserToken},
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EnrollResponse {
+  pub secret: String,
+  pub otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+  pub code: Option<String>,
+  pub recovery_code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfirmResponse {
+  pub recovery_codes: Vec<String>,
+  /// Session token, when enrollment finished a login that required MFA.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub token: Option<String>,
+}
+
+fn now_secs() -> u64 {
+  Utc::now().timestamp() as u64
+}
+
+/// Checks a TOTP code against the enrollment, remembering the step it was
+/// generated for so that it can not be used twice.
+async fn check_code(state: &AppState, mfa: &Mfa, code: &str) -> Result<(), StatusCode> {
+  let last = mfa.last_used_step.map(|step| step as u64);
+  let step = totp::verify(&mfa.secret, code, now_secs(), last).ok_or(StatusCode::UNAUTHORIZED)?;
+  match Mfa::record_step(&state.db().connection(), mfa.user_id, step as i64).await.map_err(internal_error)? {
+    true => Ok(()),
+    false => Err(StatusCode::UNAUTHORIZED),
+  }
+}
+
+#[instrument(skip_all)]
+pub async fn enroll_handler(State(state): State<AppState>, auth: MfaUser) -> Result<Json<EnrollResponse>, StatusCode> {
+  let pool = state.db().connection();
+  let user_id = auth.user.id.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+  if let Some(mfa) = Mfa::find(&pool, user_id).await.map_err(internal_error)? {
+    if mfa.enabled {
+      return Err(StatusCode::CONFLICT);
+    }
+  }
+  let secret = totp::generate_secret();
+  Mfa::start_enrollment(&pool, user_id, &secret).await.map_err(internal_error)?;
+  let otpauth_uri = totp::otpauth_uri(&state.settings().auth.mfa.issuer, &auth.user.email, &secret);
+  Ok(Json(EnrollResponse { secret, otpauth_uri }))
+}
+
+#[instrument(skip_all)]
+pub async fn confirm_handler(State(state): State<AppState>, auth: MfaUser, Json(payload): Json<CodeRequest>) -> Result<Json<ConfirmResponse>, StatusCode> {
+  let pool = state.db().connection();
+  let user_id = auth.user.id.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+  let mfa = Mfa::find(&pool, user_id).await.map_err(internal_error)?.ok_or(StatusCode::BAD_REQUEST)?;
+  if mfa.enabled {
+    return Err(StatusCode::CONFLICT);
+  }
+  check_code(&state, &mfa, payload.code.as_deref().ok_or(StatusCode::BAD_REQUEST)?).await?;
+
+  let mut tx = pool.begin().await.map_err(|err| internal_error(err.into()))?;
+  Mfa::enable(&mut tx, user_id).await.map_err(internal_error)?;
+  let recovery_codes = RecoveryCode::regenerate(&mut tx, user_id).await.map_err(internal_error)?;
+  let token = match auth.pending_token {
+    Some(pending) => {
+      UserToken::consume(&mut tx, &pending, TokenKind::MfaPending)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+      Some(issue_session(&mut tx, &state.settings().auth, user_id).await.map_err(internal_error)?)
+    }
+    None => None,
+  };
+  tx.commit().await.map_err(|err| internal_error(err.into()))?;
+  Ok(Json(ConfirmResponse { recovery_codes, token }))
+}
+
+#[instrument(skip_all)]
+pub async fn verify_handler(State(state): State<AppState>, auth: MfaUser, Json(payload): Json<CodeRequest>) -> Result<Json<SessionResponse>, StatusCode> {
+  let pending = auth.pending_token.ok_or(StatusCode::BAD_REQUEST)?;
+  let pool = state.db().connection();
+  let user_id = auth.user.id.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+  let mfa = match Mfa::find(&pool, user_id).await.map_err(internal_error)? {
+    Some(mfa) if mfa.enabled => mfa,
+    _ => return Err(StatusCode::BAD_REQUEST),
+  };
+  match (payload.code, payload.recovery_code) {
+    (Some(code), _) => check_code(&state, &mfa, &code).await?,
+    (None, Some(recovery_code)) => {
+      if !RecoveryCode::consume(&pool, user_id, &recovery_code).await.map_err(internal_error)? {
+        return Err(StatusCode::UNAUTHORIZED);
+      }
+    }
+    (None, None) => return Err(StatusCode::BAD_REQUEST),
+  }
+  let mut tx = pool.begin().await.map_err(|err| internal_error(err.into()))?;
+  UserToken::consume(&mut tx, &pending, TokenKind::MfaPending)
+    .await
+    .map_err(internal_error)?
+    .ok_or(StatusCode::UNAUTHORIZED)?;
+  let token = issue_session(&mut tx, &state.settings().auth, user_id).await.map_err(internal_error)?;
+  tx.commit().await.map_err(|err| internal_error(err.into()))?;
+  Ok(Json(SessionResponse { token, user: auth.user }))
+}
+
+#[instrument(skip_all)]
+pub async fn disable_handler(State(state): State<AppState>, AuthUser(user): AuthUser, Json(payload): Json<CodeRequest>) -> Result<StatusCode, StatusCode> {
+  let pool = state.db().connection();
+  let user_id = user.id.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+  if state.settings().auth.mfa.required_for_admins && User::is_admin(&pool, user_id).await.map_err(internal_error)? {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let mfa = Mfa::find(&pool, user_id).await.map_err(internal_error)?.ok_or(StatusCode::NOT_FOUND)?;
+  if mfa.enabled {
+    check_code(&state, &mfa, payload.code.as_deref().ok_or(StatusCode::BAD_REQUEST)?).await?;
+  }
+  Mfa::delete(&pool, user_id).await.map_err(internal_error)?;
+  Ok(StatusCode::NO_CONTENT)
+}
@@ -1,21 +1,39 @@
+use std::sync::Arc;
+
 use color_eyre::Result;
 use axum::{
     body::Body,
     http::{Request, StatusCode},
     Json,
+    middleware,
     response::{Response, IntoResponse},
     Router, 
-    routing::{get, post}, extract::State
+    routing::{delete, get, patch, post}, extract::{DefaultBodyLimit, State}
 };
+use serde::Deserialize;
 use tower::ServiceBuilder;
 use tower_http::trace::TraceLayer;
 use tracing::instrument;
 
-use crate::{app_state::AppState, model::User};
+use crate::{app_state::AppState, auth::authenticate, metrics, telemetry, model::{TokenKind, User}, rate_limit::RateLimitLayer};
 
-use self::static_files::static_path;
+use self::{auth::internal_error, cors::CorsPolicies, static_files::static_path};
 
+pub mod api_keys;
+pub(crate) mod auth;
+pub mod changes;
+pub mod collections;
+pub mod cors;
+pub mod files;
+pub mod health;
+pub mod jobs;
+pub mod mfa;
+pub mod oauth;
+pub mod realtime;
+pub mod records;
 pub(crate) mod static_files;
+pub mod tasks;
+pub mod webhooks;
 
 async fn home_handler() -> String {
     String::from("Hello server\n")
@@ -32,32 +50,124 @@ async fn users_handler(State(state): State<AppState>, req: Request<Body>) -> Res
     Ok(Json(users))
 }
 
-#[instrument]
-async fn create_user_handler(State(state): State<AppState>, Json(mut payload): Json<User>) -> Result<Response, StatusCode> {
-    payload.insert(&state.db().connection())
-        .await
-        .map_err(|err| {
-            tracing::error!("{:?}", err);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+#[derive(Deserialize)]
+pub struct NewUser {
+    #[serde(flatten)]
+    user: User,
+    password: Option<String>,
+}
+
+#[instrument(skip_all)]
+async fn create_user_handler(State(state): State<AppState>, Json(payload): Json<NewUser>) -> Result<Response, StatusCode> {
+    let NewUser { mut user, password } = payload;
+    if password.as_deref() == Some("") {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let mut tx = state.db().connection().begin().await.map_err(|err| internal_error(err.into()))?;
+    user.insert(&mut tx).await.map_err(internal_error)?;
+    if let (Some(id), Some(password)) = (user.id, password) {
+        User::set_password(&mut tx, id, &password).await.map_err(internal_error)?;
+    }
+    tx.commit().await.map_err(|err| internal_error(err.into()))?;
+    if let Err(err) = auth::send_token_mail(&state, &user, TokenKind::Verification).await {
+        tracing::error!("Unable to send verification mail: {:?}", err);
+    }
     Ok((
         StatusCode::CREATED,
         [("Content-Type", "application/json")]
     ).into_response())
 }
 
-#[instrument]
+/// Builds the application router. Hooks around record, auth and schema
+/// operations are the ones installed with [`AppState::with_hooks`].
+///
+/// Only the router is built: the queue workers, which send mail and webhook
+/// deliveries, and the scheduler are not started. Use
+/// [`Rocketbase::builder`](crate::Rocketbase::builder) for a server that runs
+/// them.
+#[instrument(skip_all)]
 pub async fn build_router(app_state: AppState) -> Result<Router> {
+    router_with(app_state, Router::new())
+}
+
+/// Builds the application router with `extra` routes of an embedding
+/// application, which are authenticated like the built in ones.
+pub(crate) fn router_with(app_state: AppState, extra: Router<AppState>) -> Result<Router> {
     // let shared_state = app_state::AppState::init().await.context("error initializing state")?;
-    let router = Router::new()
+    // Public endpoints that take credentials or send mail are rate limited.
+    let limited = Router::new()
+    .route("/users", post(create_user_handler))
+    .route("/users/request-verification", post(auth::request_verification_handler))
+    .route("/users/verify", post(auth::verify_handler))
+    .route("/users/request-password-reset", post(auth::request_password_reset_handler))
+    .route("/users/reset-password", post(auth::reset_password_handler))
+    .route("/auth/login", post(auth::login_handler))
+    .route("/auth/mfa/verify", post(mfa::verify_handler))
+    .route_layer(RateLimitLayer::new(app_state.rate_limiter()));
+    let upload_limit = DefaultBodyLimit::max(app_state.settings().storage.max_request_bytes);
+    // outside of authentication, so that preflight requests get through
+    let cors_policies = Arc::new(CorsPolicies::from_settings(&app_state.settings().cors)?);
+    // metrics are served here unless they have an address of their own
+    let metrics = match &app_state.settings().metrics {
+        Some(config) if metrics::bind_addr(config)?.is_none() => metrics::router(app_state.clone()),
+        _ => Router::new(),
+    };
+    Ok(Router::new()
     .route("/", get(home_handler))
+    .route("/healthz", get(health::healthz_handler))
+    .route("/readyz", get(health::readyz_handler))
     .route("/_/*path", get(static_path))
     .route("/users", get(users_handler))
-    .route("/users", post(create_user_handler))
-    .with_state(app_state)
+    .merge(limited)
+    .route("/auth/me", get(auth::me_handler))
+    .route("/auth/mfa/enroll", post(mfa::enroll_handler))
+    .route("/auth/mfa/confirm", post(mfa::confirm_handler))
+    .route("/auth/mfa/disable", post(mfa::disable_handler))
+    .route("/auth/oauth", get(oauth::providers_handler))
+    .route("/auth/oauth/:provider", get(oauth::authorize_handler))
+    .route("/auth/oauth/:provider/callback", get(oauth::callback_handler))
+    .route("/admin/api-keys", get(api_keys::list_api_keys_handler))
+    .route("/admin/api-keys", post(api_keys::create_api_key_handler))
+    .route("/admin/api-keys/:id", delete(api_keys::revoke_api_key_handler))
+    .route("/admin/webhooks", get(webhooks::list_webhooks_handler))
+    .route("/admin/webhooks", post(webhooks::create_webhook_handler))
+    .route("/admin/webhooks/:id", get(webhooks::get_webhook_handler))
+    .route("/admin/webhooks/:id", patch(webhooks::update_webhook_handler))
+    .route("/admin/webhooks/:id", delete(webhooks::delete_webhook_handler))
+    .route("/admin/webhooks/:id/deliveries", get(webhooks::list_deliveries_handler))
+    .route("/admin/webhooks/:id/deliveries/:delivery/redeliver", post(webhooks::redeliver_handler))
+    .route("/admin/jobs", get(jobs::list_jobs_handler))
+    .route("/admin/jobs", post(jobs::create_job_handler))
+    .route("/admin/jobs/:name", get(jobs::get_job_handler))
+    .route("/admin/jobs/:name", patch(jobs::update_job_handler))
+    .route("/admin/jobs/:name", delete(jobs::delete_job_handler))
+    .route("/admin/jobs/:name/runs", get(jobs::list_runs_handler))
+    .route("/admin/jobs/:name/run", post(jobs::run_job_handler))
+    .route("/admin/tasks", get(tasks::list_tasks_handler))
+    .route("/admin/tasks/:id", get(tasks::get_task_handler))
+    .route("/admin/tasks/:id/retry", post(tasks::retry_task_handler))
+    .route("/api/collections", get(collections::list_collections_handler))
+    .route("/api/collections", post(collections::create_collection_handler))
+    .route("/api/collections/:name", get(collections::get_collection_handler))
+    .route("/api/collections/:name", patch(collections::update_collection_handler))
+    .route("/api/collections/:name/records", get(records::list_records_handler))
+    .route("/api/collections/:name/records", post(records::create_record_handler).layer(upload_limit.clone()))
+    .route("/api/collections/:name/records/:id", get(records::get_record_handler))
+    .route("/api/collections/:name/records/:id", patch(records::update_record_handler).layer(upload_limit))
+    .route("/api/collections/:name/records/:id", delete(records::delete_record_handler))
+    .route("/api/files/:name/:id/:file", get(files::download_handler))
+    .route("/api/files/:name/:id/:file/token", post(files::file_token_handler))
+    .route("/api/changes", get(changes::changes_handler))
+    .route("/api/realtime", get(realtime::sse_handler))
+    .route("/api/realtime/ws", get(realtime::ws_handler))
+    .merge(extra)
+    .layer(middleware::from_fn_with_state(app_state.clone(), authenticate))
+    .with_state(app_state.clone())
+    .merge(metrics)
+    .layer(middleware::from_fn_with_state(app_state, metrics::track))
+    .layer(middleware::from_fn_with_state(cors_policies, cors::cors))
     .layer(
         ServiceBuilder::new()
-            .layer(TraceLayer::new_for_http())
-    );
-    Ok(router)
+            .layer(TraceLayer::new_for_http().make_span_with(telemetry::request_span::<Body>))
+    ))
 }
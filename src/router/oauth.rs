@@ -0,0 +1,96 @@
+use axum::{
+  extract::{Path, Query, State},
+  http::{header, HeaderMap, StatusCode},
+  response::{IntoResponse, Redirect},
+  Json,
+};
+use serde::Deserialize;
+use tracing::instrument;
+
+use super::auth::login_response;
+use crate::{
+  app_state::AppState,
+  auth::oauth,
+  hooks::{AuthAttempt, AuthEvent, AuthMethod, Rejection},
+};
+
+pub use super::auth::SessionResponse;
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+  code: Option<String>,
+  state: Option<String>,
+  error: Option<String>,
+}
+
+/// Cookie binding a login to the browser that started it, so that a callback
+/// with someone else's state is turned down.
+const STATE_COOKIE: &str = "rocketbase_oauth_state";
+
+fn state_cookie(value: &str, max_age: i64, secure: bool) -> String {
+  let secure = if secure { "; Secure" } else { "" };
+  format!("{}={}; Path=/auth/oauth; Max-Age={}; HttpOnly; SameSite=Lax{}", STATE_COOKIE, value, max_age, secure)
+}
+
+/// Returns the state from the cookie set when the login started.
+fn cookie_state(headers: &HeaderMap) -> Option<&str> {
+  headers.get_all(header::COOKIE).iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(';'))
+    .filter_map(|pair| pair.trim().split_once('='))
+    .find(|(name, _)| *name == STATE_COOKIE)
+    .map(|(_, value)| value)
+}
+
+fn error_status(err: oauth::OAuthError) -> StatusCode {
+  match err.status() {
+    StatusCode::INTERNAL_SERVER_ERROR => tracing::error!("{:?}", err),
+    _ => tracing::warn!("{}", err),
+  }
+  err.status()
+}
+
+pub async fn providers_handler(State(state): State<AppState>) -> Json<Vec<String>> {
+  Json(state.oauth().names().into_iter().map(String::from).collect())
+}
+
+#[instrument(skip(state))]
+pub async fn authorize_handler(State(state): State<AppState>, Path(provider): Path<String>) -> Result<impl IntoResponse, StatusCode> {
+  let oauth = state.oauth();
+  let authorization = oauth
+    .authorize(&state.db().connection(), &provider)
+    .await
+    .map_err(error_status)?;
+  let secure = oauth.provider(&provider).map_err(error_status)?.redirect_url().starts_with("https://");
+  let cookie = state_cookie(&authorization.state, oauth::STATE_TTL_SECS, secure);
+  Ok(([(header::SET_COOKIE, cookie)], Redirect::to(&authorization.url)))
+}
+
+#[instrument(skip(state, headers, params))]
+pub async fn callback_handler(
+  State(state): State<AppState>,
+  Path(provider): Path<String>,
+  headers: HeaderMap,
+  Query(params): Query<CallbackParams>,
+) -> Result<impl IntoResponse, Rejection> {
+  if let Some(error) = params.error {
+    tracing::warn!("{} denied authorization: {}", provider, error);
+    return Err(StatusCode::UNAUTHORIZED.into());
+  }
+  let (code, csrf_state) = params.code.zip(params.state).ok_or(StatusCode::BAD_REQUEST)?;
+  if cookie_state(&headers) != Some(csrf_state.as_str()) {
+    return Err(error_status(oauth::OAuthError::InvalidState).into());
+  }
+  let pool = state.db().connection();
+  let identity = state.oauth()
+    .exchange(&pool, &provider, &code, &csrf_state)
+    .await
+    .map_err(error_status)?;
+  let hooks = state.hooks();
+  let method = AuthMethod::OAuth(provider.clone());
+  hooks.run_before_auth(AuthAttempt { method: method.clone(), email: identity.email.clone() }).await?;
+  let user = oauth::login(&pool, &provider, &identity).await.map_err(error_status)?;
+  hooks.run_after_auth(AuthEvent { method, user: user.clone() }).await;
+  let cleared = state_cookie("", 0, false);
+  Ok(([(header::SET_COOKIE, cleared)], Json(login_response(&state, user).await?)))
+}
@@ -0,0 +1,214 @@
+use std::{borrow::Cow, convert::Infallible, str::FromStr, sync::Arc, time::Duration};
+
+use axum::{
+  extract::{
+    ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    Query, State,
+  },
+  http::StatusCode,
+  response::{
+    sse::{Event, KeepAlive, Sse},
+    Response,
+  },
+};
+use serde::Deserialize;
+use tokio::{
+  sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+  },
+  time::timeout,
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tracing::instrument;
+
+use super::auth::internal_error;
+use crate::{
+  app_state::AppState,
+  auth::{session_principal, Principal},
+  realtime::{
+    protocol::{ClientMessage, ServerMessage, GOING_AWAY, INVALID_MESSAGE, SLOW_CONSUMER},
+    Access, ChangeEvent, Subscriptions, Topic,
+  },
+};
+
+/// How long a WebSocket client gets to take a message before it is dropped.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Falls back to the session token in the query string for clients such as
+/// browsers that can not set headers on `EventSource` or WebSocket requests.
+async fn with_query_token(state: &AppState, principal: Option<Principal>, token: Option<String>) -> Result<Option<Principal>, StatusCode> {
+  match (principal, token) {
+    (Some(principal), _) => Ok(Some(principal)),
+    (None, Some(token)) => Ok(Some(session_principal(state, &token).await?.ok_or(StatusCode::UNAUTHORIZED)?)),
+    (None, None) => Ok(None),
+  }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RealtimeQuery {
+  /// Comma separated collections or records, as in `posts,comments/12`.
+  topics: String,
+  token: Option<String>,
+}
+
+/// Streams the changes to the subscribed topics that the client may view, as
+/// events named after the action. A client too slow to keep up gets a
+/// `lagged` event with the number of changes it missed, and the stream ends.
+#[instrument(skip(state, principal, query))]
+pub async fn sse_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Query(query): Query<RealtimeQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+  let principal = with_query_token(&state, principal, query.token).await?;
+  let subscriptions = Subscriptions::parse(&query.topics).map_err(|_| StatusCode::BAD_REQUEST)?;
+  if subscriptions.is_empty() {
+    return Err(StatusCode::BAD_REQUEST);
+  }
+  let mut changes = state.realtime().subscribe().await.map_err(internal_error)?;
+  let mut access = state.realtime().access(principal);
+  let shutdown = state.shutdown();
+  let (sender, receiver) = mpsc::channel(16);
+  tokio::spawn(async move {
+    loop {
+      let change = tokio::select! {
+        _ = sender.closed() => break,
+        // ending the stream lets the server finish draining
+        _ = shutdown.started() => break,
+        change = changes.recv() => change,
+      };
+      let change = match change {
+        Ok(change) => change,
+        // the client has to resync, for example from the change log
+        Err(RecvError::Lagged(missed)) => {
+          tracing::warn!("Realtime subscriber missed {} changes", missed);
+          let event = Event::default().event("lagged").data(format!("{{\"missed\":{}}}", missed));
+          let _ = sender.send(Ok(event)).await;
+          break;
+        }
+        Err(RecvError::Closed) => break,
+      };
+      if !subscriptions.matches(&change) || !access.can_view(&change).await {
+        continue;
+      }
+      let event = match Event::default().event(change.action.as_str()).json_data(&*change) {
+        Ok(event) => event,
+        Err(err) => {
+          tracing::error!("Unable to encode change: {:?}", err);
+          continue;
+        }
+      };
+      if sender.send(Ok(event)).await.is_err() {
+        break;
+      }
+    }
+  });
+  Ok(Sse::new(ReceiverStream::new(receiver)).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SocketQuery {
+  token: Option<String>,
+}
+
+/// Upgrades to a WebSocket multiplexing subscriptions, speaking the protocol
+/// of [`ClientMessage`] and [`ServerMessage`].
+#[instrument(skip(ws, state, principal, query))]
+pub async fn ws_handler(
+  ws: WebSocketUpgrade,
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Query(query): Query<SocketQuery>,
+) -> Result<Response, StatusCode> {
+  let principal = with_query_token(&state, principal, query.token).await?;
+  let changes = state.realtime().subscribe().await.map_err(internal_error)?;
+  Ok(ws.on_upgrade(move |socket| serve_socket(socket, state, changes, principal)))
+}
+
+async fn serve_socket(mut socket: WebSocket, state: AppState, mut changes: broadcast::Receiver<Arc<ChangeEvent>>, principal: Option<Principal>) {
+  let mut subscriptions = Subscriptions::default();
+  let mut access = state.realtime().access(principal);
+  let shutdown = state.shutdown();
+  loop {
+    let reply = tokio::select! {
+      _ = shutdown.started() => {
+        close(socket, GOING_AWAY, "server is shutting down").await;
+        return;
+      }
+      message = socket.recv() => match message {
+        Some(Ok(Message::Text(text))) => match serde_json::from_str::<ClientMessage>(&text) {
+          Ok(message) => handle_message(&state, &mut subscriptions, &mut access, message).await,
+          Err(err) => ServerMessage::Error { message: err.to_string() },
+        },
+        Some(Ok(Message::Binary(_))) => {
+          close(socket, INVALID_MESSAGE, "only text messages are supported").await;
+          return;
+        }
+        // pings are answered by axum
+        Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+        Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+      },
+      change = changes.recv() => match change {
+        Ok(change) if subscriptions.matches(&change) && access.can_view(&change).await => ServerMessage::Event((*change).clone()),
+        Ok(_) => continue,
+        Err(RecvError::Lagged(_)) => {
+          close(socket, SLOW_CONSUMER, "too many pending changes").await;
+          return;
+        }
+        Err(RecvError::Closed) => return,
+      },
+    };
+    let text = match serde_json::to_string(&reply) {
+      Ok(text) => text,
+      Err(err) => {
+        tracing::error!("Unable to encode message: {:?}", err);
+        continue;
+      }
+    };
+    match timeout(SEND_TIMEOUT, socket.send(Message::Text(text))).await {
+      Ok(Ok(())) => {}
+      Ok(Err(_)) => return,
+      Err(_) => {
+        close(socket, SLOW_CONSUMER, "not reading messages").await;
+        return;
+      }
+    }
+  }
+}
+
+async fn handle_message(state: &AppState, subscriptions: &mut Subscriptions, access: &mut Access, message: ClientMessage) -> ServerMessage {
+  let parse = |topics: &[String]| topics.iter().map(|topic| Topic::from_str(topic)).collect::<Result<Vec<_>, _>>();
+  match message {
+    ClientMessage::Subscribe { topics } => match parse(&topics) {
+      Ok(parsed) => {
+        parsed.into_iter().for_each(|topic| subscriptions.add(topic));
+        ServerMessage::Subscribed { topics }
+      }
+      Err(message) => ServerMessage::Error { message },
+    },
+    ClientMessage::Unsubscribe { topics } => match parse(&topics) {
+      Ok(parsed) => {
+        parsed.iter().for_each(|topic| subscriptions.remove(topic));
+        ServerMessage::Unsubscribed { topics }
+      }
+      Err(message) => ServerMessage::Error { message },
+    },
+    ClientMessage::Ping => ServerMessage::Pong,
+    ClientMessage::Auth { token } => match session_principal(state, &token).await {
+      Ok(Some(principal)) => {
+        access.set_principal(Some(principal));
+        ServerMessage::Authenticated
+      }
+      Ok(None) => ServerMessage::Error { message: "invalid token".into() },
+      Err(_) => ServerMessage::Error { message: "unable to authenticate".into() },
+    },
+  }
+}
+
+/// Closes the socket with `code`, giving up if the client does not take the
+/// close frame either.
+async fn close(mut socket: WebSocket, code: u16, reason: &'static str) {
+  let frame = CloseFrame { code, reason: Cow::Borrowed(reason) };
+  let _ = timeout(Duration::from_secs(1), socket.send(Message::Close(Some(frame)))).await;
+}
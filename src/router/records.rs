@@ -0,0 +1,213 @@
+use axum::{
+  extract::{Path, Query, State},
+  http::StatusCode,
+  Json,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::instrument;
+
+use super::{
+  auth::internal_error,
+  collections::collection_error,
+  files::{read_input, remove_files, store_uploads, RecordInput},
+};
+use crate::{
+  app_state::AppState,
+  auth::{can_access, Principal},
+  hooks::{RecordEvent, Rejection},
+  model::{
+    collection::{Collection, Operation, Rule},
+    file::{files_in, record_files},
+    record::ListQuery,
+    Record, Records,
+  },
+};
+
+const DEFAULT_LIMIT: i64 = 30;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct Page {
+  limit: Option<i64>,
+  offset: Option<i64>,
+}
+
+pub(super) async fn collection(state: &AppState, name: &str) -> Result<Collection, StatusCode> {
+  Collection::find(&state.db().connection(), name)
+    .await
+    .map_err(collection_error)?
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Anonymous requests are asked to authenticate, anyone else is refused.
+pub(super) fn denied(principal: Option<&Principal>) -> StatusCode {
+  match principal {
+    Some(_) => StatusCode::FORBIDDEN,
+    None => StatusCode::UNAUTHORIZED,
+  }
+}
+
+fn authorize(principal: Option<&Principal>, collection: &Collection, operation: Operation, record: Option<&Value>) -> Result<(), StatusCode> {
+  match can_access(principal, collection, operation, record) {
+    true => Ok(()),
+    false => Err(denied(principal)),
+  }
+}
+
+/// Constraint violations are the client's fault, anything else is ours.
+pub(super) fn record_error(err: color_eyre::Report) -> StatusCode {
+  match err.downcast_ref::<sqlx::Error>() {
+    Some(sqlx::Error::Database(db_err)) => {
+      tracing::debug!("Rejected record: {}", db_err);
+      StatusCode::UNPROCESSABLE_ENTITY
+    }
+    _ => internal_error(err),
+  }
+}
+
+#[instrument(skip(state, principal))]
+pub async fn list_records_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Path(name): Path<String>,
+  Query(page): Query<Page>,
+) -> Result<Json<Vec<Record>>, StatusCode> {
+  let collection = collection(&state, &name).await?;
+  authorize(principal.as_ref(), &collection, Operation::List, None)?;
+  // owners only get to list their own records
+  let owner = match (&principal, &collection.rules.list) {
+    (Some(Principal::User(user)), Rule::Owner(column)) => Some((column.as_str(), user.id.unwrap_or_default())),
+    _ => None,
+  };
+  let list = ListQuery {
+    limit: page.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+    offset: page.offset.unwrap_or(0).max(0),
+    owner,
+  };
+  let records = Records::list(&state.db().connection(), &collection, list).await.map_err(record_error)?;
+  Ok(Json(records))
+}
+
+#[instrument(skip(state, principal))]
+pub async fn get_record_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Path((name, id)): Path<(String, i64)>,
+) -> Result<Json<Record>, StatusCode> {
+  let collection = collection(&state, &name).await?;
+  let record = Records::find(&state.db().connection(), &collection, id)
+    .await
+    .map_err(record_error)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  authorize(principal.as_ref(), &collection, Operation::View, Some(&record))?;
+  Ok(Json(record))
+}
+
+#[instrument(skip(state, principal, input))]
+pub async fn create_record_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Path(name): Path<String>,
+  input: RecordInput,
+) -> Result<(StatusCode, Json<Record>), Rejection> {
+  let collection = collection(&state, &name).await?;
+  let (mut data, uploads) = read_input(&collection, input).await?;
+  // records of owner collections belong to their creator unless told otherwise
+  if let (Some(Principal::User(user)), Rule::Owner(column)) = (&principal, &collection.rules.create) {
+    if let Some(id) = user.id {
+      data.entry(column.clone()).or_insert(Value::from(id));
+    }
+  }
+  authorize(principal.as_ref(), &collection, Operation::Create, Some(&Value::Object(data.clone())))?;
+  let hooks = state.hooks();
+  let event = RecordEvent { collection, principal, record: None, data };
+  let RecordEvent { collection, principal, mut data, .. } = hooks.run_before_create(event).await?;
+  let storage = state.storage();
+  store_uploads(storage.as_ref(), &collection, uploads, &mut data).await?;
+  match Records::insert(&state.db().connection(), &collection, &data).await {
+    Ok(record) => {
+      hooks.run_after_create(RecordEvent { collection, principal, record: Some(record.clone()), data }).await;
+      Ok((StatusCode::CREATED, Json(record)))
+    }
+    Err(err) => {
+      remove_files(storage.as_ref(), &collection, &record_files(&collection, &Value::Object(data))).await;
+      Err(record_error(err).into())
+    }
+  }
+}
+
+#[instrument(skip(state, principal, input))]
+pub async fn update_record_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Path((name, id)): Path<(String, i64)>,
+  input: RecordInput,
+) -> Result<Json<Record>, Rejection> {
+  let collection = collection(&state, &name).await?;
+  let (data, uploads) = read_input(&collection, input).await?;
+  let pool = state.db().connection();
+  let existing = Records::find(&pool, &collection, id)
+    .await
+    .map_err(record_error)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  authorize(principal.as_ref(), &collection, Operation::Update, Some(&existing))?;
+  // the rule has to hold for the record as it is and as it will be, so that
+  // owners can not give records away
+  let mut updated = existing.clone();
+  if let Value::Object(fields) = &mut updated {
+    fields.extend(data.clone());
+  }
+  authorize(principal.as_ref(), &collection, Operation::Update, Some(&updated))?;
+  let hooks = state.hooks();
+  let event = RecordEvent { collection, principal, record: Some(existing), data };
+  let RecordEvent { collection, principal, record, mut data } = hooks.run_before_update(event).await?;
+  let existing = record.unwrap_or_default();
+  let storage = state.storage();
+  store_uploads(storage.as_ref(), &collection, uploads, &mut data).await?;
+  let record = match Records::update(&pool, &collection, id, &data).await {
+    Ok(Some(record)) => record,
+    failed => {
+      remove_files(storage.as_ref(), &collection, &record_files(&collection, &Value::Object(data))).await;
+      return Err(match failed {
+        Err(err) => record_error(err),
+        _ => StatusCode::NOT_FOUND,
+      }.into());
+    }
+  };
+  // files of replaced or cleared columns are no longer referenced
+  let replaced: Vec<_> = collection
+    .file_columns()
+    .filter(|cd| data.contains_key(&cd.name))
+    .flat_map(|cd| files_in(&existing[&cd.name]))
+    .collect();
+  remove_files(storage.as_ref(), &collection, &replaced).await;
+  hooks.run_after_update(RecordEvent { collection, principal, record: Some(record.clone()), data }).await;
+  Ok(Json(record))
+}
+
+#[instrument(skip(state, principal))]
+pub async fn delete_record_handler(
+  State(state): State<AppState>,
+  principal: Option<Principal>,
+  Path((name, id)): Path<(String, i64)>,
+) -> Result<StatusCode, Rejection> {
+  let collection = collection(&state, &name).await?;
+  let pool = state.db().connection();
+  let record = Records::find(&pool, &collection, id)
+    .await
+    .map_err(record_error)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  authorize(principal.as_ref(), &collection, Operation::Delete, Some(&record))?;
+  let hooks = state.hooks();
+  let event = RecordEvent { collection, principal, record: Some(record), data: Map::new() };
+  let RecordEvent { collection, principal, .. } = hooks.run_before_delete(event).await?;
+  match Records::delete(&pool, &collection, id).await.map_err(record_error)? {
+    Some(record) => {
+      remove_files(state.storage().as_ref(), &collection, &record_files(&collection, &record)).await;
+      hooks.run_after_delete(RecordEvent { collection, principal, record: Some(record), data: Map::new() }).await;
+      Ok(StatusCode::NO_CONTENT)
+    }
+    None => Err(StatusCode::NOT_FOUND.into()),
+  }
+}
@@ -0,0 +1,60 @@
+use axum::{
+  extract::{Path, Query, State},
+  http::StatusCode,
+  Json,
+};
+use serde::Deserialize;
+use tracing::instrument;
+
+use super::auth::internal_error;
+use crate::{
+  app_state::AppState,
+  auth::AdminUser,
+  model::{task::TaskStatus, Task},
+};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct TaskFilter {
+  status: Option<TaskStatus>,
+  kind: Option<String>,
+  limit: Option<i64>,
+}
+
+/// Queued and dead tasks, newest first. Tasks that ran to completion are not
+/// kept.
+#[instrument(skip(state, _admin))]
+pub async fn list_tasks_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Query(filter): Query<TaskFilter>,
+) -> Result<Json<Vec<Task>>, StatusCode> {
+  let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+  let tasks = Task::list(&state.db().connection(), filter.status, filter.kind.as_deref(), limit).await.map_err(internal_error)?;
+  Ok(Json(tasks))
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn get_task_handler(State(state): State<AppState>, _admin: AdminUser, Path(id): Path<i64>) -> Result<Json<Task>, StatusCode> {
+  let task = Task::find(&state.db().connection(), id).await.map_err(internal_error)?;
+  task.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Queues a dead task again. Tasks that are not dead can't be retried.
+#[instrument(skip(state, _admin))]
+pub async fn retry_task_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Path(id): Path<i64>,
+) -> Result<(StatusCode, Json<Task>), StatusCode> {
+  let pool = state.db().connection();
+  if let Some(task) = Task::retry(&pool, id).await.map_err(internal_error)? {
+    return Ok((StatusCode::ACCEPTED, Json(task)));
+  }
+  match Task::find(&pool, id).await.map_err(internal_error)? {
+    Some(_) => Err(StatusCode::CONFLICT),
+    None => Err(StatusCode::NOT_FOUND),
+  }
+}
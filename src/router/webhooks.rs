@@ -0,0 +1,121 @@
+use axum::{
+  extract::{Path, Query, State},
+  http::{StatusCode, Uri},
+  Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::instrument;
+
+use super::{auth::internal_error, collections::collection_error};
+use crate::{
+  app_state::AppState,
+  auth::AdminUser,
+  model::{
+    collection::Collection,
+    webhook::{NewWebhook, WebhookUpdate},
+    Delivery, Task, Webhook,
+  },
+  queue::TaskPayload,
+  webhooks::DeliverWebhook,
+};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreatedWebhook {
+  /// Key the deliveries are signed with. It can not be shown again.
+  pub secret: String,
+  pub webhook: Webhook,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeliveryPage {
+  limit: Option<i64>,
+}
+
+fn valid_url(url: &str) -> bool {
+  url.parse::<Uri>().is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some())
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn create_webhook_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Json(payload): Json<NewWebhook>,
+) -> Result<(StatusCode, Json<CreatedWebhook>), StatusCode> {
+  if payload.events.is_empty() || !valid_url(&payload.url) || payload.secret.as_ref().is_some_and(|secret| secret.is_empty()) {
+    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  let pool = state.db().connection();
+  if Collection::find(&pool, &payload.collection).await.map_err(collection_error)?.is_none() {
+    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  let webhook = Webhook::create(&pool, &payload).await.map_err(internal_error)?;
+  Ok((StatusCode::CREATED, Json(CreatedWebhook { secret: webhook.secret.clone(), webhook })))
+}
+
+#[instrument(skip_all)]
+pub async fn list_webhooks_handler(State(state): State<AppState>, _admin: AdminUser) -> Result<Json<Vec<Webhook>>, StatusCode> {
+  let webhooks = Webhook::all(&state.db().connection()).await.map_err(internal_error)?;
+  Ok(Json(webhooks))
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn get_webhook_handler(State(state): State<AppState>, _admin: AdminUser, Path(id): Path<i64>) -> Result<Json<Webhook>, StatusCode> {
+  let webhook = Webhook::find(&state.db().connection(), id).await.map_err(internal_error)?;
+  webhook.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn update_webhook_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Path(id): Path<i64>,
+  Json(payload): Json<WebhookUpdate>,
+) -> Result<Json<Webhook>, StatusCode> {
+  if payload.events.as_ref().is_some_and(Vec::is_empty) || payload.url.as_deref().is_some_and(|url| !valid_url(url)) {
+    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  let webhook = Webhook::update(&state.db().connection(), id, &payload).await.map_err(internal_error)?;
+  webhook.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[instrument(skip(state, _admin))]
+pub async fn delete_webhook_handler(State(state): State<AppState>, _admin: AdminUser, Path(id): Path<i64>) -> Result<StatusCode, StatusCode> {
+  match Webhook::delete(&state.db().connection(), id).await.map_err(internal_error)? {
+    true => Ok(StatusCode::NO_CONTENT),
+    false => Err(StatusCode::NOT_FOUND),
+  }
+}
+
+/// The delivery log of a webhook, newest first.
+#[instrument(skip(state, _admin))]
+pub async fn list_deliveries_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Path(id): Path<i64>,
+  Query(page): Query<DeliveryPage>,
+) -> Result<Json<Vec<Delivery>>, StatusCode> {
+  let pool = state.db().connection();
+  Webhook::find(&pool, id).await.map_err(internal_error)?.ok_or(StatusCode::NOT_FOUND)?;
+  let limit = page.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+  let deliveries = Delivery::for_webhook(&pool, id, limit).await.map_err(internal_error)?;
+  Ok(Json(deliveries))
+}
+
+/// Queues a past delivery again. The original stays in the log as it was.
+#[instrument(skip(state, _admin))]
+pub async fn redeliver_handler(
+  State(state): State<AppState>,
+  _admin: AdminUser,
+  Path((id, delivery_id)): Path<(i64, i64)>,
+) -> Result<(StatusCode, Json<Delivery>), StatusCode> {
+  let mut tx = state.db().connection().begin().await.map_err(|err| internal_error(err.into()))?;
+  let delivery = Delivery::redeliver(&mut tx, id, delivery_id).await.map_err(internal_error)?.ok_or(StatusCode::NOT_FOUND)?;
+  let task = DeliverWebhook { delivery_id: delivery.id };
+  Task::enqueue(&mut tx, DeliverWebhook::KIND, &json!(task), None).await.map_err(internal_error)?;
+  tx.commit().await.map_err(|err| internal_error(err.into()))?;
+  Ok((StatusCode::ACCEPTED, Json(delivery)))
+}
@@ -0,0 +1,255 @@
+use std::{collections::BTreeMap, fmt, future::Future, pin::Pin, str::FromStr, sync::Arc, time::{Duration, Instant}};
+
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use cron::Schedule;
+use sqlx::PgPool;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+  model::job::{Job, JobRun, NewJob},
+  settings,
+};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type Handler = Arc<dyn Fn(JobContext) -> BoxFuture<Result<()>> + Send + Sync>;
+
+/// What a job handler gets for a run.
+#[derive(Debug, Clone)]
+pub struct JobContext {
+  pub pool: PgPool,
+  pub job: Job,
+}
+
+/// Parses a cron expression. Five field expressions are taken to start at
+/// second zero, six and seven field ones start with the seconds.
+pub fn parse_schedule(expression: &str) -> Result<Schedule, cron::error::Error> {
+  match expression.split_whitespace().count() {
+    5 => Schedule::from_str(&format!("0 {}", expression)),
+    _ => Schedule::from_str(expression),
+  }
+}
+
+/// The first occurrence of the schedule after `after`.
+pub fn next_run(schedule: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+  parse_schedule(schedule).ok()?.after(&after).next()
+}
+
+/// A run holding the lease of its job, see [`Scheduler::start`].
+#[derive(Debug)]
+pub struct StartedRun {
+  job: Job,
+  owner: String,
+  run: JobRun,
+}
+
+impl StartedRun {
+  pub fn run(&self) -> &JobRun {
+    &self.run
+  }
+}
+
+/// Handlers jobs run, and the jobs an application comes with. Jobs are
+/// stored in `_jobs`, where the admin API can add more for the registered
+/// handlers. Every run of a job holds a lease on it, renewed while the run
+/// goes on, so that however many instances share the database only one runs
+/// it at a time. A lease is kept in `_jobs` rather than as a session advisory
+/// lock, which would tie up a pooled connection for the whole run and be let
+/// go unnoticed when that connection drops.
+#[derive(Default)]
+pub struct Scheduler {
+  handlers: BTreeMap<String, Handler>,
+  jobs: Vec<NewJob>,
+}
+
+impl Scheduler {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Registers a handler for jobs to run.
+  pub fn handler<F, Fut>(mut self, name: &str, handler: F) -> Self
+  where
+    F: Fn(JobContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+  {
+    self.handlers.insert(name.to_string(), Arc::new(move |context| Box::pin(handler(context))));
+    self
+  }
+
+  /// Registers a handler along with a job of the same name running it on
+  /// `schedule`. The job is only created when it does not exist yet, so that
+  /// changes made through the admin API are kept.
+  pub fn job<F, Fut>(mut self, name: &str, schedule: &str, handler: F) -> Self
+  where
+    F: Fn(JobContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+  {
+    self.jobs.push(NewJob {
+      name: name.to_string(),
+      schedule: schedule.to_string(),
+      handler: name.to_string(),
+      enabled: true,
+    });
+    self.handler(name, handler)
+  }
+
+  pub fn has_handler(&self, name: &str) -> bool {
+    self.handlers.contains_key(name)
+  }
+
+  /// Runs a job under its lease, returning `None` when it is already
+  /// running.
+  pub async fn run(&self, pool: &PgPool, job: Job, scheduled: bool, config: &settings::Scheduler) -> Result<Option<JobRun>> {
+    match self.start(pool, job, scheduled, config).await? {
+      Some(started) => self.finish(pool, started, config).await.map(Some),
+      None => Ok(None),
+    }
+  }
+
+  /// Takes the lease of a job and records the start of a run, returning
+  /// `None` when it is already running. Scheduled runs also move the job on
+  /// to its next occurrence, and are skipped when another instance did so
+  /// first. The run has to be carried out with [`Scheduler::finish`].
+  pub async fn start(&self, pool: &PgPool, job: Job, scheduled: bool, config: &settings::Scheduler) -> Result<Option<StartedRun>> {
+    let owner = Uuid::new_v4().to_string();
+    let lease = chrono::Duration::seconds(config.lease_secs);
+    let job = match scheduled {
+      true => Job::claim(pool, &job.name, next_run(&job.schedule, Utc::now()), &owner, lease).await?,
+      false => Job::lock(pool, &job.name, &owner, lease).await?.then_some(job),
+    };
+    let Some(job) = job else {
+      return Ok(None);
+    };
+    match JobRun::start(pool, &job.name).await {
+      Ok(run) => Ok(Some(StartedRun { job, owner, run })),
+      Err(err) => {
+        Job::unlock(pool, &job.name, &owner).await?;
+        Err(err)
+      }
+    }
+  }
+
+  /// Runs the handler of a started run while renewing the lease, then records
+  /// how it went and lets go of the job. A run that loses its lease, because
+  /// renewing it took longer than the lease lasts, is stopped and recorded as
+  /// failed, as another instance may be running the job by then.
+  pub async fn finish(&self, pool: &PgPool, started: StartedRun, config: &settings::Scheduler) -> Result<JobRun> {
+    let StartedRun { job, owner, run } = started;
+    let name = job.name.clone();
+    let lease = chrono::Duration::seconds(config.lease_secs);
+    let clock = Instant::now();
+    let error = match self.handlers.get(&job.handler) {
+      Some(handler) => {
+        // spawned so that a panicking handler fails the run only, and so that
+        // it can be stopped
+        let mut task = tokio::spawn(handler(JobContext { pool: pool.clone(), job: job.clone() }));
+        // renewed well before it runs out, so that a slow renewal does no harm
+        let mut heartbeat = tokio::time::interval(Duration::from_secs(config.lease_secs.max(1) as u64) / 3);
+        heartbeat.tick().await;
+        loop {
+          tokio::select! {
+            finished = &mut task => break match finished {
+              Ok(Ok(())) => None,
+              Ok(Err(err)) => Some(format!("{:#}", err)),
+              Err(err) => Some(format!("Job panicked: {}", err)),
+            },
+            _ = heartbeat.tick() => match Job::extend(pool, &name, &owner, lease).await {
+              Ok(true) => {}
+              Ok(false) => {
+                task.abort();
+                break Some("Lost its lease while running".to_string());
+              }
+              Err(err) => tracing::error!("{:?}", err),
+            },
+          }
+        }
+      }
+      None => Some(format!("No handler named {}", job.handler)),
+    };
+    if let Some(error) = &error {
+      tracing::warn!("Job {} failed: {}", name, error);
+    }
+    let duration_ms = clock.elapsed().as_millis() as i64;
+    let finished = JobRun::finish(pool, run.id, duration_ms, error.as_deref()).await;
+    Job::unlock(pool, &name, &owner).await?;
+    let run = finished?;
+    JobRun::prune(pool, &name, config.keep_runs).await?;
+    Ok(run)
+  }
+
+  async fn create_jobs(&self, pool: &PgPool) -> Result<()> {
+    for new_job in &self.jobs {
+      Job::create(pool, new_job, next_run(&new_job.schedule, Utc::now())).await?;
+    }
+    Ok(())
+  }
+
+  /// Creates the registered jobs and runs due ones until shutdown, then waits
+  /// for the runs in progress. Jobs run in their own task, so that slow ones
+  /// don't hold up the others.
+  pub fn spawn(self: &Arc<Self>, pool: PgPool, config: settings::Scheduler, shutdown: CancellationToken) -> JoinHandle<()> {
+    let scheduler = self.clone();
+    tokio::spawn(async move {
+      if let Err(err) = scheduler.create_jobs(&pool).await {
+        tracing::error!("{:?}", err);
+      }
+      let mut interval = tokio::time::interval(Duration::from_millis(config.poll_interval_ms));
+      interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+      // every run holds a sender, so the receiver is done once they all are
+      let (running, mut done) = mpsc::channel::<()>(1);
+      while !pool.is_closed() {
+        tokio::select! {
+          _ = shutdown.cancelled() => break,
+          _ = interval.tick() => {}
+        }
+        let due = match Job::due(&pool).await {
+          Ok(due) => due,
+          Err(err) => {
+            tracing::error!("{:?}", err);
+            continue;
+          }
+        };
+        for job in due {
+          let (scheduler, pool, running) = (scheduler.clone(), pool.clone(), running.clone());
+          tokio::spawn(async move {
+            if let Err(err) = scheduler.run(&pool, job, true, &config).await {
+              tracing::error!("{:?}", err);
+            }
+            drop(running);
+          });
+        }
+      }
+      drop(running);
+      done.recv().await;
+    })
+  }
+}
+
+impl fmt::Debug for Scheduler {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Scheduler")
+      .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+      .field("jobs", &self.jobs)
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::{TimeZone, Utc};
+
+  use super::{next_run, parse_schedule};
+
+  #[test]
+  fn should_accept_expressions_with_and_without_seconds() {
+    let now = Utc.with_ymd_and_hms(2023, 6, 15, 12, 30, 10).unwrap();
+    assert_eq!(next_run("0 3 * * *", now), Some(Utc.with_ymd_and_hms(2023, 6, 16, 3, 0, 0).unwrap()));
+    assert_eq!(next_run("*/5 * * * * *", now), Some(Utc.with_ymd_and_hms(2023, 6, 15, 12, 30, 15).unwrap()));
+    assert!(parse_schedule("@daily").is_ok());
+    assert!(parse_schedule("every day").is_err());
+    assert!(parse_schedule("61 * * * *").is_err());
+  }
+}
@@ -1,22 +1,323 @@
-use color_eyre::{Report, Result, eyre::Context};
-use axum::{Router, Server};
+use color_eyre::{Help, Report, Result, eyre::Context};
+use axum::{
+    body::Body,
+    http::Request,
+    response::IntoResponse,
+    routing::Route,
+    Router, Server,
+};
+use sqlx::PgPool;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
 use std::net::SocketAddr;
 use std::str::FromStr;
-use tracing::log::info;
+use std::time::{Duration, Instant};
+use axum::extract::connect_info::Connected;
+use hyper::{rt::Executor, server::{accept::Accept, Builder}};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::ServerConfig;
+use tokio::task::JoinHandle;
+use tower::{Layer, Service};
+use tracing::log::{info, warn};
 
-use crate::settings::SETTINGS;
+use crate::{
+    app_state::AppState,
+    auth,
+    db::DB,
+    hooks::Hooks,
+    metrics,
+    plugins::PluginHost,
+    queue::Workers,
+    router::router_with,
+    scheduler::Scheduler,
+    settings::{Settings, SETTINGS},
+    shutdown::{self, Shutdown},
+    tls::{self, CertResolver},
+    webhooks,
+};
 
+/// Serves until `shutdown` starts, then stops accepting connections and
+/// waits up to `drain` for the open ones before dropping them.
 #[tracing::instrument(skip_all)]
-pub async fn serve(router: Router) -> Result<()>{
-    let addr = SocketAddr::from_str(&format!("{}:{}", SETTINGS.host, SETTINGS.port))?;
+pub async fn serve(router: Router, addr: SocketAddr, shutdown: &Shutdown, drain: Duration) -> Result<()>{
     let builder = Server::try_bind(&addr)?;
     info!("Server started listening on {}", addr);
-    match builder
-        .serve(router.into_make_service())
-        .await {
+    serve_until(builder, router, shutdown, drain).await
+}
+
+/// Serves HTTPS, HTTP/2 included, like [`serve`] does HTTP.
+#[tracing::instrument(skip_all)]
+pub async fn serve_tls(router: Router, addr: SocketAddr, config: Arc<ServerConfig>, shutdown: &Shutdown, drain: Duration) -> Result<()>{
+    let listener = tokio::net::TcpListener::bind(addr).await.with_context(|| format!("Unable to bind {}", addr))?;
+    info!("Server started listening on https://{}", addr);
+    serve_until(Server::builder(tls::incoming(listener, config)), router, shutdown, drain).await
+}
+
+async fn serve_until<I>(builder: Builder<I>, router: Router, shutdown: &Shutdown, drain: Duration) -> Result<()>
+where
+    I: Accept,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    SocketAddr: for<'a> Connected<&'a I::Conn>,
+{
+    let connections = Connections::default();
+    let server = builder
+        .executor(connections.clone())
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown.started());
+    let deadline = async {
+        shutdown.started().await;
+        let started_at = shutdown.started_at().unwrap_or_else(Instant::now);
+        tokio::time::sleep_until((started_at + drain).into()).await;
+    };
+    tokio::select! {
+        served = server => match served {
             Err(e) => Err(Report::new(e)),
             Ok(rs) => Ok(rs)
-    }.context("Unable to create router service")?;
-    
+        }.context("Unable to create router service")?,
+        _ = deadline => {
+            warn!("Connections were still open after {:?}, dropping them", drain);
+            connections.abort();
+        }
+    }
+
     Ok(())
 }
+
+/// Spawns the tasks of the server, connections included, keeping them to
+/// be dropped when they do not drain in time.
+#[derive(Clone, Default)]
+struct Connections(Arc<Mutex<Vec<JoinHandle<()>>>>);
+
+impl Connections {
+    fn abort(&self) {
+        self.0.lock().unwrap().drain(..).for_each(|task| task.abort());
+    }
+}
+
+impl<F> Executor<F> for Connections
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn execute(&self, task: F) {
+        let mut tasks = self.0.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(tokio::spawn(task));
+    }
+}
+
+type RouterLayer = Box<dyn FnOnce(Router) -> Router + Send>;
+
+/// Rocketbase as a library: the state and router of an application, ready to
+/// be served or embedded. Made with [`Rocketbase::builder`].
+#[derive(Debug)]
+pub struct Rocketbase {
+    state: AppState,
+    router: Router,
+    plugins: Option<Arc<PluginHost>>,
+}
+
+impl Rocketbase {
+    pub fn builder() -> RocketbaseBuilder {
+        RocketbaseBuilder::default()
+    }
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+    pub fn router(&self) -> Router {
+        self.router.clone()
+    }
+    /// The plugin host, unless plugins are disabled in the settings.
+    pub fn plugins(&self) -> Option<Arc<PluginHost>> {
+        self.plugins.clone()
+    }
+    /// Serves on the host and port of the settings until SIGINT or SIGTERM,
+    /// or until the server fails, then shuts down.
+    pub async fn serve(self) -> Result<()> {
+        let shutdown = self.state.shutdown();
+        tokio::spawn(async move {
+            shutdown::signal().await;
+            info!("Shutting down");
+            shutdown.start();
+        });
+        self.serve_until_shutdown().await
+    }
+    /// Serves on the host and port of the settings until the shutdown of the
+    /// state is started some other way than by a signal.
+    pub async fn serve_until_shutdown(self) -> Result<()> {
+        let settings = self.state.settings();
+        let addr = SocketAddr::from_str(&format!("{}:{}", settings.host, settings.port))?;
+        let drain = Duration::from_secs(settings.shutdown.drain_timeout_secs);
+        let metrics = match &settings.metrics {
+            Some(config) => metrics::bind_addr(config)?,
+            None => None,
+        };
+        let shutdown = self.state.shutdown();
+        let shut_down = self.shutdown();
+        let main = async { match settings.tls.clone() {
+            Some(config) => {
+                let resolver = CertResolver::new(config.clone()).suggestion("Check the tls section of the settings")?;
+                shutdown.track(resolver.spawn_reloader(shutdown.token()));
+                let https = serve_tls(self.router, addr, tls::server_config(resolver), &shutdown, drain);
+                match config.redirect_port {
+                    Some(port) => {
+                        let http = serve(tls::redirect_router(addr.port()), SocketAddr::new(addr.ip(), port), &shutdown, drain);
+                        tokio::try_join!(https, http).map(|_| ())
+                    }
+                    None => https.await,
+                }
+            }
+            None => serve(self.router, addr, &shutdown, drain).await,
+        }};
+        let served = match metrics {
+            Some(metrics_addr) => {
+                let metrics = serve(metrics::router(self.state.clone()), metrics_addr, &shutdown, drain);
+                tokio::try_join!(main, metrics).map(|_| ())
+            }
+            None => main.await,
+        };
+        shut_down.await;
+        served
+    }
+    /// Tells background tasks to stop, gives them what is left of the drain
+    /// timeout since shutdown started to finish what they are doing, then
+    /// closes the pool.
+    pub fn shutdown(&self) -> impl Future<Output = ()> + Send + 'static {
+        let state = self.state.clone();
+        async move {
+            let drain = Duration::from_secs(state.settings().shutdown.drain_timeout_secs);
+            let shutdown = state.shutdown();
+            // connections and background tasks drain by the same deadline
+            let deadline = shutdown.started_at().unwrap_or_else(Instant::now) + drain;
+            if !shutdown.finish(deadline.saturating_duration_since(Instant::now())).await {
+                warn!("Background tasks were still running {:?} after shutdown started, stopped them", drain);
+            }
+            state.db().connection().close().await;
+            info!("Shut down");
+        }
+    }
+}
+
+/// Configures a [`Rocketbase`]. Anything not given falls back to what the
+/// binary uses: the settings files and the database they point to.
+#[derive(Default)]
+pub struct RocketbaseBuilder {
+    settings: Option<Settings>,
+    pool: Option<PgPool>,
+    routes: Vec<(String, Router<AppState>)>,
+    layers: Vec<RouterLayer>,
+    hooks: Hooks,
+    scheduler: Scheduler,
+    workers: Workers,
+}
+
+impl RocketbaseBuilder {
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+    /// Uses an existing pool instead of connecting to `database.url`. Its
+    /// database is migrated when building.
+    pub fn pool(mut self, pool: PgPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+    /// Adds routes under `prefix`, or at the root for `/`. Requests to them
+    /// are authenticated, so handlers can take a `Principal`.
+    pub fn routes(mut self, prefix: &str, routes: Router<AppState>) -> Self {
+        self.routes.push((prefix.to_string(), routes));
+        self
+    }
+    /// Wraps the whole router, built in routes included, in a tower layer.
+    /// Layers are applied in order, so the last one sees requests first.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request<Body>> + Clone + Send + 'static,
+        <L::Service as Service<Request<Body>>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request<Body>>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |router: Router| router.layer(layer)));
+        self
+    }
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+    /// Job handlers and jobs, run once built.
+    pub fn scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+    /// Task handlers, working the queue once built.
+    pub fn workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn build(self) -> Result<Rocketbase> {
+        let settings = self.settings.unwrap_or_else(|| SETTINGS.clone());
+        let db = match self.pool {
+            Some(pool) => {
+                let db = DB::new_with_pool(pool);
+                db.migrate().await?;
+                db
+            }
+            None => DB::connect(&settings.database).await
+                .suggestion("Ensure that the Database URL environment variable is correct")?,
+        };
+        let mut hooks = self.hooks;
+        let mut routes = self.routes;
+        let plugins = match settings.plugins.enabled {
+            true => {
+                let host = PluginHost::new(settings.plugins.clone(), db.connection())?;
+                host.reload().await.suggestion("Check the plugins section of the settings")?;
+                hooks = host.hooks(hooks);
+                routes.push(("/api/plugins".to_string(), host.router()));
+                Some(host)
+            }
+            false => None,
+        };
+        let (scheduler, queue) = (settings.scheduler, settings.queue);
+        let workers = webhooks::handle_deliveries(self.workers, settings.webhooks);
+        let state = AppState::from_settings(settings, db)?.with_hooks(hooks).with_scheduler(self.scheduler);
+        let workers = auth::handle_token_mail(workers, state.clone());
+        let tasks = state.shutdown();
+        let (pool, token) = (state.db().connection(), tasks.token());
+        tasks.track(state.scheduler().spawn(pool.clone(), scheduler, token.clone()));
+        tasks.track(Arc::new(workers).spawn(pool, queue, token.clone()));
+        if let Some(host) = &plugins {
+            tasks.track(host.spawn_watcher(token));
+        }
+        let extra = routes.into_iter().fold(Router::new(), |extra, (prefix, routes)| {
+            match prefix.trim_end_matches('/') {
+                "" => extra.merge(routes),
+                prefix => extra.nest(prefix, routes),
+            }
+        });
+        let router = self.layers.into_iter().fold(router_with(state.clone(), extra)?, |router, layer| layer(router));
+        Ok(Rocketbase { state, router, plugins })
+    }
+
+    /// Builds and serves, see [`Rocketbase::serve`].
+    pub async fn serve(self) -> Result<()> {
+        self.build().await?.serve().await
+    }
+}
+
+impl std::fmt::Debug for RocketbaseBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RocketbaseBuilder")
+            .field("settings", &self.settings)
+            .field("pool", &self.pool)
+            .field("routes", &self.routes.iter().map(|(prefix, _)| prefix).collect::<Vec<_>>())
+            .field("layers", &self.layers.len())
+            .field("hooks", &self.hooks)
+            .field("scheduler", &self.scheduler)
+            .field("workers", &self.workers)
+            .finish()
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Tells the server and background tasks to stop, and waits for the tasks to
+/// do so. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+  token: CancellationToken,
+  tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+  /// Tasks that ended before shutdown started and are no longer kept.
+  stopped: Arc<AtomicUsize>,
+  started_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Shutdown {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Cancelled once shutdown starts. Background tasks stop taking new work
+  /// when it is, finishing what they are in the middle of.
+  pub fn token(&self) -> CancellationToken {
+    self.token.clone()
+  }
+
+  pub fn start(&self) {
+    self.started_at.lock().unwrap().get_or_insert_with(Instant::now);
+    self.token.cancel();
+  }
+
+  /// When shutdown started, which the time allowed for draining counts from.
+  pub fn started_at(&self) -> Option<Instant> {
+    *self.started_at.lock().unwrap()
+  }
+
+  pub fn is_started(&self) -> bool {
+    self.token.is_cancelled()
+  }
+
+  /// Resolves once shutdown starts.
+  pub async fn started(&self) {
+    self.token.cancelled().await
+  }
+
+  /// Keeps a background task to be waited for when shutting down.
+  pub fn track(&self, task: JoinHandle<()>) {
+    let mut tasks = self.tasks.lock().unwrap();
+    let kept = tasks.len();
+    tasks.retain(|task| !task.is_finished());
+    if !self.is_started() {
+      self.stopped.fetch_add(kept - tasks.len(), Ordering::SeqCst);
+    }
+    tasks.push(task);
+  }
+
+  /// How many background tasks stopped before shutdown started, which they
+  /// only do when they fail.
+  pub fn stopped(&self) -> usize {
+    let finished = match self.is_started() {
+      true => 0,
+      false => self.tasks.lock().unwrap().iter().filter(|task| task.is_finished()).count(),
+    };
+    self.stopped.load(Ordering::SeqCst) + finished
+  }
+
+  /// Starts shutting down and waits up to `timeout` for the background tasks,
+  /// aborting those still running then. Returns whether all of them finished.
+  pub async fn finish(&self, timeout: Duration) -> bool {
+    self.start();
+    let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+    let finished = tokio::time::timeout(timeout, futures_util::future::join_all(tasks.iter_mut())).await.is_ok();
+    if !finished {
+      tasks.iter().for_each(JoinHandle::abort);
+    }
+    finished
+  }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM where there are signals.
+pub async fn signal() {
+  let interrupt = async {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+      tracing::error!("Unable to listen for Ctrl-C: {}", err);
+      std::future::pending::<()>().await;
+    }
+  };
+  #[cfg(unix)]
+  let terminate = async {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+      Ok(mut terminate) => {
+        terminate.recv().await;
+      }
+      Err(err) => {
+        tracing::error!("Unable to listen for SIGTERM: {}", err);
+        std::future::pending::<()>().await;
+      }
+    }
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+  tokio::select! {
+    _ = interrupt => {}
+    _ = terminate => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::Shutdown;
+
+  #[tokio::test]
+  async fn should_wait_for_tasks_until_the_timeout() {
+    let shutdown = Shutdown::new();
+    let token = shutdown.token();
+    shutdown.track(tokio::spawn(async move { token.cancelled().await }));
+    assert!(shutdown.finish(Duration::from_secs(1)).await);
+    assert!(shutdown.is_started());
+
+    let shutdown = Shutdown::new();
+    shutdown.track(tokio::spawn(std::future::pending()));
+    assert!(!shutdown.finish(Duration::from_millis(10)).await);
+  }
+
+  #[tokio::test]
+  async fn should_count_tasks_that_stopped_early() {
+    let shutdown = Shutdown::new();
+    let failed = tokio::spawn(async {});
+    while !failed.is_finished() {
+      tokio::task::yield_now().await;
+    }
+    shutdown.track(failed);
+    assert_eq!(shutdown.stopped(), 1);
+    shutdown.track(tokio::spawn(std::future::pending()));
+    assert_eq!(shutdown.stopped(), 1);
+  }
+}
@@ -0,0 +1,66 @@
+use std::{io::{ErrorKind, SeekFrom}, ops::Range, path::{Path, PathBuf}};
+
+use async_trait::async_trait;
+use color_eyre::{eyre::WrapErr, Result};
+use hyper::{body::Bytes, Body};
+use tokio::{fs::{self, File}, io::{AsyncReadExt, AsyncSeekExt}};
+use tokio_util::io::ReaderStream;
+
+use super::{check_key, Storage};
+use crate::model::user_token::generate_token;
+
+/// Keeps files in a directory of the local filesystem.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+  dir: PathBuf,
+}
+
+impl LocalStorage {
+  pub fn new(dir: impl AsRef<Path>) -> Self {
+    LocalStorage { dir: dir.as_ref().to_path_buf() }
+  }
+
+  fn path(&self, key: &str) -> Result<PathBuf> {
+    check_key(key)?;
+    Ok(self.dir.join(key))
+  }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+  async fn put(&self, key: &str, bytes: Bytes, _content_type: &str) -> Result<()> {
+    let path = self.path(key)?;
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).await.context("Unable to create storage directory")?;
+    }
+    // written under a temporary name first so that readers never see a
+    // partial file
+    let partial = path.with_extension(format!("{}.partial", &generate_token()[..8]));
+    fs::write(&partial, &bytes).await.context("Unable to write file")?;
+    fs::rename(&partial, &path).await.context("Unable to move file into place")?;
+    Ok(())
+  }
+
+  async fn read(&self, key: &str, range: Option<Range<u64>>) -> Result<Option<Body>> {
+    let mut file = match File::open(self.path(key)?).await {
+      Ok(file) => file,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+      Err(err) => return Err(err).context("Unable to open file"),
+    };
+    let body = match range {
+      Some(range) => {
+        file.seek(SeekFrom::Start(range.start)).await.context("Unable to seek in file")?;
+        Body::wrap_stream(ReaderStream::new(file.take(range.end - range.start)))
+      }
+      None => Body::wrap_stream(ReaderStream::new(file)),
+    };
+    Ok(Some(body))
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+    match fs::remove_file(self.path(key)?).await {
+      Err(err) if err.kind() != ErrorKind::NotFound => Err(err).context("Unable to delete file"),
+      _ => Ok(()),
+    }
+  }
+}
@@ -0,0 +1,74 @@
+use std::fmt::Debug;
+use std::ops::Range;
+use std::sync::Arc;
+use async_trait::async_trait;
+use color_eyre::{eyre::{eyre, WrapErr}, Result};
+use hyper::{body::{self, Bytes}, Body};
+
+use crate::settings::{self, StorageBackend};
+
+pub use self::local::LocalStorage;
+pub use self::s3::S3Storage;
+pub use self::signing::FileSigner;
+
+pub(crate) mod local;
+pub(crate) mod s3;
+pub(crate) mod signing;
+pub mod thumbs;
+
+/// Keeps the bytes of uploaded files. Keys are `/` separated paths such as
+/// `posts/3f2a9c_photo.png`. A single instance lives on `AppState`.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+  async fn put(&self, key: &str, bytes: Bytes, content_type: &str) -> Result<()>;
+  /// Streams the object, or the given bytes of it. Returns `None` when there
+  /// is nothing stored under the key. The range must lie within the object.
+  async fn read(&self, key: &str, range: Option<Range<u64>>) -> Result<Option<Body>>;
+  /// Reads the whole object into memory.
+  async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+    match self.read(key, None).await? {
+      Some(body) => Ok(Some(body::to_bytes(body).await.context("Unable to read object")?)),
+      None => Ok(None),
+    }
+  }
+  /// Deleting a key that does not exist is not an error.
+  async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Builds the storage selected by the `storage.backend` setting.
+pub fn from_settings(settings: &settings::Storage) -> Result<Arc<dyn Storage>> {
+  let storage: Arc<dyn Storage> = match settings.backend {
+    StorageBackend::Local => Arc::new(LocalStorage::new(&settings.dir)),
+    StorageBackend::S3 => {
+      let s3 = settings.s3.as_ref()
+        .ok_or_else(|| eyre!("storage.s3 must be configured for the s3 backend"))?;
+      Arc::new(S3Storage::new(s3)?)
+    }
+  };
+  Ok(storage)
+}
+
+/// Rejects keys that could escape the storage root or bucket prefix.
+pub(crate) fn check_key(key: &str) -> Result<()> {
+  let valid = !key.is_empty()
+    && !key.starts_with('/')
+    && key.split('/').all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+  match valid {
+    true => Ok(()),
+    false => Err(eyre!("Invalid storage key {}", key)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::check_key;
+
+  #[test]
+  fn should_reject_keys_outside_the_root() {
+    assert!(check_key("posts/abc_photo.png").is_ok());
+    assert!(check_key("posts/../../etc/passwd").is_err());
+    assert!(check_key("/etc/passwd").is_err());
+    assert!(check_key("posts//photo.png").is_err());
+    assert!(check_key("").is_err());
+  }
+}
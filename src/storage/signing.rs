@@ -0,0 +1,76 @@
+use std::fmt;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::model::user_token::generate_token;
+
+/// Signs file paths into tokens that grant access until they expire, for
+/// places such as `<img>` tags that can not send credentials.
+pub struct FileSigner {
+  key: Vec<u8>,
+  ttl: Duration,
+}
+
+impl fmt::Debug for FileSigner {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("FileSigner").field("ttl", &self.ttl).finish_non_exhaustive()
+  }
+}
+
+impl FileSigner {
+  /// Without a secret a random one is used, so tokens do not outlive the
+  /// process and are not accepted by other instances.
+  pub fn new(secret: Option<&str>, ttl: Duration) -> Self {
+    let key = match secret {
+      Some(secret) => secret.as_bytes().to_vec(),
+      None => {
+        tracing::warn!("storage.signing_secret is not set, file tokens only work on this instance until it restarts");
+        generate_token().into_bytes()
+      }
+    };
+    FileSigner { key, ttl }
+  }
+
+  fn mac(&self, path: &str, expires: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}:{}", path, expires).as_bytes());
+    mac
+  }
+
+  /// Returns a token for `path` and when it expires.
+  pub fn sign(&self, path: &str, now: DateTime<Utc>) -> (String, DateTime<Utc>) {
+    let expires = (now + self.ttl).timestamp();
+    let signature = hex::encode(self.mac(path, expires).finalize().into_bytes());
+    (format!("{}.{}", expires, signature), Utc.timestamp_opt(expires, 0).unwrap())
+  }
+
+  pub fn verify(&self, path: &str, token: &str, now: DateTime<Utc>) -> bool {
+    let Some((expires, signature)) = token.split_once('.') else { return false };
+    let (Ok(expires), Ok(signature)) = (expires.parse::<i64>(), hex::decode(signature)) else { return false };
+    expires > now.timestamp() && self.mac(path, expires).verify_slice(&signature).is_ok()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::{Duration, Utc};
+
+  use super::FileSigner;
+
+  #[test]
+  fn should_accept_only_fresh_tokens_for_the_signed_path() {
+    let signer = FileSigner::new(Some("secret"), Duration::seconds(60));
+    let now = Utc::now();
+    let (token, expires) = signer.sign("posts/1/a.png", now);
+    assert_eq!(expires.timestamp(), (now + Duration::seconds(60)).timestamp());
+    assert!(signer.verify("posts/1/a.png", &token, now));
+    assert!(!signer.verify("posts/2/a.png", &token, now));
+    assert!(!signer.verify("posts/1/a.png", &token, now + Duration::seconds(61)));
+    assert!(!FileSigner::new(Some("other"), Duration::seconds(60)).verify("posts/1/a.png", &token, now));
+    let forged = format!("{}{}", expires.timestamp() + 3600, &token[token.find('.').unwrap()..]);
+    assert!(!signer.verify("posts/1/a.png", &forged, now));
+    assert!(!signer.verify("posts/1/a.png", "garbage", now));
+  }
+}
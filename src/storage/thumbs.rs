@@ -0,0 +1,198 @@
+use std::{
+  collections::{HashMap, HashSet},
+  io::Cursor,
+  sync::{Arc, Mutex},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use image::{imageops::FilterType, io::{Limits, Reader}, DynamicImage, GenericImageView, ImageFormat};
+use tokio::sync::{OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+
+use crate::model::file::{Thumb, ThumbMode};
+
+/// Image types thumbnails are made of, each kept in its own format.
+pub const THUMB_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+const FILTER: FilterType = FilterType::Lanczos3;
+/// Largest width or height of an image thumbnails are made of.
+const MAX_IMAGE_SIZE: u32 = 10_000;
+/// Most memory decoding an image may take.
+const MAX_DECODE_BYTES: u64 = 256 * 1024 * 1024;
+/// Keys of thumbnails that could not be made are forgotten past this many.
+const MAX_FAILED: usize = 4096;
+
+/// Keeps thumbnail renders in check: one render per key at a time, as many
+/// at a time overall as there are CPUs, and none for keys that failed
+/// before.
+#[derive(Debug)]
+pub struct Renders {
+  slots: Arc<Semaphore>,
+  in_progress: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+  failed: Mutex<HashSet<String>>,
+}
+
+/// Held while rendering a thumbnail, see [`Renders::start`].
+pub struct RenderGuard<'a> {
+  renders: &'a Renders,
+  key: String,
+  lock: Option<OwnedMutexGuard<()>>,
+  _slot: OwnedSemaphorePermit,
+}
+
+impl Default for Renders {
+  fn default() -> Self {
+    Self::new(std::thread::available_parallelism().map_or(2, |cpus| cpus.get()))
+  }
+}
+
+impl Renders {
+  pub fn new(concurrency: usize) -> Self {
+    Renders { slots: Arc::new(Semaphore::new(concurrency)), in_progress: Mutex::default(), failed: Mutex::default() }
+  }
+
+  /// Whether the thumbnail at `key` could not be made before.
+  pub fn failed(&self, key: &str) -> bool {
+    self.failed.lock().unwrap().contains(key)
+  }
+
+  /// Remembers that the thumbnail at `key` can not be made.
+  pub fn fail(&self, key: &str) {
+    let mut failed = self.failed.lock().unwrap();
+    if failed.len() >= MAX_FAILED {
+      failed.clear();
+    }
+    failed.insert(key.to_string());
+  }
+
+  /// Waits until nobody else renders `key` and a slot is free. Whoever
+  /// waited should check whether the thumbnail was made meanwhile.
+  pub async fn start(&self, key: &str) -> RenderGuard<'_> {
+    let lock = self.in_progress.lock().unwrap().entry(key.to_string()).or_default().clone();
+    let lock = lock.lock_owned().await;
+    let slot = self.slots.clone().acquire_owned().await.expect("the semaphore is never closed");
+    RenderGuard { renders: self, key: key.to_string(), lock: Some(lock), _slot: slot }
+  }
+}
+
+impl Drop for RenderGuard<'_> {
+  fn drop(&mut self) {
+    let mut in_progress = self.renders.in_progress.lock().unwrap();
+    self.lock.take();
+    // nobody else is waiting for the key
+    if in_progress.get(&self.key).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+      in_progress.remove(&self.key);
+    }
+  }
+}
+
+/// Renders a thumbnail of an image. Returns `None` when the bytes are not an
+/// image of one of [`THUMB_TYPES`], so that the original can be served
+/// instead. This is CPU bound and belongs on a blocking thread.
+pub fn render(bytes: &[u8], thumb: &Thumb) -> Result<Option<Vec<u8>>> {
+  let format = match image::guess_format(bytes) {
+    Ok(format) if THUMB_TYPES.contains(&format.to_mime_type()) => format,
+    _ => return Ok(None),
+  };
+  let mut limits = Limits::default();
+  limits.max_image_width = Some(MAX_IMAGE_SIZE);
+  limits.max_image_height = Some(MAX_IMAGE_SIZE);
+  limits.max_alloc = Some(MAX_DECODE_BYTES);
+  let mut reader = Reader::with_format(Cursor::new(bytes), format);
+  reader.limits(limits);
+  let image = match reader.decode() {
+    Ok(image) => image,
+    Err(err) => {
+      tracing::debug!("Not making a thumbnail of an unreadable image: {}", err);
+      return Ok(None);
+    }
+  };
+  let resized = resize(&image, thumb);
+  // JPEG has no alpha channel
+  let resized = match format {
+    ImageFormat::Jpeg => DynamicImage::ImageRgb8(resized.to_rgb8()),
+    _ => resized,
+  };
+  let mut out = Cursor::new(Vec::new());
+  resized.write_to(&mut out, format).context("Unable to encode thumbnail")?;
+  Ok(Some(out.into_inner()))
+}
+
+fn resize(image: &DynamicImage, thumb: &Thumb) -> DynamicImage {
+  let Thumb { width, height, mode } = *thumb;
+  match (width, height, mode) {
+    (0, _, _) => image.resize(u32::MAX, height, FILTER),
+    (_, 0, _) => image.resize(width, u32::MAX, FILTER),
+    (_, _, ThumbMode::Fit) => image.resize(width, height, FILTER),
+    _ => {
+      // scale to cover the size, then cut away what sticks out
+      let (iw, ih) = image.dimensions();
+      let scale = f64::max(width as f64 / iw as f64, height as f64 / ih as f64);
+      let sw = ((iw as f64 * scale).round() as u32).max(width);
+      let sh = ((ih as f64 * scale).round() as u32).max(height);
+      let scaled = image.resize_exact(sw, sh, FILTER);
+      let y = match mode {
+        ThumbMode::CropTop => 0,
+        ThumbMode::CropBottom => sh - height,
+        _ => (sh - height) / 2,
+      };
+      scaled.crop_imm((sw - width) / 2, y, width, height)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use image::{DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage};
+
+  use super::{render, Renders};
+
+  fn dimensions(bytes: &[u8]) -> (u32, u32) {
+    image::load_from_memory(bytes).unwrap().dimensions()
+  }
+
+  #[test]
+  fn should_resize_in_each_mode() {
+    // top half red, bottom half blue
+    let image = RgbImage::from_fn(400, 200, |_, y| if y < 100 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) });
+    let mut png = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image).write_to(&mut png, ImageFormat::Png).unwrap();
+    let png = png.into_inner();
+    let thumb = |spec: &str| render(&png, &spec.parse().unwrap()).unwrap().unwrap();
+
+    assert_eq!(dimensions(&thumb("100x100")), (100, 100));
+    assert_eq!(dimensions(&thumb("100x100f")), (100, 50));
+    assert_eq!(dimensions(&thumb("0x50")), (100, 50));
+    assert_eq!(dimensions(&thumb("50x0")), (50, 25));
+    let top = image::load_from_memory(&thumb("40x10t")).unwrap().to_rgb8();
+    assert_eq!(top.get_pixel(20, 5), &Rgb([255, 0, 0]));
+    let bottom = image::load_from_memory(&thumb("40x10b")).unwrap().to_rgb8();
+    assert_eq!(bottom.get_pixel(20, 5), &Rgb([0, 0, 255]));
+    assert_eq!(image::guess_format(&thumb("10x10")).unwrap(), ImageFormat::Png);
+    assert!(render(b"%PDF-1.4", &"10x10".parse().unwrap()).unwrap().is_none());
+  }
+
+  #[test]
+  fn should_not_decode_huge_images() {
+    let mut png = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(RgbImage::new(20_000, 1)).write_to(&mut png, ImageFormat::Png).unwrap();
+    assert!(render(&png.into_inner(), &"10x10".parse().unwrap()).unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn should_render_a_key_once_at_a_time() {
+    let renders = Renders::new(4);
+    let first = renders.start("a").await;
+    // other keys go ahead
+    drop(renders.start("b").await);
+    let second = tokio::time::timeout(std::time::Duration::from_millis(50), renders.start("a")).await;
+    assert!(second.is_err());
+    drop(first);
+    drop(renders.start("a").await);
+    assert!(renders.in_progress.lock().unwrap().is_empty());
+
+    renders.fail("a");
+    assert!(renders.failed("a") && !renders.failed("b"));
+  }
+}
@@ -0,0 +1,275 @@
+//! Export of spans to an OpenTelemetry collector over OTLP. Requests continue
+//! the trace of their W3C `traceparent` header, and every query sqlx logs
+//! becomes a client span of the span it ran in, rather than an event of it.
+use std::time::{Duration, SystemTime};
+
+use axum::{extract::MatchedPath, http::{HeaderMap, Request}};
+use color_eyre::{eyre::WrapErr, Result};
+use opentelemetry::{
+  propagation::{Extractor, TextMapPropagator},
+  runtime,
+  sdk::{propagation::TraceContextPropagator, trace, Resource},
+  trace::{Span as _, SpanKind, Tracer as _},
+  KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::{field::{Field, Visit}, Event, Level, Metadata, Span, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_opentelemetry::{OpenTelemetrySpanExt, OtelData, PreSampledTracer};
+use tracing_subscriber::{
+  filter::{EnvFilter, FilterExt, Targets},
+  layer::{Context, Filter, Layer},
+  registry::LookupSpan,
+};
+
+use crate::settings;
+
+/// The target sqlx logs queries with.
+const QUERY_TARGET: &str = "sqlx::query";
+
+/// A layer exporting the spans `filter` lets through to the collector of
+/// `config`, along with the queries run within them. Spans are sent in
+/// batches from a thread of their own.
+pub fn layer<S>(config: &settings::Otlp, filter: &str) -> Result<impl Layer<S>>
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  let exporter = opentelemetry_otlp::new_exporter().tonic().with_endpoint(&config.endpoint);
+  let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+  let tracer = opentelemetry_otlp::new_pipeline()
+    .tracing()
+    .with_exporter(exporter)
+    .with_trace_config(trace::config().with_resource(resource))
+    .install_batch(runtime::TokioCurrentThread)
+    .context("Unable to set up the OTLP exporter")?;
+  // queries are traced even when they are not logged
+  let filter = EnvFilter::try_new(filter)?.or(Targets::new().with_target(QUERY_TARGET, Level::INFO));
+  let spans = tracing_opentelemetry::layer().with_tracer(tracer.clone()).with_filter(NotQueries);
+  Ok(spans.and_then(QuerySpans { tracer }).with_filter(filter))
+}
+
+/// Sends the spans that have not been exported yet. Blocks until they are.
+pub fn shutdown() {
+  opentelemetry::global::shutdown_tracer_provider();
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).and_then(|value| value.to_str().ok())
+  }
+  fn keys(&self) -> Vec<&str> {
+    self.0.keys().map(|key| key.as_str()).collect()
+  }
+}
+
+/// The span of a request, continuing the trace of its `traceparent` header
+/// when there is one. It is named after the route rather than the path, so
+/// that ids in paths don't make every name unique.
+pub fn request_span<B>(req: &Request<B>) -> Span {
+  let name = match req.extensions().get::<MatchedPath>() {
+    Some(route) => format!("{} {}", req.method(), route.as_str()),
+    None => req.method().to_string(),
+  };
+  let span = tracing::info_span!(
+    "request",
+    otel.name = %name,
+    otel.kind = "server",
+    method = %req.method(),
+    // without the query, which carries tokens for SSE, WebSockets and files
+    path = %req.uri().path(),
+    version = ?req.version(),
+  );
+  span.set_parent(TraceContextPropagator::new().extract(&Headers(req.headers())));
+  span
+}
+
+fn is_query(event: &Event<'_>) -> bool {
+  // sqlx logs through `log`, whose target is kept aside
+  event.normalized_metadata().map_or(event.metadata().target(), |metadata| metadata.target()) == QUERY_TARGET
+}
+
+/// Keeps the queries sqlx logs from being exported as events of the span
+/// they ran in as well, since [`QuerySpans`] exports them as spans.
+struct NotQueries;
+
+impl<S> Filter<S> for NotQueries {
+  fn enabled(&self, _metadata: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+    true
+  }
+  fn event_enabled(&self, event: &Event<'_>, _cx: &Context<'_, S>) -> bool {
+    !is_query(event)
+  }
+}
+
+/// A query as sqlx logs it.
+#[derive(Debug, PartialEq)]
+struct Query {
+  statement: String,
+  /// The first keyword, such as `SELECT`.
+  operation: String,
+  rows_affected: Option<i64>,
+  rows_returned: Option<i64>,
+  elapsed: Duration,
+}
+
+impl Query {
+  /// Reads a message such as `select 1; rows affected: 0, rows returned: 1,
+  /// elapsed: 1.020ms`, followed by the whole statement when the summary
+  /// cuts it short.
+  fn parse(message: &str) -> Option<Self> {
+    let (head, statement) = match message.split_once("\n\n") {
+      Some((head, statement)) => (head, Some(statement.trim())),
+      None => (message, None),
+    };
+    let (summary, counts) = head.rsplit_once("; rows affected: ")?;
+    let (rows_affected, rest) = counts.split_once(", rows returned: ")?;
+    let (rows_returned, elapsed) = rest.split_once(", elapsed: ")?;
+    Some(Query {
+      statement: statement.unwrap_or(summary).to_string(),
+      operation: summary.split_whitespace().next().unwrap_or("query").to_uppercase(),
+      rows_affected: Some(rows_affected.parse().ok()?),
+      rows_returned: Some(rows_returned.parse().ok()?),
+      elapsed: parse_duration(elapsed.trim())?,
+    })
+  }
+
+  /// A query whose message could not be read, as when sqlx words it
+  /// differently, kept whole rather than dropped.
+  fn unparsed(message: &str) -> Self {
+    Query {
+      statement: message.to_string(),
+      operation: "QUERY".to_string(),
+      rows_affected: None,
+      rows_returned: None,
+      elapsed: Duration::ZERO,
+    }
+  }
+}
+
+/// Reads a duration as `Debug` prints it, such as `1.020ms`.
+fn parse_duration(duration: &str) -> Option<Duration> {
+  let (value, nanos) = [("ns", 1.0), ("µs", 1e3), ("ms", 1e6), ("s", 1e9)].into_iter()
+    .find_map(|(unit, nanos)| duration.strip_suffix(unit).map(|value| (value, nanos)))?;
+  Some(Duration::from_nanos((value.parse::<f64>().ok()? * nanos).round() as u64))
+}
+
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+  fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+    if field.name() == "message" {
+      self.0 = format!("{:?}", value);
+    }
+  }
+}
+
+/// Turns the queries sqlx logs once they finish into spans that started
+/// when they did.
+struct QuerySpans {
+  tracer: trace::Tracer,
+}
+
+impl<S> Layer<S> for QuerySpans
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+    if !is_query(event) {
+      return;
+    }
+    let mut message = Message::default();
+    event.record(&mut message);
+    let query = Query::parse(&message.0).unwrap_or_else(|| Query::unparsed(&message.0));
+    let parent = ctx.event_span(event).and_then(|span| {
+      let mut extensions = span.extensions_mut();
+      extensions.get_mut::<OtelData>().map(|data| self.tracer.sampled_context(data))
+    });
+    let end = SystemTime::now();
+    let mut attributes = vec![
+      KeyValue::new("db.system", "postgresql"),
+      KeyValue::new("db.operation", query.operation.clone()),
+      KeyValue::new("db.statement", query.statement),
+    ];
+    if let Some(rows_affected) = query.rows_affected {
+      attributes.push(KeyValue::new("db.rows_affected", rows_affected));
+    }
+    if let Some(rows_returned) = query.rows_returned {
+      attributes.push(KeyValue::new("db.rows_returned", rows_returned));
+    }
+    let mut span = self.tracer
+      .span_builder(query.operation)
+      .with_kind(SpanKind::Client)
+      .with_start_time(end.checked_sub(query.elapsed).unwrap_or(end))
+      .with_attributes(attributes)
+      .start_with_context(&self.tracer, &parent.unwrap_or_default());
+    span.end_with_timestamp(end);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::{Arc, Mutex}, time::Duration};
+
+  use sqlx::PgPool;
+  use tracing::{Event, Subscriber};
+  use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+  use super::{is_query, Message, Query};
+
+  /// Keeps the messages of the queries sqlx logs.
+  struct Logged(Arc<Mutex<Vec<String>>>);
+
+  impl<S: Subscriber> Layer<S> for Logged {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+      if is_query(event) {
+        let mut message = Message::default();
+        event.record(&mut message);
+        self.0.lock().unwrap().push(message.0);
+      }
+    }
+  }
+
+  #[test]
+  fn should_parse_logged_queries() {
+    let query = Query::parse("select 1; rows affected: 0, rows returned: 1, elapsed: 1.020ms").unwrap();
+    assert_eq!(query, Query {
+      statement: "select 1".to_string(),
+      operation: "SELECT".to_string(),
+      rows_affected: Some(0),
+      rows_returned: Some(1),
+      elapsed: Duration::from_micros(1020),
+    });
+    assert!(Query::parse("Unable to connect").is_none());
+    let unparsed = Query::unparsed("select 1; took 1.020ms");
+    assert_eq!((unparsed.statement.as_str(), unparsed.operation.as_str()), ("select 1; took 1.020ms", "QUERY"));
+  }
+
+  #[sqlx::test]
+  async fn should_parse_what_sqlx_logs(pool: PgPool) {
+    // sqlx logs through `log`, which may already be forwarded by another test
+    let _ = tracing_log::LogTracer::init();
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry().with(Logged(logged.clone()));
+    {
+      let _default = tracing::subscriber::set_default(subscriber);
+      sqlx::query("select 1").execute(&pool).await.unwrap();
+      sqlx::query("update _tasks set attempts = attempts where kind = $1 and status = 'pending' and run_at <= now()")
+        .bind("none")
+        .execute(&pool).await.unwrap();
+    }
+
+    let logged = logged.lock().unwrap();
+    let queries: Vec<_> = logged.iter().map(|message| Query::parse(message).unwrap_or_else(|| panic!("unreadable: {:?}", message))).collect();
+    let select = queries.iter().find(|query| query.statement == "select 1").expect("select was not logged");
+    assert_eq!((select.operation.as_str(), select.rows_affected, select.rows_returned), ("SELECT", Some(1), Some(1)));
+    // long statements are cut short in the summary and logged in full below it
+    let update = queries.iter().find(|query| query.operation == "UPDATE").expect("update was not logged");
+    assert!(update.statement.starts_with("update") && update.statement.contains('\n'), "{:?}", update.statement);
+    assert!(update.statement.contains("run_at <= now()"), "{:?}", update.statement);
+    assert!(!update.statement.contains('…'));
+    assert_eq!((update.rows_affected, update.rows_returned), (Some(0), Some(0)));
+  }
+}
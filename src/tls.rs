@@ -0,0 +1,252 @@
+//! HTTPS with rustls. The certificate is read again whenever its files
+//! change, so that renewals take effect on the next handshake without a
+//! restart.
+use std::{
+  fmt,
+  fs::File,
+  io::{self, BufReader},
+  net::SocketAddr,
+  pin::Pin,
+  sync::{Arc, Mutex, RwLock},
+  task::{Context, Poll},
+  time::{Duration, SystemTime},
+};
+
+use axum::{
+  extract::connect_info::Connected,
+  http::{header, HeaderMap, StatusCode, Uri},
+  response::{IntoResponse, Redirect, Response},
+  Router,
+};
+use color_eyre::{eyre::{bail, WrapErr}, Result};
+use hyper::server::accept::Accept;
+use tokio::{
+  io::{AsyncRead, AsyncWrite, ReadBuf},
+  net::{TcpListener, TcpStream},
+  sync::mpsc,
+  task::JoinHandle,
+};
+use tokio_rustls::{
+  rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+  },
+  server::TlsStream,
+  TlsAcceptor,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::settings;
+
+/// How long a client gets to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn modified(path: &str) -> Result<SystemTime> {
+  let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified());
+  modified.with_context(|| format!("Unable to read {}", path))
+}
+
+/// Reads the certificate chain and private key of `config`.
+pub fn load_key(config: &settings::Tls) -> Result<CertifiedKey> {
+  let mut reader = BufReader::new(File::open(&config.cert_path).with_context(|| format!("Unable to open {}", config.cert_path))?);
+  let certs = rustls_pemfile::certs(&mut reader).context("Invalid certificate")?;
+  if certs.is_empty() {
+    bail!("No certificate in {}", config.cert_path);
+  }
+  let mut reader = BufReader::new(File::open(&config.key_path).with_context(|| format!("Unable to open {}", config.key_path))?);
+  let key = rustls_pemfile::read_all(&mut reader).context("Invalid private key")?.into_iter().find_map(|item| match item {
+    rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(key),
+    _ => None,
+  });
+  let Some(key) = key else {
+    bail!("No private key in {}", config.key_path);
+  };
+  let key = sign::any_supported_type(&PrivateKey(key)).context("Unsupported private key")?;
+  Ok(CertifiedKey::new(certs.into_iter().map(Certificate).collect(), key))
+}
+
+/// Hands out the current certificate to every handshake.
+pub struct CertResolver {
+  config: settings::Tls,
+  key: RwLock<Arc<CertifiedKey>>,
+  /// Modification times of the certificate and key files that were loaded.
+  loaded: Mutex<(SystemTime, SystemTime)>,
+}
+
+impl CertResolver {
+  pub fn new(config: settings::Tls) -> Result<Arc<Self>> {
+    let loaded = (modified(&config.cert_path)?, modified(&config.key_path)?);
+    let key = load_key(&config)?;
+    Ok(Arc::new(Self { config, key: RwLock::new(Arc::new(key)), loaded: Mutex::new(loaded) }))
+  }
+
+  /// Loads the certificate again when its files changed since, returning
+  /// whether it did. The current certificate stays when they don't load,
+  /// such as while only one of them has been replaced.
+  pub fn reload(&self) -> Result<bool> {
+    let current = (modified(&self.config.cert_path)?, modified(&self.config.key_path)?);
+    if *self.loaded.lock().unwrap() == current {
+      return Ok(false);
+    }
+    let key = load_key(&self.config)?;
+    *self.key.write().unwrap() = Arc::new(key);
+    *self.loaded.lock().unwrap() = current;
+    tracing::info!("Reloaded the certificate from {}", self.config.cert_path);
+    Ok(true)
+  }
+
+  /// Checks for new certificates every `tls.reload_interval_ms` until
+  /// shutdown.
+  pub fn spawn_reloader(self: &Arc<Self>, shutdown: CancellationToken) -> JoinHandle<()> {
+    let resolver = self.clone();
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(Duration::from_millis(resolver.config.reload_interval_ms));
+      interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+      loop {
+        tokio::select! {
+          _ = shutdown.cancelled() => break,
+          _ = interval.tick() => {}
+        }
+        if let Err(err) = resolver.reload() {
+          tracing::error!("{:?}", err);
+        }
+      }
+    })
+  }
+}
+
+impl ResolvesServerCert for CertResolver {
+  fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+    Some(self.key.read().unwrap().clone())
+  }
+}
+
+impl fmt::Debug for CertResolver {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("CertResolver").field("config", &self.config).field("loaded", &self.loaded).finish()
+  }
+}
+
+/// Server settings offering HTTP/2 and HTTP/1.1.
+pub fn server_config(resolver: Arc<CertResolver>) -> Arc<ServerConfig> {
+  let mut config = ServerConfig::builder()
+    .with_safe_defaults()
+    .with_no_client_auth()
+    .with_cert_resolver(resolver);
+  config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+  Arc::new(config)
+}
+
+/// A TLS connection that knows the address of its client, for `ConnectInfo`.
+#[derive(Debug)]
+pub struct TlsConnection {
+  stream: TlsStream<TcpStream>,
+  remote: SocketAddr,
+}
+
+impl AsyncRead for TlsConnection {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.stream).poll_read(cx, buf)
+  }
+}
+
+impl AsyncWrite for TlsConnection {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.stream).poll_write(cx, buf)
+  }
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.stream).poll_flush(cx)
+  }
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.stream).poll_shutdown(cx)
+  }
+}
+
+impl Connected<&TlsConnection> for SocketAddr {
+  fn connect_info(target: &TlsConnection) -> Self {
+    target.remote
+  }
+}
+
+/// Accepts connections on `listener`, doing handshakes concurrently so that
+/// slow clients don't hold up the others. Stops accepting once the server
+/// stops taking connections.
+pub fn incoming(listener: TcpListener, config: Arc<ServerConfig>) -> impl Accept<Conn = TlsConnection, Error = io::Error> {
+  let acceptor = TlsAcceptor::from(config);
+  let (sender, receiver) = mpsc::channel(32);
+  tokio::spawn(async move {
+    loop {
+      let accepted = tokio::select! {
+        _ = sender.closed() => break,
+        accepted = listener.accept() => accepted,
+      };
+      let (stream, remote) = match accepted {
+        Ok(accepted) => accepted,
+        Err(err) => {
+          // such as running out of file descriptors, which may pass
+          tracing::error!("Unable to accept connection: {}", err);
+          tokio::time::sleep(Duration::from_millis(100)).await;
+          continue;
+        }
+      };
+      let (acceptor, sender) = (acceptor.clone(), sender.clone());
+      tokio::spawn(async move {
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+          Ok(Ok(stream)) => {
+            let _ = sender.send(Ok(TlsConnection { stream, remote })).await;
+          }
+          Ok(Err(err)) => tracing::debug!("TLS handshake with {} failed: {}", remote, err),
+          Err(_) => tracing::debug!("TLS handshake with {} timed out", remote),
+        }
+      });
+    }
+  });
+  hyper::server::accept::from_stream(ReceiverStream::new(receiver))
+}
+
+/// Redirects every request to the same path over HTTPS on `https_port`.
+pub fn redirect_router(https_port: u16) -> Router {
+  Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move { redirect(&headers, &uri, https_port) })
+}
+
+fn redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+  let Some(host) = headers.get(header::HOST).and_then(|host| host.to_str().ok()) else {
+    return StatusCode::BAD_REQUEST.into_response();
+  };
+  // the port is dropped, leaving IPv6 addresses in their brackets
+  let host = match host.rsplit_once(':') {
+    Some((name, port)) if !port.contains(']') => name,
+    _ => host,
+  };
+  let path = uri.path_and_query().map_or("/", |path| path.as_str());
+  let location = match https_port {
+    443 => format!("https://{}{}", host, path),
+    port => format!("https://{}:{}{}", host, port, path),
+  };
+  Redirect::permanent(&location).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+  use axum::http::{header, HeaderMap, StatusCode, Uri};
+
+  use super::redirect;
+
+  fn location(host: &str, uri: &str, port: u16) -> String {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::HOST, host.parse().unwrap());
+    let response = redirect(&headers, &uri.parse::<Uri>().unwrap(), port);
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    response.headers()[header::LOCATION].to_str().unwrap().to_string()
+  }
+
+  #[test]
+  fn should_redirect_to_the_https_port() {
+    assert_eq!(location("example.com", "/api/health?x=1", 443), "https://example.com/api/health?x=1");
+    assert_eq!(location("example.com:8080", "/", 8443), "https://example.com:8443/");
+    assert_eq!(location("[::1]:80", "/a", 443), "https://[::1]/a");
+    assert_eq!(location("[::1]", "/a", 443), "https://[::1]/a");
+  }
+}
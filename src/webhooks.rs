@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use color_eyre::{eyre::eyre, Result};
+use hmac::{Hmac, Mac};
+use hyper::{header, Body, Client, Method, Request};
+use hyper_rustls::HttpsConnectorBuilder;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  auth::oauth::HttpClient,
+  model::webhook::{Delivery, DueDelivery},
+  queue::{TaskContext, TaskPayload, Workers},
+  settings,
+};
+
+pub const EVENT_HEADER: &str = "x-rocketbase-event";
+pub const DELIVERY_HEADER: &str = "x-rocketbase-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-rocketbase-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-rocketbase-signature";
+
+/// Sends a delivery. Tasks of this kind are queued along with deliveries,
+/// by the change trigger among others, and retried like any task.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeliverWebhook {
+  pub delivery_id: i64,
+}
+
+impl TaskPayload for DeliverWebhook {
+  const KIND: &'static str = "webhook_delivery";
+}
+
+/// Signature sent in [`SIGNATURE_HEADER`]: `sha256=` followed by the hex
+/// HMAC-SHA256 of `{timestamp}.{body}` keyed with the webhook secret.
+/// Receivers should also check that the timestamp is recent.
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+  mac.update(format!("{}.", timestamp).as_bytes());
+  mac.update(body);
+  format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Posts the payload, returning the response status and why the attempt
+/// failed, if it did.
+async fn send(client: &HttpClient, due: &DueDelivery, timeout: Duration) -> (Option<u16>, Option<String>) {
+  let delivery = &due.delivery;
+  let body = serde_json::to_vec(&delivery.payload.0).unwrap_or_default();
+  let timestamp = Utc::now().timestamp();
+  let request = Request::builder()
+    .method(Method::POST)
+    .uri(&due.url)
+    .header(header::CONTENT_TYPE, "application/json")
+    .header(header::USER_AGENT, "Rocketbase-Webhooks")
+    .header(EVENT_HEADER, &delivery.event)
+    .header(DELIVERY_HEADER, delivery.id)
+    .header(TIMESTAMP_HEADER, timestamp)
+    .header(SIGNATURE_HEADER, signature(&due.secret, timestamp, &body))
+    .body(Body::from(body));
+  let request = match request {
+    Ok(request) => request,
+    Err(err) => return (None, Some(format!("Invalid request: {}", err))),
+  };
+  match tokio::time::timeout(timeout, client.request(request)).await {
+    Ok(Ok(response)) if response.status().is_success() => (Some(response.status().as_u16()), None),
+    Ok(Ok(response)) => (Some(response.status().as_u16()), Some(format!("Responded with {}", response.status()))),
+    Ok(Err(err)) => (None, Some(err.to_string())),
+    Err(_) => (None, Some("Timed out".to_string())),
+  }
+}
+
+async fn deliver(client: &HttpClient, config: &settings::Webhooks, task: DeliverWebhook, context: TaskContext) -> Result<()> {
+  let Some(due) = Delivery::find_due(&context.pool, task.delivery_id).await? else {
+    return Ok(());
+  };
+  if !due.active {
+    return Delivery::cancel(&context.pool, due.delivery.id, "Webhook is inactive").await;
+  }
+  let (response_status, error) = send(client, &due, Duration::from_secs(config.timeout_secs)).await;
+  Delivery::record_attempt(&context.pool, due.delivery.id, response_status, error.as_deref(), context.last_attempt).await?;
+  match error {
+    Some(error) => Err(eyre!(error)),
+    None => Ok(()),
+  }
+}
+
+/// Adds the handler sending webhook deliveries to `workers`.
+pub fn handle_deliveries(workers: Workers, config: settings::Webhooks) -> Workers {
+  let connector = HttpsConnectorBuilder::new()
+    .with_webpki_roots()
+    .https_or_http()
+    .enable_http1()
+    .build();
+  let client: HttpClient = Client::builder().build(connector);
+  workers.handle(move |task: DeliverWebhook, context| {
+    let client = client.clone();
+    async move { deliver(&client, &config, task, context).await }
+  })
+}
+
+/// Prunes the delivery log of finished deliveries past their retention until
+/// shutdown.
+pub fn spawn_pruner(pool: PgPool, config: settings::Webhooks, shutdown: CancellationToken) -> JoinHandle<()> {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.prune_interval_secs));
+    while !pool.is_closed() {
+      tokio::select! {
+        _ = shutdown.cancelled() => break,
+        _ = interval.tick() => {}
+      }
+      match Delivery::prune(&pool, chrono::Duration::seconds(config.retention_secs)).await {
+        Ok(0) => {}
+        Ok(pruned) => tracing::info!("Pruned {} webhook deliveries", pruned),
+        Err(err) => tracing::error!("{:?}", err),
+      }
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::signature;
+
+  #[test]
+  fn should_sign_timestamp_and_body() {
+    let signed = signature("secret", 1700000000, b"{}");
+    assert!(signed.starts_with("sha256="));
+    assert_eq!(signed.len(), "sha256=".len() + 64);
+    assert_ne!(signed, signature("secret", 1700000001, b"{}"));
+    assert_ne!(signed, signature("other", 1700000000, b"{}"));
+  }
+}
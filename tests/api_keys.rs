@@ -0,0 +1,62 @@
+use http::StatusCode;
+use librocketbase::{
+    app_state::AppState,
+    auth::API_KEY_HEADER,
+    db::DB,
+    model::ApiKey,
+    router::{api_keys::CreatedApiKey, build_router},
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+mod common;
+use common::{request, session_for};
+
+#[sqlx::test]
+async fn test_admins_manage_api_keys(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let admin = session_for(&mut router, "admin@example.com").await;
+    let user = session_for(&mut router, "user@example.com").await;
+    sqlx::query("update users set admin = true where email = 'admin@example.com'").execute(&pool).await.unwrap();
+
+    let new_key = json!({"name": "nightly", "scopes": [{"collection": "posts", "operations": ["list", "view"]}]});
+    let (status, _) = request(&mut router, "POST", "/admin/api-keys", Some(&user), new_key.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, created) = request(&mut router, "POST", "/admin/api-keys", Some(&admin), new_key).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let created: CreatedApiKey = serde_json::from_value(created).unwrap();
+    assert!(created.key.starts_with(&created.api_key.prefix));
+    assert!(created.api_key.last_used_at.is_none());
+
+    let (status, _) = request(&mut router, "GET", "/users", Some(&(API_KEY_HEADER, created.key.clone())), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    // keys are not users and can not manage other keys
+    let (status, _) = request(&mut router, "GET", "/auth/me", Some(&(API_KEY_HEADER, created.key.clone())), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = request(&mut router, "GET", "/users", Some(&(API_KEY_HEADER, "rb_bogus".into())), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, listed) = request(&mut router, "GET", "/admin/api-keys", Some(&admin), Value::Null).await;
+    let listed: Vec<ApiKey> = serde_json::from_value(listed).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert!(listed[0].last_used_at.is_some());
+    assert!(!listed[0].allows("comments", librocketbase::model::collection::Operation::View));
+
+    let uri = format!("/admin/api-keys/{}", created.api_key.id);
+    assert_eq!(request(&mut router, "DELETE", &uri, Some(&admin), Value::Null).await.0, StatusCode::NO_CONTENT);
+    assert_eq!(request(&mut router, "DELETE", &uri, Some(&admin), Value::Null).await.0, StatusCode::NOT_FOUND);
+    let (status, _) = request(&mut router, "GET", "/users", Some(&(API_KEY_HEADER, created.key)), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn test_expired_api_keys_are_rejected(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let new_key = serde_json::from_value(json!({
+        "name": "old", "scopes": [{"collection": "*", "operations": ["view"]}], "expires_at": "2020-01-01T00:00:00Z"
+    })).unwrap();
+    let (_, key) = ApiKey::create(&pool, &new_key, None).await.unwrap();
+
+    let (status, _) = request(&mut router, "GET", "/users", Some(&(API_KEY_HEADER, key)), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use axum::{body::{Body, HttpBody}, http::Request, Router};
+use chrono::Duration as ChronoDuration;
+use http::{header, StatusCode};
+use librocketbase::{
+    app_state::AppState,
+    db::DB,
+    model::Changes,
+    router::{build_router, changes::{ChangesPage, NDJSON}},
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tower::{Service, ServiceExt};
+
+mod common;
+use common::{request, session_for, Auth};
+
+/// Creates a public `posts` and an admin only `secrets` collection, and
+/// returns the admin session.
+async fn setup(router: &mut Router, pool: &PgPool) -> Auth {
+    let admin = session_for(router, "admin@example.com").await;
+    sqlx::query("update users set admin = true where email = 'admin@example.com'").execute(pool).await.unwrap();
+    for (name, view) in [("posts", "public"), ("secrets", "admin")] {
+        let collection = json!({
+            "name": name,
+            "column_defs": [{"id": "9d1c7c3a-2f4b-4e0a-8b6d-5a4e3f2d1c01", "name": "title", "column_type": "Text", "required": true, "unique": false}],
+            "rules": {"list": view, "view": view}
+        });
+        let (status, _) = request(router, "POST", "/api/collections", Some(&admin), collection).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    admin
+}
+
+async fn page(router: &mut Router, uri: &str) -> ChangesPage {
+    let (status, page) = request(router, "GET", uri, None, Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_value(page).unwrap()
+}
+
+#[sqlx::test]
+async fn test_replays_changes_from_offset(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let admin = setup(&mut router, &pool).await;
+    let (_, post) = request(&mut router, "POST", "/api/collections/posts/records", Some(&admin), json!({"title": "first"})).await;
+    request(&mut router, "POST", "/api/collections/secrets/records", Some(&admin), json!({"title": "hidden"})).await;
+    let uri = format!("/api/collections/posts/records/{}", post["id"]);
+    request(&mut router, "PATCH", &uri, Some(&admin), json!({"title": "edited"})).await;
+
+    // anonymous readers skip the secret, but the offset still moves past it
+    let first = page(&mut router, "/api/changes?since=0&wait=0").await;
+    let actions: Vec<_> = first.changes.iter().map(|change| (change.action.as_str(), change.record["title"].clone())).collect();
+    assert_eq!(actions, vec![("create", json!("first")), ("update", json!("edited"))]);
+    assert!(first.changes[0].seq < first.changes[1].seq);
+    assert_eq!(first.next, first.changes[1].seq);
+    let limited = page(&mut router, "/api/changes?since=0&limit=1&wait=0").await;
+    assert_eq!(limited.next, first.changes[0].seq);
+    let caught_up = page(&mut router, &format!("/api/changes?since={}&wait=0", first.next)).await;
+    assert!(caught_up.changes.is_empty());
+    assert_eq!(caught_up.next, first.next);
+
+    // long polls return as soon as a change is committed
+    let mut poller = router.clone();
+    let poll = tokio::spawn(async move { page(&mut poller, &format!("/api/changes?since={}&wait=10", first.next)).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    request(&mut router, "DELETE", &uri, Some(&admin), Value::Null).await;
+    let polled = tokio::time::timeout(Duration::from_secs(5), poll).await.unwrap().unwrap();
+    assert_eq!(polled.changes.len(), 1);
+    assert_eq!(polled.changes[0].action.as_str(), "delete");
+    assert_eq!(polled.changes[0].record["title"], "edited");
+
+    assert_eq!(Changes::prune(&pool, ChronoDuration::zero()).await.unwrap(), 4);
+    assert!(page(&mut router, "/api/changes?since=0&wait=0").await.changes.is_empty());
+}
+
+#[sqlx::test]
+async fn test_streams_changes_as_ndjson(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let admin = setup(&mut router, &pool).await;
+    request(&mut router, "POST", "/api/collections/posts/records", Some(&admin), json!({"title": "backlog"})).await;
+
+    let stream = Request::get("/api/changes?since=0&limit=1").header(header::ACCEPT, NDJSON).body(Body::empty()).unwrap();
+    let response = router.ready().await.unwrap().call(stream).await.unwrap();
+    assert_eq!(response.headers()[header::CONTENT_TYPE], NDJSON);
+    let mut body = response.into_body();
+    request(&mut router, "POST", "/api/collections/posts/records", Some(&admin), json!({"title": "live"})).await;
+
+    let mut lines = String::new();
+    while lines.matches('\n').count() < 2 {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data()).await.expect("no change").unwrap().unwrap();
+        lines.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    let titles: Vec<Value> = lines.lines().map(|line| serde_json::from_str::<Value>(line).unwrap()["record"]["title"].clone()).collect();
+    assert_eq!(titles, vec![json!("backlog"), json!("live")]);
+}
+
+#[sqlx::test]
+async fn test_waits_for_changes_committed_late(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    setup(&mut router, &pool).await;
+
+    // the first writer is still running when a second one commits
+    let mut slow = pool.begin().await.unwrap();
+    sqlx::query("insert into posts(title) values ('slow')").execute(&mut slow).await.unwrap();
+    let fast = sqlx::query("insert into posts(title) values ('fast')").execute(&pool);
+    tokio::time::timeout(Duration::from_secs(5), fast).await.expect("writers wait on each other").unwrap();
+
+    // the later change is held back until the earlier one can't show up behind it
+    let held = page(&mut router, "/api/changes?since=0&wait=0").await;
+    assert!(held.changes.is_empty());
+    assert_eq!(held.next, 0);
+    slow.commit().await.unwrap();
+    let both = page(&mut router, "/api/changes?since=0&wait=5").await;
+    let titles: Vec<_> = both.changes.iter().map(|change| change.record["title"].clone()).collect();
+    assert_eq!(titles, vec![json!("slow"), json!("fast")]);
+    let again = page(&mut router, &format!("/api/changes?since={}&wait=0", both.changes[0].seq)).await;
+    assert_eq!(again.changes.len(), 1);
+    assert_eq!(again.changes[0].record["title"], "fast");
+}
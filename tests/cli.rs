@@ -0,0 +1,150 @@
+use librocketbase::{
+    cli::{run_with_db, shell::Shell, AdminCommand, CollectionsCommand, Command, MigrateCommand, UsersCommand},
+    db::DB,
+    model::User,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn run(db: &DB, command: Command) -> String {
+    let mut out = Vec::new();
+    run_with_db(command, db, &mut out).await.unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+fn posts() -> Value {
+    json!({
+        "name": "posts",
+        "column_defs": [
+            {"id": "6b6f1b1e-5a43-4c8e-9a3e-0f7d1c1b2a01", "name": "title", "column_type": "Text", "required": true, "unique": false}
+        ],
+        "rules": {"list": "public", "view": "public"}
+    })
+}
+
+#[sqlx::test]
+async fn test_admin_create_and_users_list(pool: PgPool) {
+    let db = DB::new_with_pool(pool.clone());
+    let create = |password: &str, reset: bool| Command::Admin(AdminCommand::Create {
+        email: "ada@example.com".to_string(),
+        name: None,
+        password: Some(password.to_string()),
+        reset,
+    });
+
+    let out = run(&db, create("secret", false)).await;
+    assert!(out.starts_with("Created administrator ada@example.com"));
+    let user = User::authenticate(&pool, "ada@example.com", "secret").await.unwrap().unwrap();
+    assert_eq!(user.name, "ada");
+    assert!(User::is_admin(&pool, user.id.unwrap()).await.unwrap());
+    assert!(User::is_verified(&pool, user.id.unwrap()).await.unwrap());
+
+    // running it again only resets the password of the same user when asked to
+    let mut out = Vec::new();
+    assert!(run_with_db(create("changed", false), &db, &mut out).await.is_err());
+    assert!(User::authenticate(&pool, "ada@example.com", "secret").await.unwrap().is_some());
+    let out = run(&db, create("changed", true)).await;
+    assert!(out.starts_with("Made ada@example.com an administrator"));
+    assert!(User::authenticate(&pool, "ada@example.com", "changed").await.unwrap().is_some());
+    let mut out = Vec::new();
+    assert!(run_with_db(create("", true), &db, &mut out).await.is_err());
+
+    let out = run(&db, Command::Users(UsersCommand::List)).await;
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("id  name  email"));
+    assert!(lines[2].ends_with("ada@example.com  yes    yes"));
+}
+
+#[sqlx::test]
+async fn test_collections_round_trip(pool: PgPool) {
+    let db = DB::new_with_pool(pool.clone());
+    let dir = std::env::temp_dir().join(format!("rocketbase-cli-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let import = dir.join("import.json");
+    let export = dir.join("export.json");
+
+    std::fs::write(&import, json!([posts()]).to_string()).unwrap();
+    let out = run(&db, Command::Collections(CollectionsCommand::Import { file: import.clone() })).await;
+    assert_eq!(out, "Created posts\n");
+
+    let mut updated = posts();
+    updated["column_defs"].as_array_mut().unwrap().push(
+        json!({"id": "6b6f1b1e-5a43-4c8e-9a3e-0f7d1c1b2a02", "name": "body", "column_type": "Text", "required": false, "unique": false})
+    );
+    std::fs::write(&import, json!([updated]).to_string()).unwrap();
+    let out = run(&db, Command::Collections(CollectionsCommand::Import { file: import.clone() })).await;
+    assert_eq!(out, "Updated posts\n");
+    sqlx::query("insert into posts (title, body) values ('hello', 'world')").execute(&pool).await.unwrap();
+
+    // nothing is imported when one of the collections is invalid
+    let mut invalid = posts();
+    invalid["name"] = json!("posts; drop table users");
+    std::fs::write(&import, json!([{"name": "drafts", "column_defs": []}, invalid]).to_string()).unwrap();
+    let mut out = Vec::new();
+    assert!(run_with_db(Command::Collections(CollectionsCommand::Import { file: import }), &db, &mut out).await.is_err());
+
+    run(&db, Command::Collections(CollectionsCommand::Export { output: Some(export.clone()) })).await;
+    let exported: Value = serde_json::from_str(&std::fs::read_to_string(&export).unwrap()).unwrap();
+    let exported = exported.as_array().unwrap();
+    assert_eq!(exported.len(), 1);
+    assert_eq!(exported[0]["name"], "posts");
+    assert_eq!(exported[0]["column_defs"].as_array().unwrap().len(), 2);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[sqlx::test]
+async fn test_migrate_status_lists_applied_migrations(pool: PgPool) {
+    let db = DB::new_with_pool(pool);
+    let out = run(&db, Command::Migrate(MigrateCommand::Status)).await;
+    let lines = out.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with("version"));
+    assert!(lines.len() > 2);
+    assert!(lines[2..].iter().all(|line| !line.ends_with("pending")));
+    assert_eq!(run(&db, Command::Migrate(MigrateCommand::Up)).await, "Database is up to date\n");
+}
+
+#[sqlx::test]
+async fn test_shell_explores_and_edits_records(pool: PgPool) {
+    let db = DB::new_with_pool(pool.clone());
+    let mut shell = Shell::new(pool.clone()).await.unwrap();
+    assert!(shell.collections().is_empty());
+    let dir = std::env::temp_dir().join(format!("rocketbase-shell-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let import = dir.join("import.json");
+    std::fs::write(&import, json!([posts()]).to_string()).unwrap();
+    run(&db, Command::Collections(CollectionsCommand::Import { file: import })).await;
+    std::fs::remove_dir_all(dir).unwrap();
+
+    async fn execute(shell: &mut Shell, line: &str) -> String {
+        let mut out = Vec::new();
+        assert!(shell.execute(line, &mut out).await.unwrap());
+        String::from_utf8(out).unwrap()
+    }
+    assert_eq!(execute(&mut shell, "collections").await, "name   columns\n-----  -------\nposts  title\n");
+    assert_eq!(shell.collections().len(), 1);
+    let out = execute(&mut shell, "describe posts").await;
+    assert!(out.contains("title   Text  yes       no"));
+    assert!(out.contains("create     admin"));
+
+    let out = execute(&mut shell, r#"insert posts {"title": "hello"}"#).await;
+    assert!(out.lines().nth(2).unwrap().starts_with("1   hello"));
+    execute(&mut shell, r#"insert posts {"title": "bye"}"#).await;
+    let out = execute(&mut shell, "update posts 1 {\"title\": \"hello world\"}").await;
+    assert!(out.contains("hello world"));
+
+    let out = execute(&mut shell, "find posts").await;
+    assert!(out.ends_with("(2 records)\n"));
+    let out = execute(&mut shell, r#"find posts {"title": "bye"}"#).await;
+    assert!(out.lines().nth(2).unwrap().starts_with("2   bye"));
+    assert!(out.ends_with("(1 records)\n"));
+    let out = execute(&mut shell, "find posts 1").await;
+    assert!(out.contains("hello world"));
+
+    let mut out = Vec::new();
+    assert!(shell.execute("find drafts", &mut out).await.is_err());
+    assert!(shell.execute("update posts 9 {}", &mut out).await.is_err());
+    assert!(shell.execute("insert posts [1]", &mut out).await.is_err());
+    assert!(shell.execute("drop posts", &mut out).await.is_err());
+    assert!(!shell.execute("exit", &mut out).await.unwrap());
+}
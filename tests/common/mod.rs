@@ -0,0 +1,59 @@
+//! Helpers shared by the integration tests. Each test binary uses a few of
+//! them only.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use axum::{body::Body, http::Request, Router};
+use http::StatusCode;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tower::{Service, ServiceExt};
+
+/// A header authenticating a request, such as a bearer session.
+pub type Auth = (&'static str, String);
+
+pub fn bearer(token: &str) -> Auth {
+    ("Authorization", format!("Bearer {}", token))
+}
+
+/// Sends a JSON request and returns the status with the JSON body, or null
+/// when the body is not JSON.
+pub async fn request(router: &mut Router, method: &str, uri: &str, auth: Option<&Auth>, body: Value) -> (StatusCode, Value) {
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Content-Type", "application/json");
+    if let Some((name, value)) = auth {
+        builder = builder.header(*name, value);
+    }
+    let response = router.ready().await.unwrap().call(builder.body(Body::from(body.to_string())).unwrap()).await.unwrap();
+    let status = response.status();
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+/// Signs up a user with the password `secret` and logs them in.
+pub async fn session_for(router: &mut Router, email: &str) -> Auth {
+    request(router, "POST", "/users", None, json!({"name": email, "email": email, "password": "secret"})).await;
+    let (_, login) = request(router, "POST", "/auth/login", None, json!({"email": email, "password": "secret"})).await;
+    bearer(login["token"].as_str().unwrap())
+}
+
+/// Signs up `admin@example.com` as an admin and logs them in.
+pub async fn admin_session(router: &mut Router, pool: &PgPool) -> Auth {
+    let email = "admin@example.com";
+    let session = session_for(router, email).await;
+    sqlx::query("update users set admin = true where email = $1").bind(email).execute(pool).await.unwrap();
+    session
+}
+
+/// Polls `done` for up to five seconds.
+pub async fn wait_until<F: Fn() -> bool>(done: F) {
+    for _ in 0..100 {
+        if done() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
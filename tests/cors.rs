@@ -0,0 +1,99 @@
+use axum::{body::Body, http::Request, response::Response, routing::get, Router};
+use http::{header, Method, StatusCode};
+use librocketbase::{
+    router::cors::CorsPolicies,
+    settings::{CorsPolicy, CorsRoute, SETTINGS},
+    Rocketbase,
+};
+use sqlx::PgPool;
+use tower::ServiceExt;
+
+fn preflight(path: &str, origin: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::OPTIONS)
+        .uri(path)
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PATCH")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn header(response: &Response, name: header::HeaderName) -> Option<&str> {
+    response.headers().get(name).map(|value| value.to_str().unwrap())
+}
+
+#[sqlx::test]
+async fn test_cors_policies_from_settings(pool: PgPool) {
+    let mut settings = SETTINGS.clone();
+    settings.cors.policy.allowed_origins = vec!["https://*.example.com".to_string()];
+    settings.cors.policy.allow_credentials = true;
+    settings.cors.routes = vec![CorsRoute {
+        path: "/public/".to_string(),
+        policy: CorsPolicy {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string()],
+            allowed_headers: vec![],
+            allow_credentials: false,
+            max_age_secs: None,
+        },
+    }];
+    let public = Router::new().route("/public/feed", get(|| async { "feed" }));
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool).routes("/", public).build().await.unwrap();
+    let router = rocketbase.router();
+
+    // preflight requests are answered before authentication
+    let response = router.clone().oneshot(preflight("/api/collections/posts/records/1", "https://app.example.com")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some("https://app.example.com"));
+    assert_eq!(header(&response, header::ACCESS_CONTROL_ALLOW_CREDENTIALS), Some("true"));
+    assert!(header(&response, header::ACCESS_CONTROL_ALLOW_METHODS).unwrap().contains("PATCH"));
+    assert_eq!(header(&response, header::ACCESS_CONTROL_MAX_AGE), Some("3600"));
+
+    let response = router.clone().oneshot(preflight("/api/collections/posts/records/1", "https://evilexample.com")).await.unwrap();
+    assert_eq!(header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+
+    let request = Request::get("/").header(header::ORIGIN, "https://app.example.com").body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some("https://app.example.com"));
+
+    // the route override allows any origin, without credentials
+    let request = Request::get("/public/feed").header(header::ORIGIN, "https://elsewhere.test").body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some("https://elsewhere.test"));
+    assert_eq!(header(&response, header::ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+}
+
+#[test]
+fn test_credentials_are_not_allowed_for_any_origin() {
+    let mut settings = SETTINGS.cors.clone();
+    settings.policy.allowed_origins = vec!["*".to_string()];
+    settings.policy.allow_credentials = true;
+    assert!(CorsPolicies::from_settings(&settings).is_err());
+    settings.policy.allow_credentials = false;
+    assert!(CorsPolicies::from_settings(&settings).is_ok());
+
+    let mut settings = SETTINGS.cors.clone();
+    settings.routes = vec![CorsRoute {
+        path: "/public".to_string(),
+        policy: CorsPolicy {
+            allowed_origins: vec!["https://app.example.com".to_string(), "*".to_string()],
+            allowed_methods: vec![],
+            allowed_headers: vec![],
+            allow_credentials: true,
+            max_age_secs: None,
+        },
+    }];
+    assert!(CorsPolicies::from_settings(&settings).is_err());
+}
+
+#[sqlx::test]
+async fn test_no_cors_headers_without_origins(pool: PgPool) {
+    let rocketbase = Rocketbase::builder().settings(SETTINGS.clone()).pool(pool).build().await.unwrap();
+    let request = Request::get("/").header(header::ORIGIN, "https://app.example.com").body(Body::empty()).unwrap();
+    let response = rocketbase.router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+}
@@ -0,0 +1,60 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderValue, Request},
+    response::Response,
+    routing::get,
+    Router,
+};
+use http::StatusCode;
+use librocketbase::{app_state::AppState, auth::Principal, hooks::Hooks, settings::SETTINGS, Rocketbase};
+use serde_json::json;
+use sqlx::PgPool;
+use tower::{util::MapResponseLayer, Service, ServiceExt};
+
+async fn whoami(State(state): State<AppState>, principal: Option<Principal>) -> String {
+    let name = principal.and_then(|principal| principal.user().map(|user| user.email.clone()));
+    format!("{} {}", state.settings().port, name.unwrap_or_else(|| "anonymous".to_string()))
+}
+
+async fn call(router: &mut Router, request: Request<Body>) -> (StatusCode, Option<HeaderValue>, String) {
+    let response = router.ready().await.unwrap().call(request).await.unwrap();
+    let status = response.status();
+    let header = response.headers().get("x-embedded").cloned();
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, header, String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+#[sqlx::test]
+async fn test_builder_adds_routes_layers_and_hooks(pool: PgPool) {
+    let mut settings = SETTINGS.clone();
+    settings.port = 4321;
+    let hooks = Hooks::new().before_auth(|_| async { Err(StatusCode::SERVICE_UNAVAILABLE.into()) });
+    let rocketbase = Rocketbase::builder()
+        .settings(settings)
+        .pool(pool)
+        .routes("/ext/", Router::new().route("/whoami", get(whoami)))
+        .layer(MapResponseLayer::new(|mut response: Response| {
+            response.headers_mut().insert("x-embedded", HeaderValue::from_static("yes"));
+            response
+        }))
+        .hooks(hooks)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(rocketbase.state().settings().port, 4321);
+    let mut router = rocketbase.router();
+
+    let (status, header, body) = call(&mut router, Request::get("/ext/whoami").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "4321 anonymous");
+    assert_eq!(header.unwrap(), "yes");
+    // built in routes are still there, with the layer and hooks applied
+    let (status, header, _) = call(&mut router, Request::get("/").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header.unwrap(), "yes");
+    let login = json!({"email": "someone@example.com", "password": "secret"});
+    let request = Request::post("/auth/login").header("Content-Type", "application/json").body(Body::from(login.to_string())).unwrap();
+    let (status, _, _) = call(&mut router, request).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{HeaderMap, Request},
+    routing::get,
+    Router,
+};
+use http::{header, StatusCode};
+use librocketbase::{
+    app_state::AppState,
+    db::DB,
+    model::user_token::generate_token,
+    settings::S3,
+    storage::{LocalStorage, S3Storage, Storage},
+};
+use librocketbase::router::build_router;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tower::{Service, ServiceExt};
+
+mod common;
+use common::{admin_session, request, Auth};
+
+const BOUNDARY: &str = "rocketbase-test-boundary";
+
+enum Part<'a> {
+    Text(&'a str, &'a str),
+    File(&'a str, &'a str, &'a str, &'a [u8]),
+}
+
+fn multipart(parts: &[Part]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\n", BOUNDARY).as_bytes());
+        match part {
+            Part::Text(name, value) => {
+                body.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", name, value).as_bytes());
+            }
+            Part::File(name, file_name, mime_type, bytes) => {
+                body.extend_from_slice(format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                    name, file_name, mime_type
+                ).as_bytes());
+                body.extend_from_slice(bytes);
+                body.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    body
+}
+
+async fn send_bytes(router: &mut Router, method: &str, uri: &str, auth: &Auth, content_type: &str, body: Vec<u8>) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(auth.0, auth.1.clone())
+        .body(Body::from(body))
+        .unwrap();
+    let response = router.ready().await.unwrap().call(request).await.unwrap();
+    let status = response.status();
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+fn storage_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("rocketbase-files-{}", &generate_token()[..8]))
+}
+
+#[sqlx::test]
+async fn test_uploads_and_cleans_up_files(pool: PgPool) {
+    let dir = storage_dir();
+    let storage = Arc::new(LocalStorage::new(&dir));
+    let app_state = AppState::init_with_db(DB::new_with_pool(pool.clone())).with_storage(storage.clone());
+    let mut router = build_router(app_state).await.unwrap();
+    let admin = admin_session(&mut router, &pool).await;
+    let docs = json!({
+        "name": "docs",
+        "column_defs": [
+            {"id": "4c7e2b1a-0d3f-4a6b-9c8e-1f2a3b4c5d01", "name": "title", "column_type": "Text", "required": true, "unique": false},
+            {"id": "4c7e2b1a-0d3f-4a6b-9c8e-1f2a3b4c5d02", "name": "attachment", "column_type": {"File": {"max_size": 16, "mime_types": ["text/*"]}}, "required": false, "unique": false},
+            {"id": "4c7e2b1a-0d3f-4a6b-9c8e-1f2a3b4c5d03", "name": "photos", "column_type": {"File": {"max_size": 1024, "multiple": true}}, "required": false, "unique": false}
+        ]
+    });
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), docs).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let form = format!("multipart/form-data; boundary={}", BOUNDARY);
+
+    let (status, record) = send_bytes(&mut router, "POST", "/api/collections/docs/records", &admin, &form, multipart(&[
+        Part::Text("title", "notes"),
+        Part::File("attachment", "../notes.txt", "text/plain", b"hello"),
+        Part::File("photos", "a.png", "image/png", b"png-a"),
+        Part::File("photos", "b.png", "image/png", b"png-b"),
+    ])).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(record["title"], "notes");
+    assert_eq!(record["attachment"]["original_name"], "../notes.txt");
+    assert_eq!(record["attachment"]["size"], 5);
+    assert_eq!(record["photos"].as_array().unwrap().len(), 2);
+    let attachment_key = format!("docs/{}", record["attachment"]["name"].as_str().unwrap());
+    assert!(attachment_key.ends_with("_notes.txt"));
+    assert_eq!(storage.get(&attachment_key).await.unwrap().unwrap(), Bytes::from_static(b"hello"));
+
+    let uri = format!("/api/collections/docs/records/{}", record["id"]);
+    let (status, _) = send_bytes(&mut router, "PATCH", &uri, &admin, &form, multipart(&[
+        Part::File("attachment", "big.txt", "text/plain", &[b'x'; 17]),
+    ])).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let (status, _) = send_bytes(&mut router, "PATCH", &uri, &admin, &form, multipart(&[
+        Part::File("attachment", "page.html", "text/html", b"<p>"),
+    ])).await;
+    assert_eq!(status, StatusCode::OK);
+    // replaced files are removed from storage
+    assert!(storage.get(&attachment_key).await.unwrap().is_none());
+    let (status, _) = send_bytes(&mut router, "PATCH", &uri, &admin, &form, multipart(&[
+        Part::File("attachment", "run.exe", "application/octet-stream", b"MZ"),
+    ])).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    // metadata can not be pointed at other files
+    let (status, _) = request(&mut router, "PATCH", &uri, Some(&admin), json!({"attachment": record["photos"][0]})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let (_, current) = request(&mut router, "GET", &uri, Some(&admin), Value::Null).await;
+    let remaining: Vec<String> = std::iter::once(&current["attachment"])
+        .chain(current["photos"].as_array().unwrap())
+        .map(|file| format!("docs/{}", file["name"].as_str().unwrap()))
+        .collect();
+    let (status, _) = request(&mut router, "DELETE", &uri, Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    for key in remaining {
+        assert!(storage.get(&key).await.unwrap().is_none(), "{} was left behind", key);
+    }
+    std::fs::remove_dir_all(dir).ok();
+}
+
+async fn download(router: &mut Router, uri: &str, auth: Option<&Auth>, range: Option<&str>) -> (StatusCode, HeaderMap, Bytes) {
+    let mut builder = Request::builder().uri(uri);
+    if let Some((name, value)) = auth {
+        builder = builder.header(*name, value);
+    }
+    if let Some(range) = range {
+        builder = builder.header(header::RANGE, range);
+    }
+    let response = router.ready().await.unwrap().call(builder.body(Body::empty()).unwrap()).await.unwrap();
+    let (parts, body) = response.into_parts();
+    (parts.status, parts.headers, hyper::body::to_bytes(body).await.unwrap())
+}
+
+#[sqlx::test]
+async fn test_downloads_follow_view_rules_and_ranges(pool: PgPool) {
+    let dir = storage_dir();
+    let app_state = AppState::init_with_db(DB::new_with_pool(pool.clone())).with_storage(Arc::new(LocalStorage::new(&dir)));
+    let mut router = build_router(app_state).await.unwrap();
+    let admin = admin_session(&mut router, &pool).await;
+    let docs = json!({
+        "name": "docs",
+        "column_defs": [
+            {"id": "4c7e2b1a-0d3f-4a6b-9c8e-1f2a3b4c5d01", "name": "title", "column_type": "Text", "required": true, "unique": false},
+            {"id": "4c7e2b1a-0d3f-4a6b-9c8e-1f2a3b4c5d02", "name": "attachment", "column_type": {"File": {"max_size": 1024}}, "required": false, "unique": false}
+        ]
+    });
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), docs).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let form = format!("multipart/form-data; boundary={}", BOUNDARY);
+    // the declared type is not trusted for serving
+    let (_, record) = send_bytes(&mut router, "POST", "/api/collections/docs/records", &admin, &form, multipart(&[
+        Part::Text("title", "notes"),
+        Part::File("attachment", "notes.txt", "text/html", b"hello world"),
+    ])).await;
+    let uri = format!("/api/files/docs/{}/{}", record["id"], record["attachment"]["name"].as_str().unwrap());
+
+    let (status, _, _) = download(&mut router, &uri, None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, headers, body) = download(&mut router, &uri, Some(&admin), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, Bytes::from_static(b"hello world"));
+    assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+    assert_eq!(headers[header::CONTENT_LENGTH], "11");
+    assert_eq!(headers[header::ACCEPT_RANGES], "bytes");
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(headers[header::CONTENT_DISPOSITION], "inline; filename=\"notes.txt\"");
+    let (_, headers, _) = download(&mut router, &format!("{}?download=true", uri), Some(&admin), None).await;
+    assert_eq!(headers[header::CONTENT_DISPOSITION], "attachment; filename=\"notes.txt\"");
+
+    let (status, headers, body) = download(&mut router, &uri, Some(&admin), Some("bytes=6-")).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, Bytes::from_static(b"world"));
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes 6-10/11");
+    let (status, _, body) = download(&mut router, &uri, Some(&admin), Some("bytes=1-3")).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, Bytes::from_static(b"ell"));
+    let (_, headers, body) = download(&mut router, &uri, Some(&admin), Some("bytes=-3")).await;
+    assert_eq!(body, Bytes::from_static(b"rld"));
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes 8-10/11");
+    let (status, headers, _) = download(&mut router, &uri, Some(&admin), Some("bytes=11-")).await;
+    assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes */11");
+    let (status, _, _) = download(&mut router, &format!("/api/files/docs/{}/other.txt", record["id"]), Some(&admin), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // tokens let anonymous requests in, for that file only
+    let (status, _) = request(&mut router, "POST", &format!("{}/token", uri), None, Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, signed) = request(&mut router, "POST", &format!("{}/token", uri), Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    let url = signed["url"].as_str().unwrap();
+    let (status, _, body) = download(&mut router, url, None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, Bytes::from_static(b"hello world"));
+    let token = signed["token"].as_str().unwrap();
+    let (expires, signature) = token.split_once('.').unwrap();
+    let extended = format!("{}?token={}.{}", uri, expires.parse::<i64>().unwrap() + 3600, signature);
+    let (status, _, _) = download(&mut router, &extended, None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let expired = format!("{}?token=1.{}", uri, signature);
+    let (status, _, _) = download(&mut router, &expired, None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[sqlx::test]
+async fn test_thumbnails_are_limited_to_listed_sizes(pool: PgPool) {
+    let dir = storage_dir();
+    let storage = Arc::new(LocalStorage::new(&dir));
+    let app_state = AppState::init_with_db(DB::new_with_pool(pool.clone())).with_storage(storage.clone());
+    let mut router = build_router(app_state).await.unwrap();
+    let admin = admin_session(&mut router, &pool).await;
+    let products = json!({
+        "name": "products",
+        "column_defs": [
+            {"id": "4c7e2b1a-0d3f-4a6b-9c8e-1f2a3b4c5d01", "name": "photo", "column_type": {"File": {"max_size": 65536, "thumbs": ["20x20", "20x0", "20x20f"]}}, "required": false, "unique": false},
+            {"id": "4c7e2b1a-0d3f-4a6b-9c8e-1f2a3b4c5d02", "name": "manual", "column_type": {"File": {"max_size": 1024, "thumbs": ["20x20"]}}, "required": false, "unique": false}
+        ]
+    });
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), products).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let invalid = json!({
+        "name": "invalid",
+        "column_defs": [
+            {"id": "4c7e2b1a-0d3f-4a6b-9c8e-1f2a3b4c5d01", "name": "photo", "column_type": {"File": {"max_size": 1024, "thumbs": ["huge"]}}, "required": false, "unique": false}
+        ]
+    });
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), invalid).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_rgb8(80, 40).write_to(&mut png, image::ImageFormat::Png).unwrap();
+    let form = format!("multipart/form-data; boundary={}", BOUNDARY);
+    let (status, record) = send_bytes(&mut router, "POST", "/api/collections/products/records", &admin, &form, multipart(&[
+        Part::File("photo", "photo.png", "image/png", png.get_ref()),
+        Part::File("manual", "manual.txt", "text/plain", b"read me"),
+    ])).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let photo = format!("/api/files/products/{}/{}", record["id"], record["photo"]["name"].as_str().unwrap());
+    let manual = format!("/api/files/products/{}/{}", record["id"], record["manual"]["name"].as_str().unwrap());
+
+    for (thumb, size) in [("20x20", (20, 20)), ("20x0", (20, 10)), ("20x20f", (20, 10))] {
+        let (status, headers, body) = download(&mut router, &format!("{}?thumb={}", photo, thumb), Some(&admin), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+        assert_eq!(image::load_from_memory(&body).unwrap().to_rgb8().dimensions(), size, "{}", thumb);
+    }
+    let thumb_key = format!("products/thumbs/{}/20x20", record["photo"]["name"].as_str().unwrap());
+    assert!(storage.get(&thumb_key).await.unwrap().is_some());
+    let (status, _, _) = download(&mut router, &format!("{}?thumb=40x40", photo), Some(&admin), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // files that are not images are served as they are
+    let (status, _, body) = download(&mut router, &format!("{}?thumb=20x20", manual), Some(&admin), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, Bytes::from_static(b"read me"));
+
+    let (status, _) = request(&mut router, "DELETE", &format!("/api/collections/products/records/{}", record["id"]), Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(storage.get(&thumb_key).await.unwrap().is_none());
+    std::fs::remove_dir_all(dir).ok();
+}
+
+/// Objects kept by the stand-in S3 server, keyed by path.
+type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+async fn object(State(objects): State<Objects>, Path(path): Path<String>, headers: HeaderMap, request: Request<Body>) -> Result<Vec<u8>, StatusCode> {
+    let method = request.method().clone();
+    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+    let signed = headers.get(header::AUTHORIZATION).is_some_and(|value| value.to_str().unwrap().starts_with("AWS4-HMAC-SHA256 Credential=minio/"));
+    if !signed || headers["x-amz-content-sha256"] != hex::encode(Sha256::digest(&body)) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut objects = objects.lock().unwrap();
+    match method.as_str() {
+        "PUT" => {
+            objects.insert(path, body.to_vec());
+            Ok(vec![])
+        }
+        "GET" => objects.get(&path).cloned().ok_or(StatusCode::NOT_FOUND),
+        _ => {
+            objects.remove(&path);
+            Err(StatusCode::NO_CONTENT)
+        }
+    }
+}
+
+async fn assert_round_trips(storage: &dyn Storage) {
+    let key = format!("docs/{}_a b.txt", &generate_token()[..8]);
+    assert!(storage.get(&key).await.unwrap().is_none());
+    storage.put(&key, Bytes::from_static(b"hello"), "text/plain").await.unwrap();
+    assert_eq!(storage.get(&key).await.unwrap().unwrap(), Bytes::from_static(b"hello"));
+    storage.delete(&key).await.unwrap();
+    assert!(storage.get(&key).await.unwrap().is_none());
+    assert!(storage.get("docs/../secrets").await.is_err());
+}
+
+#[tokio::test]
+async fn test_s3_storage_signs_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let app = Router::new().route("/*path", get(object).put(object).delete(object)).with_state(Objects::default());
+    tokio::spawn(async move { axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()).await.unwrap() });
+    let config = S3 {
+        endpoint,
+        bucket: "rocketbase".into(),
+        region: "us-east-1".into(),
+        access_key: "minio".into(),
+        secret_key: "minio-secret".into(),
+        path_style: true,
+    };
+    assert_round_trips(&S3Storage::new(&config).unwrap()).await;
+}
+
+/// Runs against a real MinIO, for example one started with
+/// `docker run -p 9000:9000 minio/minio server /data` and a bucket created
+/// for the test, with `ROCKETBASE_TEST_S3_ENDPOINT`, `_BUCKET`, `_ACCESS_KEY`
+/// and `_SECRET_KEY` set.
+#[tokio::test]
+#[ignore]
+async fn test_s3_storage_against_minio() {
+    let var = |name: &str| std::env::var(format!("ROCKETBASE_TEST_S3_{}", name)).unwrap();
+    let config = S3 {
+        endpoint: var("ENDPOINT"),
+        bucket: var("BUCKET"),
+        region: "us-east-1".into(),
+        access_key: var("ACCESS_KEY"),
+        secret_key: var("SECRET_KEY"),
+        path_style: true,
+    };
+    assert_round_trips(&S3Storage::new(&config).unwrap()).await;
+}
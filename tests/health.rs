@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use axum::{body::Body, http::Request, Router};
+use http::StatusCode;
+use librocketbase::{settings::SETTINGS, Rocketbase};
+use serde_json::Value;
+use sqlx::PgPool;
+use tower::ServiceExt;
+
+async fn get(router: &Router, path: &str) -> (StatusCode, Value) {
+    let response = router.clone().oneshot(Request::get(path).body(Body::empty()).unwrap()).await.unwrap();
+    let status = response.status();
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+#[sqlx::test]
+async fn test_health_and_readiness(pool: PgPool) {
+    let mut settings = SETTINGS.clone();
+    settings.plugins.enabled = false;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool).build().await.unwrap();
+    let state = rocketbase.state().clone();
+    let router = rocketbase.router();
+
+    let (status, body) = get(&router, "/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ok");
+
+    let (status, body) = get(&router, "/readyz").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["status"], "ok");
+    for check in ["database", "migrations", "workers"] {
+        assert_eq!(body[check]["status"], "ok", "{}", body);
+        assert!(body[check]["latency_ms"].as_f64().unwrap() >= 0.0);
+        assert!(body[check].get("error").is_none());
+    }
+
+    // a background task that stops before shutdown failed
+    let failed = tokio::spawn(async {});
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    state.shutdown().track(failed);
+    let (status, body) = get(&router, "/readyz").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "failed");
+    assert_eq!(body["database"]["status"], "ok");
+    assert_eq!(body["workers"]["status"], "failed");
+    assert_eq!(body["workers"]["error"], "Check failed, see the server log");
+}
+
+#[sqlx::test]
+async fn test_not_ready_during_shutdown(pool: PgPool) {
+    let mut settings = SETTINGS.clone();
+    settings.plugins.enabled = false;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool).build().await.unwrap();
+    let router = rocketbase.router();
+    rocketbase.state().shutdown().start();
+
+    let (status, body) = get(&router, "/readyz").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "shutting_down");
+    let (status, _) = get(&router, "/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+}
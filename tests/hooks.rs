@@ -0,0 +1,105 @@
+use std::sync::{Arc, Mutex};
+
+use http::StatusCode;
+use librocketbase::{
+    app_state::AppState,
+    db::DB,
+    hooks::{AuthMethod, Hooks, Rejection},
+    router::build_router,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+mod common;
+use common::{bearer, request};
+
+fn notes() -> Value {
+    json!({
+        "name": "notes",
+        "column_defs": [
+            {"id": "0e1f2a3b-4c5d-4e6f-8a9b-0c1d2e3f4a01", "name": "title", "column_type": "Text", "required": true, "unique": false}
+        ]
+    })
+}
+
+#[sqlx::test]
+async fn test_hooks_change_and_reject_operations(pool: PgPool) {
+    let events: Arc<Mutex<Vec<String>>> = Arc::default();
+    let log = |events: &Arc<Mutex<Vec<String>>>| {
+        let events = events.clone();
+        move |entry: String| events.lock().unwrap().push(entry)
+    };
+    let (created, deleted, schema, signed_in) = (log(&events), log(&events), log(&events), log(&events));
+    let hooks = Hooks::new()
+        .before_create(|mut event| async move {
+            let title = event.data["title"].as_str().unwrap_or_default().to_uppercase();
+            if title == "SPAM" {
+                return Err(Rejection::new(StatusCode::UNPROCESSABLE_ENTITY, "no spam"));
+            }
+            event.data.insert("title".to_string(), Value::from(title));
+            Ok(event)
+        })
+        .after_create(move |event| {
+            created(format!("created {}", event.record.unwrap()["title"].as_str().unwrap()));
+            async {}
+        })
+        .before_delete(|event| async move {
+            match event.record.as_ref().unwrap()["title"] == "KEEP" {
+                true => Err(StatusCode::CONFLICT.into()),
+                false => Ok(event),
+            }
+        })
+        .after_delete(move |event| {
+            deleted(format!("deleted {}", event.record.unwrap()["id"]));
+            async {}
+        })
+        .on_schema_change(move |change| {
+            schema(format!("schema {} {}", change.collection.name, change.previous.is_some()));
+            async {}
+        })
+        .before_auth(|mut attempt| async move {
+            if attempt.email.as_deref() == Some("blocked@example.com") {
+                return Err(StatusCode::FORBIDDEN.into());
+            }
+            attempt.email = attempt.email.map(|email| email.to_lowercase());
+            Ok(attempt)
+        })
+        .after_auth(move |event| {
+            assert_eq!(event.method, AuthMethod::Password);
+            signed_in(format!("signed in {}", event.user.email));
+            async {}
+        });
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone())).with_hooks(hooks)).await.unwrap();
+
+    for email in ["admin@example.com", "blocked@example.com"] {
+        request(&mut router, "POST", "/users", None, json!({"name": email, "email": email, "password": "secret"})).await;
+    }
+    sqlx::query("update users set admin = true where email = 'admin@example.com'").execute(&pool).await.unwrap();
+    let (status, _) = request(&mut router, "POST", "/auth/login", None, json!({"email": "blocked@example.com", "password": "secret"})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, login) = request(&mut router, "POST", "/auth/login", None, json!({"email": "Admin@Example.com", "password": "secret"})).await;
+    assert_eq!(status, StatusCode::OK);
+    let admin = bearer(login["token"].as_str().unwrap());
+
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), notes()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, body) = request(&mut router, "POST", "/api/collections/notes/records", Some(&admin), json!({"title": "spam"})).await;
+    assert_eq!((status, body), (StatusCode::UNPROCESSABLE_ENTITY, json!({"error": "no spam"})));
+    let (status, note) = request(&mut router, "POST", "/api/collections/notes/records", Some(&admin), json!({"title": "hello"})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(note["title"], "HELLO");
+    let (_, kept) = request(&mut router, "POST", "/api/collections/notes/records", Some(&admin), json!({"title": "keep"})).await;
+
+    let (status, body) = request(&mut router, "DELETE", &format!("/api/collections/notes/records/{}", kept["id"]), Some(&admin), Value::Null).await;
+    assert_eq!((status, body), (StatusCode::CONFLICT, Value::Null));
+    let (status, _) = request(&mut router, "DELETE", &format!("/api/collections/notes/records/{}", note["id"]), Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    assert_eq!(*events.lock().unwrap(), vec![
+        "signed in admin@example.com".to_string(),
+        "schema notes false".to_string(),
+        "created HELLO".to_string(),
+        "created KEEP".to_string(),
+        format!("deleted {}", note["id"]),
+    ]);
+}
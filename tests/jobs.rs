@@ -0,0 +1,178 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::Router;
+use color_eyre::eyre::eyre;
+use http::StatusCode;
+use librocketbase::{scheduler::Scheduler, settings::SETTINGS, Rocketbase};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+mod common;
+use common::{admin_session, request, Auth};
+
+/// Polls the runs of `job` for up to five seconds until the one started as
+/// `run` is over, and returns it.
+async fn finished(router: &mut Router, admin: &Auth, job: &str, run: &Value) -> Value {
+    for _ in 0..100 {
+        let (_, runs) = request(router, "GET", &format!("/admin/jobs/{}/runs", job), Some(admin), Value::Null).await;
+        let found = runs.as_array().unwrap().iter().find(|found| found["id"] == run["id"]).cloned();
+        match found {
+            Some(found) if found["status"] != "running" => return found,
+            _ => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+    panic!("run {} of {} is not over", run["id"], job);
+}
+
+#[sqlx::test]
+async fn test_scheduled_and_admin_jobs_record_runs(pool: PgPool) {
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let counter = ticks.clone();
+    let scheduler = Scheduler::new()
+        .job("tick", "* * * * * *", move |context| {
+            let counter = counter.clone();
+            async move {
+                sqlx::query("select 1").execute(&context.pool).await?;
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .handler("fail", |_| async { Err(eyre!("boom")) });
+    let mut settings = SETTINGS.clone();
+    settings.scheduler.poll_interval_ms = 100;
+    settings.scheduler.keep_runs = 2;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).scheduler(scheduler).build().await.unwrap();
+    let mut router = rocketbase.router();
+
+    let admin = admin_session(&mut router, &pool).await;
+
+    // the registered job runs on its schedule, recording the run once it is over
+    let succeeded = |runs: &Value| runs.as_array().unwrap().iter().any(|run| run["status"] == "succeeded" && run["duration_ms"].is_i64());
+    let mut runs = Value::Null;
+    for _ in 0..50 {
+        let status;
+        (status, runs) = request(&mut router, "GET", "/admin/jobs/tick/runs", Some(&admin), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        if succeeded(&runs) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(ticks.load(Ordering::SeqCst) > 0);
+    assert!(succeeded(&runs), "{}", runs);
+
+    // jobs added through the admin API run registered handlers
+    let nightly = json!({"name": "nightly", "schedule": "0 3 * * *", "handler": "fail"});
+    let (status, _) = request(&mut router, "POST", "/admin/jobs", None, nightly.clone()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = request(&mut router, "POST", "/admin/jobs", Some(&admin), json!({"name": "nightly", "schedule": "0 3 * * *", "handler": "missing"})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = request(&mut router, "POST", "/admin/jobs", Some(&admin), json!({"name": "nightly", "schedule": "at three", "handler": "fail"})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, job) = request(&mut router, "POST", "/admin/jobs", Some(&admin), nightly.clone()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(job["next_run_at"].as_str().unwrap().contains("T03:00:00"));
+    let (status, _) = request(&mut router, "POST", "/admin/jobs", Some(&admin), nightly).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, run) = request(&mut router, "POST", "/admin/jobs/nightly/run", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(run["status"], "running");
+    let run = finished(&mut router, &admin, "nightly", &run).await;
+    assert_eq!(run["status"], "failed");
+    assert_eq!(run["error"], "boom");
+
+    // only one instance runs a job at a time, until its lease runs out
+    sqlx::query("update _jobs set locked_by = 'elsewhere', locked_until = now() + interval '1 minute' where name = 'nightly'")
+        .execute(&pool).await.unwrap();
+    let (status, _) = request(&mut router, "POST", "/admin/jobs/nightly/run", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    sqlx::query("update _jobs set locked_until = now() - interval '1 second' where name = 'nightly'").execute(&pool).await.unwrap();
+
+    for _ in 0..2 {
+        let (_, run) = request(&mut router, "POST", "/admin/jobs/nightly/run", Some(&admin), Value::Null).await;
+        finished(&mut router, &admin, "nightly", &run).await;
+    }
+    let (_, runs) = request(&mut router, "GET", "/admin/jobs/nightly/runs", Some(&admin), Value::Null).await;
+    assert_eq!(runs.as_array().unwrap().len(), 2);
+
+    let (status, job) = request(&mut router, "PATCH", "/admin/jobs/nightly", Some(&admin), json!({"schedule": "30 4 * * *", "enabled": false})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(job["enabled"], false);
+    assert!(job["next_run_at"].as_str().unwrap().contains("T04:30:00"));
+    let (status, jobs) = request(&mut router, "GET", "/admin/jobs", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(jobs.as_array().unwrap().len(), 2);
+    let (status, _) = request(&mut router, "DELETE", "/admin/jobs/nightly", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = request(&mut router, "GET", "/admin/jobs/nightly", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[sqlx::test]
+async fn test_runs_renew_their_lease(pool: PgPool) {
+    let scheduler = Scheduler::new().handler("slow", |_| async {
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        Ok(())
+    });
+    let mut settings = SETTINGS.clone();
+    settings.scheduler.lease_secs = 1;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).scheduler(scheduler).build().await.unwrap();
+    let mut router = rocketbase.router();
+    let admin = admin_session(&mut router, &pool).await;
+    let slow = json!({"name": "slow", "schedule": "0 3 * * *", "handler": "slow"});
+    let (status, _) = request(&mut router, "POST", "/admin/jobs", Some(&admin), slow).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, run) = request(&mut router, "POST", "/admin/jobs/slow/run", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    // past the first lease, the run still holds the job
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let (status, _) = request(&mut router, "POST", "/admin/jobs/slow/run", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let run = finished(&mut router, &admin, "slow", &run).await;
+    assert_eq!(run["status"], "succeeded");
+    let locked: Option<String> = sqlx::query_scalar("select locked_by from _jobs where name = 'slow'").fetch_one(&pool).await.unwrap();
+    assert!(locked.is_none());
+}
+
+#[sqlx::test]
+async fn test_runs_that_lose_their_lease_are_stopped(pool: PgPool) {
+    let done = Arc::new(AtomicUsize::new(0));
+    let counter = done.clone();
+    let scheduler = Scheduler::new().handler("slow", move |_| {
+        let counter = counter.clone();
+        async move {
+            tokio::time::sleep(Duration::from_millis(2500)).await;
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    });
+    let mut settings = SETTINGS.clone();
+    settings.scheduler.lease_secs = 1;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).scheduler(scheduler).build().await.unwrap();
+    let mut router = rocketbase.router();
+    let admin = admin_session(&mut router, &pool).await;
+    let slow = json!({"name": "slow", "schedule": "0 3 * * *", "handler": "slow"});
+    let (status, _) = request(&mut router, "POST", "/admin/jobs", Some(&admin), slow).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, run) = request(&mut router, "POST", "/admin/jobs/slow/run", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    // another instance taking over once the lease seemed to run out
+    sqlx::query("update _jobs set locked_by = 'elsewhere', locked_until = now() + interval '1 minute' where name = 'slow'")
+        .execute(&pool).await.unwrap();
+    let run = finished(&mut router, &admin, "slow", &run).await;
+    assert_eq!(run["status"], "failed");
+    assert_eq!(run["error"], "Lost its lease while running");
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(done.load(Ordering::SeqCst), 0);
+    let locked: Option<String> = sqlx::query_scalar("select locked_by from _jobs where name = 'slow'").fetch_one(&pool).await.unwrap();
+    assert_eq!(locked.as_deref(), Some("elsewhere"));
+}
@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::{body::Body, http::Request, Router};
+use color_eyre::Result;
+use http::StatusCode;
+use librocketbase::{
+    app_state::AppState,
+    auth,
+    db::DB,
+    mailer::{Mailer, Message},
+    queue::Workers,
+    router::build_router,
+    settings::SETTINGS,
+};
+use serde_json::json;
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+use tower::{Service, ServiceExt};
+
+mod common;
+
+#[derive(Debug, Default, Clone)]
+struct CapturingMailer {
+    sent: Arc<Mutex<Vec<Message>>>,
+}
+
+impl CapturingMailer {
+    fn last_token(&self) -> String {
+        let sent = self.sent.lock().unwrap();
+        let body = &sent.last().expect("no mail was sent").body;
+        let start = body.find("token=").unwrap() + "token=".len();
+        body[start..].split_whitespace().next().unwrap().to_string()
+    }
+}
+
+#[async_trait]
+impl Mailer for CapturingMailer {
+    async fn send(&self, message: Message) -> Result<()> {
+        self.sent.lock().unwrap().push(message);
+        Ok(())
+    }
+}
+
+async fn setup(pool: PgPool) -> (Router, CapturingMailer) {
+    let mailer = CapturingMailer::default();
+    let app_state = AppState::init_with_db(DB::new_with_pool(pool.clone())).with_mailer(Arc::new(mailer.clone()));
+    // requested tokens are mailed from the queue
+    let mut queue = SETTINGS.queue;
+    queue.poll_interval_ms = 20;
+    let workers = auth::handle_token_mail(Workers::new(), app_state.clone());
+    Arc::new(workers).spawn(pool, queue, CancellationToken::new());
+    (build_router(app_state).await.unwrap(), mailer)
+}
+
+/// Waits for the queued mail to be sent.
+async fn wait_for_queue(pool: &PgPool) {
+    for _ in 0..100 {
+        let queued: i64 = sqlx::query_scalar("select count(*) from _tasks").fetch_one(pool).await.unwrap();
+        if queued == 0 {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("queued mail was not sent");
+}
+
+async fn post(router: &mut Router, uri: &str, body: serde_json::Value) -> StatusCode {
+    let request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    router.ready().await.unwrap().call(request).await.unwrap().status()
+}
+
+#[sqlx::test]
+async fn test_signup_sends_verification_mail(pool: PgPool) {
+    let (mut router, mailer) = setup(pool.clone()).await;
+
+    let status = post(&mut router, "/users", json!({"name": "userman", "email": "email@email.com", "password": ""})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let status = post(&mut router, "/users", json!({"name": "userman", "email": "email@email.com", "password": "secret"})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(mailer.sent.lock().unwrap()[0].to, "email@email.com");
+
+    let token = mailer.last_token();
+    assert_eq!(post(&mut router, "/users/verify", json!({"token": token})).await, StatusCode::NO_CONTENT);
+    let verified: bool = sqlx::query_scalar("select verified from users where email = 'email@email.com'")
+        .fetch_one(&pool).await.unwrap();
+    assert!(verified);
+
+    // tokens are single use
+    assert_eq!(post(&mut router, "/users/verify", json!({"token": token})).await, StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test]
+async fn test_password_reset(pool: PgPool) {
+    let (mut router, mailer) = setup(pool.clone()).await;
+    post(&mut router, "/users", json!({"name": "userman", "email": "email@email.com", "password": "secret"})).await;
+    let (_, login) = common::request(&mut router, "POST", "/auth/login", None, json!({"email": "email@email.com", "password": "secret"})).await;
+    let session = common::bearer(login["token"].as_str().unwrap());
+    mailer.sent.lock().unwrap().clear();
+
+    let status = post(&mut router, "/users/request-password-reset", json!({"email": "nobody@email.com"})).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    wait_for_queue(&pool).await;
+    assert!(mailer.sent.lock().unwrap().is_empty());
+
+    let status = post(&mut router, "/users/request-password-reset", json!({"email": "email@email.com"})).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    wait_for_queue(&pool).await;
+    let other = mailer.last_token();
+    post(&mut router, "/users/request-password-reset", json!({"email": "email@email.com"})).await;
+    wait_for_queue(&pool).await;
+    let token = mailer.last_token();
+
+    // a reset token can not be used to verify an address
+    assert_eq!(post(&mut router, "/users/verify", json!({"token": token})).await, StatusCode::BAD_REQUEST);
+
+    let status = post(&mut router, "/users/reset-password", json!({"token": token, "password": "n3w"})).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let hash: Option<String> = sqlx::query_scalar("select password_hash from users where email = 'email@email.com'")
+        .fetch_one(&pool).await.unwrap();
+    assert!(hash.unwrap().starts_with("$argon2"));
+
+    // sessions and links from before the reset are revoked
+    let (status, _) = common::request(&mut router, "GET", "/auth/me", Some(&session), json!(null)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let status = post(&mut router, "/users/reset-password", json!({"token": other, "password": "0ther"})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
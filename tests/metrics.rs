@@ -0,0 +1,105 @@
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+use axum::{body::Body, http::Request, Router};
+use http::{header, StatusCode};
+use librocketbase::{
+    queue::TaskPayload,
+    settings::{self, SETTINGS},
+    Rocketbase,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tower::ServiceExt;
+
+#[derive(Serialize, Deserialize)]
+struct Noop;
+
+impl TaskPayload for Noop {
+    const KIND: &'static str = "noop";
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+async fn get(router: &Router, path: &str, token: Option<&str>) -> (StatusCode, String) {
+    let mut request = Request::get(path);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let response = router.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    let status = response.status();
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+#[sqlx::test]
+async fn test_metrics_behind_a_token(pool: PgPool) {
+    let mut settings = SETTINGS.clone();
+    settings.plugins.enabled = false;
+    settings.metrics = Some(settings::Metrics { token: Some("scrape-me".to_string()), bind: None });
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool).build().await.unwrap();
+    let state = rocketbase.state().clone();
+    let router = rocketbase.router();
+
+    assert_eq!(get(&router, "/metrics", None).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(get(&router, "/metrics", Some("wrong")).await.0, StatusCode::UNAUTHORIZED);
+
+    get(&router, "/", None).await;
+    get(&router, "/api/collections/missing/records/12", None).await;
+    state.db().enqueue(&Noop).await.unwrap();
+    let _subscription = state.realtime().subscribe().await.unwrap();
+
+    let (status, body) = get(&router, "/metrics", Some("scrape-me")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"rocketbase_http_requests_total{method="GET",route="/",status="200"} 1"#), "{}", body);
+    // requests are labelled by route, not by the ids in their paths
+    assert!(body.contains(r#"route="/api/collections/:name/records/:id""#), "{}", body);
+    assert!(body.contains(r#"rocketbase_http_request_duration_seconds_bucket{method="GET",route="/",status="200""#), "{}", body);
+    assert!(body.contains(r#"rocketbase_queue_tasks{status="pending"} 1"#), "{}", body);
+    assert!(body.contains(r#"rocketbase_queue_tasks{status="dead"} 0"#), "{}", body);
+    assert!(body.contains("rocketbase_realtime_subscribers 1"), "{}", body);
+    assert!(body.contains("rocketbase_db_pool_connections "), "{}", body);
+    assert!(body.contains("rocketbase_db_pool_idle_connections "), "{}", body);
+    assert!(!body.contains("rocketbase_db_pool_acquire_seconds"), "{}", body);
+}
+
+#[sqlx::test]
+async fn test_metrics_on_their_own_address(pool: PgPool) {
+    let (addr, metrics_addr) = (free_addr(), free_addr());
+    let mut settings = SETTINGS.clone();
+    settings.host = addr.ip().to_string();
+    settings.port = addr.port() as i32;
+    settings.plugins.enabled = false;
+    settings.metrics = Some(settings::Metrics { token: None, bind: Some(metrics_addr.to_string()) });
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool).build().await.unwrap();
+    let state = rocketbase.state().clone();
+    assert_eq!(get(&rocketbase.router(), "/metrics", None).await.0, StatusCode::NOT_FOUND);
+    let server = tokio::spawn(rocketbase.serve_until_shutdown());
+    for _ in 0..100 {
+        if TcpStream::connect(metrics_addr).is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let response = hyper::Client::new().get(format!("http://{}/metrics", metrics_addr).parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8(body.to_vec()).unwrap().contains("rocketbase_queue_tasks"));
+
+    state.shutdown().start();
+    server.await.unwrap().unwrap();
+}
+
+#[sqlx::test]
+async fn test_metrics_need_a_guard(pool: PgPool) {
+    let mut settings = SETTINGS.clone();
+    settings.plugins.enabled = false;
+    settings.metrics = Some(settings::Metrics { token: None, bind: None });
+    let built = Rocketbase::builder().settings(settings).pool(pool).build().await;
+    assert!(built.is_err());
+}
@@ -0,0 +1,99 @@
+use axum::Router;
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use http::StatusCode;
+use librocketbase::{
+    app_state::AppState,
+    auth::totp,
+    db::DB,
+    router::{build_router, mfa::{ConfirmResponse, EnrollResponse}},
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+mod common;
+use common::{bearer, request};
+
+async fn post(router: &mut Router, uri: &str, token: Option<&str>, body: Value) -> (StatusCode, Value) {
+    request(router, "POST", uri, token.map(bearer).as_ref(), body).await
+}
+
+fn current_code(secret: &str) -> String {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = totp::step_at(Utc::now().timestamp() as u64);
+    format!("{:06}", totp::code_at(&secret, step, totp::DIGITS))
+}
+
+#[sqlx::test]
+async fn test_totp_enrollment_and_login(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool))).await.unwrap();
+    let credentials = json!({"email": "mfa@example.com", "password": "secret"});
+    post(&mut router, "/users", None, json!({"name": "mfa", "email": "mfa@example.com", "password": "secret"})).await;
+
+    let (status, _) = post(&mut router, "/auth/login", None, json!({"email": "mfa@example.com", "password": "wrong"})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, login) = post(&mut router, "/auth/login", None, credentials.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let session = login["token"].as_str().unwrap().to_string();
+
+    let (status, enroll) = post(&mut router, "/auth/mfa/enroll", Some(&session), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    let enroll: EnrollResponse = serde_json::from_value(enroll).unwrap();
+    assert!(enroll.otpauth_uri.starts_with("otpauth://totp/Rocketbase:mfa%40example.com?secret="));
+
+    let code = current_code(&enroll.secret);
+    let (status, confirm) = post(&mut router, "/auth/mfa/confirm", Some(&session), json!({"code": code})).await;
+    assert_eq!(status, StatusCode::OK);
+    let confirm: ConfirmResponse = serde_json::from_value(confirm).unwrap();
+    assert_eq!(confirm.recovery_codes.len(), 10);
+    // 80 bits each
+    assert_eq!(confirm.recovery_codes[0].replace('-', "").len(), 20);
+    assert!(confirm.token.is_none());
+
+    // logins now stop at the second factor
+    let (_, login) = post(&mut router, "/auth/login", None, credentials.clone()).await;
+    assert_eq!(login["enrollment_required"], json!(false));
+    let mfa_token = login["mfa_token"].as_str().unwrap().to_string();
+    assert_eq!(request(&mut router, "GET", "/auth/me", Some(&bearer(&mfa_token)), Value::Null).await.0, StatusCode::UNAUTHORIZED);
+
+    // the code used to confirm enrollment can not be replayed
+    let (status, _) = post(&mut router, "/auth/mfa/verify", Some(&mfa_token), json!({"code": code})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let recovery = json!({"recovery_code": confirm.recovery_codes[0]});
+    let (status, verified) = post(&mut router, "/auth/mfa/verify", Some(&mfa_token), recovery.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(verified["user"]["email"], "mfa@example.com");
+
+    let (_, login) = post(&mut router, "/auth/login", None, credentials).await;
+    let mfa_token = login["mfa_token"].as_str().unwrap();
+    let (status, _) = post(&mut router, "/auth/mfa/verify", Some(mfa_token), recovery).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn test_concurrent_verifies_accept_a_code_once(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let credentials = json!({"email": "mfa@example.com", "password": "secret"});
+    post(&mut router, "/users", None, json!({"name": "mfa", "email": "mfa@example.com", "password": "secret"})).await;
+    let (_, login) = post(&mut router, "/auth/login", None, credentials.clone()).await;
+    let session = login["token"].as_str().unwrap().to_string();
+    let (_, enroll) = post(&mut router, "/auth/mfa/enroll", Some(&session), Value::Null).await;
+    let enroll: EnrollResponse = serde_json::from_value(enroll).unwrap();
+    let code = current_code(&enroll.secret);
+    assert_eq!(post(&mut router, "/auth/mfa/confirm", Some(&session), json!({"code": code})).await.0, StatusCode::OK);
+    // forget the step of the confirmation so that the code can be used once more
+    sqlx::query("update _user_mfa set last_used_step = null").execute(&pool).await.unwrap();
+
+    let (_, first) = post(&mut router, "/auth/login", None, credentials.clone()).await;
+    let (_, second) = post(&mut router, "/auth/login", None, credentials).await;
+    let (mut first_router, mut second_router) = (router.clone(), router.clone());
+    let body = json!({"code": code});
+    let (first, second) = tokio::join!(
+        post(&mut first_router, "/auth/mfa/verify", first["mfa_token"].as_str(), body.clone()),
+        post(&mut second_router, "/auth/mfa/verify", second["mfa_token"].as_str(), body.clone()),
+    );
+    let mut statuses = [first.0, second.0];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::UNAUTHORIZED]);
+}
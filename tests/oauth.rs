@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    extract::{Form, State},
+    http::Request,
+    routing::{get, post},
+    Json, Router,
+};
+use http::{header, StatusCode};
+use librocketbase::{
+    app_state::AppState,
+    auth::oauth::{pkce_challenge, OAuthRegistry},
+    db::DB,
+    model::User,
+    router::{build_router, oauth::SessionResponse},
+    settings::{OAuthProvider, OAuthProviderKind},
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tower::{Service, ServiceExt};
+
+/// What the stand-in provider expects and returns.
+#[derive(Clone, Default)]
+struct MockProvider {
+    addr: String,
+    challenge: Arc<Mutex<String>>,
+    userinfo: Arc<Mutex<Value>>,
+}
+
+async fn discovery(State(mock): State<MockProvider>) -> Json<Value> {
+    Json(json!({
+        "issuer": mock.addr,
+        "authorization_endpoint": format!("{}/authorize", mock.addr),
+        "token_endpoint": format!("{}/token", mock.addr),
+        "userinfo_endpoint": format!("{}/userinfo", mock.addr),
+    }))
+}
+
+async fn token(State(mock): State<MockProvider>, Form(form): Form<HashMap<String, String>>) -> Result<Json<Value>, StatusCode> {
+    let challenge = mock.challenge.lock().unwrap().clone();
+    if form["code"] != "good-code" || pkce_challenge(&form["code_verifier"]) != challenge {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(Json(json!({"access_token": "mock-access-token", "token_type": "Bearer"})))
+}
+
+async fn userinfo(State(mock): State<MockProvider>, request: Request<Body>) -> Result<Json<Value>, StatusCode> {
+    match request.headers().get(header::AUTHORIZATION) {
+        Some(value) if value == "Bearer mock-access-token" => Ok(Json(mock.userinfo.lock().unwrap().clone())),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+fn start_mock_provider(claims: Value) -> MockProvider {
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+    let mock = MockProvider {
+        addr: format!("http://{}", listener.local_addr().unwrap()),
+        userinfo: Arc::new(Mutex::new(claims)),
+        ..Default::default()
+    };
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/token", post(token))
+        .route("/userinfo", get(userinfo))
+        .with_state(mock.clone());
+    tokio::spawn(async move {
+        axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()).await.unwrap();
+    });
+    mock
+}
+
+async fn setup(pool: PgPool, mock: &MockProvider) -> Router {
+    let provider = OAuthProvider {
+        kind: OAuthProviderKind::Oidc,
+        client_id: "client".into(),
+        client_secret: "secret".into(),
+        issuer: Some(mock.addr.clone()),
+        scopes: None,
+        redirect_url: None,
+        auth_url: None,
+        token_url: None,
+        userinfo_url: None,
+    };
+    let registry = OAuthRegistry::new(&HashMap::from([("mock".to_string(), provider)]), "http://localhost:3000");
+    let app_state = AppState::init_with_db(DB::new_with_pool(pool)).with_oauth(registry);
+    build_router(app_state).await.unwrap()
+}
+
+async fn get_uri(router: &mut Router, uri: &str, token: Option<&str>) -> http::Response<axum::body::BoxBody> {
+    get_with_cookie(router, uri, token, None).await
+}
+
+async fn get_with_cookie(router: &mut Router, uri: &str, token: Option<&str>, cookie: Option<&str>) -> http::Response<axum::body::BoxBody> {
+    let mut builder = Request::builder().uri(uri);
+    if let Some(token) = token {
+        builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    if let Some(cookie) = cookie {
+        builder = builder.header(header::COOKIE, cookie);
+    }
+    router.ready().await.unwrap().call(builder.body(Body::empty()).unwrap()).await.unwrap()
+}
+
+/// Runs the redirect leg of the flow and returns the state with the cookie
+/// it came with.
+async fn start_sign_in(router: &mut Router, mock: &MockProvider) -> (String, String) {
+    let response = get_uri(router, "/auth/oauth/mock", None).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let location = response.headers()[header::LOCATION].to_str().unwrap().to_string();
+    assert!(location.starts_with(&format!("{}/authorize?", mock.addr)));
+    let params: HashMap<String, String> = serde_urlencoded::from_str(location.split_once('?').unwrap().1).unwrap();
+    assert_eq!(params["code_challenge_method"], "S256");
+    assert_eq!(params["redirect_uri"], "http://localhost:3000/auth/oauth/mock/callback");
+    *mock.challenge.lock().unwrap() = params["code_challenge"].clone();
+    let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+    assert!(cookie.contains("HttpOnly"));
+    (params["state"].clone(), cookie.split(';').next().unwrap().to_string())
+}
+
+/// Runs the redirect and callback legs of the flow and returns the callback response.
+async fn sign_in(router: &mut Router, mock: &MockProvider) -> http::Response<axum::body::BoxBody> {
+    let (state, cookie) = start_sign_in(router, mock).await;
+    get_with_cookie(router, &format!("/auth/oauth/mock/callback?code=good-code&state={}", state), None, Some(&cookie)).await
+}
+
+async fn login(router: &mut Router, email: &str, password: &str) -> StatusCode {
+    let request = Request::post("/auth/login")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({"email": email, "password": password}).to_string()))
+        .unwrap();
+    router.ready().await.unwrap().call(request).await.unwrap().status()
+}
+
+#[sqlx::test]
+async fn test_oidc_login_creates_and_links_user(pool: PgPool) {
+    let mock = start_mock_provider(json!({"sub": "42", "email": "oidc@example.com", "email_verified": true, "name": "Oidc"}));
+    let mut router = setup(pool.clone(), &mock).await;
+
+    let response = sign_in(&mut router, &mock).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let session: SessionResponse = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+    assert_eq!(session.user.email, "oidc@example.com");
+
+    let response = get_uri(&mut router, "/auth/me", Some(&session.token)).await;
+    let me: User = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+    assert_eq!(me.id, session.user.id);
+
+    // signing in again reuses the linked user
+    let response = sign_in(&mut router, &mock).await;
+    let again: SessionResponse = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+    assert_eq!(again.user.id, session.user.id);
+    let links: i64 = sqlx::query_scalar("select count(*) from _external_auths").fetch_one(&pool).await.unwrap();
+    assert_eq!(links, 1);
+}
+
+#[sqlx::test]
+async fn test_callback_rejects_unknown_state(pool: PgPool) {
+    let mock = start_mock_provider(json!({"sub": "42"}));
+    let mut router = setup(pool, &mock).await;
+
+    let response = get_uri(&mut router, "/auth/oauth/mock/callback?code=good-code&state=forged", None).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(get_uri(&mut router, "/auth/oauth/nope", None).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(get_uri(&mut router, "/auth/me", None).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn test_expired_states_are_pruned(pool: PgPool) {
+    let mock = start_mock_provider(json!({"sub": "42", "email": "oidc@example.com", "email_verified": true}));
+    let mut router = setup(pool.clone(), &mock).await;
+    sqlx::query("insert into _oauth_states(state, provider, code_verifier, expires_at) values('abandoned', 'mock', 'v', now() - interval '1 minute')")
+        .execute(&pool).await.unwrap();
+
+    let cookie = "rocketbase_oauth_state=abandoned";
+    let response = get_with_cookie(&mut router, "/auth/oauth/mock/callback?code=good-code&state=abandoned", None, Some(cookie)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(sign_in(&mut router, &mock).await.status(), StatusCode::OK);
+    let states: i64 = sqlx::query_scalar("select count(*) from _oauth_states").fetch_one(&pool).await.unwrap();
+    assert_eq!(states, 0);
+}
+
+#[sqlx::test(fixtures("users"))]
+async fn test_unverified_email_does_not_take_over_account(pool: PgPool) {
+    let mock = start_mock_provider(json!({"sub": "7", "email": "email@email.com", "email_verified": false}));
+    let mut router = setup(pool, &mock).await;
+
+    assert_eq!(sign_in(&mut router, &mock).await.status(), StatusCode::CONFLICT);
+}
+
+#[sqlx::test]
+async fn test_callback_needs_the_browser_that_started_the_login(pool: PgPool) {
+    let mock = start_mock_provider(json!({"sub": "42", "email": "oidc@example.com", "email_verified": true}));
+    let mut router = setup(pool.clone(), &mock).await;
+
+    // a victim lured to the attacker's callback URL has no matching cookie
+    let (state, _) = start_sign_in(&mut router, &mock).await;
+    let uri = format!("/auth/oauth/mock/callback?code=good-code&state={}", state);
+    assert_eq!(get_uri(&mut router, &uri, None).await.status(), StatusCode::BAD_REQUEST);
+    let (_, other) = start_sign_in(&mut router, &mock).await;
+    assert_eq!(get_with_cookie(&mut router, &uri, None, Some(&other)).await.status(), StatusCode::BAD_REQUEST);
+    let users: i64 = sqlx::query_scalar("select count(*) from users").fetch_one(&pool).await.unwrap();
+    assert_eq!(users, 0);
+}
+
+#[sqlx::test]
+async fn test_linking_an_unverified_account_takes_away_its_credentials(pool: PgPool) {
+    let mock = start_mock_provider(json!({"sub": "7", "email": "victim@example.com", "email_verified": true}));
+    let mut router = setup(pool.clone(), &mock).await;
+    // someone else registered the address first, with a password of their own
+    let request = Request::post("/users")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({"name": "squatter", "email": "victim@example.com", "password": "stolen"}).to_string()))
+        .unwrap();
+    router.ready().await.unwrap().call(request).await.unwrap();
+    assert_eq!(login(&mut router, "victim@example.com", "stolen").await, StatusCode::OK);
+    let sessions: i64 = sqlx::query_scalar("select count(*) from _user_tokens where kind = 'session'").fetch_one(&pool).await.unwrap();
+    assert_eq!(sessions, 1);
+
+    assert_eq!(sign_in(&mut router, &mock).await.status(), StatusCode::OK);
+    assert_eq!(login(&mut router, "victim@example.com", "stolen").await, StatusCode::UNAUTHORIZED);
+    let sessions: i64 = sqlx::query_scalar("select count(*) from _user_tokens where kind = 'session'").fetch_one(&pool).await.unwrap();
+    assert_eq!(sessions, 1, "only the session of the provider login is left");
+}
+
+#[sqlx::test]
+async fn test_linking_a_verified_account_keeps_its_password(pool: PgPool) {
+    let mock = start_mock_provider(json!({"sub": "7", "email": "owner@example.com", "email_verified": true}));
+    let mut router = setup(pool.clone(), &mock).await;
+    let request = Request::post("/users")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({"name": "owner", "email": "owner@example.com", "password": "mine"}).to_string()))
+        .unwrap();
+    router.ready().await.unwrap().call(request).await.unwrap();
+    sqlx::query("update users set verified = true").execute(&pool).await.unwrap();
+
+    assert_eq!(sign_in(&mut router, &mock).await.status(), StatusCode::OK);
+    assert_eq!(login(&mut router, "owner@example.com", "mine").await, StatusCode::OK);
+}
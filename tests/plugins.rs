@@ -0,0 +1,194 @@
+use std::{
+    fs::File,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use http::StatusCode;
+use librocketbase::{settings::SETTINGS, Rocketbase};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+mod common;
+use common::{admin_session, request};
+
+/// A plugin in WebAssembly text, with `texts` laid out in its data section.
+/// `{N}` in `funcs` is replaced by the packed location of the Nth text, and
+/// `{N.ptr}` and `{N.len}` by its parts.
+fn plugin(texts: &[String], funcs: &str) -> String {
+    let mut data = String::new();
+    let mut funcs = funcs.to_string();
+    let mut offset = 0;
+    for (i, text) in texts.iter().enumerate() {
+        data.push_str(&format!("  (data (i32.const {}) \"{}\")\n", offset, text.replace('\\', "\\\\").replace('"', "\\\"")));
+        funcs = funcs
+            .replace(&format!("{{{}.ptr}}", i), &offset.to_string())
+            .replace(&format!("{{{}.len}}", i), &text.len().to_string())
+            .replace(&format!("{{{}}}", i), &(((offset as i64) << 32) | text.len() as i64).to_string());
+        offset += text.len();
+    }
+    format!(r#"(module
+  (import "rocketbase" "register_hook" (func $register_hook (param i32 i32)))
+  (import "rocketbase" "register_route" (func $register_route (param i32 i32)))
+  (import "rocketbase" "call" (func $call (param i32 i32) (result i64)))
+  (memory (export "memory") 1)
+  (global $heap (mut i32) (i32.const 8192))
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (global.get $heap) (local.get $len)))
+    (local.get $ptr))
+{}{})"#, data, funcs)
+}
+
+fn greeter(greeting: &str) -> String {
+    let texts = [
+        r#"{"event":"before_create","collection":"notes","handler":"stamp"}"#.to_string(),
+        r#"{"event":"before_delete","handler":"keep"}"#.to_string(),
+        r#"{"method":"GET","path":"/hello","handler":"hello"}"#.to_string(),
+        r#"{"method":"POST","path":"/notes","handler":"add"}"#.to_string(),
+        r#"{"method":"GET","path":"/spin","handler":"spin"}"#.to_string(),
+        r#"{"method":"GET","path":"/grow","handler":"grow"}"#.to_string(),
+        r#"{"method":"GET","path":"/huge","handler":"huge"}"#.to_string(),
+        r#"{"data":{"title":"stamped"}}"#.to_string(),
+        r#"{"reject":409,"message":"kept by greeter"}"#.to_string(),
+        json!({"status": 200, "body": greeting}).to_string(),
+        r#"{"op":"create","collection":"notes","data":{"title":"added"}}"#.to_string(),
+        r#""denied""#.to_string(),
+        r#""grown""#.to_string(),
+    ];
+    plugin(&texts, r#"
+  (func (export "init")
+    (call $register_hook (i32.const {0.ptr}) (i32.const {0.len}))
+    (call $register_hook (i32.const {1.ptr}) (i32.const {1.len}))
+    (call $register_route (i32.const {2.ptr}) (i32.const {2.len}))
+    (call $register_route (i32.const {3.ptr}) (i32.const {3.len}))
+    (call $register_route (i32.const {4.ptr}) (i32.const {4.len}))
+    (call $register_route (i32.const {5.ptr}) (i32.const {5.len}))
+    (call $register_route (i32.const {6.ptr}) (i32.const {6.len})))
+  (func (export "stamp") (param i32 i32) (result i64) (i64.const {7}))
+  (func (export "keep") (param i32 i32) (result i64) (i64.const {8}))
+  (func (export "hello") (param i32 i32) (result i64) (i64.const {9}))
+  (func (export "add") (param i32 i32) (result i64)
+    (call $call (i32.const {10.ptr}) (i32.const {10.len})))
+  (func (export "spin") (param i32 i32) (result i64)
+    (loop $forever (br $forever))
+    (unreachable))
+  (func (export "grow") (param i32 i32) (result i64)
+    (if (result i64) (i32.eq (memory.grow (i32.const 2000)) (i32.const -1))
+      (then (i64.const {11}))
+      (else (i64.const {12}))))
+  (func (export "huge") (param i32 i32) (result i64)
+    (i64.const 0x7fffffff))
+"#)
+}
+
+/// Writes a plugin, moving its modification time forward so that it is seen
+/// as changed even within the resolution of the file system.
+fn write_plugin(path: &PathBuf, source: &str, ahead_secs: u64) {
+    std::fs::write(path, source).unwrap();
+    let modified = SystemTime::now() + Duration::from_secs(ahead_secs);
+    File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+}
+
+#[sqlx::test]
+async fn test_plugins_register_hooks_and_routes_within_limits(pool: PgPool) {
+    let dir = std::env::temp_dir().join(format!("rocketbase-plugins-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("greeter.wat");
+    write_plugin(&path, &greeter("v1"), 1);
+    let mut settings = SETTINGS.clone();
+    settings.plugins.enabled = true;
+    settings.plugins.dir = dir.to_string_lossy().to_string();
+    settings.plugins.fuel_per_call = 1_000_000;
+    settings.plugins.max_memory_bytes = 4 << 20;
+    settings.plugins.reload_interval_ms = 60 * 60 * 1000;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).build().await.unwrap();
+    let host = rocketbase.plugins().unwrap();
+    let mut router = rocketbase.router();
+
+    let admin = admin_session(&mut router, &pool).await;
+    let notes = json!({
+        "name": "notes",
+        "column_defs": [
+            {"id": "0e1f2a3b-4c5d-4e6f-8a9b-0c1d2e3f4a01", "name": "title", "column_type": "Text", "required": true, "unique": false}
+        ]
+    });
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), notes).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // hooks
+    let (status, note) = request(&mut router, "POST", "/api/collections/notes/records", Some(&admin), json!({"title": "mine"})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(note["title"], "stamped");
+    let (status, body) = request(&mut router, "DELETE", &format!("/api/collections/notes/records/{}", note["id"]), Some(&admin), Value::Null).await;
+    assert_eq!((status, body), (StatusCode::CONFLICT, json!({"error": "kept by greeter"})));
+
+    // routes and the host API, which skips hooks
+    let (status, body) = request(&mut router, "GET", "/api/plugins/greeter/hello", None, Value::Null).await;
+    assert_eq!((status, body), (StatusCode::OK, json!("v1")));
+    let (status, body) = request(&mut router, "POST", "/api/plugins/greeter/notes", None, Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["ok"]["title"], "added");
+    let (status, _) = request(&mut router, "DELETE", "/api/plugins/greeter/hello", None, Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = request(&mut router, "GET", "/api/plugins/other/hello", None, Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // limits
+    let (status, _) = request(&mut router, "GET", "/api/plugins/greeter/spin", None, Value::Null).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    let (status, body) = request(&mut router, "GET", "/api/plugins/greeter/grow", None, Value::Null).await;
+    assert_eq!((status, body), (StatusCode::OK, json!("denied")));
+    let (status, _) = request(&mut router, "GET", "/api/plugins/greeter/huge", None, Value::Null).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    // reloading, keeping the running version when the new one is broken
+    write_plugin(&path, &greeter("v2"), 2);
+    host.reload().await.unwrap();
+    let (_, body) = request(&mut router, "GET", "/api/plugins/greeter/hello", None, Value::Null).await;
+    assert_eq!(body, json!("v2"));
+    write_plugin(&path, "(module", 3);
+    host.reload().await.unwrap();
+    let (_, body) = request(&mut router, "GET", "/api/plugins/greeter/hello", None, Value::Null).await;
+    assert_eq!(body, json!("v2"));
+    std::fs::remove_file(&path).unwrap();
+    host.reload().await.unwrap();
+    assert!(host.plugins().is_empty());
+    let (status, _) = request(&mut router, "GET", "/api/plugins/greeter/hello", None, Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = request(&mut router, "DELETE", &format!("/api/collections/notes/records/{}", note["id"]), Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[sqlx::test]
+async fn test_plugin_calls_time_out(pool: PgPool) {
+    let dir = std::env::temp_dir().join(format!("rocketbase-plugins-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    write_plugin(&dir.join("greeter.wat"), &greeter("v1"), 1);
+    let spinner = plugin(&[], r#"
+  (func (export "init")
+    (loop $forever (br $forever)))
+"#);
+    write_plugin(&dir.join("spinner.wat"), &spinner, 1);
+    let mut settings = SETTINGS.clone();
+    settings.plugins.enabled = true;
+    settings.plugins.dir = dir.to_string_lossy().to_string();
+    // enough fuel to spin for far longer than the test waits
+    settings.plugins.fuel_per_call = 1 << 50;
+    settings.plugins.call_timeout_ms = 200;
+    settings.plugins.reload_interval_ms = 60 * 60 * 1000;
+    let build = Rocketbase::builder().settings(settings).pool(pool).build();
+    let rocketbase = tokio::time::timeout(Duration::from_secs(5), build).await.expect("init was not given up").unwrap();
+    let names: Vec<_> = rocketbase.plugins().unwrap().plugins().iter().map(|plugin| plugin.name.clone()).collect();
+    assert_eq!(names, vec!["greeter".to_string()]);
+    let mut router = rocketbase.router();
+
+    let spin = request(&mut router, "GET", "/api/plugins/greeter/spin", None, Value::Null);
+    let (status, _) = tokio::time::timeout(Duration::from_secs(5), spin).await.expect("call was not given up");
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use axum::{body::Body, http::Request, Router};
+use http::{header, StatusCode};
+use librocketbase::{
+    app_state::AppState,
+    db::DB,
+    rate_limit::{MemoryStore, PostgresStore, RateLimiter, Store},
+    router::build_router,
+    settings::{Lockout, RateLimit, RateLimitStore, RateWindow},
+};
+use serde_json::json;
+use sqlx::PgPool;
+use tower::{Service, ServiceExt};
+
+fn config(ip_max: u32) -> RateLimit {
+    RateLimit {
+        enabled: true,
+        store: RateLimitStore::Memory,
+        trusted_proxies: 1,
+        ip: RateWindow { max_requests: ip_max, window_secs: 60 },
+        account: RateWindow { max_requests: 100, window_secs: 60 },
+        lockout: Lockout { threshold: 3, base_secs: 60, max_secs: 600 },
+    }
+}
+
+async fn setup(pool: PgPool, config: RateLimit, store: Arc<dyn Store>) -> Router {
+    let app_state = AppState::init_with_db(DB::new_with_pool(pool))
+        .with_rate_limiter(RateLimiter::new(config, store));
+    let mut router = build_router(app_state).await.unwrap();
+    login(&mut router, "10.0.0.1", "/users", "secret").await;
+    router
+}
+
+async fn login(router: &mut Router, ip: &str, uri: &str, password: &str) -> http::Response<axum::body::BoxBody> {
+    let body = json!({"name": "user", "email": "user@example.com", "password": password});
+    let request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .header("X-Forwarded-For", ip)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    router.ready().await.unwrap().call(request).await.unwrap()
+}
+
+async fn assert_locks_out_after_failures(mut router: Router) {
+    for _ in 0..3 {
+        let response = login(&mut router, "10.0.0.2", "/auth/login", "wrong").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    // even the right password is refused while the account is locked, from
+    // any address
+    let response = login(&mut router, "10.0.0.3", "/auth/login", "secret").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: i64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+}
+
+#[sqlx::test]
+async fn test_memory_store_locks_out_accounts(pool: PgPool) {
+    let router = setup(pool, config(100), Arc::new(MemoryStore::default())).await;
+    assert_locks_out_after_failures(router).await;
+}
+
+#[sqlx::test]
+async fn test_postgres_store_locks_out_accounts(pool: PgPool) {
+    let router = setup(pool.clone(), config(100), Arc::new(PostgresStore::new(pool.clone()))).await;
+    assert_locks_out_after_failures(router).await;
+    let locked: i64 = sqlx::query_scalar("select count(*) from _auth_failures where locked_until > now()")
+        .fetch_one(&pool).await.unwrap();
+    assert_eq!(locked, 1);
+}
+
+#[sqlx::test]
+async fn test_limits_requests_per_address(pool: PgPool) {
+    let mut router = setup(pool, config(2), Arc::new(MemoryStore::default())).await;
+
+    assert_eq!(login(&mut router, "10.0.0.2", "/auth/login", "secret").await.status(), StatusCode::OK);
+    assert_eq!(login(&mut router, "10.0.0.2", "/auth/login", "secret").await.status(), StatusCode::OK);
+    assert_eq!(login(&mut router, "10.0.0.2", "/auth/login", "secret").await.status(), StatusCode::TOO_MANY_REQUESTS);
+    // the client can prepend to the header, but not change what the proxy saw
+    assert_eq!(login(&mut router, "10.9.9.9, 10.0.0.2", "/auth/login", "secret").await.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(login(&mut router, "10.0.0.4", "/auth/login", "secret").await.status(), StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn test_postgres_store_prunes_expired_entries(pool: PgPool) {
+    sqlx::query("insert into _rate_limits(key, count, window_ends) values ('ip:old', 3, now() - interval '1 minute'), ('ip:new', 3, now() + interval '1 minute')")
+        .execute(&pool).await.unwrap();
+    sqlx::query("insert into _auth_failures(key, failures, locked_until, updated_at) values \
+        ('account:old', 2, null, now() - interval '2 days'), \
+        ('account:locked', 9, now() + interval '1 hour', now() - interval '2 days'), \
+        ('account:new', 2, null, now())")
+        .execute(&pool).await.unwrap();
+
+    let store = PostgresStore::new(pool.clone());
+    assert_eq!(store.prune().await.unwrap(), 2);
+    let limits: Vec<String> = sqlx::query_scalar("select key from _rate_limits").fetch_all(&pool).await.unwrap();
+    assert_eq!(limits, ["ip:new"]);
+    let mut failures: Vec<String> = sqlx::query_scalar("select key from _auth_failures").fetch_all(&pool).await.unwrap();
+    failures.sort();
+    assert_eq!(failures, ["account:locked", "account:new"]);
+}
@@ -0,0 +1,173 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    time::Duration,
+};
+
+use axum::{body::{Body, BoxBody, HttpBody}, http::Request, Router};
+use futures_util::{SinkExt, StreamExt};
+use http::StatusCode;
+use librocketbase::{
+    app_state::AppState,
+    db::DB,
+    realtime::{self, protocol::{ClientMessage, ServerMessage}},
+    router::build_router,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tower::{Service, ServiceExt};
+
+mod common;
+use common::{request, session_for, Auth};
+
+/// Reads server sent events until one named `name` arrives.
+async fn next_event(body: &mut BoxBody, name: &str) -> Value {
+    let mut buffer = String::new();
+    loop {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data()).await.expect("no event").unwrap().unwrap();
+        buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        while let Some(end) = buffer.find("\n\n") {
+            let event: String = buffer.drain(..end + 2).collect();
+            let mut lines = event.lines();
+            if lines.next() == Some(&format!("event:{}", name)) {
+                return serde_json::from_str(lines.next().unwrap().strip_prefix("data:").unwrap()).unwrap();
+            }
+        }
+    }
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn send(socket: &mut Socket, message: ClientMessage) {
+    socket.send(Message::Text(serde_json::to_string(&message).unwrap())).await.unwrap();
+}
+
+async fn receive(socket: &mut Socket) -> ServerMessage {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.expect("no message").unwrap().unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+/// Sets up a notes collection only visible to the owner of each note, and
+/// returns the sessions of an admin, alice and bob.
+async fn setup(router: &mut Router, pool: &PgPool) -> [Auth; 3] {
+    let admin = session_for(router, "admin@example.com").await;
+    let alice = session_for(router, "alice@example.com").await;
+    let bob = session_for(router, "bob@example.com").await;
+    sqlx::query("update users set admin = true where email = 'admin@example.com'").execute(pool).await.unwrap();
+    let notes = json!({
+        "name": "notes",
+        "column_defs": [
+            {"id": "0a5e3c6e-8c1f-4d3b-b1a2-3c4d5e6f7a01", "name": "text", "column_type": "Text", "required": true, "unique": false},
+            {"id": "0a5e3c6e-8c1f-4d3b-b1a2-3c4d5e6f7a02", "name": "owner", "column_type": "User", "required": true, "unique": false}
+        ],
+        "rules": {"list": {"owner": "owner"}, "view": {"owner": "owner"}, "create": {"owner": "owner"}, "update": {"owner": "owner"}, "delete": {"owner": "owner"}}
+    });
+    let (status, _) = request(router, "POST", "/api/collections", Some(&admin), notes).await;
+    assert_eq!(status, StatusCode::CREATED);
+    [admin, alice, bob]
+}
+
+#[sqlx::test]
+async fn test_streams_visible_changes(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let [admin, alice, bob] = setup(&mut router, &pool).await;
+
+    let (status, _) = request(&mut router, "GET", "/api/realtime?topics=notes&token=bogus", None, Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let subscribe = Request::get(format!("/api/realtime?topics=notes&token={}", alice.1.strip_prefix("Bearer ").unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    let response = router.ready().await.unwrap().call(subscribe).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut events = response.into_body();
+
+    // bob's note is not visible to alice and must not be streamed to her
+    request(&mut router, "POST", "/api/collections/notes/records", Some(&bob), json!({"text": "bob's"})).await;
+    let (_, note) = request(&mut router, "POST", "/api/collections/notes/records", Some(&alice), json!({"text": "alice's"})).await;
+    let event = next_event(&mut events, "create").await;
+    assert_eq!(event["action"], "create");
+    assert_eq!(event["collection"], "notes");
+    assert_eq!(event["record"], note);
+
+    let uri = format!("/api/collections/notes/records/{}", note["id"]);
+    request(&mut router, "DELETE", &uri, Some(&alice), Value::Null).await;
+    let event = next_event(&mut events, "delete").await;
+    assert_eq!(event["record"]["id"], note["id"]);
+
+    // rules changed while subscribed apply to the next change
+    let (_, mut notes) = request(&mut router, "GET", "/api/collections/notes", Some(&admin), Value::Null).await;
+    notes["rules"]["view"] = json!("admin");
+    let (status, _) = request(&mut router, "PATCH", "/api/collections/notes", Some(&admin), notes.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    request(&mut router, "POST", "/api/collections/notes/records", Some(&alice), json!({"text": "hidden"})).await;
+    notes["rules"]["view"] = json!("authenticated");
+    request(&mut router, "PATCH", "/api/collections/notes", Some(&admin), notes).await;
+    let (_, note) = request(&mut router, "POST", "/api/collections/notes/records", Some(&bob), json!({"text": "shared"})).await;
+    let event = next_event(&mut events, "create").await;
+    assert_eq!(event["record"], note);
+}
+
+#[sqlx::test]
+async fn test_multiplexes_subscriptions_over_websocket(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let [_, alice, bob] = setup(&mut router, &pool).await;
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = router.clone().into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(server));
+
+    let bob_token = bob.1.strip_prefix("Bearer ").unwrap().to_string();
+    let (mut socket, _) = connect_async(format!("ws://{}/api/realtime/ws?token={}", addr, bob_token)).await.unwrap();
+    send(&mut socket, ClientMessage::Ping).await;
+    assert_eq!(receive(&mut socket).await, ServerMessage::Pong);
+    send(&mut socket, ClientMessage::Subscribe { topics: vec!["notes".into()] }).await;
+    assert_eq!(receive(&mut socket).await, ServerMessage::Subscribed { topics: vec!["notes".into()] });
+
+    // changes arrive in order, so alice's note would come before bob's
+    request(&mut router, "POST", "/api/collections/notes/records", Some(&alice), json!({"text": "hidden"})).await;
+    let (_, note) = request(&mut router, "POST", "/api/collections/notes/records", Some(&bob), json!({"text": "bob's"})).await;
+    match receive(&mut socket).await {
+        ServerMessage::Event(event) => assert_eq!(event.record, note),
+        message => panic!("expected an event, got {:?}", message),
+    }
+
+    let token = alice.1.strip_prefix("Bearer ").unwrap().to_string();
+    send(&mut socket, ClientMessage::Auth { token }).await;
+    assert_eq!(receive(&mut socket).await, ServerMessage::Authenticated);
+    let (_, note) = request(&mut router, "POST", "/api/collections/notes/records", Some(&alice), json!({"text": "alice's"})).await;
+    match receive(&mut socket).await {
+        ServerMessage::Event(event) => assert_eq!(event.record, note),
+        message => panic!("expected an event, got {:?}", message),
+    }
+
+    send(&mut socket, ClientMessage::Unsubscribe { topics: vec!["notes".into()] }).await;
+    assert_eq!(receive(&mut socket).await, ServerMessage::Unsubscribed { topics: vec!["notes".into()] });
+    request(&mut router, "POST", "/api/collections/notes/records", Some(&alice), json!({"text": "unheard"})).await;
+    send(&mut socket, ClientMessage::Auth { token: "bogus".into() }).await;
+    assert!(matches!(receive(&mut socket).await, ServerMessage::Error { .. }));
+}
+
+#[sqlx::test]
+async fn test_ends_streams_of_lagging_clients(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let [admin, ..] = setup(&mut router, &pool).await;
+    let subscribe = Request::get(format!("/api/realtime?topics=notes&token={}", admin.1.strip_prefix("Bearer ").unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    let mut events = router.ready().await.unwrap().call(subscribe).await.unwrap().into_body();
+
+    // more changes than are buffered for a client that does not read
+    let count = realtime::CAPACITY * 2;
+    sqlx::query("insert into notes(text, owner) select 'note', (select id from users limit 1) from generate_series(1, $1)")
+        .bind(count as i32)
+        .execute(&pool).await.unwrap();
+    let lagged = next_event(&mut events, "lagged").await;
+    assert!(lagged["missed"].as_u64().unwrap() > 0);
+    let end = tokio::time::timeout(Duration::from_secs(5), events.data()).await.expect("stream did not end");
+    assert!(end.is_none());
+}
@@ -0,0 +1,94 @@
+use http::StatusCode;
+use librocketbase::{app_state::AppState, auth::API_KEY_HEADER, db::DB, router::build_router};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+mod common;
+use common::{request, session_for};
+
+fn posts() -> Value {
+    json!({
+        "name": "posts",
+        "column_defs": [
+            {"id": "6b6f1b1e-5a43-4c8e-9a3e-0f7d1c1b2a01", "name": "title", "column_type": "Text", "required": true, "unique": false},
+            {"id": "6b6f1b1e-5a43-4c8e-9a3e-0f7d1c1b2a02", "name": "author", "column_type": "User", "required": true, "unique": false}
+        ],
+        "rules": {"list": "public", "view": "public", "create": {"owner": "author"}, "update": {"owner": "author"}, "delete": {"owner": "author"}}
+    })
+}
+
+#[sqlx::test]
+async fn test_admins_manage_collections(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let admin = session_for(&mut router, "admin@example.com").await;
+    let user = session_for(&mut router, "user@example.com").await;
+    sqlx::query("update users set admin = true where email = 'admin@example.com'").execute(&pool).await.unwrap();
+
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&user), posts()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), posts()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), posts()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let mut invalid = posts();
+    invalid["name"] = json!("posts; drop table users");
+    let (status, _) = request(&mut router, "POST", "/api/collections", Some(&admin), invalid).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let mut updated = posts();
+    updated["column_defs"].as_array_mut().unwrap().push(
+        json!({"id": "6b6f1b1e-5a43-4c8e-9a3e-0f7d1c1b2a03", "name": "body", "column_type": "Text", "required": false, "unique": false})
+    );
+    let (status, _) = request(&mut router, "PATCH", "/api/collections/posts", Some(&admin), updated).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, collection) = request(&mut router, "GET", "/api/collections/posts", Some(&admin), Value::Null).await;
+    assert_eq!(collection["column_defs"].as_array().unwrap().len(), 3);
+}
+
+#[sqlx::test]
+async fn test_records_follow_access_rules(pool: PgPool) {
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let admin = session_for(&mut router, "admin@example.com").await;
+    let alice = session_for(&mut router, "alice@example.com").await;
+    let bob = session_for(&mut router, "bob@example.com").await;
+    sqlx::query("update users set admin = true where email = 'admin@example.com'").execute(&pool).await.unwrap();
+    request(&mut router, "POST", "/api/collections", Some(&admin), posts()).await;
+
+    let (status, _) = request(&mut router, "POST", "/api/collections/posts/records", None, json!({"title": "anonymous"})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, post) = request(&mut router, "POST", "/api/collections/posts/records", Some(&alice), json!({"title": "hello"})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(post["title"], "hello");
+    assert!(post["author"].is_i64());
+    let uri = format!("/api/collections/posts/records/{}", post["id"]);
+
+    let (status, listed) = request(&mut router, "GET", "/api/collections/posts/records", None, Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    let (status, _) = request(&mut router, "PATCH", &uri, Some(&bob), json!({"title": "mine now"})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    // owners can not hand records over to someone else
+    let (status, _) = request(&mut router, "PATCH", &uri, Some(&alice), json!({"author": post["author"].as_i64().unwrap() + 1})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, updated) = request(&mut router, "PATCH", &uri, Some(&alice), json!({"title": "hello again"})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["title"], "hello again");
+    assert!(updated["updated_at"].is_string());
+
+    let (status, _) = request(&mut router, "DELETE", &uri, Some(&bob), Value::Null).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = request(&mut router, "DELETE", &uri, Some(&alice), Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = request(&mut router, "GET", &uri, None, Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // API keys are limited to their scopes
+    let new_key = json!({"name": "reader", "scopes": [{"collection": "posts", "operations": ["list"]}]});
+    let (_, created) = request(&mut router, "POST", "/admin/api-keys", Some(&admin), new_key).await;
+    let key = (API_KEY_HEADER, created["key"].as_str().unwrap().to_string());
+    let (status, _) = request(&mut router, "GET", "/api/collections/posts/records", Some(&key), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = request(&mut router, "POST", "/api/collections/posts/records", Some(&key), json!({"title": "bot", "author": 1})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,118 @@
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{routing::get, Router};
+use futures_util::StreamExt;
+use librocketbase::{
+    queue::{TaskPayload, Workers},
+    settings::{Settings, SETTINGS},
+    Rocketbase,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+mod common;
+use common::wait_until;
+
+#[derive(Serialize, Deserialize)]
+struct Slow;
+
+impl TaskPayload for Slow {
+    const KIND: &'static str = "slow";
+}
+
+/// Settings for serving on a free port.
+fn settings(drain_timeout_secs: u64) -> (Settings, SocketAddr) {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut settings = SETTINGS.clone();
+    settings.host = addr.ip().to_string();
+    settings.port = addr.port() as i32;
+    settings.plugins.enabled = false;
+    settings.queue.poll_interval_ms = 20;
+    settings.shutdown.drain_timeout_secs = drain_timeout_secs;
+    (settings, addr)
+}
+
+#[sqlx::test]
+async fn test_shutdown_drains_requests_and_background_tasks(pool: PgPool) {
+    let (started, finished) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+    let (task_started, task_finished) = (started.clone(), finished.clone());
+    let workers = Workers::new().handle(move |_: Slow, _| {
+        let (started, finished) = (task_started.clone(), task_finished.clone());
+        async move {
+            started.store(true, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            finished.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    });
+    let slow = Router::new().route("/slow", get(|| async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        "done"
+    }));
+    let (settings, addr) = settings(5);
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).workers(workers).routes("/", slow).build().await.unwrap();
+    let state = rocketbase.state().clone();
+    let server = tokio::spawn(rocketbase.serve_until_shutdown());
+    wait_until(|| TcpStream::connect(addr).is_ok()).await;
+
+    let (mut socket, _) = connect_async(format!("ws://{}/api/realtime/ws", addr)).await.unwrap();
+    state.db().enqueue(&Slow).await.unwrap();
+    wait_until(|| started.load(Ordering::SeqCst)).await;
+    let response = tokio::spawn(hyper::Client::new().get(format!("http://{}/slow", addr).parse().unwrap()));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    state.shutdown().start();
+
+    match socket.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 1001),
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+    let response = response.await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "done");
+    server.await.unwrap().unwrap();
+    assert!(finished.load(Ordering::SeqCst));
+    assert!(pool.is_closed());
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[sqlx::test]
+async fn test_shutdown_drops_connections_after_the_drain_timeout(pool: PgPool) {
+    let stuck = Router::new().route("/stuck", get(|| async {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        "done"
+    }));
+    let started = Arc::new(AtomicBool::new(false));
+    let task_started = started.clone();
+    let workers = Workers::new().handle(move |_: Slow, _| {
+        task_started.store(true, Ordering::SeqCst);
+        async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }
+    });
+    let (settings, addr) = settings(1);
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).workers(workers).routes("/", stuck).build().await.unwrap();
+    let state = rocketbase.state().clone();
+    let server = tokio::spawn(rocketbase.serve_until_shutdown());
+    wait_until(|| TcpStream::connect(addr).is_ok()).await;
+
+    state.db().enqueue(&Slow).await.unwrap();
+    wait_until(|| started.load(Ordering::SeqCst)).await;
+    let response = tokio::spawn(hyper::Client::new().get(format!("http://{}/stuck", addr).parse().unwrap()));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let start = Instant::now();
+    state.shutdown().start();
+    server.await.unwrap().unwrap();
+    // connections and background tasks share the one drain timeout
+    assert!(start.elapsed() < Duration::from_millis(1800), "{:?}", start.elapsed());
+    assert!(response.await.unwrap().is_err());
+    assert!(pool.is_closed());
+}
@@ -0,0 +1,213 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use color_eyre::eyre::eyre;
+use http::StatusCode;
+use librocketbase::{
+    model::{task::TaskStatus, Task},
+    queue::{TaskPayload, Workers},
+    settings::SETTINGS,
+    Rocketbase,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+mod common;
+use common::{admin_session, request, wait_until};
+
+#[derive(Serialize, Deserialize)]
+struct Greet {
+    name: String,
+}
+
+impl TaskPayload for Greet {
+    const KIND: &'static str = "greet";
+}
+
+#[derive(Serialize, Deserialize)]
+struct Flaky {
+    failures: i32,
+}
+
+impl TaskPayload for Flaky {
+    const KIND: &'static str = "flaky";
+}
+
+#[derive(Serialize, Deserialize)]
+struct Slow;
+
+impl TaskPayload for Slow {
+    const KIND: &'static str = "slow";
+}
+
+#[sqlx::test]
+async fn test_workers_retry_tasks_until_they_are_dead(pool: PgPool) {
+    let greeted: Arc<Mutex<Vec<String>>> = Arc::default();
+    let (running, most_running, slow_done) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let log = greeted.clone();
+    let (now, most, done) = (running.clone(), most_running.clone(), slow_done.clone());
+    let workers = Workers::new()
+        .handle(move |greet: Greet, _| {
+            log.lock().unwrap().push(greet.name);
+            async { Ok(()) }
+        })
+        .handle(|flaky: Flaky, context| async move {
+            match context.attempts > flaky.failures {
+                true => Ok(()),
+                false => Err(eyre!("attempt {} failed", context.attempts)),
+            }
+        })
+        .handle(move |_: Slow, _| {
+            let (now, most, done) = (now.clone(), most.clone(), done.clone());
+            async move {
+                most.fetch_max(now.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                now.fetch_sub(1, Ordering::SeqCst);
+                done.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+    let mut settings = SETTINGS.clone();
+    settings.queue.concurrency = 2;
+    settings.queue.max_attempts = 3;
+    settings.queue.retry_base_ms = 10;
+    settings.queue.poll_interval_ms = 20;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).workers(workers).build().await.unwrap();
+    let db = rocketbase.state().db();
+    let mut router = rocketbase.router();
+
+    let admin = admin_session(&mut router, &pool).await;
+
+    db.enqueue(&Greet { name: "ada".to_string() }).await.unwrap();
+    let recovers = db.enqueue(&Flaky { failures: 2 }).await.unwrap();
+    let doomed = db.enqueue(&Flaky { failures: 5 }).await.unwrap();
+    let malformed = Task::enqueue(&pool, "greet", &json!({"nom": "bob"}), None).await.unwrap();
+    for _ in 0..5 {
+        db.enqueue(&Slow).await.unwrap();
+    }
+
+    wait_until(|| slow_done.load(Ordering::SeqCst) == 5).await;
+    assert_eq!(slow_done.load(Ordering::SeqCst), 5);
+    assert_eq!(most_running.load(Ordering::SeqCst), 2);
+    assert_eq!(*greeted.lock().unwrap(), vec!["ada".to_string()]);
+
+    let mut dead = Value::Null;
+    for _ in 0..100 {
+        (_, dead) = request(&mut router, "GET", "/admin/tasks?status=dead", Some(&admin), Value::Null).await;
+        if dead.as_array().unwrap().len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let dead = dead.as_array().unwrap();
+    assert_eq!(dead.len(), 2);
+    assert_eq!(dead[0]["id"], malformed.id);
+    assert_eq!(dead[0]["attempts"], 1);
+    assert!(dead[0]["last_error"].as_str().unwrap().starts_with("Invalid payload"));
+    assert_eq!(dead[1]["id"], doomed.id);
+    assert_eq!(dead[1]["attempts"], 3);
+    assert_eq!(dead[1]["last_error"], "attempt 3 failed");
+    // done tasks are gone
+    let (status, _) = request(&mut router, "GET", &format!("/admin/tasks/{}", recovers.id), Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let uri = format!("/admin/tasks/{}/retry", doomed.id);
+    let (status, _) = request(&mut router, "POST", &uri, None, Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, task) = request(&mut router, "POST", &uri, Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(task["status"], "pending");
+    assert_eq!(task["attempts"], 0);
+    let (status, _) = request(&mut router, "POST", &uri, Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = request(&mut router, "POST", "/admin/tasks/0/retry", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, tasks) = request(&mut router, "GET", "/admin/tasks?kind=greet", Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tasks.as_array().unwrap().len(), 1);
+}
+
+#[sqlx::test]
+async fn test_workers_renew_leases_of_long_tasks(pool: PgPool) {
+    let (started, finished) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let (start, finish) = (started.clone(), finished.clone());
+    let workers = Workers::new().handle(move |_: Slow, _| {
+        let (start, finish) = (start.clone(), finish.clone());
+        async move {
+            start.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(2500)).await;
+            finish.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    });
+    let mut settings = SETTINGS.clone();
+    settings.queue.lease_secs = 1;
+    settings.queue.poll_interval_ms = 20;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).workers(workers).build().await.unwrap();
+    let task = rocketbase.state().db().enqueue(&Slow).await.unwrap();
+
+    for _ in 0..100 {
+        if finished.load(Ordering::SeqCst) > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(finished.load(Ordering::SeqCst), 1);
+    // outlived its first lease without being taken over
+    assert_eq!(started.load(Ordering::SeqCst), 1);
+    let mut done = false;
+    for _ in 0..100 {
+        if Task::find(&pool, task.id).await.unwrap().is_none() {
+            done = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(done);
+}
+
+#[sqlx::test]
+async fn test_tasks_out_of_attempts_are_dead_and_pruned(pool: PgPool) {
+    let started = Arc::new(AtomicUsize::new(0));
+    let start = started.clone();
+    let workers = Workers::new().handle(move |_: Slow, _| {
+        start.fetch_add(1, Ordering::SeqCst);
+        async { Ok(()) }
+    });
+    let mut settings = SETTINGS.clone();
+    settings.queue.max_attempts = 3;
+    settings.queue.poll_interval_ms = 20;
+    let _rocketbase = Rocketbase::builder().settings(settings).pool(pool.clone()).workers(workers).build().await.unwrap();
+
+    // tasks of workers that died during the last and an earlier attempt
+    let expired = |attempts: i32| {
+        sqlx::query_scalar::<_, i64>("insert into _tasks(kind, payload, status, attempts, locked_until) \
+            values('slow', 'null', 'running', $1, now() - interval '1 second') returning id")
+            .bind(attempts)
+            .fetch_one(&pool)
+    };
+    let (last, earlier) = (expired(3).await.unwrap(), expired(1).await.unwrap());
+    for _ in 0..100 {
+        if Task::find(&pool, earlier).await.unwrap().is_none() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(Task::find(&pool, earlier).await.unwrap().is_none());
+    assert_eq!(started.load(Ordering::SeqCst), 1);
+    let dead = Task::find(&pool, last).await.unwrap().unwrap();
+    assert_eq!(dead.status, TaskStatus::Dead);
+    assert_eq!(dead.attempts, 3);
+    assert_eq!(dead.last_error.as_deref(), Some("Lease ran out during the last attempt"));
+
+    assert_eq!(Task::prune(&pool, chrono::Duration::hours(1)).await.unwrap(), 0);
+    sqlx::query("update _tasks set updated_at = now() - interval '2 hours' where id = $1").bind(last).execute(&pool).await.unwrap();
+    assert_eq!(Task::prune(&pool, chrono::Duration::hours(1)).await.unwrap(), 1);
+    assert!(Task::find(&pool, last).await.unwrap().is_none());
+}
@@ -0,0 +1,88 @@
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use axum::{body::Body, http::Request};
+use http::StatusCode;
+use librocketbase::{
+    settings::{self, SETTINGS},
+    telemetry, Rocketbase,
+};
+use opentelemetry_proto::tonic::{
+    collector::trace::v1::{
+        trace_service_server::{TraceService, TraceServiceServer},
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    },
+    trace::v1::{span::SpanKind, Span},
+};
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+const PARENT_ID: &str = "b7ad6b7169203331";
+
+/// Stands in for a collector, handing over the spans it is sent.
+struct Collector(mpsc::UnboundedSender<Vec<Span>>);
+
+#[tonic::async_trait]
+impl TraceService for Collector {
+    async fn export(&self, request: tonic::Request<ExportTraceServiceRequest>) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+        let spans = request.into_inner().resource_spans.into_iter()
+            .flat_map(|resource| resource.scope_spans)
+            .flat_map(|scope| scope.spans)
+            .collect();
+        let _ = self.0.send(spans);
+        Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+    }
+}
+
+#[sqlx::test]
+async fn test_exports_request_and_query_spans(pool: PgPool) {
+    let addr: SocketAddr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (sender, mut received) = mpsc::unbounded_channel();
+    tokio::spawn(tonic::transport::Server::builder().add_service(TraceServiceServer::new(Collector(sender))).serve(addr));
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let config = settings::Otlp { endpoint: format!("http://{}", addr), service_name: "rocketbase-test".to_string() };
+    tracing_subscriber::registry().with(telemetry::layer(&config, "info").unwrap()).try_init().unwrap();
+
+    let mut settings = SETTINGS.clone();
+    settings.plugins.enabled = false;
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool).build().await.unwrap();
+    let request = Request::get("/readyz?token=secret-token")
+        .header("traceparent", format!("00-{}-{}-01", TRACE_ID, PARENT_ID))
+        .body(Body::empty())
+        .unwrap();
+    let response = rocketbase.router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // the request span ends with its body
+    hyper::body::to_bytes(response.into_body()).await.unwrap();
+    tokio::task::spawn_blocking(telemetry::shutdown).await.unwrap();
+
+    let mut spans = Vec::new();
+    while let Ok(batch) = received.try_recv() {
+        spans.extend(batch);
+    }
+    let request = spans.iter().find(|span| span.name == "GET /readyz").expect("no request span");
+    assert_eq!(hex::encode(&request.trace_id), TRACE_ID);
+    assert_eq!(hex::encode(&request.parent_span_id), PARENT_ID);
+    assert_eq!(request.kind, SpanKind::Server as i32);
+    // the query string, which may hold tokens, is left out
+    let attributes = format!("{:?}", request.attributes);
+    assert!(attributes.contains("/readyz") && !attributes.contains("secret-token"), "{}", attributes);
+    // queries are their own spans, not events on the request
+    assert!(!format!("{:?}", request.events).contains("rows returned"), "{:?}", request.events);
+    let query = spans.iter()
+        .find(|span| span.parent_span_id == request.span_id && format!("{:?}", span.attributes).contains("select 1"))
+        .expect("no query span");
+    assert_eq!(query.name, "SELECT");
+    assert_eq!(query.trace_id, request.trace_id);
+    assert_eq!(query.kind, SpanKind::Client as i32);
+    assert!(format!("{:?}", query.attributes).contains("postgresql"));
+    assert!(query.start_time_unix_nano <= query.end_time_unix_nano);
+}
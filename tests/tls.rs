@@ -0,0 +1,119 @@
+use std::{
+    fs::File,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{routing::get, Router};
+use http::{header, Request, StatusCode, Version};
+use hyper::Body;
+use librocketbase::{
+    settings::{self, SETTINGS},
+    Rocketbase,
+};
+use sqlx::PgPool;
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Writes a self-signed certificate for localhost, returning it in DER.
+fn write_certificate(dir: &Path, modified: SystemTime) -> Vec<u8> {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = certificate.serialize_pem().unwrap();
+    for (name, pem) in [("cert.pem", cert.clone()), ("key.pem", certificate.serialize_private_key_pem())] {
+        std::fs::write(dir.join(name), pem).unwrap();
+        File::options().write(true).open(dir.join(name)).unwrap().set_modified(modified).unwrap();
+    }
+    // every serialization is signed anew, so the DER is read back
+    rustls_pemfile::certs(&mut cert.as_bytes()).unwrap().remove(0)
+}
+
+/// Connects over TLS trusting `trusted`, returning the certificate the server
+/// presented and the protocol it picked.
+async fn connect(addr: SocketAddr, trusted: &[&[u8]]) -> (tokio_rustls::client::TlsStream<tokio::net::TcpStream>, Vec<u8>, Option<Vec<u8>>) {
+    let mut roots = RootCertStore::empty();
+    for der in trusted {
+        roots.add(&Certificate(der.to_vec())).unwrap();
+    }
+    let mut config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap();
+    let (_, connection) = stream.get_ref();
+    let presented = connection.peer_certificates().unwrap()[0].0.clone();
+    let protocol = connection.alpn_protocol().map(<[u8]>::to_vec);
+    (stream, presented, protocol)
+}
+
+async fn wait_until_listening(addr: SocketAddr) {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[sqlx::test]
+async fn test_serves_https_and_reloads_certificates(pool: PgPool) {
+    let dir = std::env::temp_dir().join(format!("rocketbase-tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let first = write_certificate(&dir, SystemTime::now() - Duration::from_secs(60));
+    let (port, redirect_port) = (free_port(), free_port());
+    let mut settings = SETTINGS.clone();
+    settings.host = "127.0.0.1".to_string();
+    settings.port = port as i32;
+    settings.plugins.enabled = false;
+    settings.tls = Some(settings::Tls {
+        cert_path: dir.join("cert.pem").to_string_lossy().into_owned(),
+        key_path: dir.join("key.pem").to_string_lossy().into_owned(),
+        redirect_port: Some(redirect_port),
+        reload_interval_ms: 50,
+    });
+    let hello = Router::new().route("/hello", get(|| async { "hello" }));
+    let rocketbase = Rocketbase::builder().settings(settings).pool(pool).routes("/", hello).build().await.unwrap();
+    let state = rocketbase.state().clone();
+    let server = tokio::spawn(rocketbase.serve_until_shutdown());
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    wait_until_listening(addr).await;
+
+    let (stream, presented, protocol) = connect(addr, &[&first]).await;
+    assert_eq!(presented, first);
+    assert_eq!(protocol.as_deref(), Some(&b"h2"[..]));
+    let (mut sender, connection) = hyper::client::conn::Builder::new().http2_only(true).handshake::<_, Body>(stream).await.unwrap();
+    tokio::spawn(connection);
+    let response = sender.send_request(Request::get("https://localhost/hello").body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), Version::HTTP_2);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "hello");
+
+    let second = write_certificate(&dir, SystemTime::now());
+    let mut presented = first.clone();
+    for _ in 0..100 {
+        (_, presented, _) = connect(addr, &[&first, &second]).await;
+        if presented == second {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(presented, second);
+
+    let redirect = Request::get(format!("http://127.0.0.1:{}/hello?x=1", redirect_port))
+        .header(header::HOST, format!("localhost:{}", redirect_port))
+        .body(Body::empty())
+        .unwrap();
+    let response = hyper::Client::new().request(redirect).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(response.headers()[header::LOCATION], format!("https://localhost:{}/hello?x=1", port));
+
+    state.shutdown().start();
+    server.await.unwrap().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,155 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::HeaderMap,
+    routing::post,
+    Router,
+};
+use http::StatusCode;
+use librocketbase::{
+    app_state::AppState,
+    db::DB,
+    model::Delivery,
+    queue::Workers,
+    router::build_router,
+    settings::{self, SETTINGS},
+    webhooks::{self, DELIVERY_HEADER, EVENT_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+
+mod common;
+use common::{admin_session, request, Auth};
+
+/// Requests received by the stand-in endpoint.
+type Received = Arc<Mutex<Vec<(String, HeaderMap, Bytes)>>>;
+
+/// Fails the first request to `/hook` and every request to `/fail`.
+async fn endpoint(State(received): State<Received>, Path(path): Path<String>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let mut received = received.lock().unwrap();
+    let first = !received.iter().any(|(seen, _, _)| seen == &path);
+    received.push((path.clone(), headers, body));
+    match (path.as_str(), first) {
+        ("hook", false) => StatusCode::OK,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Waits for the delivery log of a webhook to satisfy `done`.
+async fn wait_for_deliveries(router: &mut Router, admin: &Auth, webhook: &Value, done: impl Fn(&[Value]) -> bool) -> Vec<Value> {
+    let uri = format!("/admin/webhooks/{}/deliveries", webhook["id"]);
+    for _ in 0..200 {
+        let (_, deliveries) = request(router, "GET", &uri, Some(admin), Value::Null).await;
+        let deliveries = deliveries.as_array().cloned().unwrap_or_default();
+        if done(&deliveries) {
+            return deliveries;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("deliveries of webhook {} did not settle", webhook["id"]);
+}
+
+#[sqlx::test]
+async fn test_webhooks_retry_sign_and_redeliver(pool: PgPool) {
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let received = Received::default();
+    let app = Router::new().route("/:path", post(endpoint)).with_state(received.clone());
+    tokio::spawn(async move { axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()).await.unwrap() });
+
+    let mut router = build_router(AppState::init_with_db(DB::new_with_pool(pool.clone()))).await.unwrap();
+    let admin = admin_session(&mut router, &pool).await;
+    let orders = json!({
+        "name": "orders",
+        "column_defs": [
+            {"id": "5d1c2b3a-4e5f-4a6b-8c7d-9e0f1a2b3c01", "name": "title", "column_type": "Text", "required": true, "unique": false}
+        ]
+    });
+    request(&mut router, "POST", "/api/collections", Some(&admin), orders).await;
+
+    let (status, _) = request(&mut router, "POST", "/admin/webhooks", None, json!({"collection": "orders", "events": ["create"], "url": base_url})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let invalid = [
+        json!({"collection": "orders", "events": ["create"], "url": "ftp://example.com"}),
+        json!({"collection": "orders", "events": [], "url": format!("{}/hook", base_url)}),
+        json!({"collection": "missing", "events": ["create"], "url": format!("{}/hook", base_url)}),
+    ];
+    for payload in invalid {
+        let (status, _) = request(&mut router, "POST", "/admin/webhooks", Some(&admin), payload).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let new_hook = json!({"collection": "orders", "events": ["create", "delete"], "url": format!("{}/hook", base_url), "secret": "s3cret"});
+    let (status, created) = request(&mut router, "POST", "/admin/webhooks", Some(&admin), new_hook).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["secret"], "s3cret");
+    let hook = created["webhook"].clone();
+    assert!(hook.get("secret").is_none());
+    let (_, created) = request(&mut router, "POST", "/admin/webhooks", Some(&admin), json!({"collection": "orders", "events": ["create"], "url": format!("{}/fail", base_url)})).await;
+    let failing = created["webhook"].clone();
+
+    let (_, order) = request(&mut router, "POST", "/api/collections/orders/records", Some(&admin), json!({"title": "first"})).await;
+    // not subscribed to updates
+    request(&mut router, "PATCH", &format!("/api/collections/orders/records/{}", order["id"]), Some(&admin), json!({"title": "changed"})).await;
+
+    let mut queue = SETTINGS.queue;
+    queue.max_attempts = 3;
+    queue.retry_base_ms = 20;
+    queue.poll_interval_ms = 20;
+    let workers = webhooks::handle_deliveries(Workers::new(), settings::Webhooks { timeout_secs: 5, ..SETTINGS.webhooks });
+    let shutdown = CancellationToken::new();
+    let dispatcher = Arc::new(workers).spawn(pool.clone(), queue, shutdown.clone());
+
+    let deliveries = wait_for_deliveries(&mut router, &admin, &hook, |deliveries| deliveries.first().is_some_and(|d| d["status"] == "delivered")).await;
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0]["attempts"], 2);
+    assert_eq!(deliveries[0]["response_status"], 200);
+    let deliveries = wait_for_deliveries(&mut router, &admin, &failing, |deliveries| deliveries.first().is_some_and(|d| d["status"] == "failed")).await;
+    assert_eq!(deliveries[0]["attempts"], 3);
+    assert_eq!(deliveries[0]["response_status"], 500);
+
+    {
+        let received = received.lock().unwrap();
+        let hook_requests: Vec<_> = received.iter().filter(|(path, _, _)| path == "hook").collect();
+        assert_eq!(hook_requests.len(), 2);
+        let (_, headers, body) = hook_requests[1];
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert_eq!(headers[SIGNATURE_HEADER], webhooks::signature("s3cret", timestamp, body).as_str());
+        assert_eq!(headers[EVENT_HEADER], "create");
+        // retries are the same delivery
+        assert_eq!(headers[DELIVERY_HEADER], hook_requests[0].1[DELIVERY_HEADER]);
+        let payload: Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload["collection"], "orders");
+        assert_eq!(payload["record"]["title"], "first");
+        assert_eq!(received.iter().filter(|(path, _, _)| path == "fail").count(), 3);
+    }
+
+    let (_, log) = request(&mut router, "GET", &format!("/admin/webhooks/{}/deliveries", hook["id"]), Some(&admin), Value::Null).await;
+    let uri = format!("/admin/webhooks/{}/deliveries/{}/redeliver", hook["id"], log[0]["id"]);
+    let (status, redelivery) = request(&mut router, "POST", &uri, Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(redelivery["status"], "pending");
+    let (status, _) = request(&mut router, "POST", &format!("/admin/webhooks/{}/deliveries/{}/redeliver", failing["id"], log[0]["id"]), Some(&admin), Value::Null).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    request(&mut router, "DELETE", &format!("/api/collections/orders/records/{}", order["id"]), Some(&admin), Value::Null).await;
+    let deliveries = wait_for_deliveries(&mut router, &admin, &hook, |deliveries| {
+        deliveries.len() == 3 && deliveries.iter().all(|d| d["status"] == "delivered")
+    }).await;
+    assert_eq!(deliveries[0]["event"], "delete");
+    assert_eq!(deliveries[1]["payload"], deliveries[2]["payload"]);
+    shutdown.cancel();
+    dispatcher.await.unwrap();
+
+    // finished deliveries are pruned once past their retention
+    request(&mut router, "POST", &uri, Some(&admin), Value::Null).await;
+    assert_eq!(Delivery::prune(&pool, chrono::Duration::days(1)).await.unwrap(), 0);
+    assert_eq!(Delivery::prune(&pool, chrono::Duration::zero()).await.unwrap(), 4);
+    let deliveries = wait_for_deliveries(&mut router, &admin, &hook, |deliveries| deliveries.len() == 1).await;
+    assert_eq!(deliveries[0]["status"], "pending");
+}